@@ -0,0 +1,180 @@
+//! Run weval from a Cargo build script.
+//!
+//! Interpreter projects that ship a wevaled Wasm artifact can produce
+//! it as part of `cargo build`:
+//!
+//! ```no_run
+//! // build.rs
+//! fn main() -> Result<(), weval_build::Error> {
+//!     let out = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+//!     weval_build::Weval::new("interp.wasm", out.join("interp.wevaled.wasm"))
+//!         .wizen(true)
+//!         .preopen("scripts")
+//!         .run()
+//! }
+//! ```
+//!
+//! This runs the `weval` command-line tool: the one named by the
+//! `WEVAL_BIN` environment variable, or else `weval` on `PATH` (as
+//! installed by `cargo install weval`). Cargo is told to rerun the
+//! build script when the input module, any other file or directory
+//! passed to weval, or `WEVAL_BIN` changes. When weval fails, the
+//! error carries its output, so it shows up in Cargo's.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The environment variable naming the weval binary to run.
+pub const WEVAL_BIN: &str = "WEVAL_BIN";
+
+/// One weval invocation, from an input module to an output module.
+#[derive(Clone, Debug)]
+pub struct Weval {
+    input: PathBuf,
+    output: PathBuf,
+    wizen: bool,
+    args: Vec<OsString>,
+    depends_on: Vec<PathBuf>,
+    emit_rerun_if_changed: bool,
+}
+
+impl Weval {
+    /// Specialize `input` into `output`.
+    pub fn new(input: impl Into<PathBuf>, output: impl Into<PathBuf>) -> Weval {
+        Weval {
+            input: input.into(),
+            output: output.into(),
+            wizen: false,
+            args: vec![],
+            depends_on: vec![],
+            emit_rerun_if_changed: true,
+        }
+    }
+
+    /// Whether to Wizen the input first (`-w`).
+    pub fn wizen(mut self, wizen: bool) -> Weval {
+        self.wizen = wizen;
+        self
+    }
+
+    /// Make a host directory available to the guest while Wizening
+    /// (`--dir`). Its contents are tracked like the input module's.
+    pub fn preopen(mut self, dir: impl Into<PathBuf>) -> Weval {
+        let dir = dir.into();
+        self.args.push("--dir".into());
+        self.args.push(dir.clone().into());
+        self.depends_on.push(dir);
+        self
+    }
+
+    /// Let the guest see the build's environment variables while
+    /// Wizening (`--inherit-env`); by default it sees none. They are
+    /// not tracked, so changing one does not rerun the build script.
+    pub fn inherit_env(mut self, inherit: bool) -> Weval {
+        self.args.push("--inherit-env".into());
+        self.args.push(inherit.to_string().into());
+        self
+    }
+
+    /// Use a results cache file (`--cache`), e.g. under `OUT_DIR`.
+    pub fn cache(mut self, path: impl Into<PathBuf>) -> Weval {
+        self.args.push("--cache".into());
+        self.args.push(path.into().into());
+        self
+    }
+
+    /// Pass any other argument to weval as is.
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Weval {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Rerun the build script when `path` changes too, e.g. a
+    /// module passed with `--preload`.
+    pub fn depends_on(mut self, path: impl Into<PathBuf>) -> Weval {
+        self.depends_on.push(path.into());
+        self
+    }
+
+    /// Whether to print `cargo:rerun-if-changed` lines (on by
+    /// default; turn off when not running in a build script).
+    pub fn emit_rerun_if_changed(mut self, emit: bool) -> Weval {
+        self.emit_rerun_if_changed = emit;
+        self
+    }
+
+    /// The command that `run` executes.
+    pub fn command(&self) -> Command {
+        let bin = std::env::var_os(WEVAL_BIN).unwrap_or_else(|| "weval".into());
+        let mut cmd = Command::new(bin);
+        cmd.arg("weval");
+        if self.wizen {
+            cmd.arg("-w");
+        }
+        cmd.arg("-i").arg(&self.input);
+        cmd.arg("-o").arg(&self.output);
+        cmd.args(&self.args);
+        cmd
+    }
+
+    /// Run weval, failing with its output if it does not succeed.
+    pub fn run(&self) -> Result<(), Error> {
+        if self.emit_rerun_if_changed {
+            println!("cargo:rerun-if-env-changed={}", WEVAL_BIN);
+            for path in std::iter::once(&self.input).chain(&self.depends_on) {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+
+        let mut cmd = self.command();
+        let output = cmd.output().map_err(|e| Error {
+            message: format!(
+                "could not run {:?}: {} (install weval with `cargo install weval`, \
+                 or set {} to its path)",
+                cmd.get_program(),
+                e,
+                WEVAL_BIN
+            ),
+        })?;
+        if !output.status.success() {
+            return Err(Error {
+                message: format!(
+                    "weval failed on {} ({}):\n{}{}",
+                    self.input.display(),
+                    output.status,
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// The output module.
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+}
+
+/// A failure to run weval or a failed weval run, with its output.
+pub struct Error {
+    message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+// Build scripts report an error returned from `main` with `Debug`;
+// show the message rather than a struct dump.
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,5 +1,6 @@
 #pragma once
 
+#include <stddef.h>
 #include <stdint.h>
 #include <stdlib.h>
 #include <string.h>
@@ -42,6 +43,7 @@ typedef enum {
   weval_req_arg_f32 = 2,
   weval_req_arg_f64 = 3,
   weval_req_arg_buffer = 4,
+  weval_req_arg_struct = 5,
   weval_req_arg_none = 255,
 } weval_req_arg_type;
 
@@ -63,11 +65,39 @@ struct weval_req_arg_t {
       /* Size of buffer in data stream; next arg follows inline data. */
       uint32_t padded_len;
     } buffer;
+    struct {
+      /* A pointer to a struct of the given length, only some of whose
+       * fields are constant; a `weval_req_struct_fields_t` header, the
+       * fields, then the struct's data follow. Loads from other bytes
+       * happen at runtime. */
+      uint32_t len;
+      /* Size of the header, fields and data in the data stream; next
+       * arg follows. */
+      uint32_t padded_len;
+    } strukt;
   } u;
 };
 
+typedef struct {
+  uint32_t num_fields;
+  uint32_t reserved; /* zero */
+} weval_req_struct_fields_t;
+
+/* A constant field of a struct argument. */
+typedef struct {
+  uint32_t offset;
+  uint32_t len;
+} weval_req_struct_field_t;
+
+/* An initializer for the `weval_req_struct_field_t` of a member. */
+#define WEVAL_FIELD(type, member) \
+  { offsetof(type, member), sizeof(((type*)0)->member) }
+
 extern weval_req_t* weval_req_pending_head;
 extern bool weval_is_wevaled;
+/* Set by `weval --runtime-hooks`: the host fulfills requests made
+ * after wevaling. */
+extern bool weval_runtime_hooks;
 
 #define WEVAL_DEFINE_GLOBALS()                                          \
   weval_req_t* weval_req_pending_head;                                  \
@@ -80,6 +110,12 @@ extern bool weval_is_wevaled;
   __attribute__((export_name("weval.is.wevaled"))) bool*                \
   __weval_is_wevaled() {                                                \
     return &weval_is_wevaled;                                           \
+  }                                                                     \
+                                                                        \
+  bool weval_runtime_hooks;                                             \
+  __attribute__((export_name("weval.runtime.hooks"))) bool*             \
+  __weval_runtime_hooks() {                                             \
+    return &weval_runtime_hooks;                                        \
   }
 
 #define WEVAL_DEFINE_TARGET(index, func)             \
@@ -88,8 +124,14 @@ extern bool weval_is_wevaled;
     return (weval_func_t) & (func);                  \
   }
 
+/* Declare a function, taking and returning only numbers, as an entry
+ * point for `weval fuzz`, e.g.
+ * `WEVAL_FUZZ_ENTRY(run) int32_t fuzz_run(int32_t x) { ... }`. */
+#define WEVAL_FUZZ_ENTRY(name) \
+  __attribute__((export_name("weval.fuzz." #name)))
+
 static inline void weval_request(weval_req_t* req) {
-  if (weval_is_wevaled) {
+  if (weval_is_wevaled && !weval_runtime_hooks) {
       /* nothing! */
   } else {
     req->next = weval_req_pending_head;
@@ -190,6 +232,8 @@ void weval_abort_specialization(uint32_t line_number, uint32_t fatal)
     WEVAL_WASM_IMPORT("abort.specialization");
 void weval_assert_const32(uint32_t value, uint32_t line_no)
     WEVAL_WASM_IMPORT("assert.const32");
+void weval_assert_not_escaped(const void* ptr, uint32_t line_no)
+    WEVAL_WASM_IMPORT("assert.not.escaped");
 void weval_print(const char* message, uint32_t line, uint32_t val)
     WEVAL_WASM_IMPORT("print");
 void weval_context_bucket(uint32_t bucket) WEVAL_WASM_IMPORT("context.bucket");
@@ -288,6 +332,19 @@ struct SpecializeMemory : ArgSpec<T> {
   SpecializeMemory(const SpecializeMemory& other) = default;
 };
 
+/* A pointer to a struct whose listed fields (see `WEVAL_FIELD`) are
+ * constant. `fields` must outlive the `weval()` call. */
+template <typename T>
+struct SpecializeStruct : ArgSpec<T> {
+  T ptr;
+  const weval_req_struct_field_t* fields;
+  uint32_t num_fields;
+  SpecializeStruct(T ptr_, const weval_req_struct_field_t* fields_,
+                   uint32_t num_fields_)
+      : ptr(ptr_), fields(fields_), num_fields(num_fields_) {}
+  SpecializeStruct(const SpecializeStruct& other) = default;
+};
+
 namespace impl {
 template <typename Ret, typename... Args>
 using FuncPtr = Ret (*)(Args...);
@@ -426,6 +483,44 @@ struct StoreArgs<SpecializeMemory<T>, Rest...> {
   }
 };
 
+template <typename T, typename... Rest>
+struct StoreArgs<SpecializeStruct<T>, Rest...> {
+  bool operator()(ArgWriter& args, SpecializeStruct<T> arg0, Rest... rest) {
+    uint32_t len = sizeof(*arg0.ptr);
+    uint32_t table_len = sizeof(weval_req_struct_fields_t) +
+                         arg0.num_fields * sizeof(weval_req_struct_field_t);
+    weval_req_arg_t arg;
+    arg.specialize = 1;
+    arg.ty = weval_req_arg_struct;
+    arg.u.raw = 0;
+    arg.u.strukt.len = len;
+    arg.u.strukt.padded_len = table_len + ((len + 7) & ~7);
+    if (!args.write(arg)) {
+      return false;
+    }
+    weval_req_struct_fields_t header;
+    header.num_fields = arg0.num_fields;
+    header.reserved = 0;
+    if (!args.write(header)) {
+      return false;
+    }
+    for (uint32_t i = 0; i < arg0.num_fields; i++) {
+      if (!args.write(arg0.fields[i])) {
+        return false;
+      }
+    }
+    const uint8_t* src = reinterpret_cast<const uint8_t*>(arg0.ptr);
+    uint8_t* dst = args.alloc((len + 7) & ~7);
+    if (!dst) {
+      return false;
+    }
+    memcpy(dst, src, len);
+    // Ensure deterministic (zeroed) padding bytes.
+    memset(dst + len, 0, ((len + 7) & ~7) - len);
+    return StoreArgs<Rest...>()(args, rest...);
+  }
+};
+
 template <typename T, typename... Rest>
 struct StoreArgs<RuntimeArg<T>, Rest...> {
   bool operator()(ArgWriter& args, RuntimeArg<T> arg0, Rest... rest) {
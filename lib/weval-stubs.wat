@@ -15,6 +15,7 @@
  (func (export "abort.specialization") (param i32 i32))
  (func (export "assert.const32") (param i32 i32))
  (func (export "assert.const.memory") (param i32 i32))
+ (func (export "assert.not.escaped") (param i32 i32))
  (func (export "specialize.value") (param i32 i32 i32) (result i32)
  local.get 0)
  (func (export "print") (param i32 i32 i32))
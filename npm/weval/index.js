@@ -2,8 +2,10 @@ import { endianness } from "node:os";
 import { fileURLToPath } from "node:url";
 import { dirname, join, parse } from "node:path";
 import { platform, arch } from "node:process";
-import { mkdir } from "node:fs/promises";
+import { mkdir, mkdtemp, readFile, rm } from "node:fs/promises";
 import { existsSync } from "node:fs";
+import { execFile } from "node:child_process";
+import { tmpdir } from "node:os";
 
 import decompress from "decompress";
 import decompressUnzip from "decompress-unzip";
@@ -76,4 +78,104 @@ export async function getWeval(opts) {
   return exe;
 }
 
+/**
+ * Error from a failed weval run, with its output.
+ *
+ * `code` is the error code weval reports with `--error-json` (e.g.
+ * `directive-failed`, `unsupported` or `budget-exceeded`, or `error` for
+ * anything else), and `details` the whole report: the code, message,
+ * causes and, for a failed directive, the directive. Both are undefined if
+ * weval wrote no report, as when its arguments are invalid.
+ */
+export class WevalError extends Error {
+  constructor(message, { code, details, exitCode, stdout, stderr }) {
+    super(message);
+    this.name = "WevalError";
+    this.code = code;
+    this.details = details;
+    this.exitCode = exitCode;
+    this.stdout = stdout;
+    this.stderr = stderr;
+  }
+}
+
+/**
+ * Run weval on a module
+ *
+ * @param {object} opts
+ * @param {string} opts.input - Input module
+ * @param {string} opts.output - Output module
+ * @param {boolean} [opts.wizen] - Whether to Wizen the input first
+ * @param {string[]} [opts.dirs] - Directories to preopen while Wizening
+ * @param {boolean} [opts.inheritEnv] - Whether the guest sees weval's
+ *   environment variables while Wizening (by default it sees none)
+ * @param {string} [opts.cache] - Results cache file
+ * @param {string[]} [opts.args] - Any other command-line arguments (but not
+ *   `--stats-json` or `--error-json`, which the wrapper passes itself)
+ * @param {string} [opts.wevalPath] - weval binary to run, instead of downloading one
+ * @param {string} [opts.downloadDir] - Directory to which the binary should be downloaded
+ * @returns {Promise<{stats: object, stdout: string, stderr: string}>} the
+ *   stats and directive outcomes of the run (as written by `--stats-json`),
+ *   and weval's output; rejects with a `WevalError` if weval fails
+ */
+export async function weval(opts) {
+  const exe = opts.wevalPath || (await getWeval({ downloadDir: opts.downloadDir }));
+  const statsDir = await mkdtemp(join(tmpdir(), "weval-stats-"));
+  const statsPath = join(statsDir, "stats.json");
+  const errorPath = join(statsDir, "error.json");
+
+  const args = [
+    "weval",
+    "-i",
+    opts.input,
+    "-o",
+    opts.output,
+    "--stats-json",
+    statsPath,
+    "--error-json",
+    errorPath,
+  ];
+  if (opts.wizen) {
+    args.push("-w");
+  }
+  for (const dir of opts.dirs || []) {
+    args.push("--dir", dir);
+  }
+  if (opts.inheritEnv) {
+    args.push("--inherit-env", "true");
+  }
+  if (opts.cache) {
+    args.push("--cache", opts.cache);
+  }
+  args.push(...(opts.args || []));
+
+  try {
+    const { err, stdout, stderr } = await new Promise((resolve) => {
+      execFile(exe, args, { maxBuffer: 64 << 20 }, (err, stdout, stderr) => {
+        resolve({ err, stdout, stderr });
+      });
+    });
+    if (err) {
+      let details;
+      try {
+        details = JSON.parse(await readFile(errorPath, "utf8"));
+      } catch {
+        // No report: weval failed before running, e.g. on its arguments.
+      }
+      const detail = (details && details.message) || stderr.trim() || err.message;
+      throw new WevalError(`weval failed on ${opts.input}: ${detail}`, {
+        code: details && details.code,
+        details,
+        exitCode: err.code,
+        stdout,
+        stderr,
+      });
+    }
+    const stats = JSON.parse(await readFile(statsPath, "utf8"));
+    return { stats, stdout, stderr };
+  } finally {
+    await rm(statsDir, { recursive: true, force: true });
+  }
+}
+
 export default getWeval;
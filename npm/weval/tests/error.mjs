@@ -0,0 +1,32 @@
+import assert from "node:assert";
+import { test } from "node:test";
+import { tmpdir } from "node:os";
+import { join } from "node:path";
+import { mkdtemp, rm, writeFile } from "node:fs/promises";
+
+import { weval, WevalError } from "../index.js";
+
+export default async function tests() {
+  // Runs against a local build (`WEVAL_BIN=target/debug/weval`), or the
+  // downloaded release otherwise.
+  test("errors carry weval's error code", async () => {
+    const dir = await mkdtemp(join(tmpdir(), "weval-error-"));
+    try {
+      // A component (its preamble alone), which weval rejects.
+      const input = join(dir, "component.wasm");
+      await writeFile(input, Buffer.from([0, 0x61, 0x73, 0x6d, 0x0d, 0, 1, 0]));
+      await assert.rejects(
+        weval({ input, output: join(dir, "out.wasm"), wevalPath: process.env.WEVAL_BIN }),
+        (e) => {
+          assert(e instanceof WevalError);
+          assert.strictEqual(e.code, "unsupported");
+          assert.strictEqual(e.details.code, "unsupported");
+          assert(Array.isArray(e.details.causes));
+          return true;
+        }
+      );
+    } finally {
+      await rm(dir, { recursive: true, force: true });
+    }
+  });
+}
@@ -1,3 +1,5 @@
 import { default as downloadTests }  from "./download.mjs";
+import { default as errorTests } from "./error.mjs";
 
 await downloadTests();
+await errorTests();
@@ -0,0 +1,85 @@
+//! `weval batch`: several weval runs in one process.
+//!
+//! Projects that weval several interpreter variants per build can run
+//! them all with one invocation, sharing the process's thread pool and
+//! the built-in stubs, and an in-memory results cache per input module
+//! (as `weval serve` does) for jobs that name no `--cache`.
+//!
+//! Jobs come from `-i`/`-o` pairs on the command line, and from a jobs
+//! file: a JSON array with one entry per job, each an array of
+//! `weval weval` arguments (e.g. `["-i", "a.wasm", "-o", "a.out.wasm",
+//! "--cache", "a.db"]`). Arguments after `--` are added to every job.
+
+use crate::serve::WarmCaches;
+use std::path::{Path, PathBuf};
+
+pub(crate) fn batch(
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+    jobs_file: Option<PathBuf>,
+    common: Vec<String>,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        inputs.len() == outputs.len(),
+        "each input module (-i) needs an output module (-o)"
+    );
+    let mut jobs: Vec<Vec<String>> = inputs
+        .iter()
+        .zip(&outputs)
+        .map(|(input, output)| {
+            vec![
+                "-i".to_owned(),
+                input.to_string_lossy().into_owned(),
+                "-o".to_owned(),
+                output.to_string_lossy().into_owned(),
+            ]
+        })
+        .collect();
+    if let Some(path) = &jobs_file {
+        jobs.extend(read_jobs(path)?);
+    }
+    anyhow::ensure!(!jobs.is_empty(), "no jobs: give -i/-o pairs or --jobs");
+
+    // Check every job's arguments before running any.
+    let cmds = jobs
+        .iter()
+        .enumerate()
+        .map(|(i, args)| {
+            crate::cli::weval_command(args.iter().chain(&common))
+                .map_err(|e| anyhow::anyhow!("job {}: {}", i + 1, e))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let warm = WarmCaches::default();
+    let mut failed = 0;
+    for (i, cmd) in cmds.into_iter().enumerate() {
+        if let crate::cli::Command::Weval(opts) = &cmd {
+            eprintln!(
+                "[{}/{}] {} -> {}",
+                i + 1,
+                jobs.len(),
+                opts.input_module.display(),
+                opts.output_module.display()
+            );
+        }
+        if let Err(e) = crate::cli::run(cmd, Some(&warm)) {
+            eprintln!("job {} failed: {:#}", i + 1, e);
+            failed += 1;
+        }
+    }
+    anyhow::ensure!(failed == 0, "{} of {} jobs failed", failed, jobs.len());
+    Ok(())
+}
+
+fn read_jobs(path: &Path) -> anyhow::Result<Vec<Vec<String>>> {
+    let context = || format!("reading jobs file {}", path.display());
+    let text =
+        std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", context(), e))?;
+    serde_json::from_str(&text).map_err(|e| {
+        anyhow::anyhow!(
+            "{}: {} (expected an array of argument arrays)",
+            context(),
+            e
+        )
+    })
+}
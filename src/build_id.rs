@@ -0,0 +1,61 @@
+//! Build IDs.
+//!
+//! Toolchains (e.g. `wasm-ld --build-id`) can record a build ID in a
+//! `build_id` custom section whose contents are a byte vector: a
+//! LEB128 length, then the ID. When the input has one, the output
+//! gets a derived ID in its place, so that caches keyed on build IDs
+//! (Wasmtime's, a CDN's) see the wevaled module as a distinct artifact
+//! that is stable across identical weval runs.
+//!
+//! The results cache needs nothing extra: it is keyed on a hash of
+//! the whole input, which includes the section.
+
+use sha2::{Digest, Sha256};
+use waffle::wasm_encoder::{self, Encode, Section};
+use waffle::wasmparser::{BinaryReader, Parser, Payload, WasmFeatures};
+
+pub(crate) const SECTION_NAME: &str = "build_id";
+
+/// The build ID of `module`, if it has one.
+pub(crate) fn read(module: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    for payload in Parser::new(0).parse_all(module) {
+        match payload? {
+            Payload::CustomSection(reader) if reader.name() == SECTION_NAME => {
+                let mut data =
+                    BinaryReader::new(reader.data(), reader.data_offset(), WasmFeatures::default());
+                let len = data.read_var_u32()? as usize;
+                let id = data.read_bytes(len)?;
+                anyhow::ensure!(data.eof(), "trailing bytes in the build_id section");
+                return Ok(Some(id.to_vec()));
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// The build ID for an output module (without a `build_id` section)
+/// produced from an input with build ID `input_id`: a hash of the
+/// weval version, the input ID and the output, as long as the input
+/// ID (up to 32 bytes).
+pub(crate) fn derive(input_id: &[u8], output: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"weval ");
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update((input_id.len() as u64).to_le_bytes());
+    hasher.update(input_id);
+    hasher.update(output);
+    let hash = hasher.finalize();
+    hash[..input_id.len().clamp(1, hash.len())].to_vec()
+}
+
+/// Append a `build_id` section to `module`.
+pub(crate) fn append(module: &mut Vec<u8>, id: &[u8]) {
+    let mut data = vec![];
+    id.encode(&mut data);
+    wasm_encoder::CustomSection {
+        name: SECTION_NAME.into(),
+        data: data.into(),
+    }
+    .append_to(module);
+}
@@ -21,12 +21,14 @@ pub(crate) struct CacheData {
     pub body: Vec<u8>,
 }
 
+#[cfg(feature = "cache")]
 pub(crate) struct Cache {
     module_hash: ModuleHash,
     db: Option<sqlite::ConnectionThreadSafe>,
     db_ro: Option<sqlite::ConnectionThreadSafe>,
 }
 
+#[cfg(feature = "cache")]
 pub(crate) struct CacheThreadCtx<'a> {
     cache: &'a Cache,
     lookup_stmt: Option<sqlite::Statement<'a>>,
@@ -34,6 +36,7 @@ pub(crate) struct CacheThreadCtx<'a> {
     ro_lookup_stmt: Option<sqlite::Statement<'a>>,
 }
 
+#[cfg(feature = "cache")]
 impl Cache {
     pub fn open(
         path: Option<&Path>,
@@ -117,6 +120,7 @@ impl Cache {
     }
 }
 
+#[cfg(feature = "cache")]
 impl<'a> CacheThreadCtx<'a> {
     pub fn lookup(&mut self, key: &[u8]) -> anyhow::Result<Option<CacheData>> {
         let mut result = None;
@@ -153,3 +157,45 @@ impl<'a> CacheThreadCtx<'a> {
         Ok(())
     }
 }
+
+/// Without the `cache` feature, there is no database: nothing is
+/// found and nothing is stored.
+#[cfg(not(feature = "cache"))]
+pub(crate) struct Cache;
+
+#[cfg(not(feature = "cache"))]
+pub(crate) struct CacheThreadCtx<'a>(std::marker::PhantomData<&'a Cache>);
+
+#[cfg(not(feature = "cache"))]
+impl Cache {
+    pub fn open(
+        path: Option<&Path>,
+        path_ro: Option<&Path>,
+        _module_hash: ModuleHash,
+    ) -> anyhow::Result<Cache> {
+        anyhow::ensure!(
+            path.is_none() && path_ro.is_none(),
+            "weval was built without results caching (the `cache` feature)"
+        );
+        Ok(Cache)
+    }
+
+    pub fn can_insert(&self) -> bool {
+        false
+    }
+
+    pub fn thread(&self) -> anyhow::Result<CacheThreadCtx<'_>> {
+        Ok(CacheThreadCtx(std::marker::PhantomData))
+    }
+}
+
+#[cfg(not(feature = "cache"))]
+impl<'a> CacheThreadCtx<'a> {
+    pub fn lookup(&mut self, _key: &[u8]) -> anyhow::Result<Option<CacheData>> {
+        Ok(None)
+    }
+
+    pub fn insert(&mut self, _key: &[u8], _data: CacheData) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! `weval list-candidates`: find functions that look worth
+//! specializing.
+//!
+//! A good target is an interpreter: a function with a loop that
+//! dispatches through a `br_table` (or calls its opcode handlers
+//! through a `call_indirect`), reachable from the module's exports.
+//! Functions with a loop and either are candidates; those
+//! whose loop `--auto-dispatch` recognizes (keyed on a load from one
+//! of their parameters) rank first, then those reachable from an
+//! export, then the largest.
+
+use crate::dispatch;
+use crate::intrinsics::Intrinsics;
+use fxhash::FxHashMap;
+use std::collections::VecDeque;
+use waffle::cfg::CFGInfo;
+use waffle::entity::EntityRef;
+use waffle::wasmparser::Operator as WasmOp;
+use waffle::{ExportKind, Func, FuncDecl, Module, Terminator, Type};
+
+/// What a scan of a function's bytecode finds.
+#[derive(Default)]
+struct Scan {
+    /// Bytecode size, in bytes.
+    size: usize,
+    callees: Vec<Func>,
+    calls_indirect: bool,
+    has_loop: bool,
+    has_br_table: bool,
+}
+
+/// How a function is reached from the module's exports.
+#[derive(Clone)]
+struct Reach {
+    export: String,
+    /// Calls from the export (0 for the export itself).
+    depth: usize,
+    /// Whether the path goes through an indirect call.
+    indirect: bool,
+}
+
+/// A dispatch loop found in a candidate.
+struct Dispatch {
+    param: usize,
+    /// Whether the function already calls weval's context intrinsics
+    /// (so needs no `--auto-dispatch`).
+    uses_contexts: bool,
+    /// Targets of the `br_table`, including the default, or entries
+    /// of the handler table.
+    opcodes: usize,
+    /// IR instructions in the loop body.
+    loop_insts: usize,
+}
+
+struct Candidate {
+    func: Func,
+    size: usize,
+    insts: usize,
+    dispatch: Option<Dispatch>,
+    reach: Option<Reach>,
+}
+
+/// List up to `limit` candidates in `module`, best first, as lines to
+/// print.
+pub(crate) fn list(module: &Module, limit: usize) -> anyhow::Result<Vec<String>> {
+    let scans = module
+        .funcs
+        .entries()
+        .map(|(func, decl)| Ok((func, scan(decl)?)))
+        .collect::<anyhow::Result<FxHashMap<_, _>>>()?;
+    let reach = reach(module, &scans);
+    let intrinsics = Intrinsics::find(module);
+
+    let mut candidates = vec![];
+    for (func, s) in &scans {
+        if !s.has_loop || !(s.has_br_table || s.calls_indirect) {
+            continue;
+        }
+        let body = module.clone_and_expand_body(*func)?;
+        let cfg = CFGInfo::new(&body);
+        let uses_contexts = dispatch::uses_contexts(&body, &intrinsics);
+        let dispatch = body.blocks[body.entry]
+            .params
+            .iter()
+            .enumerate()
+            .filter(|(_, &(ty, _))| ty == Type::I32)
+            .find_map(|(param, _)| {
+                let l = dispatch::find_loops(&body, &cfg, param)
+                    .into_iter()
+                    .next()?;
+                let opcodes = match (l.handler_table, &body.blocks[l.dispatch].terminator) {
+                    (Some(table), _) => module.tables[table]
+                        .func_elements
+                        .as_ref()
+                        .map_or(0, |elems| elems.len()),
+                    (None, Terminator::Select { targets, .. }) => targets.len() + 1,
+                    _ => 0,
+                };
+                let loop_insts = l.body.iter().map(|&b| body.blocks[b].insts.len()).sum();
+                Some(Dispatch {
+                    param,
+                    uses_contexts,
+                    opcodes,
+                    loop_insts,
+                })
+            });
+        candidates.push(Candidate {
+            func: *func,
+            size: s.size,
+            insts: body.blocks.values().map(|block| block.insts.len()).sum(),
+            dispatch,
+            reach: reach.get(func).cloned(),
+        });
+    }
+    candidates.sort_by_key(|c| {
+        (
+            c.dispatch.is_none(),
+            c.reach.is_none(),
+            std::cmp::Reverse(c.size),
+            c.func,
+        )
+    });
+
+    if candidates.is_empty() {
+        return Ok(vec![
+            "no candidates: no function has a loop with a br_table or call_indirect".to_owned(),
+        ]);
+    }
+    let mut lines = vec![];
+    for c in candidates.iter().take(limit) {
+        let name = module.funcs[c.func].name();
+        lines.push(format!(
+            "{} ({}): {} bytes, {} IR instructions",
+            name, c.func, c.size, c.insts
+        ));
+        match &c.dispatch {
+            Some(d) => {
+                lines.push(format!(
+                    "  dispatch loop on parameter {} over {} opcodes, about {} IR instructions \
+                     per specialized bytecode instruction",
+                    d.param,
+                    d.opcodes,
+                    d.loop_insts / d.opcodes.max(1)
+                ));
+                if d.uses_contexts {
+                    lines.push("  already uses weval's context intrinsics".to_owned());
+                } else {
+                    lines.push(format!("  try: --auto-dispatch {}:{}", name, d.param));
+                }
+            }
+            None => lines.push("  a loop, but no dispatch loop keyed on a parameter".to_owned()),
+        }
+        lines.push(match &c.reach {
+            Some(r) if r.depth == 0 => format!("  exported as `{}`", r.export),
+            Some(r) => format!(
+                "  reached from export `{}` in {} call{}{}",
+                r.export,
+                r.depth,
+                if r.depth == 1 { "" } else { "s" },
+                if r.indirect {
+                    ", through the function table"
+                } else {
+                    ""
+                }
+            ),
+            None => "  not reached from any export".to_owned(),
+        });
+    }
+    if candidates.len() > limit {
+        lines.push(format!("... and {} more", candidates.len() - limit));
+    }
+    Ok(lines)
+}
+
+fn scan(decl: &FuncDecl) -> anyhow::Result<Scan> {
+    let FuncDecl::Lazy(_, _, body) = decl else {
+        return Ok(Scan::default());
+    };
+    let mut s = Scan {
+        size: body.range().len(),
+        ..Scan::default()
+    };
+    for op in body.get_operators_reader()? {
+        match op? {
+            WasmOp::Call { function_index } | WasmOp::ReturnCall { function_index } => {
+                s.callees.push(Func::from(function_index))
+            }
+            WasmOp::CallIndirect { .. } | WasmOp::ReturnCallIndirect { .. } => {
+                s.calls_indirect = true
+            }
+            WasmOp::Loop { .. } => s.has_loop = true,
+            WasmOp::BrTable { .. } => s.has_br_table = true,
+            _ => {}
+        }
+    }
+    Ok(s)
+}
+
+/// Breadth-first from the exported functions, taking an indirect call
+/// to reach every function in a table.
+fn reach(module: &Module, scans: &FxHashMap<Func, Scan>) -> FxHashMap<Func, Reach> {
+    let table_funcs = module
+        .tables
+        .values()
+        .filter_map(|table| table.func_elements.as_ref())
+        .flatten()
+        .copied()
+        .filter(|func| func.is_valid())
+        .collect::<Vec<_>>();
+
+    let mut reached = FxHashMap::default();
+    let mut queue = VecDeque::new();
+    for export in &module.exports {
+        if let ExportKind::Func(func) = export.kind {
+            let r = Reach {
+                export: export.name.clone(),
+                depth: 0,
+                indirect: false,
+            };
+            if reached.insert(func, r.clone()).is_none() {
+                queue.push_back((func, r));
+            }
+        }
+    }
+    while let Some((func, r)) = queue.pop_front() {
+        let Some(s) = scans.get(&func) else {
+            continue;
+        };
+        let direct = s.callees.iter().map(|&callee| (callee, r.indirect));
+        let indirect = s
+            .calls_indirect
+            .then_some(table_funcs.iter().map(|&callee| (callee, true)))
+            .into_iter()
+            .flatten();
+        for (callee, indirect) in direct.chain(indirect) {
+            if reached.contains_key(&callee) {
+                continue;
+            }
+            let next = Reach {
+                export: r.export.clone(),
+                depth: r.depth + 1,
+                indirect,
+            };
+            reached.insert(callee, next.clone());
+            queue.push_back((callee, next));
+        }
+    }
+    reached
+}
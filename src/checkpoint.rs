@@ -0,0 +1,66 @@
+//! Checkpoints of a run's specializations, for resuming it.
+//!
+//! With `--checkpoint DIR`, each specialized function is written to
+//! its own file in DIR as soon as it is compiled, rather than at the
+//! end of the run as for the cache; `--resume DIR` then reuses those
+//! files, so that a run killed partway (out of memory, or at a CI
+//! timeout) picks up where it stopped. Files are named by a hash of
+//! the input module (and the options that change results, as for the
+//! cache) and the directive, so a checkpoint of a different input is
+//! never reused. As with the cache, functions that refer to other
+//! directives' results are not checkpointed.
+
+use crate::cache::{compute_hash, CacheData, ModuleHash};
+use crate::report::hex;
+use std::path::{Path, PathBuf};
+
+pub(crate) struct Checkpoint {
+    dir: PathBuf,
+    module_hash: ModuleHash,
+    /// Whether to reuse checkpoints already in `dir`.
+    resume: bool,
+}
+
+impl Checkpoint {
+    pub(crate) fn open(dir: &Path, module_hash: ModuleHash, resume: bool) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("creating checkpoint directory {:?}: {}", dir, e))?;
+        Ok(Checkpoint {
+            dir: dir.to_owned(),
+            module_hash,
+            resume,
+        })
+    }
+
+    fn path(&self, key: &[u8]) -> PathBuf {
+        let hash = compute_hash(&[&self.module_hash[..], key].concat());
+        self.dir.join(format!("{}.bin", hex(&hash)))
+    }
+
+    /// The checkpointed result of the directive with cache key `key`,
+    /// when resuming.
+    pub(crate) fn load(&self, key: &[u8]) -> anyhow::Result<Option<CacheData>> {
+        if !self.resume {
+            return Ok(None);
+        }
+        let path = self.path(key);
+        match std::fs::read(&path) {
+            Ok(bytes) => bincode::deserialize(&bytes[..])
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("reading checkpoint {:?}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("reading checkpoint {:?}: {}", path, e)),
+        }
+    }
+
+    /// Checkpoint the result of the directive with cache key `key`.
+    /// The file is renamed into place, so that a run killed while
+    /// writing it leaves no partial checkpoint.
+    pub(crate) fn save(&self, key: &[u8], data: &CacheData) -> anyhow::Result<()> {
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&tmp, bincode::serialize(data)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
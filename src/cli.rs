@@ -0,0 +1,1861 @@
+//! The `weval` command line.
+
+#[cfg(feature = "wizer")]
+use crate::guest_output;
+use crate::{
+    batch, build_id, cache, candidates, checkpoint, dedup, directive, dispatch, emit_facts, error,
+    eval, filter, fuzz, golden, host, image, intrinsics, opcode_stubs, osr, pc_profile, policy,
+    precompile, preset, profile, progress, pure_imports, query, report, runtime_hooks, serve,
+    snapshot, verify,
+};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+const STUBS: &'static str = include_str!("../lib/weval-stubs.wat");
+
+/// How many PCs of each inferred context bucket `--show-stats` lists.
+const MAX_LISTED_BUCKET_PCS: usize = 8;
+
+// Parsed once per run; the size of the `Weval` variant does not matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, StructOpt)]
+pub enum Command {
+    /// Partially evaluate a Wasm module, optionally wizening first.
+    Weval(WevalOptions),
+
+    /// Serve `weval` requests as JSON-RPC 2.0 over stdin and stdout,
+    /// one message per line, keeping specialization results cached in
+    /// memory from one request to the next.
+    Serve {
+        /// How many input modules to keep results caches and parsed
+        /// modules for, dropping the least recently used beyond that.
+        #[structopt(long = "max-warm-modules", value_name = "N", default_value = "16")]
+        max_warm_modules: usize,
+    },
+
+    /// Run several `weval` jobs in one process, sharing its thread
+    /// pool and stubs, and in-memory results caches per input module.
+    Batch {
+        /// An input module; each needs a matching `-o`.
+        #[structopt(short = "i", number_of_values = 1)]
+        inputs: Vec<PathBuf>,
+
+        /// The output module for the `-i` in the same position.
+        #[structopt(short = "o", number_of_values = 1)]
+        outputs: Vec<PathBuf>,
+
+        /// JSON file listing more jobs: an array with, per job, an
+        /// array of `weval weval` arguments.
+        #[structopt(long = "jobs")]
+        jobs: Option<PathBuf>,
+
+        /// `weval weval` arguments to add to every job.
+        #[structopt(last = true)]
+        common: Vec<String>,
+    },
+
+    /// Check a specialized module against its generic module: call
+    /// entry points with random arguments in both, under Wasmtime, and
+    /// compare the results and memory, shrinking any mismatching
+    /// arguments.
+    Fuzz {
+        /// The generic module (the input to `weval weval`, after any
+        /// Wizening).
+        #[structopt(long = "generic")]
+        generic: PathBuf,
+
+        /// The specialized module (the output of `weval weval`).
+        #[structopt(long = "specialized")]
+        specialized: PathBuf,
+
+        /// Module (binary or text format) to use instead of the
+        /// built-in weval stubs, which provide the `weval` intrinsics.
+        #[structopt(long = "stubs")]
+        stubs: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        opts: fuzz::FuzzOptions,
+    },
+
+    /// Query the memory image weval specializes against: read or
+    /// hexdump memory, show a global's value, or find the data segment
+    /// covering an address.
+    Query {
+        /// The Wasm module to query.
+        #[structopt(short = "i")]
+        input_module: PathBuf,
+
+        /// Whether to Wizen the module first, and query the snapshot.
+        #[structopt(short = "w")]
+        wizen: bool,
+
+        #[structopt(flatten)]
+        wizen_opts: WizenOptions,
+
+        #[structopt(subcommand)]
+        query: query::Query,
+    },
+
+    /// List functions that look worth specializing: interpreter-like
+    /// functions with a loop dispatching through a `br_table` or a
+    /// `call_indirect`, reachable from the module's exports, with their sizes and the
+    /// `--auto-dispatch` argument to try.
+    ListCandidates {
+        /// The Wasm module to analyze.
+        #[structopt(short = "i")]
+        input_module: PathBuf,
+
+        /// How many candidates to list.
+        #[structopt(long = "limit", value_name = "N", default_value = "10")]
+        limit: usize,
+    },
+}
+
+/// Parse the arguments of a `weval weval` command line (without the
+/// program and subcommand names).
+pub(crate) fn weval_command<I>(args: I) -> Result<Command, structopt::clap::Error>
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let args: Vec<String> = args
+        .into_iter()
+        .map(|arg| arg.as_ref().to_owned())
+        .collect();
+    let mut cmd =
+        Command::from_iter_safe(["weval", "weval"].into_iter().map(String::from).chain(args))?;
+    apply_env_flags(&mut cmd).map_err(|e| {
+        structopt::clap::Error::with_description(
+            &e.to_string(),
+            structopt::clap::ErrorKind::InvalidValue,
+        )
+    })?;
+    Ok(cmd)
+}
+
+/// Turn on the `weval weval` flags set in the environment.
+fn apply_env_flags(cmd: &mut Command) -> anyhow::Result<()> {
+    match cmd {
+        Command::Weval(opts) => opts.apply_env_flags(),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn main() -> anyhow::Result<()> {
+    let mut cmd = Command::from_args();
+    apply_env_flags(&mut cmd)?;
+    let self_profile = match &cmd {
+        Command::Weval(opts) => opts.self_profile.clone(),
+        Command::Serve { .. }
+        | Command::Batch { .. }
+        | Command::Fuzz { .. }
+        | Command::Query { .. }
+        | Command::ListCandidates { .. } => None,
+    };
+    let profile = self_profile.as_ref().map(|_| profile::Profile::new());
+    init_tracing(profile.as_ref());
+
+    let result = run(cmd, None).map(|_| ());
+
+    // Write the profile even if the run failed, to show where.
+    if let (Some(path), Some(profile)) = (self_profile, profile) {
+        profile.write(&path)?;
+    }
+    result
+}
+
+/// Run a command, returning the stats of a `weval` run. `warm` is the
+/// server's state when running on behalf of `weval serve`.
+pub(crate) fn run(
+    cmd: Command,
+    warm: Option<&serve::WarmCaches>,
+) -> anyhow::Result<serde_json::Value> {
+    match cmd {
+        Command::Weval(opts) => {
+            let threads = opts.threads;
+            let error_json = opts.error_json.clone();
+            let run_weval = move || weval(opts, warm);
+            let result = match threads {
+                Some(n) => rayon::ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .build()?
+                    .install(run_weval),
+                None => run_weval(),
+            };
+            if let (Err(e), Some(path)) = (&result, &error_json) {
+                std::fs::write(path, error::to_json(e).to_string())?;
+            }
+            result
+        }
+        Command::Serve { max_warm_modules } => match warm {
+            Some(_) => anyhow::bail!("cannot serve from within `weval serve`"),
+            None => serve::serve(max_warm_modules).map(|_| serde_json::Value::Null),
+        },
+        Command::Batch {
+            inputs,
+            outputs,
+            jobs,
+            common,
+        } => match warm {
+            Some(_) => anyhow::bail!("cannot run a batch from within `weval serve`"),
+            None => batch::batch(inputs, outputs, jobs, common).map(|_| serde_json::Value::Null),
+        },
+        Command::Fuzz {
+            generic,
+            specialized,
+            stubs,
+            opts,
+        } => {
+            let stubs = match stubs {
+                Some(path) => std::fs::read(&path).map_err(|e| {
+                    anyhow::anyhow!("reading stubs module {}: {}", path.display(), e)
+                })?,
+                None => builtin_stubs()?.to_vec(),
+            };
+            fuzz::fuzz(&generic, &specialized, &stubs, &opts).map(|_| serde_json::Value::Null)
+        }
+        Command::Query {
+            input_module,
+            wizen: do_wizen,
+            wizen_opts,
+            query,
+        } => {
+            let bytes = std::fs::read(&input_module)?;
+            check_core_module(&bytes[..], do_wizen)?;
+            let bytes = if do_wizen {
+                wizen(bytes, wizen_opts)?
+            } else {
+                bytes
+            };
+            let module = waffle::Module::from_wasm_bytes(&bytes[..], &Default::default())?;
+            for line in query::run(&module, &query)? {
+                println!("{}", line);
+            }
+            Ok(serde_json::Value::Null)
+        }
+        Command::ListCandidates {
+            input_module,
+            limit,
+        } => {
+            let bytes = std::fs::read(&input_module)?;
+            check_core_module(&bytes[..], false)?;
+            let module = waffle::Module::from_wasm_bytes(&bytes[..], &Default::default())?;
+            for line in candidates::list(&module, limit)? {
+                println!("{}", line);
+            }
+            Ok(serde_json::Value::Null)
+        }
+    }
+}
+
+// The options of `weval weval`. (A doc comment here would become the
+// subcommand's description in `--help`.)
+#[derive(Clone, Debug, StructOpt)]
+pub struct WevalOptions {
+    /// The input Wasm module.
+    #[structopt(short = "i")]
+    pub(crate) input_module: PathBuf,
+
+    /// The output Wasm module.
+    #[structopt(short = "o")]
+    pub(crate) output_module: PathBuf,
+
+    /// Whether to Wizen the module first.
+    #[structopt(short = "w")]
+    wizen: bool,
+
+    #[structopt(flatten)]
+    wizen_opts: WizenOptions,
+
+    /// Wizen the module twice and fail, listing the divergent
+    /// memory ranges and globals, if the snapshots differ
+    /// (requires `-w`).
+    #[structopt(long = "check-determinism")]
+    check_determinism: bool,
+
+    /// Verify that the module to specialize is a coherent weval
+    /// snapshot before collecting directives (e.g. when it was
+    /// Wizened outside of weval).
+    #[structopt(long = "verify-snapshot")]
+    verify_snapshot: bool,
+
+    /// Skip malformed entries in the module's weval request list,
+    /// with a warning, rather than failing. A request list whose
+    /// links are corrupted still fails.
+    #[structopt(long = "skip-malformed-requests")]
+    skip_malformed_requests: bool,
+
+    /// Cache file to use.
+    #[structopt(long = "cache", env = "WEVAL_CACHE")]
+    cache: Option<PathBuf>,
+
+    /// Read-only cache file to query.
+    #[structopt(long = "cache-ro", env = "WEVAL_CACHE_RO")]
+    cache_ro: Option<PathBuf>,
+
+    /// Write each specialized function to a file in this directory
+    /// as soon as it is done, for `--resume` to pick up if the run
+    /// is interrupted.
+    #[structopt(
+        long = "checkpoint",
+        value_name = "DIR",
+        env = "WEVAL_CHECKPOINT",
+        conflicts_with = "resume"
+    )]
+    checkpoint: Option<PathBuf>,
+
+    /// Resume an interrupted run from the checkpoints in this
+    /// directory (of the same input and options), reusing the
+    /// functions it specialized and checkpointing the rest there
+    /// too.
+    #[structopt(long = "resume", value_name = "DIR")]
+    resume: Option<PathBuf>,
+
+    /// Show which memory ranges and globals Wizening changed,
+    /// relative to the original data segments (requires `-w`).
+    #[structopt(long = "show-wizen-changes")]
+    show_wizen_changes: bool,
+
+    /// Show stats on specialization code size.
+    #[structopt(long = "show-stats")]
+    show_stats: bool,
+
+    /// Show the N largest specialized functions and their growth
+    /// relative to the generic function.
+    #[structopt(long = "show-largest", env = "WEVAL_SHOW_LARGEST")]
+    show_largest: Option<usize>,
+
+    /// Write a self-contained HTML report of stats, directive
+    /// outcomes and specialized function sizes to this file.
+    #[structopt(long = "report-html")]
+    report_html: Option<PathBuf>,
+
+    /// Write the same stats and directive outcomes as JSON to this
+    /// file.
+    #[structopt(long = "stats-json")]
+    stats_json: Option<PathBuf>,
+
+    /// If the run fails, write the error as JSON to this file: a
+    /// code (e.g. `intrinsic-mismatch`, `directive-failed`,
+    /// `malformed-request`, `intrinsic-failed`, `unsupported`,
+    /// `budget-exceeded`, `internal-invariant`,
+    /// `policy-violation`, or `error` for anything else), the
+    /// message, and its causes.
+    #[structopt(long = "error-json")]
+    error_json: Option<PathBuf>,
+
+    /// Write a JSON build-artifact manifest to this file: the
+    /// input and output module hashes, and for each directive the
+    /// specialized function it produced, its size, and whether it
+    /// came from the cache.
+    #[structopt(long = "manifest")]
+    manifest: Option<PathBuf>,
+
+    /// Output IR for generic and specialized functions to files in a directory.
+    #[structopt(long = "output-ir")]
+    output_ir: Option<PathBuf>,
+
+    /// Also output Graphviz DOT files for each directive's context
+    /// tree and specialized CFG (requires `--output-ir`).
+    #[structopt(long = "output-ir-dot")]
+    output_ir_dot: bool,
+
+    /// Output IR only for directives on this function (a name,
+    /// export name or index); may be repeated.
+    #[structopt(long = "output-ir-func", value_name = "FUNC", number_of_values = 1)]
+    output_ir_funcs: Vec<String>,
+
+    /// Output IR only for directives with this user ID; may be
+    /// repeated.
+    #[structopt(long = "output-ir-directive", value_name = "N", number_of_values = 1)]
+    output_ir_directives: Vec<u32>,
+
+    /// Output only the final IR of specialized functions (after
+    /// DCE), not the generic IR or escape reports.
+    #[structopt(long = "output-ir-final")]
+    output_ir_final: bool,
+
+    /// Compare the IR of each specialized function in the output
+    /// against its snapshot in this directory, failing on any
+    /// difference (after writing the output).
+    #[structopt(
+        long = "check-ir-against",
+        value_name = "DIR",
+        env = "WEVAL_CHECK_IR_AGAINST"
+    )]
+    check_ir_against: Option<PathBuf>,
+
+    /// With `--check-ir-against`, write the snapshots instead of
+    /// comparing against them.
+    #[structopt(long = "update-ir")]
+    update_ir: bool,
+
+    /// Write the evaluator's results for each directive (contexts,
+    /// abstract values, block entry states and what became of each
+    /// call) to a directory, as Datalog relations in tab-separated
+    /// `.facts` files, with their declarations in `schema.dl`.
+    #[structopt(long = "emit-facts", value_name = "DIR")]
+    emit_facts: Option<PathBuf>,
+
+    /// Detect the dispatch loops of interpreter function FUNC (a
+    /// name, export name or index), keyed on a load from the
+    /// bytecode buffer in its parameter PARAM, and specialize them
+    /// per PC as if it used weval's context intrinsics.
+    #[structopt(
+        long = "auto-dispatch",
+        value_name = "FUNC:PARAM",
+        parse(try_from_str = dispatch::parse_spec),
+        number_of_values = 1
+    )]
+    auto_dispatch: Vec<(String, usize)>,
+
+    /// Specialize only the dispatch of `--auto-dispatch` loops per
+    /// PC, calling opcode handlers outlined into shared functions
+    /// rather than copying them into every PC: less folds, but
+    /// specialized code is much smaller.
+    #[structopt(long = "dispatch-only")]
+    dispatch_only: bool,
+
+    /// Also export each opcode handler of the `--auto-dispatch`
+    /// loop as a function taking its entry state, with a
+    /// `weval.opcode-stubs` section describing them, for use as
+    /// templates by a JIT.
+    #[structopt(long = "opcode-stubs")]
+    opcode_stubs: bool,
+
+    /// Specialize dispatch loops only for the hot PCs in this
+    /// execution-count profile (lines of `PC COUNT`), continuing
+    /// in generic dispatch from cold PCs.
+    #[structopt(long = "pc-profile", env = "WEVAL_PC_PROFILE")]
+    pc_profile: Option<PathBuf>,
+
+    /// The `--pc-profile` counts are per opcode (the byte at each
+    /// PC) rather than per PC.
+    #[structopt(long = "pc-profile-by-opcode")]
+    pc_profile_by_opcode: bool,
+
+    /// The count at which a PC (or opcode) in `--pc-profile` is
+    /// hot.
+    #[structopt(
+        long = "hot-pc-min-count",
+        value_name = "N",
+        default_value = "1",
+        env = "WEVAL_HOT_PC_MIN_COUNT"
+    )]
+    hot_pc_min_count: u64,
+
+    /// Specialize function FUNC (a name, export name or index) as
+    /// the host asks, whether or not the module makes weval
+    /// requests itself, with the parameters given by `--const-arg`
+    /// constant, and export the result.
+    #[structopt(long = "specialize-func", value_name = "FUNC")]
+    specialize_func: Option<String>,
+
+    /// A constant parameter for `--specialize-func`: `N=VALUE`, or
+    /// `N=@FILE` for a pointer to a buffer holding FILE's contents
+    /// (e.g. bytecode), which loads through it read while
+    /// specializing.
+    #[structopt(
+        long = "const-arg",
+        value_name = "N=VALUE|N=@FILE",
+        parse(try_from_str = host::parse_const_arg),
+        number_of_values = 1
+    )]
+    const_args: Vec<(usize, host::ConstArg)>,
+
+    /// Name of the export for the `--specialize-func` result
+    /// (default: `FUNC.specialized`).
+    #[structopt(long = "specialized-export", value_name = "NAME")]
+    specialized_export: Option<String>,
+
+    /// Also export an entry into the `--auto-dispatch` loop of the
+    /// `--specialize-func` function at bytecode PC (an offset into
+    /// the buffer, if the PC points into it), specialized from
+    /// there on, as `EXPORT.osrPC`: an engine can transfer a
+    /// running loop into it. It takes the exported function's
+    /// parameters followed by the loop's state.
+    #[structopt(long = "osr-pc", value_name = "PC", number_of_values = 1)]
+    osr_pcs: Vec<u32>,
+
+    /// Outline blocks of code repeated across many specializations
+    /// of the same generic function into shared helper functions.
+    #[structopt(long = "outline-common")]
+    outline_common: bool,
+
+    /// The fewest instructions (not counting constants) in a block
+    /// for `--outline-common` to outline it (default: 8).
+    #[structopt(
+        long = "outline-min-insts",
+        value_name = "N",
+        env = "WEVAL_OUTLINE_MIN_INSTS"
+    )]
+    outline_min_insts: Option<usize>,
+
+    /// The fewest places a block must occur in for
+    /// `--outline-common` to outline it (default: 4).
+    #[structopt(
+        long = "outline-min-count",
+        value_name = "N",
+        env = "WEVAL_OUTLINE_MIN_COUNT"
+    )]
+    outline_min_count: Option<usize>,
+
+    /// Let specialized functions use other directives' results
+    /// directly: a load of another directive's result becomes the
+    /// table index of its specialization, and a call through it a
+    /// direct call. This assumes the module never changes a result
+    /// once weval has written it (e.g. by reusing its memory).
+    #[structopt(long = "link-results")]
+    link_results: bool,
+
+    /// Once all directives are specialized, constant-propagate the
+    /// specialized functions again with the table indices of the
+    /// results known, and call specializations (or other known
+    /// functions) reached through results directly rather than
+    /// through the table.
+    #[structopt(long = "devirtualize-results")]
+    devirtualize_results: bool,
+
+    /// Abandon a specialization with more than FACTOR times as many
+    /// instructions as its generic function, leaving its directive
+    /// to the generic function.
+    #[structopt(long = "max-growth", value_name = "FACTOR", env = "WEVAL_MAX_GROWTH")]
+    max_growth: Option<f64>,
+
+    /// Optimize for size over speed. Changes the defaults to:
+    /// `--outline-common` with low thresholds; `--dispatch-only`
+    /// for `--auto-dispatch` loops, unless a `--pc-profile` names
+    /// the hot PCs, which are then the only ones specialized; and
+    /// a `--max-growth` of 2.
+    #[structopt(long = "opt-size")]
+    opt_size: bool,
+
+    /// Specialize quickly, for edit-compile-test loops: skip the
+    /// cleanup passes over specialized code that only make it
+    /// smaller or faster, specialize loops at only the first few
+    /// hundred PCs reached per directive the module makes
+    /// (continuing in generic dispatch from the rest), and collect
+    /// no statistics. Not for release builds.
+    #[structopt(long = "fast")]
+    fast: bool,
+
+    /// Declare the imported function NAME from MODULE pure: it
+    /// accesses no memory, globals or tables and has no other
+    /// effects, so calls to it are no barrier to optimizing
+    /// specialized code. Imports can also be listed in a
+    /// `weval.pure-imports` custom section.
+    #[structopt(
+        long = "pure-import",
+        value_name = "MODULE:NAME",
+        parse(try_from_str = pure_imports::parse_spec),
+        number_of_values = 1
+    )]
+    pure_imports: Vec<(String, String)>,
+
+    /// Check the IR's invariants (SSA form, types, CFG) after every
+    /// pass over specialized functions and before emission,
+    /// failing with the pass and function that broke one.
+    #[structopt(long = "verify")]
+    verify: bool,
+
+    /// Preserve Wasm trap semantics in specialized code: keep loads
+    /// and other operators that may trap even when their results
+    /// are unused, and do not move, merge or remove memory
+    /// accesses, for code not known never to trap. Folding never
+    /// removes a trap.
+    #[structopt(long = "preserve-traps")]
+    preserve_traps: bool,
+
+    /// Do not fold operators to a NaN, whose sign and payload the
+    /// engine may choose differently, so that specialized code
+    /// computes the same bits as the generic code on any engine.
+    /// Such folds are counted in `--show-stats` and the report
+    /// either way.
+    #[structopt(long = "deterministic-folds")]
+    deterministic_folds: bool,
+
+    /// Add hooks for the host to fulfill requests the module makes
+    /// after it is deployed: an import, `weval-runtime.request`,
+    /// and an export, `weval-runtime.service`, that calls it for
+    /// each pending request and installs the table index the host
+    /// returns.
+    #[structopt(long = "runtime-hooks")]
+    runtime_hooks: bool,
+
+    /// Emit a `weval.pressure` custom section with register-pressure
+    /// estimates for specialized functions, as hints for the
+    /// engine's compiler.
+    #[structopt(long = "pressure-hints")]
+    pressure_hints: bool,
+
+    /// Keep the `weval` intrinsic imports and calls in the output,
+    /// so that it can be wevaled again (e.g. to specialize an
+    /// interpreter that the specialized functions still run).
+    /// The output then needs the weval stubs to run. To Wizen it
+    /// again, keep its initialization function too
+    /// (`--keep-init-func`).
+    #[structopt(long = "keep-intrinsics")]
+    keep_intrinsics: bool,
+
+    #[structopt(flatten)]
+    precompile_opts: precompile::PrecompileOptions,
+
+    #[structopt(flatten)]
+    exit_policy: policy::ExitPolicy,
+
+    /// Abandon a directive, leaving its function generic, when
+    /// it would bring the estimated memory of all specializations
+    /// in progress above this many GiB.
+    #[structopt(
+        long = "max-memory-gb",
+        value_name = "GIB",
+        env = "WEVAL_MAX_MEMORY_GB"
+    )]
+    max_memory_gb: Option<f64>,
+
+    /// How many threads to specialize directives on (default: one
+    /// per CPU).
+    #[structopt(long = "threads", value_name = "N", env = "WEVAL_THREADS")]
+    threads: Option<usize>,
+
+    /// Write a profile of weval's own phases (per directive and
+    /// per pass) to this file, in Chrome trace-event JSON format.
+    #[structopt(long = "self-profile")]
+    self_profile: Option<PathBuf>,
+
+    /// Start from the option defaults tuned for a kind of
+    /// interpreter: `spidermonkey` or `quickjs-style`. Options
+    /// given explicitly override the preset's values; a preset
+    /// can turn flags on but not off.
+    #[structopt(
+        long = "preset",
+        value_name = "NAME",
+        env = "WEVAL_PRESET",
+        parse(try_from_str = preset::find)
+    )]
+    preset: Option<&'static preset::Preset>,
+
+    /// Emit verbose progress messages.
+    #[structopt(short = "v", long = "verbose")]
+    verbose: bool,
+
+    /// How to show progress while specializing: `fancy` (a bar),
+    /// `plain` (a line with the directive count and elapsed time
+    /// every few seconds, for logs), `tui` (a bar, plus each
+    /// directive in progress with its size so far, memory use and
+    /// recent warnings), `none`, or `auto` (with `--verbose`, a bar
+    /// on a terminal and plain lines otherwise).
+    #[structopt(
+        long = "progress",
+        default_value = "auto",
+        value_name = "MODE",
+        env = "WEVAL_PROGRESS"
+    )]
+    progress: progress::ProgressMode,
+
+    /// Monitor the run in a terminal UI: `--progress tui`.
+    #[structopt(long = "tui")]
+    tui: bool,
+}
+
+impl WevalOptions {
+    /// The flags, by long name (`wizen` for `-w`).
+    fn flags(&mut self) -> Vec<(&'static str, &mut bool)> {
+        let mut flags = vec![
+            ("wizen", &mut self.wizen),
+            ("check-determinism", &mut self.check_determinism),
+            ("verify-snapshot", &mut self.verify_snapshot),
+            ("skip-malformed-requests", &mut self.skip_malformed_requests),
+            ("show-wizen-changes", &mut self.show_wizen_changes),
+            ("show-stats", &mut self.show_stats),
+            ("output-ir-dot", &mut self.output_ir_dot),
+            ("output-ir-final", &mut self.output_ir_final),
+            ("update-ir", &mut self.update_ir),
+            ("dispatch-only", &mut self.dispatch_only),
+            ("opcode-stubs", &mut self.opcode_stubs),
+            ("pc-profile-by-opcode", &mut self.pc_profile_by_opcode),
+            ("outline-common", &mut self.outline_common),
+            ("link-results", &mut self.link_results),
+            ("devirtualize-results", &mut self.devirtualize_results),
+            ("opt-size", &mut self.opt_size),
+            ("fast", &mut self.fast),
+            ("verify", &mut self.verify),
+            ("preserve-traps", &mut self.preserve_traps),
+            ("deterministic-folds", &mut self.deterministic_folds),
+            ("runtime-hooks", &mut self.runtime_hooks),
+            ("pressure-hints", &mut self.pressure_hints),
+            ("keep-intrinsics", &mut self.keep_intrinsics),
+            ("verbose", &mut self.verbose),
+            ("tui", &mut self.tui),
+            ("keep-init-func", &mut self.wizen_opts.keep_init_func),
+        ];
+        flags.extend(self.exit_policy.flags());
+        flags
+    }
+
+    /// Turn on the flags set in the environment, as `WEVAL_FAST=1`
+    /// for `--fast`. (Clap reads the environment only for options
+    /// that take values.) A flag can be turned on but not off, as on
+    /// the command line.
+    fn apply_env_flags(&mut self) -> anyhow::Result<()> {
+        for (name, flag) in self.flags() {
+            let var = format!("WEVAL_{}", name.to_uppercase().replace('-', "_"));
+            match std::env::var(&var).as_deref() {
+                Err(std::env::VarError::NotPresent) | Ok("" | "0" | "false") => {}
+                Ok("1" | "true") => *flag = true,
+                _ => anyhow::bail!("{} must be `true` or `false` (or 1 or 0)", var),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options for Wizening, passed through to Wizer.
+//
+// The guest runs sandboxed: it gets WASI, but no environment variables
+// and no directories unless given them here (see
+// `WizenOptions::SANDBOX`), as weval may run untrusted modules.
+#[derive(Clone, Debug, StructOpt)]
+pub struct WizenOptions {
+    /// Directories to preopen during Wizening (none by default).
+    #[structopt(long = "dir")]
+    preopens: Vec<PathBuf>,
+
+    /// Directories to preopen during Wizening under a different guest
+    /// path, as `GUEST_DIR::HOST_DIR`.
+    #[structopt(
+        long = "mapdir",
+        value_name = "GUEST_DIR::HOST_DIR",
+        parse(try_from_str = parse_map_dir)
+    )]
+    map_dirs: Vec<(PathBuf, PathBuf)>,
+
+    /// Fail if Wizening (running the initialization function and
+    /// taking the snapshot) takes longer than this many seconds. The
+    /// guest cannot be interrupted, so it runs on until weval exits;
+    /// for that reason this is rejected by `weval serve` and `weval
+    /// batch`.
+    #[structopt(
+        long = "wizen-timeout",
+        value_name = "SECS",
+        env = "WEVAL_WIZEN_TIMEOUT"
+    )]
+    timeout: Option<u64>,
+
+    /// Module (binary or text format) to use instead of the built-in
+    /// weval stubs, which provide the `weval` intrinsics during
+    /// Wizening. It must export every intrinsic the guest imports.
+    #[structopt(long = "stubs", env = "WEVAL_STUBS")]
+    stubs: Option<PathBuf>,
+
+    /// Additional modules to make available for import during
+    /// Wizening, as `NAME=PATH` (binary or text format), alongside
+    /// the weval stubs. Their code and state are not part of the
+    /// output.
+    #[structopt(
+        long = "preload",
+        value_name = "NAME=PATH",
+        parse(try_from_str = parse_preload),
+        number_of_values = 1
+    )]
+    preloads: Vec<(String, PathBuf)>,
+
+    /// Name of the Wizer initialization function to call.
+    #[structopt(
+        long = "init-func",
+        default_value = "wizer.initialize",
+        env = "WEVAL_INIT_FUNC"
+    )]
+    init_func: String,
+
+    /// Keep exporting the initialization function after Wizening.
+    #[structopt(long = "keep-init-func")]
+    keep_init_func: bool,
+
+    /// Function export renamings to apply after Wizening, as
+    /// `dst=src`: export `src` under the name `dst`, replacing any
+    /// existing `dst` export. Giving any renaming replaces the
+    /// default; an empty one (`--rename-func ''`) disables it.
+    #[structopt(
+        long = "rename-func",
+        alias = "func-rename",
+        value_name = "dst=src",
+        default_value = "_start=wizer.resume",
+        number_of_values = 1
+    )]
+    func_renames: Vec<String>,
+
+    /// Whether to provide WASI during Wizening (default: true).
+    #[structopt(
+        long = "allow-wasi",
+        value_name = "true|false",
+        env = "WEVAL_ALLOW_WASI"
+    )]
+    allow_wasi: Option<bool>,
+
+    /// Whether the environment variables are inherited during
+    /// Wizening (default: false).
+    #[structopt(
+        long = "inherit-env",
+        value_name = "true|false",
+        env = "WEVAL_INHERIT_ENV"
+    )]
+    inherit_env: Option<bool>,
+
+    /// Whether stdin, stdout and stderr are inherited during
+    /// Wizening (default: true).
+    #[structopt(
+        long = "inherit-stdio",
+        value_name = "true|false",
+        env = "WEVAL_INHERIT_STDIO"
+    )]
+    inherit_stdio: Option<bool>,
+
+    /// Enable or disable the bulk memory proposal during Wizening.
+    #[structopt(
+        long = "wasm-bulk-memory",
+        value_name = "true|false",
+        parse(try_from_str),
+        default_value = "true",
+        env = "WEVAL_WASM_BULK_MEMORY"
+    )]
+    wasm_bulk_memory: bool,
+
+    /// Enable or disable the multi-memory proposal during Wizening
+    /// (Wizer's default: true).
+    #[structopt(
+        long = "wasm-multi-memory",
+        value_name = "true|false",
+        env = "WEVAL_WASM_MULTI_MEMORY"
+    )]
+    wasm_multi_memory: Option<bool>,
+
+    /// Enable or disable the multi-value proposal during Wizening
+    /// (Wizer's default: true).
+    #[structopt(
+        long = "wasm-multi-value",
+        value_name = "true|false",
+        env = "WEVAL_WASM_MULTI_VALUE"
+    )]
+    wasm_multi_value: Option<bool>,
+
+    /// Enable or disable the SIMD proposal during Wizening (Wizer's
+    /// default: true).
+    #[structopt(long = "wasm-simd", value_name = "true|false", env = "WEVAL_WASM_SIMD")]
+    wasm_simd: Option<bool>,
+}
+
+/// What the guest may use while Wizening, unless the options say
+/// otherwise.
+#[cfg(feature = "wizer")]
+struct Sandbox {
+    allow_wasi: bool,
+    inherit_env: bool,
+    inherit_stdio: bool,
+}
+
+impl WizenOptions {
+    /// The defaults: WASI, for output and clocks, but nothing of the
+    /// host's beyond stdio. Guest output is captured and replayed.
+    #[cfg(feature = "wizer")]
+    const SANDBOX: Sandbox = Sandbox {
+        allow_wasi: true,
+        inherit_env: false,
+        inherit_stdio: true,
+    };
+
+    /// The sandbox these options ask for.
+    #[cfg(feature = "wizer")]
+    fn sandbox(&self) -> Sandbox {
+        Sandbox {
+            allow_wasi: self.allow_wasi.unwrap_or(Self::SANDBOX.allow_wasi),
+            inherit_env: self.inherit_env.unwrap_or(Self::SANDBOX.inherit_env),
+            inherit_stdio: self.inherit_stdio.unwrap_or(Self::SANDBOX.inherit_stdio),
+        }
+    }
+
+    /// The stubs module providing the `weval` intrinsics.
+    fn stubs(&self) -> anyhow::Result<Vec<u8>> {
+        match &self.stubs {
+            Some(path) => std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("reading stubs module {}: {}", path.display(), e)),
+            None => builtin_stubs().map(|stubs| stubs.to_vec()),
+        }
+    }
+}
+
+/// The built-in stubs in binary form, converted once per process.
+fn builtin_stubs() -> anyhow::Result<&'static [u8]> {
+    static STUBS_WASM: std::sync::OnceLock<Vec<u8>> = std::sync::OnceLock::new();
+    if let Some(stubs) = STUBS_WASM.get() {
+        return Ok(stubs);
+    }
+    let stubs = wat::parse_str(STUBS)?;
+    Ok(STUBS_WASM.get_or_init(|| stubs))
+}
+
+fn parse_map_dir(s: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+    match s.split_once("::") {
+        Some((guest, host)) if !host.contains("::") => Ok((guest.into(), host.into())),
+        _ => anyhow::bail!("must contain exactly one double colon ('::')"),
+    }
+}
+
+fn parse_preload(s: &str) -> anyhow::Result<(String, PathBuf)> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() => Ok((name.to_owned(), path.into())),
+        _ => anyhow::bail!("must be of the form NAME=PATH"),
+    }
+}
+
+/// Configure Wizer according to `opts`, for a module with the given
+/// function exports.
+#[cfg(feature = "wizer")]
+fn wizer_for(
+    opts: WizenOptions,
+    func_exports: &fxhash::FxHashSet<String>,
+) -> anyhow::Result<wizer::Wizer> {
+    let stubs = opts.stubs()?;
+    let sandbox = opts.sandbox();
+    anyhow::ensure!(
+        sandbox.allow_wasi || (opts.preopens.is_empty() && opts.map_dirs.is_empty()),
+        "--dir and --mapdir require WASI (--allow-wasi true)"
+    );
+    tracing::info!(
+        "Wizening with WASI: {}, environment: {}, stdio: {}, directories: {:?}",
+        sandbox.allow_wasi,
+        sandbox.inherit_env,
+        sandbox.inherit_stdio,
+        opts.preopens
+            .iter()
+            .chain(opts.map_dirs.iter().map(|(_, host)| host))
+            .collect::<Vec<_>>()
+    );
+    let mut w = wizer::Wizer::new();
+    w.allow_wasi(sandbox.allow_wasi)?;
+    w.init_func(opts.init_func);
+    w.keep_init_func(opts.keep_init_func);
+    w.inherit_env(sandbox.inherit_env);
+    w.inherit_stdio(sandbox.inherit_stdio);
+    for preopen in opts.preopens {
+        w.dir(&preopen);
+    }
+    for (guest, host) in opts.map_dirs {
+        w.map_dir(guest, host);
+    }
+    w.wasm_bulk_memory(opts.wasm_bulk_memory);
+    if let Some(enable) = opts.wasm_multi_memory {
+        w.wasm_multi_memory(enable);
+    }
+    if let Some(enable) = opts.wasm_multi_value {
+        w.wasm_multi_value(enable);
+    }
+    if let Some(enable) = opts.wasm_simd {
+        w.wasm_simd(enable);
+    }
+    w.preload_bytes("weval", stubs)?;
+    for (name, path) in opts.preloads {
+        anyhow::ensure!(
+            name != "weval",
+            "cannot preload a module named `weval`: the weval stubs use that name"
+        );
+        let bytes = std::fs::read(&path)
+            .map_err(|e| anyhow::anyhow!("reading preload module {}: {}", path.display(), e))?;
+        w.preload_bytes(&name, bytes)?;
+    }
+    for rename in opts.func_renames.iter().filter(|rename| !rename.is_empty()) {
+        let (dst, src) = rename
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("invalid function renaming: {}", rename))?;
+        // Wizer drops `dst` when renaming from a missing `src`, as
+        // with the default renaming on an already-wevaled module.
+        if !func_exports.contains(src) {
+            tracing::info!("skipping renaming {}: no export `{}`", rename, src);
+            continue;
+        }
+        w.func_rename(dst, src);
+    }
+    Ok(w)
+}
+
+/// Run `f` on another thread, failing if it does not finish within
+/// `secs` seconds. The thread is abandoned then; it stops when the
+/// process exits, so this is only for one-shot runs.
+#[cfg(feature = "wizer")]
+fn with_timeout(
+    secs: u64,
+    f: impl FnOnce() -> anyhow::Result<Vec<u8>> + Send + 'static,
+) -> anyhow::Result<Vec<u8>> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    // Wasm runs on the thread's stack, so give it as much as the main
+    // thread has.
+    std::thread::Builder::new()
+        .name("wizen".to_owned())
+        .stack_size(8 << 20)
+        .spawn(move || {
+            let _ = tx.send(f());
+        })?;
+    match rx.recv_timeout(std::time::Duration::from_secs(secs)) {
+        Ok(result) => result,
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            anyhow::bail!(error::WevalError::BudgetExceeded(format!(
+                "initialization exceeded budget: Wizening did not finish within {}s \
+                 (see --wizen-timeout)",
+                secs
+            )))
+        }
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+            anyhow::bail!("Wizening thread panicked")
+        }
+    }
+}
+
+#[cfg(feature = "wizer")]
+fn wizen(raw_bytes: Vec<u8>, opts: WizenOptions) -> anyhow::Result<Vec<u8>> {
+    let capture = opts.sandbox().inherit_stdio;
+    let timeout = opts.timeout;
+    let func_exports = func_exports(&raw_bytes[..])?;
+    let run = move || wizer_for(opts, &func_exports)?.run(&raw_bytes[..]);
+    let run = move || match timeout {
+        Some(secs) => with_timeout(secs, run),
+        None => run(),
+    };
+    if !capture {
+        return run();
+    }
+    let (result, output) = guest_output::capture(run)?;
+    match result {
+        Ok(bytes) => {
+            output.replay()?;
+            Ok(bytes)
+        }
+        Err(e) => Err(output.attach(e)),
+    }
+}
+
+/// The index of a module's start function, if it has one.
+fn start_func(module: &[u8]) -> anyhow::Result<Option<u32>> {
+    use waffle::wasmparser::{Parser, Payload};
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::StartSection { func, .. } = payload? {
+            return Ok(Some(func));
+        }
+    }
+    Ok(None)
+}
+
+/// The names of a module's function exports.
+#[cfg(feature = "wizer")]
+fn func_exports(module: &[u8]) -> anyhow::Result<fxhash::FxHashSet<String>> {
+    use waffle::wasmparser::{ExternalKind, Parser, Payload};
+    let mut names = fxhash::FxHashSet::default();
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::ExportSection(reader) = payload? {
+            for export in reader {
+                let export = export?;
+                if export.kind == ExternalKind::Func {
+                    names.insert(export.name.to_owned());
+                }
+            }
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(not(feature = "wizer"))]
+fn wizen(_raw_bytes: Vec<u8>, _opts: WizenOptions) -> anyhow::Result<Vec<u8>> {
+    anyhow::bail!("weval was built without Wizening support (the `wizer` feature)")
+}
+
+/// Print what Wizening changed in memories and globals, from the
+/// original module to the snapshot.
+fn show_snapshot_changes(original: &[u8], snapshot: &[u8]) -> anyhow::Result<()> {
+    let frontend_opts = waffle::FrontendOptions::default();
+    let before = image::build_image(
+        &waffle::Module::from_wasm_bytes(original, &frontend_opts)?,
+        None,
+    )?;
+    let after = image::build_image(
+        &waffle::Module::from_wasm_bytes(snapshot, &frontend_opts)?,
+        None,
+    )?;
+    eprintln!("Changes made by Wizening:");
+    for line in image::describe_changes(&before, &after) {
+        eprintln!("  {}", line);
+    }
+    Ok(())
+}
+
+/// Check that two snapshots of the same module are identical, and
+/// otherwise fail with where their memories and globals diverge.
+fn check_same_snapshot(a: &[u8], b: &[u8]) -> anyhow::Result<()> {
+    if a == b {
+        return Ok(());
+    }
+    let frontend_opts = waffle::FrontendOptions::default();
+    let image_a = image::build_image(&waffle::Module::from_wasm_bytes(a, &frontend_opts)?, None)?;
+    let image_b = image::build_image(&waffle::Module::from_wasm_bytes(b, &frontend_opts)?, None)?;
+    let diffs = image::diff(&image_a, &image_b);
+    if diffs.is_empty() {
+        anyhow::bail!(
+            "Wizening is nondeterministic: snapshots differ outside memories and globals"
+        );
+    }
+    anyhow::bail!(
+        "Wizening is nondeterministic: snapshots differ in:\n  {}",
+        diffs.join("\n  ")
+    );
+}
+
+/// Check that the input is a core module that Wizer and weval can
+/// process, with guidance if it targets WASI preview 2.
+///
+/// Wizer snapshots core modules and provides WASI preview 1 only, and
+/// weval specializes core modules. A guest built for WASI preview 2
+/// (a component, or a core module importing `wasi:*` interfaces
+/// before componentization) goes through weval as its preview-1 core
+/// module with the adapter applied afterward:
+///
+/// 1. build the guest for `wasm32-wasip1`;
+/// 2. Wizen and weval that core module (`weval weval -w ...`);
+/// 3. turn the result into a component with `wasm-tools component new
+///    --adapt wasi_snapshot_preview1=wasi_snapshot_preview1.reactor.wasm`
+///    (or `.command.wasm`), using the adapter matching the runtime.
+///
+/// The snapshot keeps its preview-1 imports, which the adapter then
+/// maps to preview 2, so behavior is the same as adapting the
+/// unprocessed module.
+///
+/// `wasi:*` imports only matter to Wizer, so they are rejected only
+/// when `wizening`; a module already Wizened elsewhere may keep them.
+fn check_core_module(bytes: &[u8], wizening: bool) -> anyhow::Result<()> {
+    use waffle::wasmparser::{Parser, Payload};
+    const GUIDANCE: &str = "build the guest for wasm32-wasip1, run weval on that core module, \
+                            then adapt the output with `wasm-tools component new --adapt \
+                            wasi_snapshot_preview1=<adapter>.wasm`";
+    if Parser::is_component(bytes) {
+        anyhow::bail!(error::WevalError::Unsupported(format!(
+            "input is a component, but weval processes core modules only; {}",
+            GUIDANCE
+        )));
+    }
+    if !wizening {
+        return Ok(());
+    }
+    for payload in Parser::new(0).parse_all(bytes) {
+        if let Payload::ImportSection(reader) = payload? {
+            for import in reader {
+                let import = import?;
+                if import.module.starts_with("wasi:") {
+                    anyhow::bail!(error::WevalError::Unsupported(format!(
+                        "input imports `{}` from the WASI preview 2 interface `{}`, \
+                         which Wizer cannot provide; {}",
+                        import.name, import.module, GUIDANCE
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set up logging and span timing, filtered by `RUST_LOG`.
+///
+/// Log output is organized into spans per directive (`directive`,
+/// with `user_id` and `func` fields), per context within a directive
+/// (`context`), and per optimization pass (`pass`, with a `name`
+/// field). Span-close events report time spent in each span. Filters
+/// may select on span fields, e.g.
+/// `RUST_LOG='weval[directive{user_id=42}]=trace'` to trace only one
+/// directive.
+fn init_tracing(profile: Option<&std::sync::Arc<profile::Profile>>) {
+    use tracing_subscriber::prelude::*;
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .with_filter(tracing_subscriber::EnvFilter::from_default_env());
+    // Profile down to per-pass spans, whatever the log filter.
+    let profile = profile.map(|profile| {
+        profile
+            .layer()
+            .with_filter(tracing_subscriber::filter::LevelFilter::DEBUG)
+    });
+    let _ = tracing_subscriber::registry()
+        .with(fmt)
+        .with(profile)
+        .with(policy::WarningLayer.with_filter(tracing_subscriber::filter::LevelFilter::WARN))
+        .try_init();
+}
+
+/// Weval a wasm.
+pub(crate) fn weval(
+    opts: WevalOptions,
+    warm: Option<&serve::WarmCaches>,
+) -> anyhow::Result<serde_json::Value> {
+    let WevalOptions {
+        input_module,
+        output_module,
+        wizen: do_wizen,
+        wizen_opts,
+        check_determinism,
+        verify_snapshot,
+        skip_malformed_requests,
+        cache,
+        cache_ro,
+        checkpoint,
+        resume,
+        show_wizen_changes,
+        show_stats,
+        show_largest,
+        report_html,
+        stats_json,
+        error_json: _,
+        manifest: manifest_path,
+        output_ir,
+        output_ir_dot,
+        output_ir_funcs,
+        output_ir_directives,
+        output_ir_final,
+        check_ir_against,
+        update_ir,
+        emit_facts,
+        auto_dispatch,
+        dispatch_only,
+        opcode_stubs,
+        pc_profile,
+        pc_profile_by_opcode,
+        hot_pc_min_count,
+        specialize_func,
+        const_args,
+        specialized_export,
+        osr_pcs,
+        outline_common,
+        outline_min_insts,
+        outline_min_count,
+        link_results,
+        devirtualize_results,
+        max_growth,
+        opt_size,
+        fast,
+        pure_imports,
+        verify,
+        preserve_traps,
+        deterministic_folds,
+        runtime_hooks,
+        pressure_hints,
+        keep_intrinsics,
+        precompile_opts,
+        exit_policy,
+        max_memory_gb,
+        threads: _,
+        self_profile: _,
+        preset,
+        verbose,
+        progress,
+        tui,
+    } = opts;
+    let progress = if tui {
+        progress::ProgressMode::Tui
+    } else {
+        progress
+    };
+    if let Some(preset) = preset {
+        tracing::info!("using preset {}", preset.name);
+    }
+    // `--opt-size` overrides the preset's defaults, but not options
+    // given explicitly.
+    let outline_common = outline_common || opt_size || preset.is_some_and(|p| p.outline_common);
+    let outline_min_insts = outline_min_insts
+        .or(opt_size.then_some(preset::OPT_SIZE_OUTLINE_MIN_INSTS))
+        .or(preset.map(|p| p.outline_min_insts))
+        .unwrap_or(preset::DEFAULT_OUTLINE_MIN_INSTS);
+    let outline_min_count = outline_min_count
+        .or(opt_size.then_some(preset::OPT_SIZE_OUTLINE_MIN_COUNT))
+        .or(preset.map(|p| p.outline_min_count))
+        .unwrap_or(preset::DEFAULT_OUTLINE_MIN_COUNT);
+    let dispatch_only =
+        dispatch_only || (opt_size && !auto_dispatch.is_empty() && pc_profile.is_none());
+    let max_growth = max_growth.or(opt_size.then_some(preset::OPT_SIZE_MAX_GROWTH));
+    let link_results = link_results || preset.is_some_and(|p| p.link_results);
+    let devirtualize_results =
+        devirtualize_results || preset.is_some_and(|p| p.devirtualize_results);
+    let max_memory_gb = max_memory_gb.or(preset.and_then(|p| p.max_memory_gb));
+
+    // Warnings count for this run only (under `weval serve`, too).
+    policy::take_warnings();
+    if let Some(gb) = max_memory_gb {
+        anyhow::ensure!(gb > 0.0, "--max-memory-gb must be positive");
+    }
+    if let Some(factor) = max_growth {
+        anyhow::ensure!(factor > 0.0, "--max-growth must be positive");
+    }
+    anyhow::ensure!(
+        pc_profile.is_some() || !pc_profile_by_opcode,
+        "--pc-profile-by-opcode requires --pc-profile"
+    );
+    anyhow::ensure!(
+        !auto_dispatch.is_empty() || !dispatch_only,
+        "--dispatch-only requires --auto-dispatch"
+    );
+    anyhow::ensure!(
+        auto_dispatch.len() == 1 || !opcode_stubs,
+        "--opcode-stubs requires exactly one --auto-dispatch"
+    );
+    anyhow::ensure!(
+        check_ir_against.is_some() || !update_ir,
+        "--update-ir requires --check-ir-against"
+    );
+    let hot_pcs = pc_profile
+        .map(|path| pc_profile::HotPcs::load(&path, hot_pc_min_count, pc_profile_by_opcode))
+        .transpose()?;
+    anyhow::ensure!(
+        specialize_func.is_some() || (const_args.is_empty() && specialized_export.is_none()),
+        "--const-arg and --specialized-export require --specialize-func"
+    );
+    anyhow::ensure!(
+        specialize_func.is_some() || osr_pcs.is_empty(),
+        "--osr-pc requires --specialize-func"
+    );
+    anyhow::ensure!(
+        precompile_opts.output.is_none() || cfg!(feature = "precompile"),
+        "weval was built without precompilation support (the `precompile` feature)"
+    );
+    if warm.is_some() && wizen_opts.timeout.is_some() {
+        // Wizer cannot interrupt the guest, so the Wizening thread of
+        // a request that timed out would run on in the server.
+        anyhow::bail!(error::WevalError::Unsupported(
+            "--wizen-timeout is not supported by `weval serve` or `weval batch`".to_owned()
+        ));
+    }
+    if verbose {
+        eprintln!("Reading raw module bytes...");
+    }
+    let mut raw_bytes = std::fs::read(&input_module)?;
+    check_core_module(&raw_bytes[..], do_wizen)?;
+
+    // Compute a hash of the original module so we can cache results
+    // keyed on that hash (and weval request arg strings).
+    let input_hash = cache::compute_hash(&raw_bytes[..]);
+    let input_build_id = build_id::read(&raw_bytes[..])?;
+    // Functions move with `--runtime-hooks`, and specialized code
+    // differs with `--preserve-traps`, `--deterministic-folds` and
+    // `--pure-import`, so results cached without them do not apply.
+    let mut pure_import_flags = pure_imports
+        .iter()
+        .map(|(module, name)| format!("pure-import={}:{}", module, name))
+        .collect::<Vec<_>>();
+    pure_import_flags.sort();
+    pure_import_flags.dedup();
+    let cache_hash = [
+        (runtime_hooks, "runtime-hooks"),
+        (preserve_traps, "preserve-traps"),
+        (deterministic_folds, "deterministic-folds"),
+    ]
+    .iter()
+    .filter(|(on, _)| *on)
+    .map(|(_, flag)| *flag)
+    .chain(pure_import_flags.iter().map(String::as_str))
+    .fold(input_hash, |hash, flag| {
+        cache::compute_hash(&[&hash[..], flag.as_bytes()].concat())
+    });
+
+    // Open the cache and read-only cache, if any. A server keeps its
+    // own in memory, used when the request names no cache file.
+    let cache = match warm {
+        Some(warm) if cache.is_none() && cache_ro.is_none() => warm.get(cache_hash)?,
+        _ => std::sync::Arc::new(cache::Cache::open(
+            cache.as_ref().map(|p| p.as_path()),
+            cache_ro.as_ref().map(|p| p.as_path()),
+            cache_hash,
+        )?),
+    };
+    let checkpoint = match (&checkpoint, &resume) {
+        (_, Some(dir)) => Some(checkpoint::Checkpoint::open(dir, cache_hash, true)?),
+        (Some(dir), None) => Some(checkpoint::Checkpoint::open(dir, cache_hash, false)?),
+        (None, None) => None,
+    };
+
+    // Wizer runs the start function and drops the start section, so
+    // note which function it was.
+    let input_start = if do_wizen {
+        start_func(&raw_bytes[..])?
+    } else {
+        None
+    };
+
+    // Optionally, Wizen the module first.
+    let module_bytes = if do_wizen {
+        if verbose {
+            eprintln!("Wizening the module with its input...");
+        }
+        // Keep the original bytes only if they are needed below.
+        let input = if check_determinism || show_wizen_changes {
+            raw_bytes.clone()
+        } else {
+            std::mem::take(&mut raw_bytes)
+        };
+        let bytes = tracing::info_span!("wizen").in_scope(|| wizen(input, wizen_opts.clone()))?;
+        if check_determinism {
+            if verbose {
+                eprintln!("Wizening again to check determinism...");
+            }
+            let again = tracing::info_span!("wizen")
+                .in_scope(|| wizen(raw_bytes.clone(), wizen_opts.clone()))?;
+            check_same_snapshot(&bytes[..], &again[..])?;
+        }
+        if show_wizen_changes {
+            show_snapshot_changes(&raw_bytes[..], &bytes[..])?;
+        }
+        drop(raw_bytes);
+        bytes
+    } else {
+        anyhow::ensure!(!check_determinism, "--check-determinism requires -w");
+        anyhow::ensure!(!show_wizen_changes, "--show-wizen-changes requires -w");
+        raw_bytes
+    };
+
+    // Load module.
+    if verbose {
+        eprintln!("Parsing the module...");
+    }
+    let mut frontend_opts = waffle::FrontendOptions::default();
+    frontend_opts.debug = true;
+    let warm_module = tracing::info_span!("parse").in_scope(|| {
+        warm.map(|warm| {
+            // Without Wizening, the bytes are the input, already hashed.
+            let hash = if do_wizen {
+                cache::compute_hash(&module_bytes[..])
+            } else {
+                input_hash
+            };
+            warm.module(hash, &module_bytes[..], &frontend_opts)
+        })
+        .transpose()
+    })?;
+    let mut module = match &warm_module {
+        Some(warm_module) => warm_module.module(),
+        None => tracing::info_span!("parse")
+            .in_scope(|| waffle::Module::from_wasm_bytes(&module_bytes[..], &frontend_opts))?,
+    };
+    let wizened_start = input_start.filter(|_| module.start_func.is_none());
+    let runtime_request = runtime_hooks
+        .then(|| runtime_hooks::add_request_import(&mut module))
+        .transpose()?;
+    // The new import moved the functions after it up one.
+    let wizened_start = wizened_start.map(|start| match runtime_request {
+        Some(request) if waffle::Func::from(start) >= request => waffle::Func::from(start + 1),
+        _ => waffle::Func::from(start),
+    });
+
+    let mut auto_dispatch = dispatch::resolve(&module, &auto_dispatch)?;
+    let pure_imports = pure_imports::resolve(&module, &pure_imports)?;
+    let stubs = if opcode_stubs {
+        let (&func, &param) = auto_dispatch.iter().next().unwrap();
+        opcode_stubs::add(&mut module, func, param)?
+    } else {
+        vec![]
+    };
+    let host_request = specialize_func
+        .map(|func| host::Request::new(&module, &func, &const_args, specialized_export))
+        .transpose()?;
+    let mut osr_requests = vec![];
+    if let Some(request) = host_request.as_ref().filter(|_| !osr_pcs.is_empty()) {
+        let func = request.directive.func;
+        let &param = auto_dispatch.get(&func).ok_or_else(|| {
+            anyhow::anyhow!("--osr-pc requires --auto-dispatch for the --specialize-func function")
+        })?;
+        let (osr, state) = osr::add_entry(&mut module, func, param)?;
+        auto_dispatch.insert(osr, param);
+        for &pc in &osr_pcs {
+            osr_requests.push(request.osr(osr, pc, state)?);
+        }
+    }
+
+    // Build module image. The data segments are kept: they are
+    // patched from the image at the end.
+    if verbose {
+        eprintln!("Building memory image...");
+    }
+    let mut im =
+        tracing::info_span!("build_image").in_scope(|| image::build_image(&module, None))?;
+
+    // Optionally, check the snapshot before trusting its request list.
+    if verify_snapshot {
+        if verbose {
+            eprintln!("Verifying the snapshot...");
+        }
+        snapshot::verify(&module, &im, &wizen_opts.stubs()?[..])?;
+    }
+
+    // Collect directives.
+    let mut directives = tracing::info_span!("collect_directives")
+        .in_scope(|| directive::collect(&module, &mut im, skip_malformed_requests))?;
+    for request in host_request.iter().chain(&osr_requests) {
+        directives.push(request.directive.clone());
+    }
+    tracing::debug!("Directives: {:?}", directives);
+
+    // Make sure IR output directory exists.
+    if let Some(dir) = &output_ir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let output_ir_funcs = output_ir_funcs
+        .iter()
+        .map(|name| {
+            intrinsics::find_func(&module, name)
+                .ok_or_else(|| anyhow::anyhow!("--output-ir-func: no function named `{}`", name))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let output_ir = output_ir.map(|dir| eval::IrOutput {
+        dir,
+        dot: output_ir_dot,
+        funcs: output_ir_funcs,
+        user_ids: output_ir_directives,
+        final_only: output_ir_final,
+    });
+    let eval_options = eval::EvalOptions {
+        output_ir,
+        auto_dispatch,
+        dispatch_only,
+        hot_pcs,
+        outline_common: outline_common.then_some(dedup::Options {
+            min_insts: outline_min_insts,
+            min_count: outline_min_count,
+        }),
+        link_results,
+        devirtualize_results,
+        max_growth,
+        fast,
+        facts_output: emit_facts.map(emit_facts::FactsOutput::new).transpose()?,
+        pure_imports,
+        wizened_start,
+        verify,
+        preserve_traps,
+        deterministic_folds,
+        memory: max_memory_gb
+            .map(|gb| eval::MemoryBudget::new((gb * (1u64 << 30) as f64) as usize)),
+        function_passes: vec![],
+    };
+
+    // Partially evaluate.
+    if verbose {
+        eprintln!("Specializing functions...");
+    }
+    let progress = progress::Progress::new(progress, verbose);
+    let mut result = tracing::info_span!("specialize").in_scope(|| {
+        eval::partially_evaluate(
+            module,
+            &mut im,
+            &directives[..],
+            progress,
+            &eval_options,
+            &cache,
+            checkpoint.as_ref(),
+        )
+    })?;
+    if let Some(facts_output) = eval_options.facts_output {
+        facts_output.write()?;
+    }
+    for request in host_request.iter().chain(&osr_requests) {
+        request.export(&mut result)?;
+    }
+    if let Some(request) = runtime_request {
+        runtime_hooks::add_service(&mut result.module, request, &mut im)?;
+    }
+    // The bodies still in IR form are those weval generated: outlined
+    // code, OSR entries and runtime hooks.
+    if verify {
+        for decl in result.module.funcs.values() {
+            if let waffle::FuncDecl::Body(_, name, body) = decl {
+                verify::check(&result.module, body, "code generation", name)?;
+            }
+        }
+    }
+
+    // Update memories in module.
+    if verbose {
+        eprintln!("Updatimg memory image...");
+    }
+    tracing::info_span!("update_image").in_scope(|| image::update(&mut result.module, &im));
+    drop(im);
+
+    tracing::debug!("Final module:\n{}", result.module.display());
+
+    if show_stats {
+        for stats in &result.stats {
+            eprintln!(
+                "Function {}: {} blocks, {} insts)",
+                stats.generic, stats.generic_blocks, stats.generic_insts,
+            );
+            eprintln!(
+                "   specialized ({} times): {} blocks, {} insts",
+                stats.specializations, stats.specialized_blocks, stats.specialized_insts
+            );
+            eprintln!(
+                "   virtstack: {} reads ({} mem), {} writes ({} mem)",
+                stats.virtstack_reads,
+                stats.virtstack_reads_mem,
+                stats.virtstack_writes,
+                stats.virtstack_writes_mem
+            );
+            eprintln!(
+                "   locals: {} reads ({} mem), {} writes ({} mem)",
+                stats.local_reads,
+                stats.local_reads_mem,
+                stats.local_writes,
+                stats.local_writes_mem
+            );
+            eprintln!("   flush stores elided: {}", stats.flush_stores_elided);
+            eprintln!("   compare chains to br_table: {}", stats.switch_chains);
+            eprintln!(
+                "   loop-invariant instructions hoisted: {}",
+                stats.licm_hoisted
+            );
+            eprintln!("   irreducible edges: {}", stats.irreducible_edges);
+            eprintln!(
+                "   live values at block starts: {} ({} per block)",
+                stats.live_value_at_block_start,
+                (stats.live_value_at_block_start as f64) / (stats.specialized_blocks as f64),
+            );
+            eprintln!(
+                "   constants: {} module-wide, {} per-directive",
+                stats.module_consts, stats.directive_consts
+            );
+            if stats.nondeterministic_folds > 0 {
+                eprintln!(
+                    "   folds to NaN (bits engine-dependent): {}",
+                    stats.nondeterministic_folds
+                );
+            }
+            for (user_id, buckets) in &stats.inferred_buckets {
+                eprintln!(
+                    "   inferred context buckets (user ID {}): {}",
+                    user_id,
+                    buckets.len()
+                );
+                for (bucket, pcs) in buckets {
+                    let mut listed = pcs
+                        .iter()
+                        .take(MAX_LISTED_BUCKET_PCS)
+                        .map(|pc| format!("{:#x}", pc))
+                        .collect::<Vec<_>>();
+                    if pcs.len() > MAX_LISTED_BUCKET_PCS {
+                        listed.push("...".to_owned());
+                    }
+                    eprintln!(
+                        "     bucket {}: {} PCs ({})",
+                        bucket,
+                        pcs.len(),
+                        listed.join(", ")
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(n) = show_largest {
+        let mut sizes = result.sizes.clone();
+        sizes.sort_by_key(|size| std::cmp::Reverse(size.specialized_bytes));
+        let total: usize = sizes.iter().map(|size| size.specialized_bytes).sum();
+        eprintln!(
+            "Largest specialized functions ({} total, {} bytes):",
+            sizes.len(),
+            total
+        );
+        for size in sizes.iter().take(n) {
+            eprintln!(
+                "  {} ({}): {} bytes ({:.1}% of total), {:.1}x generic {} ({} bytes)",
+                size.specialized,
+                result.module.funcs[size.specialized].name(),
+                size.specialized_bytes,
+                100.0 * (size.specialized_bytes as f64) / (total as f64),
+                size.growth(),
+                size.generic,
+                size.generic_bytes,
+            );
+            eprintln!(
+                "     from directive with user ID {} (output at {:#x}){}",
+                size.user_id,
+                size.func_index_out_addr,
+                if size.cache_hit { ", cached" } else { "" },
+            );
+        }
+    }
+
+    if let Some(report_html) = &report_html {
+        let html = report::html(&input_module.to_string_lossy(), &result);
+        std::fs::write(report_html, html)?;
+    }
+    let stats = report::json(&input_module.to_string_lossy(), &result);
+    if let Some(stats_json) = &stats_json {
+        std::fs::write(stats_json, stats.to_string())?;
+    }
+    let mut manifest = manifest_path
+        .as_ref()
+        .map(|_| report::manifest(&input_module.to_string_lossy(), &input_hash, &result));
+
+    if verbose {
+        eprintln!("Serializing back to binary form...");
+    }
+    let pressure = if pressure_hints {
+        result
+            .sizes
+            .iter()
+            .filter_map(|size| {
+                Some((
+                    waffle::entity::EntityRef::index(size.specialized) as u32,
+                    size.pressure?,
+                ))
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    // Compacting locals is a cleanup `--fast` skips.
+    let specialized = if fast {
+        Default::default()
+    } else {
+        result
+            .sizes
+            .iter()
+            .map(|size| waffle::entity::EntityRef::index(size.specialized) as u32)
+            .collect()
+    };
+    let golden_funcs = check_ir_against.as_ref().map(|_| {
+        let names = golden::names(
+            result
+                .sizes
+                .iter()
+                .map(|size| (result.module.funcs[size.generic].name(), size.user_id)),
+        );
+        names
+            .into_iter()
+            .zip(&result.sizes)
+            .map(|(name, size)| {
+                (
+                    name,
+                    waffle::entity::EntityRef::index(size.specialized) as u32,
+                )
+            })
+            .collect::<Vec<_>>()
+    });
+    let bytes = tracing::info_span!("encode").in_scope(|| result.module.to_wasm_bytes())?;
+    let fallbacks = policy::fallbacks(&result.module, &result.outcomes);
+    // The input bytes back lazily-parsed function bodies, so can go
+    // only with the module.
+    drop(result);
+    drop(module_bytes);
+
+    if verbose {
+        eprintln!("Performing post-filter pass to remove intrinsics...");
+    }
+    let (mut bytes, func_indices) = tracing::info_span!("filter")
+        .in_scope(|| filter::filter(&bytes[..], pressure, specialized, keep_intrinsics))?;
+    if opcode_stubs {
+        opcode_stubs::append_section(&mut bytes, &stubs);
+    }
+    let output_build_id = input_build_id.as_ref().map(|input_id| {
+        let id = build_id::derive(input_id, &bytes[..]);
+        build_id::append(&mut bytes, &id);
+        id
+    });
+
+    if verbose {
+        eprintln!("Writing output file...");
+    }
+    std::fs::write(&output_module, &bytes[..])?;
+    if let (Some(path), Some(manifest)) = (&manifest_path, manifest.as_mut()) {
+        report::remap_manifest_indices(manifest, &func_indices);
+        manifest["output"] = serde_json::json!({
+            "path": output_module.to_string_lossy(),
+            "sha256": report::hex(&cache::compute_hash(&bytes[..])),
+            "bytes": bytes.len(),
+            "build_id": output_build_id.as_deref().map(report::hex),
+        });
+        manifest["input"]["build_id"] = input_build_id.as_deref().map(report::hex).into();
+        std::fs::write(path, manifest.to_string())?;
+    }
+
+    if let (Some(dir), Some(funcs)) = (&check_ir_against, golden_funcs) {
+        let funcs = funcs
+            .into_iter()
+            .map(|(name, index)| (name, func_indices.get(&index).copied().unwrap_or(index)))
+            .collect::<Vec<_>>();
+        tracing::info_span!("check_ir")
+            .in_scope(|| golden::check(dir, &bytes[..], &funcs, update_ir))?;
+    }
+
+    // Precompile the final module, after filtering.
+    if let Some(cwasm) = &precompile_opts.output {
+        if verbose {
+            eprintln!("Precompiling with Wasmtime...");
+        }
+        let compiled = tracing::info_span!("precompile")
+            .in_scope(|| precompile::precompile(&bytes[..], &precompile_opts))?;
+        std::fs::write(cwasm, compiled)?;
+    }
+
+    exit_policy.check(&policy::take_warnings(), fallbacks)?;
+
+    if verbose {
+        eprintln!("Done.");
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A core module that was Wizened before componentization, so
+    /// still imports a WASI preview 2 interface.
+    const PRE_WIZENED: &str = r#"
+        (module
+          (import "wasi:cli/environment@0.2.0" "get-arguments" (func (param i32)))
+          (memory (export "memory") 1)
+          (func (export "_start")))
+    "#;
+
+    fn write_module(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("weval-{}-{}", std::process::id(), name));
+        std::fs::write(&path, wat::parse_str(PRE_WIZENED).unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn wasi_p2_import_without_wizening() {
+        let input = write_module("p2-in.wasm");
+        let output = input.with_file_name(format!("weval-{}-p2-out.wasm", std::process::id()));
+        let args = [
+            "-i",
+            input.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ];
+        run(weval_command(args).unwrap(), None).unwrap();
+        assert!(std::fs::metadata(&output).is_ok());
+
+        let cmd =
+            Command::from_iter_safe(["weval", "list-candidates", "-i", input.to_str().unwrap()])
+                .unwrap();
+        run(cmd, None).unwrap();
+
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+    }
+
+    #[test]
+    fn wasi_p2_import_rejected_when_wizening() {
+        let bytes = wat::parse_str(PRE_WIZENED).unwrap();
+        let err = check_core_module(&bytes[..], true).unwrap_err();
+        assert!(err.to_string().contains("WASI preview 2"), "{}", err);
+        check_core_module(&bytes[..], false).unwrap();
+    }
+
+    #[test]
+    fn component_rejected() {
+        let bytes = wat::parse_str("(component)").unwrap();
+        assert!(check_core_module(&bytes[..], false).is_err());
+        assert!(check_core_module(&bytes[..], true).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Compacting the locals of emitted specialized functions.
+//!
+//! Waffle's backend gives each SSA value a local, reusing one only
+//! within a conservative live range, and declares locals in the order
+//! it allocates them; a large specialized function comes out with
+//! many sparse locals of interleaved types, which baseline compilers
+//! handle poorly. As part of the final filter, we renumber the locals
+//! of each specialized function: a local never accessed is dropped,
+//! locals of one type whose live intervals do not overlap share a
+//! slot, and each type's locals are declared as one run.
+//!
+//! A local's live interval runs from its first to its last access in
+//! the body, widened to cover any loop around an access, so that a
+//! value carried around a backedge stays live across the whole loop.
+//! This is sound because the backend writes a value's local before
+//! any read of it on every path (the value's definition dominates its
+//! uses); a local whose first access is a read is kept live from the
+//! start of the body all the same. Parameters are never renumbered.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use waffle::wasmparser::{FunctionBody, Operator, ValType};
+
+/// New local indices and declarations for one function body.
+pub(crate) struct Compaction {
+    /// The new index of each local, by old index; parameters and
+    /// locals never accessed map to themselves.
+    remap: Vec<u32>,
+    /// The new declarations of the non-parameter locals.
+    pub(crate) decls: Vec<(u32, ValType)>,
+    /// Locals declared before compaction.
+    pub(crate) before: u32,
+}
+
+impl Compaction {
+    /// The new index of local `index`.
+    pub(crate) fn local(&self, index: u32) -> u32 {
+        self.remap[index as usize]
+    }
+}
+
+/// The live interval of a local, in operator indices.
+struct Interval {
+    start: usize,
+    end: usize,
+    /// Outermost loops around accesses, whose ends are only known
+    /// once the body has been read.
+    loops: Vec<usize>,
+}
+
+/// The slots of one type: how many, and the free ones (with the end
+/// of their last interval).
+struct Slots {
+    ty: ValType,
+    count: u32,
+    free: BinaryHeap<Reverse<(usize, u32)>>,
+}
+
+/// A control frame: for a `loop`, its index in `loops`; and the
+/// outermost loop the frame is in, if any.
+struct Frame {
+    this_loop: Option<usize>,
+    outer_loop: Option<usize>,
+}
+
+/// Compute a compaction of the locals of `body`, a function with
+/// `num_params` parameters.
+pub(crate) fn compact(body: &FunctionBody, num_params: u32) -> anyhow::Result<Compaction> {
+    let mut types = vec![];
+    for decl in body.get_locals_reader()? {
+        let (count, ty) = decl?;
+        types.extend(std::iter::repeat_n(ty, count as usize));
+    }
+    let before = types.len() as u32;
+
+    let mut intervals: Vec<Option<Interval>> = types.iter().map(|_| None).collect();
+    // Start and end of each loop.
+    let mut loops: Vec<(usize, usize)> = vec![];
+    // The function body's own frame is never popped.
+    let mut frames = vec![Frame {
+        this_loop: None,
+        outer_loop: None,
+    }];
+    for (i, op) in body.get_operators_reader()?.into_iter().enumerate() {
+        let (local, read) = match op? {
+            Operator::Block { .. } | Operator::If { .. } | Operator::Try { .. } => {
+                let outer_loop = frames.last().unwrap().outer_loop;
+                frames.push(Frame {
+                    this_loop: None,
+                    outer_loop,
+                });
+                continue;
+            }
+            Operator::Loop { .. } => {
+                let outer_loop = frames.last().unwrap().outer_loop;
+                frames.push(Frame {
+                    this_loop: Some(loops.len()),
+                    outer_loop: outer_loop.or(Some(loops.len())),
+                });
+                loops.push((i, usize::MAX));
+                continue;
+            }
+            Operator::End | Operator::Delegate { .. } => {
+                if frames.len() > 1 {
+                    if let Some(l) = frames.pop().unwrap().this_loop {
+                        loops[l].1 = i;
+                    }
+                }
+                continue;
+            }
+            Operator::LocalGet { local_index } => (local_index, true),
+            Operator::LocalSet { local_index } | Operator::LocalTee { local_index } => {
+                (local_index, false)
+            }
+            _ => continue,
+        };
+        let Some(local) = local.checked_sub(num_params) else {
+            continue;
+        };
+        let outer_loop = frames.last().unwrap().outer_loop;
+        let interval = intervals[local as usize].get_or_insert_with(|| Interval {
+            start: if read { 0 } else { i },
+            end: i,
+            loops: vec![],
+        });
+        interval.end = i;
+        if let Some(l) = outer_loop {
+            if interval.loops.last() != Some(&l) {
+                interval.loops.push(l);
+            }
+        }
+    }
+
+    // Widen each interval over its loops, then allocate slots per
+    // type by linear scan, in order of interval start.
+    let mut by_start = vec![];
+    for (local, interval) in intervals.iter().enumerate() {
+        let Some(interval) = interval else {
+            continue;
+        };
+        let (mut start, mut end) = (interval.start, interval.end);
+        for &l in &interval.loops {
+            start = start.min(loops[l].0);
+            end = end.max(loops[l].1);
+        }
+        by_start.push((start, end, local));
+    }
+    by_start.sort_unstable();
+
+    // Per type, in order of first allocation.
+    let mut slots: Vec<Slots> = vec![];
+    let mut slot = vec![None; types.len()];
+    for &(start, end, local) in &by_start {
+        let ty = types[local];
+        let index = match slots.iter().position(|slots| slots.ty == ty) {
+            Some(index) => index,
+            None => {
+                slots.push(Slots {
+                    ty,
+                    count: 0,
+                    free: BinaryHeap::new(),
+                });
+                slots.len() - 1
+            }
+        };
+        let slots = &mut slots[index];
+        let s = match slots.free.peek() {
+            Some(&Reverse((last_end, s))) if last_end < start => {
+                slots.free.pop();
+                s
+            }
+            _ => {
+                slots.count += 1;
+                slots.count - 1
+            }
+        };
+        slots.free.push(Reverse((end, s)));
+        slot[local] = Some((index, s));
+    }
+
+    // Each type's slots follow those of the types before it.
+    let mut base = vec![];
+    let mut next = num_params;
+    let mut decls = vec![];
+    for slots in &slots {
+        base.push(next);
+        next += slots.count;
+        decls.push((slots.count, slots.ty));
+    }
+    let remap = (0..num_params)
+        .chain(slot.iter().enumerate().map(|(local, slot)| match slot {
+            Some((index, s)) => base[*index] + s,
+            None => num_params + local as u32,
+        }))
+        .collect();
+    Ok(Compaction {
+        remap,
+        decls,
+        before,
+    })
+}
@@ -0,0 +1,696 @@
+//! Constant folding with exact Wasm semantics.
+//!
+//! Every fold of a numeric operator over constants, in the abstract
+//! interpreter and elsewhere, goes through `eval` here, which follows
+//! the spec's definition of the operator bit for bit rather than the
+//! host's:
+//!
+//! - integer division and remainder, and the trapping float-to-int
+//!   conversions, do not fold where they would trap, so the trap stays
+//!   in the residual code; `rem_s` of the minimum value by -1 is 0, not
+//!   a trap;
+//! - shift and rotate counts are taken modulo the bit width;
+//! - `min`/`max` order -0 below +0; `nearest` rounds ties to even;
+//!   `abs`, `neg` and `copysign` operate on the sign bit alone, so they
+//!   preserve NaN payloads, as the spec requires;
+//! - a floating-point operator whose result is NaN produces the
+//!   canonical NaN, which the spec permits in every case, regardless of
+//!   the NaN the host's instructions would produce.
+//!
+//! So a fold gives the same result on every host weval runs on. Only
+//! folds to NaN may still differ from what the engine running the
+//! generic code computes, since the spec leaves the engine free to
+//! choose the NaN's sign and payload; `is_nondeterministic` picks
+//! those out, and weval counts them, or with `--deterministic-folds`
+//! leaves them to the engine. (Float-to-int conversions have no such
+//! freedom: the trapping ones do not fold where they would trap, and
+//! the saturating ones are fully specified.)
+
+use crate::value::WasmVal;
+use waffle::Operator;
+
+const F32_CANONICAL_NAN: u32 = 0x7fc0_0000;
+const F64_CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+/// The result of the numeric operator `op` on constant `args`: `None`
+/// if `op` is not a numeric operator on scalars, if the arguments are
+/// not of the types it takes, or if it traps on them.
+pub(crate) fn eval(op: Operator, args: &[WasmVal]) -> Option<WasmVal> {
+    match *args {
+        [x] => unary(op, x),
+        [x, y] => binary(op, x, y),
+        _ => None,
+    }
+}
+
+/// Whether `result`, of `op`, is one the engine may compute
+/// differently: a NaN from an operator other than those defined on
+/// bits.
+pub(crate) fn is_nondeterministic(op: Operator, result: WasmVal) -> bool {
+    let nan = match result {
+        WasmVal::F32(bits) => f32::from_bits(bits).is_nan(),
+        WasmVal::F64(bits) => f64::from_bits(bits).is_nan(),
+        _ => false,
+    };
+    nan && !matches!(
+        op,
+        Operator::F32Abs
+            | Operator::F32Neg
+            | Operator::F32Copysign
+            | Operator::F64Abs
+            | Operator::F64Neg
+            | Operator::F64Copysign
+            | Operator::F32ReinterpretI32
+            | Operator::F64ReinterpretI64
+    )
+}
+
+fn bool(b: bool) -> WasmVal {
+    WasmVal::I32(u32::from(b))
+}
+
+fn f32(x: f32) -> WasmVal {
+    WasmVal::F32(if x.is_nan() {
+        F32_CANONICAL_NAN
+    } else {
+        x.to_bits()
+    })
+}
+
+fn f64(x: f64) -> WasmVal {
+    WasmVal::F64(if x.is_nan() {
+        F64_CANONICAL_NAN
+    } else {
+        x.to_bits()
+    })
+}
+
+/// `x` truncated toward zero, if that is in `min..max`, the range of
+/// the integer type it is converted to; `None` where the conversion
+/// traps. (Every `f32` is exactly an `f64`, as are the bounds.)
+fn trunc(x: f64, min: f64, max: f64) -> Option<f64> {
+    let x = x.trunc();
+    (x >= min && x < max).then_some(x)
+}
+
+const I32_MIN: f64 = -2147483648.0;
+const I32_END: f64 = 2147483648.0;
+const U32_END: f64 = 4294967296.0;
+const I64_MIN: f64 = -9223372036854775808.0;
+const I64_END: f64 = 9223372036854775808.0;
+const U64_END: f64 = 18446744073709551616.0;
+
+/// `min`, or with `max`, `max`: NaN if either is NaN, and -0 below +0.
+fn min_max(a: f64, b: f64, max: bool) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        // Equal, but possibly zeroes of different signs.
+        if (a.is_sign_negative() && !max) || (b.is_sign_negative() && max) {
+            a
+        } else {
+            b
+        }
+    } else if (a < b) != max {
+        a
+    } else {
+        b
+    }
+}
+
+fn unary(op: Operator, x: WasmVal) -> Option<WasmVal> {
+    use WasmVal::*;
+    Some(match (op, x) {
+        (Operator::I32Eqz, I32(a)) => bool(a == 0),
+        (Operator::I64Eqz, I64(a)) => bool(a == 0),
+        (Operator::I32Clz, I32(a)) => I32(a.leading_zeros()),
+        (Operator::I32Ctz, I32(a)) => I32(a.trailing_zeros()),
+        (Operator::I32Popcnt, I32(a)) => I32(a.count_ones()),
+        (Operator::I64Clz, I64(a)) => I64(a.leading_zeros().into()),
+        (Operator::I64Ctz, I64(a)) => I64(a.trailing_zeros().into()),
+        (Operator::I64Popcnt, I64(a)) => I64(a.count_ones().into()),
+        (Operator::I32Extend8S, I32(a)) => I32(a as i8 as i32 as u32),
+        (Operator::I32Extend16S, I32(a)) => I32(a as i16 as i32 as u32),
+        (Operator::I64Extend8S, I64(a)) => I64(a as i8 as i64 as u64),
+        (Operator::I64Extend16S, I64(a)) => I64(a as i16 as i64 as u64),
+        (Operator::I64Extend32S, I64(a)) => I64(a as i32 as i64 as u64),
+        (Operator::I32WrapI64, I64(a)) => I32(a as u32),
+        (Operator::I64ExtendI32S, I32(a)) => I64(a as i32 as i64 as u64),
+        (Operator::I64ExtendI32U, I32(a)) => I64(a.into()),
+
+        (Operator::F32Abs, F32(a)) => F32(a & 0x7fff_ffff),
+        (Operator::F32Neg, F32(a)) => F32(a ^ 0x8000_0000),
+        (Operator::F64Abs, F64(a)) => F64(a & 0x7fff_ffff_ffff_ffff),
+        (Operator::F64Neg, F64(a)) => F64(a ^ 0x8000_0000_0000_0000),
+        (Operator::F32Ceil, F32(a)) => f32(f32::from_bits(a).ceil()),
+        (Operator::F32Floor, F32(a)) => f32(f32::from_bits(a).floor()),
+        (Operator::F32Trunc, F32(a)) => f32(f32::from_bits(a).trunc()),
+        (Operator::F32Nearest, F32(a)) => f32(f32::from_bits(a).round_ties_even()),
+        (Operator::F32Sqrt, F32(a)) => f32(f32::from_bits(a).sqrt()),
+        (Operator::F64Ceil, F64(a)) => f64(f64::from_bits(a).ceil()),
+        (Operator::F64Floor, F64(a)) => f64(f64::from_bits(a).floor()),
+        (Operator::F64Trunc, F64(a)) => f64(f64::from_bits(a).trunc()),
+        (Operator::F64Nearest, F64(a)) => f64(f64::from_bits(a).round_ties_even()),
+        (Operator::F64Sqrt, F64(a)) => f64(f64::from_bits(a).sqrt()),
+
+        (Operator::I32TruncF32S, F32(a)) => {
+            I32(trunc(f32::from_bits(a).into(), I32_MIN, I32_END)? as i32 as u32)
+        }
+        (Operator::I32TruncF32U, F32(a)) => {
+            I32(trunc(f32::from_bits(a).into(), 0.0, U32_END)? as u32)
+        }
+        (Operator::I32TruncF64S, F64(a)) => {
+            I32(trunc(f64::from_bits(a), I32_MIN, I32_END)? as i32 as u32)
+        }
+        (Operator::I32TruncF64U, F64(a)) => I32(trunc(f64::from_bits(a), 0.0, U32_END)? as u32),
+        (Operator::I64TruncF32S, F32(a)) => {
+            I64(trunc(f32::from_bits(a).into(), I64_MIN, I64_END)? as i64 as u64)
+        }
+        (Operator::I64TruncF32U, F32(a)) => {
+            I64(trunc(f32::from_bits(a).into(), 0.0, U64_END)? as u64)
+        }
+        (Operator::I64TruncF64S, F64(a)) => {
+            I64(trunc(f64::from_bits(a), I64_MIN, I64_END)? as i64 as u64)
+        }
+        (Operator::I64TruncF64U, F64(a)) => I64(trunc(f64::from_bits(a), 0.0, U64_END)? as u64),
+        // Rust's float-to-int casts saturate, and take NaN to 0, as
+        // these do.
+        (Operator::I32TruncSatF32S, F32(a)) => I32(f32::from_bits(a) as i32 as u32),
+        (Operator::I32TruncSatF32U, F32(a)) => I32(f32::from_bits(a) as u32),
+        (Operator::I32TruncSatF64S, F64(a)) => I32(f64::from_bits(a) as i32 as u32),
+        (Operator::I32TruncSatF64U, F64(a)) => I32(f64::from_bits(a) as u32),
+        (Operator::I64TruncSatF32S, F32(a)) => I64(f32::from_bits(a) as i64 as u64),
+        (Operator::I64TruncSatF32U, F32(a)) => I64(f32::from_bits(a) as u64),
+        (Operator::I64TruncSatF64S, F64(a)) => I64(f64::from_bits(a) as i64 as u64),
+        (Operator::I64TruncSatF64U, F64(a)) => I64(f64::from_bits(a) as u64),
+        // Rust's int-to-float casts round to nearest, ties to even, as
+        // these do.
+        (Operator::F32ConvertI32S, I32(a)) => f32(a as i32 as f32),
+        (Operator::F32ConvertI32U, I32(a)) => f32(a as f32),
+        (Operator::F32ConvertI64S, I64(a)) => f32(a as i64 as f32),
+        (Operator::F32ConvertI64U, I64(a)) => f32(a as f32),
+        (Operator::F64ConvertI32S, I32(a)) => f64(a as i32 as f64),
+        (Operator::F64ConvertI32U, I32(a)) => f64(a as f64),
+        (Operator::F64ConvertI64S, I64(a)) => f64(a as i64 as f64),
+        (Operator::F64ConvertI64U, I64(a)) => f64(a as f64),
+        (Operator::F32DemoteF64, F64(a)) => f32(f64::from_bits(a) as f32),
+        (Operator::F64PromoteF32, F32(a)) => f64(f32::from_bits(a).into()),
+        (Operator::I32ReinterpretF32, F32(a)) => I32(a),
+        (Operator::I64ReinterpretF64, F64(a)) => I64(a),
+        (Operator::F32ReinterpretI32, I32(a)) => F32(a),
+        (Operator::F64ReinterpretI64, I64(a)) => F64(a),
+
+        _ => return None,
+    })
+}
+
+fn binary(op: Operator, x: WasmVal, y: WasmVal) -> Option<WasmVal> {
+    use WasmVal::*;
+    Some(match (op, x, y) {
+        (Operator::I32Eq, I32(a), I32(b)) => bool(a == b),
+        (Operator::I32Ne, I32(a), I32(b)) => bool(a != b),
+        (Operator::I32LtS, I32(a), I32(b)) => bool((a as i32) < (b as i32)),
+        (Operator::I32LtU, I32(a), I32(b)) => bool(a < b),
+        (Operator::I32GtS, I32(a), I32(b)) => bool((a as i32) > (b as i32)),
+        (Operator::I32GtU, I32(a), I32(b)) => bool(a > b),
+        (Operator::I32LeS, I32(a), I32(b)) => bool((a as i32) <= (b as i32)),
+        (Operator::I32LeU, I32(a), I32(b)) => bool(a <= b),
+        (Operator::I32GeS, I32(a), I32(b)) => bool((a as i32) >= (b as i32)),
+        (Operator::I32GeU, I32(a), I32(b)) => bool(a >= b),
+        (Operator::I64Eq, I64(a), I64(b)) => bool(a == b),
+        (Operator::I64Ne, I64(a), I64(b)) => bool(a != b),
+        (Operator::I64LtS, I64(a), I64(b)) => bool((a as i64) < (b as i64)),
+        (Operator::I64LtU, I64(a), I64(b)) => bool(a < b),
+        (Operator::I64GtS, I64(a), I64(b)) => bool((a as i64) > (b as i64)),
+        (Operator::I64GtU, I64(a), I64(b)) => bool(a > b),
+        (Operator::I64LeS, I64(a), I64(b)) => bool((a as i64) <= (b as i64)),
+        (Operator::I64LeU, I64(a), I64(b)) => bool(a <= b),
+        (Operator::I64GeS, I64(a), I64(b)) => bool((a as i64) >= (b as i64)),
+        (Operator::I64GeU, I64(a), I64(b)) => bool(a >= b),
+
+        (Operator::I32Add, I32(a), I32(b)) => I32(a.wrapping_add(b)),
+        (Operator::I32Sub, I32(a), I32(b)) => I32(a.wrapping_sub(b)),
+        (Operator::I32Mul, I32(a), I32(b)) => I32(a.wrapping_mul(b)),
+        // `checked_div` fails exactly where these trap: on a zero
+        // divisor, and on overflow.
+        (Operator::I32DivS, I32(a), I32(b)) => I32((a as i32).checked_div(b as i32)? as u32),
+        (Operator::I32DivU, I32(a), I32(b)) => I32(a.checked_div(b)?),
+        (Operator::I32RemS, I32(a), I32(b)) if b != 0 => {
+            I32((a as i32).wrapping_rem(b as i32) as u32)
+        }
+        (Operator::I32RemU, I32(a), I32(b)) => I32(a.checked_rem(b)?),
+        (Operator::I32And, I32(a), I32(b)) => I32(a & b),
+        (Operator::I32Or, I32(a), I32(b)) => I32(a | b),
+        (Operator::I32Xor, I32(a), I32(b)) => I32(a ^ b),
+        (Operator::I32Shl, I32(a), I32(b)) => I32(a.wrapping_shl(b)),
+        (Operator::I32ShrS, I32(a), I32(b)) => I32((a as i32).wrapping_shr(b) as u32),
+        (Operator::I32ShrU, I32(a), I32(b)) => I32(a.wrapping_shr(b)),
+        (Operator::I32Rotl, I32(a), I32(b)) => I32(a.rotate_left(b % 32)),
+        (Operator::I32Rotr, I32(a), I32(b)) => I32(a.rotate_right(b % 32)),
+
+        (Operator::I64Add, I64(a), I64(b)) => I64(a.wrapping_add(b)),
+        (Operator::I64Sub, I64(a), I64(b)) => I64(a.wrapping_sub(b)),
+        (Operator::I64Mul, I64(a), I64(b)) => I64(a.wrapping_mul(b)),
+        (Operator::I64DivS, I64(a), I64(b)) => I64((a as i64).checked_div(b as i64)? as u64),
+        (Operator::I64DivU, I64(a), I64(b)) => I64(a.checked_div(b)?),
+        (Operator::I64RemS, I64(a), I64(b)) if b != 0 => {
+            I64((a as i64).wrapping_rem(b as i64) as u64)
+        }
+        (Operator::I64RemU, I64(a), I64(b)) => I64(a.checked_rem(b)?),
+        (Operator::I64And, I64(a), I64(b)) => I64(a & b),
+        (Operator::I64Or, I64(a), I64(b)) => I64(a | b),
+        (Operator::I64Xor, I64(a), I64(b)) => I64(a ^ b),
+        (Operator::I64Shl, I64(a), I64(b)) => I64(a.wrapping_shl(b as u32)),
+        (Operator::I64ShrS, I64(a), I64(b)) => I64((a as i64).wrapping_shr(b as u32) as u64),
+        (Operator::I64ShrU, I64(a), I64(b)) => I64(a.wrapping_shr(b as u32)),
+        (Operator::I64Rotl, I64(a), I64(b)) => I64(a.rotate_left((b % 64) as u32)),
+        (Operator::I64Rotr, I64(a), I64(b)) => I64(a.rotate_right((b % 64) as u32)),
+
+        (Operator::F32Eq, F32(a), F32(b)) => bool(f32::from_bits(a) == f32::from_bits(b)),
+        (Operator::F32Ne, F32(a), F32(b)) => bool(f32::from_bits(a) != f32::from_bits(b)),
+        (Operator::F32Lt, F32(a), F32(b)) => bool(f32::from_bits(a) < f32::from_bits(b)),
+        (Operator::F32Gt, F32(a), F32(b)) => bool(f32::from_bits(a) > f32::from_bits(b)),
+        (Operator::F32Le, F32(a), F32(b)) => bool(f32::from_bits(a) <= f32::from_bits(b)),
+        (Operator::F32Ge, F32(a), F32(b)) => bool(f32::from_bits(a) >= f32::from_bits(b)),
+        (Operator::F64Eq, F64(a), F64(b)) => bool(f64::from_bits(a) == f64::from_bits(b)),
+        (Operator::F64Ne, F64(a), F64(b)) => bool(f64::from_bits(a) != f64::from_bits(b)),
+        (Operator::F64Lt, F64(a), F64(b)) => bool(f64::from_bits(a) < f64::from_bits(b)),
+        (Operator::F64Gt, F64(a), F64(b)) => bool(f64::from_bits(a) > f64::from_bits(b)),
+        (Operator::F64Le, F64(a), F64(b)) => bool(f64::from_bits(a) <= f64::from_bits(b)),
+        (Operator::F64Ge, F64(a), F64(b)) => bool(f64::from_bits(a) >= f64::from_bits(b)),
+
+        (Operator::F32Add, F32(a), F32(b)) => f32(f32::from_bits(a) + f32::from_bits(b)),
+        (Operator::F32Sub, F32(a), F32(b)) => f32(f32::from_bits(a) - f32::from_bits(b)),
+        (Operator::F32Mul, F32(a), F32(b)) => f32(f32::from_bits(a) * f32::from_bits(b)),
+        (Operator::F32Div, F32(a), F32(b)) => f32(f32::from_bits(a) / f32::from_bits(b)),
+        // Both are exact in `f64`, as is the minimum or maximum.
+        (Operator::F32Min, F32(a), F32(b)) => {
+            f32(min_max(f32::from_bits(a).into(), f32::from_bits(b).into(), false) as f32)
+        }
+        (Operator::F32Max, F32(a), F32(b)) => {
+            f32(min_max(f32::from_bits(a).into(), f32::from_bits(b).into(), true) as f32)
+        }
+        (Operator::F32Copysign, F32(a), F32(b)) => F32((a & 0x7fff_ffff) | (b & 0x8000_0000)),
+        (Operator::F64Add, F64(a), F64(b)) => f64(f64::from_bits(a) + f64::from_bits(b)),
+        (Operator::F64Sub, F64(a), F64(b)) => f64(f64::from_bits(a) - f64::from_bits(b)),
+        (Operator::F64Mul, F64(a), F64(b)) => f64(f64::from_bits(a) * f64::from_bits(b)),
+        (Operator::F64Div, F64(a), F64(b)) => f64(f64::from_bits(a) / f64::from_bits(b)),
+        (Operator::F64Min, F64(a), F64(b)) => {
+            f64(min_max(f64::from_bits(a), f64::from_bits(b), false))
+        }
+        (Operator::F64Max, F64(a), F64(b)) => {
+            f64(min_max(f64::from_bits(a), f64::from_bits(b), true))
+        }
+        (Operator::F64Copysign, F64(a), F64(b)) => {
+            F64((a & 0x7fff_ffff_ffff_ffff) | (b & 0x8000_0000_0000_0000))
+        }
+
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    //! Vectors from the spec test suite (`conversions.wast`,
+    //! `i32.wast`, `i64.wast`, `f32.wast`, `f64.wast`, ...).
+
+    use super::*;
+    use WasmVal::*;
+
+    fn f32c(x: f32) -> WasmVal {
+        F32(x.to_bits())
+    }
+
+    fn f64c(x: f64) -> WasmVal {
+        F64(x.to_bits())
+    }
+
+    #[test]
+    fn trunc_traps_out_of_range() {
+        let cases: &[(Operator, WasmVal, Option<WasmVal>)] = &[
+            (
+                Operator::I32TruncF32S,
+                f32c(-2147483648.0),
+                Some(I32(0x8000_0000)),
+            ),
+            (
+                Operator::I32TruncF32S,
+                f32c(2147483520.0),
+                Some(I32(2147483520)),
+            ),
+            (Operator::I32TruncF32S, f32c(2147483648.0), None),
+            (Operator::I32TruncF32S, f32c(-2147483904.0), None),
+            (Operator::I32TruncF32S, f32c(f32::INFINITY), None),
+            (Operator::I32TruncF32S, f32c(f32::NAN), None),
+            (Operator::I32TruncF32S, f32c(-0.9), Some(I32(0))),
+            (Operator::I32TruncF32U, f32c(-0.9), Some(I32(0))),
+            (Operator::I32TruncF32U, f32c(-1.0), None),
+            (
+                Operator::I32TruncF32U,
+                f32c(4294967040.0),
+                Some(I32(0xffff_ff00)),
+            ),
+            (Operator::I32TruncF32U, f32c(4294967296.0), None),
+            (
+                Operator::I32TruncF64S,
+                f64c(-2147483648.9),
+                Some(I32(0x8000_0000)),
+            ),
+            (
+                Operator::I32TruncF64S,
+                f64c(2147483647.9),
+                Some(I32(0x7fff_ffff)),
+            ),
+            (Operator::I32TruncF64S, f64c(-2147483649.0), None),
+            (Operator::I32TruncF64S, f64c(2147483648.0), None),
+            (
+                Operator::I32TruncF64U,
+                f64c(4294967295.9),
+                Some(I32(0xffff_ffff)),
+            ),
+            (Operator::I32TruncF64U, f64c(4294967296.0), None),
+            (Operator::I32TruncF64U, f64c(-0.9), Some(I32(0))),
+            (Operator::I32TruncF64U, f64c(-1.0), None),
+            (
+                Operator::I64TruncF32S,
+                f32c(-9223372036854775808.0),
+                Some(I64(1 << 63)),
+            ),
+            (Operator::I64TruncF32S, f32c(9223372036854775808.0), None),
+            (
+                Operator::I64TruncF32U,
+                f32c(18446742974197923840.0),
+                Some(I64(0xffff_ff00_0000_0000)),
+            ),
+            (Operator::I64TruncF32U, f32c(18446744073709551616.0), None),
+            (
+                Operator::I64TruncF64S,
+                f64c(9223372036854774784.0),
+                Some(I64(0x7fff_ffff_ffff_fc00)),
+            ),
+            (Operator::I64TruncF64S, f64c(9223372036854775808.0), None),
+            (Operator::I64TruncF64S, f64c(-9223372036854777856.0), None),
+            (
+                Operator::I64TruncF64U,
+                f64c(18446744073709549568.0),
+                Some(I64(0xffff_ffff_ffff_f800)),
+            ),
+            (Operator::I64TruncF64U, f64c(18446744073709551616.0), None),
+            (Operator::I64TruncF64U, f64c(f64::NEG_INFINITY), None),
+        ];
+        for &(op, x, expected) in cases {
+            assert_eq!(eval(op, &[x]), expected, "{} {:?}", op, x);
+        }
+    }
+
+    #[test]
+    fn trunc_sat() {
+        let cases: &[(Operator, WasmVal, WasmVal)] = &[
+            (Operator::I32TruncSatF32S, f32c(f32::NAN), I32(0)),
+            (
+                Operator::I32TruncSatF32S,
+                f32c(f32::INFINITY),
+                I32(0x7fff_ffff),
+            ),
+            (
+                Operator::I32TruncSatF32S,
+                f32c(f32::NEG_INFINITY),
+                I32(0x8000_0000),
+            ),
+            (
+                Operator::I32TruncSatF32S,
+                f32c(2147483648.0),
+                I32(0x7fff_ffff),
+            ),
+            (Operator::I32TruncSatF32S, f32c(-1.9), I32(-1i32 as u32)),
+            (Operator::I32TruncSatF32U, f32c(-1.0), I32(0)),
+            (
+                Operator::I32TruncSatF32U,
+                f32c(4294967296.0),
+                I32(0xffff_ffff),
+            ),
+            (
+                Operator::I32TruncSatF64S,
+                f64c(-2147483649.0),
+                I32(0x8000_0000),
+            ),
+            (Operator::I32TruncSatF64U, f64c(1e16), I32(0xffff_ffff)),
+            (Operator::I32TruncSatF64U, f64c(-f64::NAN), I32(0)),
+            (
+                Operator::I64TruncSatF32S,
+                f32c(9223372036854775808.0),
+                I64(0x7fff_ffff_ffff_ffff),
+            ),
+            (Operator::I64TruncSatF32U, f32c(-1.0), I64(0)),
+            (
+                Operator::I64TruncSatF64S,
+                f64c(f64::NEG_INFINITY),
+                I64(1 << 63),
+            ),
+            (Operator::I64TruncSatF64U, f64c(f64::NAN), I64(0)),
+            (
+                Operator::I64TruncSatF64U,
+                f64c(18446744073709551616.0),
+                I64(u64::MAX),
+            ),
+        ];
+        for &(op, x, expected) in cases {
+            assert_eq!(eval(op, &[x]), Some(expected), "{} {:?}", op, x);
+        }
+    }
+
+    #[test]
+    fn div_rem_traps_and_overflow() {
+        let cases: &[(Operator, WasmVal, WasmVal, Option<WasmVal>)] = &[
+            (
+                Operator::I32RemS,
+                I32(0x8000_0000),
+                I32(-1i32 as u32),
+                Some(I32(0)),
+            ),
+            (
+                Operator::I32RemS,
+                I32(-5i32 as u32),
+                I32(2),
+                Some(I32(-1i32 as u32)),
+            ),
+            (Operator::I32RemS, I32(1), I32(0), None),
+            (Operator::I32DivS, I32(0x8000_0000), I32(-1i32 as u32), None),
+            (
+                Operator::I32DivS,
+                I32(-5i32 as u32),
+                I32(2),
+                Some(I32(-2i32 as u32)),
+            ),
+            (
+                Operator::I32DivU,
+                I32(0x8000_0000),
+                I32(-1i32 as u32),
+                Some(I32(0)),
+            ),
+            (Operator::I32DivU, I32(1), I32(0), None),
+            (
+                Operator::I32RemU,
+                I32(0x8000_0000),
+                I32(-1i32 as u32),
+                Some(I32(0x8000_0000)),
+            ),
+            (Operator::I64RemS, I64(1 << 63), I64(u64::MAX), Some(I64(0))),
+            (Operator::I64RemS, I64(1), I64(0), None),
+            (Operator::I64DivS, I64(1 << 63), I64(u64::MAX), None),
+            (Operator::I64RemU, I64(1), I64(0), None),
+        ];
+        for &(op, x, y, expected) in cases {
+            assert_eq!(eval(op, &[x, y]), expected, "{} {:?} {:?}", op, x, y);
+        }
+    }
+
+    #[test]
+    fn shift_and_rotate_counts_wrap() {
+        let cases: &[(Operator, WasmVal, WasmVal, WasmVal)] = &[
+            (Operator::I32Shl, I32(1), I32(32), I32(1)),
+            (Operator::I32Shl, I32(1), I32(33), I32(2)),
+            (
+                Operator::I32Shl,
+                I32(1),
+                I32(-1i32 as u32),
+                I32(0x8000_0000),
+            ),
+            (
+                Operator::I32ShrS,
+                I32(0x8000_0000),
+                I32(33),
+                I32(0xc000_0000),
+            ),
+            (
+                Operator::I32ShrU,
+                I32(0x8000_0000),
+                I32(33),
+                I32(0x4000_0000),
+            ),
+            (Operator::I32ShrU, I32(1), I32(32), I32(1)),
+            (
+                Operator::I32Rotl,
+                I32(0xfe00_dc00),
+                I32(4),
+                I32(0xe00d_c00f),
+            ),
+            (Operator::I32Rotl, I32(1), I32(32), I32(1)),
+            (
+                Operator::I32Rotl,
+                I32(0xabcd_9876),
+                I32(33),
+                I32(0x579b_30ed),
+            ),
+            (
+                Operator::I32Rotr,
+                I32(0xb0c1_d2e3),
+                I32(5),
+                I32(0x1d86_0e97),
+            ),
+            (Operator::I32Rotr, I32(1), I32(33), I32(0x8000_0000)),
+            (Operator::I64Shl, I64(1), I64(64), I64(1)),
+            (Operator::I64Shl, I64(1), I64(u64::MAX), I64(1 << 63)),
+            (
+                Operator::I64ShrS,
+                I64(1 << 63),
+                I64(65),
+                I64(0xc000_0000_0000_0000),
+            ),
+            (
+                Operator::I64ShrU,
+                I64(1 << 63),
+                I64(65),
+                I64(0x4000_0000_0000_0000),
+            ),
+            (Operator::I64Rotl, I64(1), I64(65), I64(2)),
+            (
+                Operator::I64Rotl,
+                I64(0xabd1_234e_f567_809c),
+                I64(63),
+                I64(0x55e8_91a7_7ab3_c04e),
+            ),
+            (Operator::I64Rotr, I64(1), I64(64), I64(1)),
+            (
+                Operator::I64Rotr,
+                I64(0xabcd_1234_ef56_7809),
+                I64(0x35),
+                I64(0x6891_a77a_b3c0_4d5e),
+            ),
+        ];
+        for &(op, x, y, expected) in cases {
+            assert_eq!(eval(op, &[x, y]), Some(expected), "{} {:?} {:?}", op, x, y);
+        }
+    }
+
+    #[test]
+    fn min_max_signed_zeros() {
+        let cases: &[(Operator, WasmVal, WasmVal, WasmVal)] = &[
+            (Operator::F32Min, f32c(-0.0), f32c(0.0), f32c(-0.0)),
+            (Operator::F32Min, f32c(0.0), f32c(-0.0), f32c(-0.0)),
+            (Operator::F32Max, f32c(-0.0), f32c(0.0), f32c(0.0)),
+            (Operator::F32Max, f32c(0.0), f32c(-0.0), f32c(0.0)),
+            (Operator::F32Min, f32c(-0.0), f32c(-0.0), f32c(-0.0)),
+            (Operator::F32Max, f32c(-1.0), f32c(-0.0), f32c(-0.0)),
+            (
+                Operator::F32Min,
+                f32c(f32::NAN),
+                f32c(1.0),
+                F32(F32_CANONICAL_NAN),
+            ),
+            (Operator::F64Min, f64c(-0.0), f64c(0.0), f64c(-0.0)),
+            (Operator::F64Min, f64c(0.0), f64c(-0.0), f64c(-0.0)),
+            (Operator::F64Max, f64c(-0.0), f64c(0.0), f64c(0.0)),
+            (Operator::F64Max, f64c(0.0), f64c(-0.0), f64c(0.0)),
+            (
+                Operator::F64Max,
+                f64c(1.0),
+                f64c(f64::NAN),
+                F64(F64_CANONICAL_NAN),
+            ),
+        ];
+        for &(op, x, y, expected) in cases {
+            assert_eq!(eval(op, &[x, y]), Some(expected), "{} {:?} {:?}", op, x, y);
+        }
+    }
+
+    #[test]
+    fn nearest_ties_to_even() {
+        let cases: &[(f64, f64)] = &[
+            (0.5, 0.0),
+            (1.5, 2.0),
+            (2.5, 2.0),
+            (-0.5, -0.0),
+            (-1.5, -2.0),
+            (-2.5, -2.0),
+            (-0.0, -0.0),
+            (4.5, 4.0),
+            (8388609.0, 8388609.0),
+        ];
+        for &(x, expected) in cases {
+            assert_eq!(
+                eval(Operator::F32Nearest, &[f32c(x as f32)]),
+                Some(f32c(expected as f32)),
+                "f32.nearest {}",
+                x
+            );
+            assert_eq!(
+                eval(Operator::F64Nearest, &[f64c(x)]),
+                Some(f64c(expected)),
+                "f64.nearest {}",
+                x
+            );
+        }
+        assert_eq!(
+            eval(Operator::F64Nearest, &[f64c(4503599627370497.0)]),
+            Some(f64c(4503599627370497.0))
+        );
+    }
+
+    #[test]
+    fn nan_canonicalization() {
+        // A signaling NaN with a payload, and its negation.
+        let snan32 = F32(0x7fa0_0000);
+        let snan64 = F64(0x7ff4_0000_0000_0000);
+        let nan32 = F32(F32_CANONICAL_NAN);
+        let canonical32 = Some(nan32);
+        let canonical64 = Some(F64(F64_CANONICAL_NAN));
+        let inf32 = f32c(f32::INFINITY);
+        let ninf32 = f32c(f32::NEG_INFINITY);
+        let inf64 = f64c(f64::INFINITY);
+
+        assert_eq!(eval(Operator::F32Add, &[inf32, ninf32]), canonical32);
+        assert_eq!(
+            eval(Operator::F32Div, &[f32c(0.0), f32c(-0.0)]),
+            canonical32
+        );
+        assert_eq!(eval(Operator::F32Sqrt, &[f32c(-1.0)]), canonical32);
+        assert_eq!(eval(Operator::F32Add, &[snan32, f32c(1.0)]), canonical32);
+        assert_eq!(eval(Operator::F32Ceil, &[F32(0xffa0_0000)]), canonical32);
+        assert_eq!(eval(Operator::F32DemoteF64, &[snan64]), canonical32);
+        assert_eq!(eval(Operator::F64Mul, &[f64c(0.0), inf64]), canonical64);
+        assert_eq!(eval(Operator::F64Sub, &[inf64, inf64]), canonical64);
+        assert_eq!(eval(Operator::F64Nearest, &[snan64]), canonical64);
+        assert_eq!(eval(Operator::F64PromoteF32, &[snan32]), canonical64);
+
+        // Operators on the sign bit keep the payload.
+        assert_eq!(eval(Operator::F32Neg, &[snan32]), Some(F32(0xffa0_0000)));
+        assert_eq!(eval(Operator::F32Abs, &[F32(0xffa0_0000)]), Some(snan32));
+        assert_eq!(
+            eval(Operator::F32Copysign, &[snan32, f32c(-1.0)]),
+            Some(F32(0xffa0_0000))
+        );
+        assert_eq!(
+            eval(Operator::F64Neg, &[snan64]),
+            Some(F64(0xfff4_0000_0000_0000))
+        );
+        assert_eq!(
+            eval(Operator::F32ReinterpretI32, &[I32(0x7fa0_0000)]),
+            Some(snan32)
+        );
+
+        assert!(is_nondeterministic(Operator::F32Add, nan32));
+        assert!(!is_nondeterministic(Operator::F32Neg, F32(0xffa0_0000)));
+        assert!(!is_nondeterministic(Operator::F32Add, f32c(1.0)));
+    }
+}
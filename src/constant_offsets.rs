@@ -1,6 +1,25 @@
 //! Constant-offset "remat" pass: rewrite x+k to local additions off
 //! of one base, to minimize live value / register pressure. Also push
 //! these offsets into loads/stores where possible.
+//!
+//! Alongside offsets we track the known alignment of each value (the
+//! number of low bits known to be zero), so that masking an aligned
+//! base plus a small offset, e.g. rounding a stack pointer up to a
+//! slot boundary or selecting a field in a scaled register-file
+//! slot, is still seen as an offset from that base.
+//!
+//! A multiply or left shift of `base + k` by a constant is seen as an
+//! offset from a new value `base * c` (materialized just before the
+//! multiply if used), so that indexing an array of fixed-size records
+//! with `(i + k) * size` shares one scaled index across `k`.
+//!
+//! Selects and blockparams whose inputs all agree keep the common
+//! description, including "the same value", so an address merged
+//! after a branch is still seen as an offset from its base.
+//!
+//! Pushing an offset into a load or store changes where it traps: the
+//! address operand wraps at 2^32, but the immediate offset does not.
+//! With `--preserve-traps`, addresses are left as computed.
 
 use fxhash::{FxHashMap, FxHashSet};
 use std::collections::{BTreeMap, VecDeque};
@@ -37,15 +56,68 @@ impl AbsValue {
     }
 }
 
-pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
+/// The description of `value` for merging at a select or blockparam:
+/// an otherwise-unknown value is itself plus zero, so that merging it
+/// with itself keeps it as the base.
+fn as_offset(value: Value, abs: AbsValue) -> AbsValue {
+    match abs {
+        AbsValue::Bottom => AbsValue::Offset(value, 0),
+        abs => abs,
+    }
+}
+
+/// Known alignment: the number of low bits known to be zero (32 for
+/// the value zero), or `None` if not yet computed.
+type Align = Option<u32>;
+
+fn meet_align(a: Align, b: Align) -> Align {
+    match (a, b) {
+        (None, x) | (x, None) => x,
+        (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+    }
+}
+
+/// Simplify `(base + k) & mask` or `(base + k) | mask`, given that
+/// the low `align` bits of `base` are zero (if known).
+fn mask_offset(op: Operator, base: Value, k: u32, align: Align, mask: u32) -> AbsValue {
+    let Some(align) = align else {
+        return AbsValue::Bottom;
+    };
+    let low = u32::MAX.checked_shr(32 - align).unwrap_or(0);
+    match op {
+        // Clearing only low bits leaves `base` intact.
+        Operator::I32And if mask | low == u32::MAX => AbsValue::Offset(base, k & mask),
+        // Keeping only low bits discards `base`.
+        Operator::I32And if mask & !low == 0 => AbsValue::Constant(k & mask),
+        // Setting only low bits cannot carry into `base`.
+        Operator::I32Or if mask & !low == 0 => AbsValue::Offset(base, k | mask),
+        _ => AbsValue::Bottom,
+    }
+}
+
+pub fn run(func: &mut FunctionBody, cfg: &CFGInfo, preserve_traps: bool) {
     waffle::passes::resolve_aliases::run(func);
-    log::trace!(
+    tracing::trace!(
         "constant_offsets pass running on:\n{}",
         func.display_verbose("| ", None)
     );
     // Compute a fixpoint analysis: which values are some original SSA
     // value plus an offset?
     let mut values: PerEntity<Value, AbsValue> = PerEntity::default();
+    let mut align: PerEntity<Value, Align> = PerEntity::default();
+    // For each multiply or shift, a placeholder for the scaled base
+    // (`base * c` or `base << c`) if its input is an offset value,
+    // and the `(base, c)` args it was last computed from.
+    let mut scaled: FxHashMap<Value, Value> = FxHashMap::default();
+    let mut scaled_args: FxHashMap<Value, (Value, Value)> = FxHashMap::default();
+    for &block in cfg.rpo.values() {
+        for &inst in &func.blocks[block].insts {
+            if let ValueDef::Operator(Operator::I32Mul | Operator::I32Shl, _, _) = func.values[inst]
+            {
+                scaled.insert(inst, func.values.push(ValueDef::None));
+            }
+        }
+    }
 
     let mut workqueue: VecDeque<Block> = VecDeque::new();
     let mut workqueue_set: FxHashSet<Block> = FxHashSet::default();
@@ -56,14 +128,15 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
 
     for &(_, param) in &func.blocks[func.entry].params {
         values[param] = AbsValue::Bottom;
+        align[param] = Some(0);
     }
 
     while let Some(block) = workqueue.pop_front() {
-        log::trace!("processing {}", block);
+        tracing::trace!("processing {}", block);
         workqueue_set.remove(&block);
 
         for &inst in &func.blocks[block].insts {
-            log::trace!("block {} value {}: {:?}", block, inst, func.values[inst]);
+            tracing::trace!("block {} value {}: {:?}", block, inst, func.values[inst]);
             match &func.values[inst] {
                 ValueDef::BlockParam(..) => {
                     unreachable!();
@@ -71,11 +144,39 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
 
                 ValueDef::Alias(orig) => {
                     values[inst] = values[*orig];
+                    align[inst] = align[*orig];
                 }
 
                 ValueDef::Operator(op, args, tys) if tys.len() == 1 => {
                     let args = &func.arg_pool[*args];
-                    log::trace!(" -> args = {:?}", args);
+                    tracing::trace!(" -> args = {:?}", args);
+
+                    align[inst] = if args.iter().any(|&arg| align[arg].is_none()) {
+                        None
+                    } else {
+                        let arg_align = |i: usize| align[args[i]].unwrap();
+                        match op {
+                            Operator::I32Const { value } => Some(value.trailing_zeros()),
+                            Operator::I32Add
+                            | Operator::I32Sub
+                            | Operator::I32Or
+                            | Operator::I32Xor => Some(std::cmp::min(arg_align(0), arg_align(1))),
+                            Operator::I32And => Some(std::cmp::max(arg_align(0), arg_align(1))),
+                            Operator::Select | Operator::TypedSelect { .. } => {
+                                Some(std::cmp::min(arg_align(0), arg_align(1)))
+                            }
+                            Operator::I32Mul => {
+                                Some(std::cmp::min(32, arg_align(0) + arg_align(1)))
+                            }
+                            Operator::I32Shl => match values[args[1]] {
+                                AbsValue::Constant(k) => {
+                                    Some(std::cmp::min(32, arg_align(0) + (k & 31)))
+                                }
+                                _ => Some(0),
+                            },
+                            _ => Some(0),
+                        }
+                    };
 
                     match op {
                         Operator::I32Const { value } => {
@@ -124,6 +225,65 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
                                 _ => AbsValue::Bottom,
                             };
                         }
+                        Operator::I32And | Operator::I32Or => {
+                            let x = args[0];
+                            let y = args[1];
+                            values[inst] = match (values[x], values[y]) {
+                                (AbsValue::Top, _) | (_, AbsValue::Top) => AbsValue::Top,
+                                (AbsValue::Constant(k1), AbsValue::Constant(k2)) => {
+                                    AbsValue::Constant(match op {
+                                        Operator::I32And => k1 & k2,
+                                        _ => k1 | k2,
+                                    })
+                                }
+                                (AbsValue::Offset(base, k), AbsValue::Constant(mask))
+                                | (AbsValue::Constant(mask), AbsValue::Offset(base, k)) => {
+                                    mask_offset(*op, base, k, align[base], mask)
+                                }
+                                (_, AbsValue::Constant(mask)) => {
+                                    mask_offset(*op, x, 0, align[x], mask)
+                                }
+                                (AbsValue::Constant(mask), _) => {
+                                    mask_offset(*op, y, 0, align[y], mask)
+                                }
+                                _ => AbsValue::Bottom,
+                            };
+                        }
+                        Operator::Select | Operator::TypedSelect { .. } => {
+                            let x = as_offset(args[0], values[args[0]]);
+                            let y = as_offset(args[1], values[args[1]]);
+                            values[inst] = match values[args[2]] {
+                                AbsValue::Top => AbsValue::Top,
+                                AbsValue::Constant(k) if k != 0 => x,
+                                AbsValue::Constant(_) => y,
+                                _ => AbsValue::meet(x, y),
+                            };
+                        }
+                        Operator::I32Mul | Operator::I32Shl => {
+                            let (x, y) = match (op, values[args[0]]) {
+                                (Operator::I32Mul, AbsValue::Constant(_)) => (args[1], args[0]),
+                                _ => (args[0], args[1]),
+                            };
+                            let scale = |k: u32, c: u32| match op {
+                                Operator::I32Mul => k.wrapping_mul(c),
+                                _ => k.wrapping_shl(c),
+                            };
+                            values[inst] = match (values[x], values[y]) {
+                                (AbsValue::Top, _) | (_, AbsValue::Top) => AbsValue::Top,
+                                (AbsValue::Constant(k), AbsValue::Constant(c)) => {
+                                    AbsValue::Constant(scale(k, c))
+                                }
+                                (AbsValue::Offset(base, k), AbsValue::Constant(c)) => {
+                                    let value = scaled[&inst];
+                                    scaled_args.insert(inst, (base, y));
+                                    align[value] = align[base].map(|a| {
+                                        std::cmp::min(32, a + scale(1, c).trailing_zeros())
+                                    });
+                                    AbsValue::Offset(value, scale(k, c))
+                                }
+                                _ => AbsValue::Bottom,
+                            };
+                        }
                         _ => {
                             values[inst] = AbsValue::Bottom;
                         }
@@ -132,26 +292,35 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
 
                 _ => {
                     values[inst] = AbsValue::Bottom;
+                    align[inst] = Some(0);
                 }
             }
-            log::trace!(" -> values[{}] = {:?}", inst, values[inst]);
+            tracing::trace!(
+                " -> values[{}] = {:?}, align {:?}",
+                inst,
+                values[inst],
+                align[inst]
+            );
         }
 
         func.blocks[block].terminator.visit_targets(|target| {
             let mut changed = false;
             let succ_params = &func.blocks[target.block].params;
             for (&arg, &(_, blockparam)) in target.args.iter().zip(succ_params.iter()) {
-                let new = AbsValue::meet(values[arg], values[blockparam]);
-                log::trace!(" -> block {} target {}: arg {} to blockparam {}: value {:?} -> {:?}",
+                let new = AbsValue::meet(as_offset(arg, values[arg]), values[blockparam]);
+                tracing::trace!(" -> block {} target {}: arg {} to blockparam {}: value {:?} -> {:?}",
                             block, target.block, arg, blockparam, values[blockparam], new);
                 changed |= new != values[blockparam];
                 values[blockparam] = new;
+                let new_align = meet_align(align[arg], align[blockparam]);
+                changed |= new_align != align[blockparam];
+                align[blockparam] = new_align;
             }
 
             if changed ||
                 visited.insert(target.block) ||
                 (block != target.block && cfg.dominates(block, target.block)) {
-                log::trace!(" -> at least one blockparam changed, or we dominate target block; enqueuing {}",
+                tracing::trace!(" -> at least one blockparam changed, or we dominate target block; enqueuing {}",
                             target.block);
                 if workqueue_set.insert(target.block) {
                     workqueue.push_back(target.block);
@@ -160,6 +329,17 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
         });
     }
 
+    // Define the scaled bases that are used.
+    let i32_ty = func.single_type_list(Type::I32);
+    for (&inst, &(base, c)) in &scaled_args {
+        let ValueDef::Operator(op, _, _) = func.values[inst] else {
+            unreachable!()
+        };
+        let args = func.arg_pool.double(base, c);
+        func.values[scaled[&inst]] = ValueDef::Operator(op, args, i32_ty);
+        func.source_locs[scaled[&inst]] = func.source_locs[inst];
+    }
+
     // Constant-fold conditional branches.
     for &block in cfg.rpo.values() {
         // If the terminator is a CondBr and has a constant input now,
@@ -181,11 +361,12 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
         }
     }
 
-    // Find the set of all values used as addresses to loads/stores.
+    // Find the set of all values used as addresses to loads/stores
+    // whose offsets we may rewrite.
     let mut used_as_addr = FxHashSet::default();
     for (_, def) in func.values.entries() {
         if let ValueDef::Operator(op, args, _) = def {
-            if op.is_load() || op.is_store() {
+            if !preserve_traps && (op.is_load() || op.is_store()) {
                 used_as_addr.insert(func.arg_pool[*args][0]);
             }
         }
@@ -215,7 +396,6 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
     // this (we need to insert the i32const first).
     let mut offset_base: BTreeMap<Value, Value> = BTreeMap::new();
     let mut offset_base_const: BTreeMap<Value, Value> = BTreeMap::new();
-    let i32_ty = func.single_type_list(Type::I32);
     for (&value, &offset) in &min_offset_from {
         assert!(offset <= 0);
         if offset != 0 {
@@ -230,7 +410,7 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
             let add = func.add_value(ValueDef::Operator(Operator::I32Sub, args, i32_ty));
             offset_base_const.insert(value, k);
             offset_base.insert(value, add);
-            log::trace!(
+            tracing::trace!(
                 "created common base {} (and const {}) associated with offset-from value {}",
                 k,
                 add,
@@ -244,7 +424,7 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
     // Now, for each value that's an Offset, rewrite it to an add
     // instruction.
     for (block, block_def) in func.blocks.entries_mut() {
-        log::trace!("rewriting in block {}", block);
+        tracing::trace!("rewriting in block {}", block);
         let mut computed_offsets: FxHashMap<AbsValue, Value> = FxHashMap::default();
         let mut new_insts = vec![];
 
@@ -257,17 +437,29 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
         }
 
         for inst in std::mem::take(&mut block_def.insts) {
-            log::trace!("visiting inst {}: {:?}", inst, values[inst]);
+            tracing::trace!("visiting inst {}: {:?}", inst, values[inst]);
+
+            // Insert the scaled base this value is an offset from,
+            // and its common base if any.
+            if let Some(&value) = scaled.get(&inst) {
+                if matches!(values[inst], AbsValue::Offset(base, _) if base == value) {
+                    new_insts.push(value);
+                    if let Some(common_base_const) = offset_base_const.get(&value) {
+                        new_insts.push(*common_base_const);
+                        new_insts.push(*offset_base.get(&value).unwrap());
+                    }
+                }
+            }
 
             // Handle loads/stores.
             if let ValueDef::Operator(op, args, tys) = &func.values[inst] {
-                if op.is_load() || op.is_store() {
+                if !preserve_traps && (op.is_load() || op.is_store()) {
                     let args = &func.arg_pool[*args];
                     let tys = *tys;
                     let addr = args[0];
-                    log::trace!("load/store with addr {}", addr);
+                    tracing::trace!("load/store with addr {}", addr);
                     if let AbsValue::Offset(base, this_offset) = values[addr] {
-                        log::trace!("inst {} is a load/store with addr that is offset from base {}; pushing offset into instruction", inst, base);
+                        tracing::trace!("inst {} is a load/store with addr that is offset from base {}; pushing offset into instruction", inst, base);
                         // Update the offset embedded in the Operator
                         // and use the `base` value instead as the
                         // address arg.
@@ -326,12 +518,12 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
                         let add = func.values.push(ValueDef::Operator(op, args, i32_ty));
                         func.source_locs[k] = func.source_locs[inst];
                         func.source_locs[add] = func.source_locs[inst];
-                        log::trace!(" -> recomputed as {}", add);
+                        tracing::trace!(" -> recomputed as {}", add);
                         new_insts.push(add);
                         add
                     }
                 });
-                log::trace!(" -> rewrite to {}", computed_offset);
+                tracing::trace!(" -> rewrite to {}", computed_offset);
                 func.values[inst] = ValueDef::Alias(computed_offset);
             } else {
                 new_insts.push(inst);
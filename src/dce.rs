@@ -2,17 +2,20 @@
 
 use fxhash::FxHashSet;
 use waffle::{
-    cfg::CFGInfo, Block, FunctionBody, Operator, SideEffect, Terminator, Value, ValueDef,
+    cfg::CFGInfo, Block, Func, FunctionBody, Operator, SideEffect, Terminator, Value, ValueDef,
 };
 
-fn op_can_be_removed(op: &Operator) -> bool {
+fn op_can_be_removed(op: &Operator, pure_imports: &FxHashSet<Func>, preserve_traps: bool) -> bool {
     // Pure ops, and also we allow loads and table.gets to be removed
     // too, because we do not need to uphold Wasm trap semantics at
     // this point (we assume the interpreter is a well-behaved
-    // non-trapping program). Also allow global.gets to be removed if
-    // unused (they technically have a read side-effect but really
-    // should be considered pure).
+    // non-trapping program), unless `preserve_traps` is set. Also
+    // allow global.gets to be removed if unused (they technically
+    // have a read side-effect but really should be considered pure).
     match op {
+        // With `--preserve-traps`, an op that may trap (including any
+        // load) stays.
+        op if preserve_traps && op.effects().contains(&SideEffect::Trap) => false,
         // If a load is unused, we can remove it because we're assuming
         // the program doesn't trap (so we don't need to preserve traps
         // due to out-of- bounds addresses).
@@ -29,6 +32,8 @@ fn op_can_be_removed(op: &Operator) -> bool {
         // Finally, all pure ops (computation only, no accesses to
         // ambient state and no side-effects) can be removed if unused.
         op if op.is_pure() => true,
+        // Likewise calls to imports declared pure.
+        Operator::Call { function_index } => pure_imports.contains(function_index),
         _ => false,
     }
 }
@@ -37,7 +42,13 @@ fn op_can_be_removed(op: &Operator) -> bool {
 /// instruction that itself is used (or for a branch arg, for which
 /// any target's corresponding blockparam is used). Returns `true` if
 /// any changes occurred to the used-value set.
-fn scan_block(func: &FunctionBody, block: Block, used: &mut FxHashSet<Value>) -> bool {
+fn scan_block(
+    func: &FunctionBody,
+    block: Block,
+    used: &mut FxHashSet<Value>,
+    pure_imports: &FxHashSet<Func>,
+    preserve_traps: bool,
+) -> bool {
     let mark_used = |used: &mut FxHashSet<Value>, mut arg: Value| -> bool {
         let mut changed = false;
         changed |= used.insert(arg);
@@ -48,15 +59,15 @@ fn scan_block(func: &FunctionBody, block: Block, used: &mut FxHashSet<Value>) ->
         changed
     };
 
-    log::trace!("DCE: scanning {}", block);
+    tracing::trace!("DCE: scanning {}", block);
     let mut changed = false;
 
     func.blocks[block].terminator.visit_targets(|target| {
-        log::trace!(" -> considering succ {}", target.block);
+        tracing::trace!(" -> considering succ {}", target.block);
         let succ_params = &func.blocks[target.block].params;
         for (&arg, &(_, param)) in target.args.iter().zip(succ_params.iter()) {
             if used.contains(&param) {
-                log::trace!(
+                tracing::trace!(
                     "  -> succ blockparam {} is used; marking arg {} used from term on {}",
                     param,
                     arg,
@@ -68,12 +79,12 @@ fn scan_block(func: &FunctionBody, block: Block, used: &mut FxHashSet<Value>) ->
     });
     match &func.blocks[block].terminator {
         Terminator::CondBr { cond: value, .. } | Terminator::Select { value, .. } => {
-            log::trace!(" -> marking branch input {} used", value);
+            tracing::trace!(" -> marking branch input {} used", value);
             changed |= mark_used(used, *value);
         }
         Terminator::Return { values } => {
             for &value in values {
-                log::trace!(" -> marking return value {} used", value);
+                tracing::trace!(" -> marking return value {} used", value);
                 changed |= mark_used(used, value);
             }
         }
@@ -87,17 +98,17 @@ fn scan_block(func: &FunctionBody, block: Block, used: &mut FxHashSet<Value>) ->
             }
             ValueDef::PickOutput(value, ..) => {
                 if used.contains(&inst) {
-                    log::trace!(" -> marking pick-output src {} used", value);
+                    tracing::trace!(" -> marking pick-output src {} used", value);
                     changed |= mark_used(used, *value);
                 }
             }
             ValueDef::Operator(op, args, _) => {
-                if !op_can_be_removed(op) {
+                if !op_can_be_removed(op, pure_imports, preserve_traps) {
                     changed |= used.insert(inst);
                 }
                 if used.contains(&inst) {
                     for &arg in &func.arg_pool[*args] {
-                        log::trace!(" -> marking arg {} used from {}", arg, inst);
+                        tracing::trace!(" -> marking arg {} used from {}", arg, inst);
                         changed |= mark_used(used, arg);
                     }
                 }
@@ -111,19 +122,34 @@ fn scan_block(func: &FunctionBody, block: Block, used: &mut FxHashSet<Value>) ->
     changed
 }
 
-pub(crate) fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
+/// Remove unreachable blocks' contents, then instructions, block
+/// parameters and branch arguments whose values are never used.
+/// Unused loads and other operators that may only trap are removed
+/// too, unless `preserve_traps`; calls to `pure_imports` are removed
+/// like pure operators.
+pub fn run(
+    func: &mut FunctionBody,
+    cfg: &CFGInfo,
+    pure_imports: &FxHashSet<Func>,
+    preserve_traps: bool,
+) {
     // For any unreachable blocks, empty their contents and
     // terminators, and remove all blockparams (and there will then be
     // no targets with branch args to adjust because only an
     // unreachable block can branch to an unreachable block).
+    let mut removed_edges = false;
     for (block, block_def) in func.blocks.entries_mut() {
         if cfg.rpo_pos[block].is_none() {
-            log::trace!("removing unreachable block {}", block);
+            tracing::trace!("removing unreachable block {}", block);
             block_def.insts.clear();
             block_def.params.clear();
             block_def.terminator = Terminator::Unreachable;
+            removed_edges |= !block_def.succs.is_empty();
         }
     }
+    if removed_edges {
+        func.recompute_edges();
+    }
 
     // Now compute value uses.
     let mut used = FxHashSet::default();
@@ -133,9 +159,9 @@ pub(crate) fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
     loop {
         let mut changed = false;
         for &block in cfg.rpo.values().rev() {
-            changed |= scan_block(func, block, &mut used);
+            changed |= scan_block(func, block, &mut used, pure_imports, preserve_traps);
         }
-        log::trace!("done with all blocks; changed = {}", changed);
+        tracing::trace!("done with all blocks; changed = {}", changed);
         if !changed {
             break;
         }
@@ -0,0 +1,317 @@
+//! Outlining of code repeated across specializations.
+//!
+//! Specializations of one generic function often share code that does
+//! not depend on the constants they were specialized for, such as
+//! slow paths that call into the runtime. With `--outline-common`, a
+//! block whose instructions are identical (the same operators and
+//! constants, with the same dataflow among them) in at least
+//! `--outline-min-count` places across the specializations of a
+//! generic function, and number at least `--outline-min-insts` (not
+//! counting constants), is moved into a helper function that every
+//! place calls instead. The helper takes the values the instructions
+//! use from outside the block and returns those used after it.
+
+use fxhash::FxHashMap;
+use waffle::{
+    Block, Func, FuncDecl, FunctionBody, Module, Operator, SignatureData, Type, Value, ValueDef,
+};
+
+/// Thresholds for outlining.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Options {
+    /// Fewest instructions worth outlining.
+    pub min_insts: usize,
+    /// Fewest occurrences worth outlining.
+    pub min_count: usize,
+}
+
+/// An instruction of a block, with its arguments numbered relative to
+/// the block.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Inst {
+    Operator(Operator, Vec<Arg>, Vec<Type>),
+    PickOutput(Arg, u32, Type),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Arg {
+    /// The result of the block's instruction with this index.
+    Inst(usize),
+    /// The block's input with this index.
+    Input(usize),
+}
+
+/// A block's instructions, as outlined.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Sequence {
+    insts: Vec<Inst>,
+    inputs: Vec<Type>,
+    /// Instructions whose results are used after the block.
+    outputs: Vec<usize>,
+}
+
+impl Sequence {
+    /// The number of instructions, not counting constants.
+    fn size(&self) -> usize {
+        self.insts
+            .iter()
+            .filter(|inst| {
+                !matches!(
+                    inst,
+                    Inst::Operator(
+                        Operator::I32Const { .. }
+                            | Operator::I64Const { .. }
+                            | Operator::F32Const { .. }
+                            | Operator::F64Const { .. },
+                        ..
+                    )
+                )
+            })
+            .count()
+    }
+}
+
+/// A block in a body whose instructions form a sequence.
+struct Occurrence {
+    body: usize,
+    block: Block,
+    /// The values the block uses from outside it.
+    inputs: Vec<Value>,
+}
+
+/// Outline blocks repeated across `bodies`, the specializations of the
+/// generic function `generic`, into helpers added to `module`. Returns
+/// the number of blocks outlined.
+pub(crate) fn outline(
+    module: &mut Module,
+    generic: Func,
+    bodies: &mut [&mut FunctionBody],
+    opts: Options,
+) -> usize {
+    let mut sequences: Vec<(Sequence, Vec<Occurrence>)> = vec![];
+    let mut index: FxHashMap<Sequence, usize> = FxHashMap::default();
+    for (i, body) in bodies.iter().enumerate() {
+        let uses = use_counts(body);
+        for block in body.blocks.iter() {
+            if body.blocks[block].insts.len() < opts.min_insts {
+                continue;
+            }
+            let Some((sequence, inputs)) = sequence(body, block, &uses) else {
+                continue;
+            };
+            if sequence.size() < opts.min_insts {
+                continue;
+            }
+            let occurrence = Occurrence {
+                body: i,
+                block,
+                inputs,
+            };
+            match index.get(&sequence) {
+                Some(&idx) => sequences[idx].1.push(occurrence),
+                None => {
+                    index.insert(sequence.clone(), sequences.len());
+                    sequences.push((sequence, vec![occurrence]));
+                }
+            }
+        }
+    }
+
+    let name = module.funcs[generic].name().to_owned();
+    let mut outlined = 0;
+    let mut helpers = 0;
+    for (sequence, occurrences) in sequences {
+        if occurrences.len() < opts.min_count {
+            continue;
+        }
+        let returns = sequence
+            .outputs
+            .iter()
+            .map(|&idx| match &sequence.insts[idx] {
+                Inst::Operator(_, _, tys) => tys[0],
+                Inst::PickOutput(_, _, ty) => *ty,
+            })
+            .collect::<Vec<_>>();
+        let sig = module.signatures.push(SignatureData {
+            params: sequence.inputs.clone(),
+            returns: returns.clone(),
+        });
+        let helper = helper_body(module, sig, &sequence);
+        let helper = module.funcs.push(FuncDecl::Body(
+            sig,
+            format!("{}.common{}", name, helpers),
+            helper,
+        ));
+        helpers += 1;
+        tracing::debug!(
+            "outlining {} instructions in {} places into {}",
+            sequence.insts.len(),
+            occurrences.len(),
+            helper
+        );
+
+        for occurrence in occurrences {
+            let body = &mut *bodies[occurrence.body];
+            let block = occurrence.block;
+            let old = std::mem::take(&mut body.blocks[block].insts);
+            let call = body.add_op(
+                block,
+                Operator::Call {
+                    function_index: helper,
+                },
+                &occurrence.inputs[..],
+                &returns[..],
+            );
+            for (i, &idx) in sequence.outputs.iter().enumerate() {
+                let result = if returns.len() == 1 {
+                    call
+                } else {
+                    let pick = body.add_value(ValueDef::PickOutput(call, i as u32, returns[i]));
+                    body.append_to_block(block, pick);
+                    pick
+                };
+                body.set_alias(old[idx], result);
+            }
+            outlined += 1;
+        }
+    }
+    if helpers > 0 {
+        tracing::info!(
+            "{}: outlined {} blocks into {} helpers",
+            generic,
+            outlined,
+            helpers
+        );
+    }
+    outlined
+}
+
+/// How many times each value is used, after resolving aliases.
+fn use_counts(body: &FunctionBody) -> FxHashMap<Value, usize> {
+    let mut uses = FxHashMap::default();
+    for block in body.blocks.values() {
+        for &inst in &block.insts {
+            match &body.values[inst] {
+                ValueDef::Operator(_, args, _) => {
+                    for &arg in &body.arg_pool[*args] {
+                        *uses.entry(body.resolve_alias(arg)).or_insert(0) += 1;
+                    }
+                }
+                ValueDef::PickOutput(value, ..) => {
+                    *uses.entry(body.resolve_alias(*value)).or_insert(0) += 1;
+                }
+                _ => {}
+            }
+        }
+        block.terminator.visit_uses(|value| {
+            *uses.entry(body.resolve_alias(value)).or_insert(0) += 1;
+        });
+    }
+    uses
+}
+
+/// The sequence that the instructions of `block` form, and the values
+/// they use from outside it; `None` if they cannot be outlined.
+fn sequence(
+    body: &FunctionBody,
+    block: Block,
+    uses: &FxHashMap<Value, usize>,
+) -> Option<(Sequence, Vec<Value>)> {
+    let mut positions = FxHashMap::default();
+    let mut inputs: Vec<Value> = vec![];
+    let mut input_tys = vec![];
+    let mut inner_uses: FxHashMap<Value, usize> = FxHashMap::default();
+    let mut arg = |value: Value,
+                   positions: &FxHashMap<Value, usize>,
+                   inner_uses: &mut FxHashMap<Value, usize>|
+     -> Option<Arg> {
+        let value = body.resolve_alias(value);
+        if let Some(&idx) = positions.get(&value) {
+            *inner_uses.entry(value).or_insert(0) += 1;
+            return Some(Arg::Inst(idx));
+        }
+        let idx = match inputs.iter().position(|&input| input == value) {
+            Some(idx) => idx,
+            None => {
+                input_tys.push(body.values[value].ty(&body.type_pool)?);
+                inputs.push(value);
+                inputs.len() - 1
+            }
+        };
+        Some(Arg::Input(idx))
+    };
+
+    let mut insts = vec![];
+    for (idx, &value) in body.blocks[block].insts.iter().enumerate() {
+        let inst = match &body.values[value] {
+            ValueDef::Operator(op, args, tys) => {
+                let args = body.arg_pool[*args]
+                    .iter()
+                    .map(|&a| arg(a, &positions, &mut inner_uses))
+                    .collect::<Option<Vec<_>>>()?;
+                Inst::Operator(*op, args, body.type_pool[*tys].to_vec())
+            }
+            ValueDef::PickOutput(from, i, ty) => {
+                Inst::PickOutput(arg(*from, &positions, &mut inner_uses)?, *i, *ty)
+            }
+            _ => return None,
+        };
+        insts.push(inst);
+        positions.insert(value, idx);
+    }
+
+    // Results used after the block are returned, which takes a type.
+    let mut outputs = vec![];
+    for (idx, &value) in body.blocks[block].insts.iter().enumerate() {
+        let total = uses.get(&value).copied().unwrap_or(0);
+        if total > inner_uses.get(&value).copied().unwrap_or(0) {
+            match &insts[idx] {
+                Inst::Operator(_, _, tys) if tys.len() == 1 => {}
+                Inst::PickOutput(..) => {}
+                _ => return None,
+            }
+            outputs.push(idx);
+        }
+    }
+
+    Some((
+        Sequence {
+            insts,
+            inputs: input_tys,
+            outputs,
+        },
+        inputs,
+    ))
+}
+
+fn helper_body(module: &Module, sig: waffle::Signature, sequence: &Sequence) -> FunctionBody {
+    let mut body = FunctionBody::new(module, sig);
+    let entry = body.entry;
+    let params = body.blocks[entry]
+        .params
+        .iter()
+        .map(|&(_, value)| value)
+        .collect::<Vec<_>>();
+    let mut values: Vec<Value> = vec![];
+    let value = |arg: &Arg, values: &[Value]| match *arg {
+        Arg::Inst(idx) => values[idx],
+        Arg::Input(idx) => params[idx],
+    };
+    for inst in &sequence.insts {
+        let new_value = match inst {
+            Inst::Operator(op, args, tys) => {
+                let args = args.iter().map(|a| value(a, &values)).collect::<Vec<_>>();
+                body.add_op(entry, *op, &args[..], &tys[..])
+            }
+            Inst::PickOutput(from, i, ty) => {
+                let pick = body.add_value(ValueDef::PickOutput(value(from, &values), *i, *ty));
+                body.append_to_block(entry, pick);
+                pick
+            }
+        };
+        values.push(new_value);
+    }
+    let rets = sequence.outputs.iter().map(|&idx| values[idx]).collect();
+    body.set_terminator(entry, waffle::Terminator::Return { values: rets });
+    body
+}
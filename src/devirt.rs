@@ -0,0 +1,220 @@
+//! Devirtualization of calls through directives' results.
+//!
+//! While a directive is specialized, the table indices other
+//! directives will produce are not yet known, so an indirect call
+//! through one is devirtualized only when the loaded result reaches
+//! the call unchanged. Guest code often tests the result first, e.g.
+//! to fall back to the generic function while no specialization
+//! exists, which hides the callee. With `--devirtualize-results`,
+//! once every directive's result is known, specialized functions are
+//! constant-propagated again, with loads of results folded to their
+//! table indices, and indirect calls whose table index is then
+//! constant become direct calls.
+
+use crate::const_eval;
+use crate::value::WasmVal;
+use fxhash::{FxHashMap, FxHashSet};
+use waffle::{Func, FunctionBody, Memory, Operator, Signature, Table, Terminator, Value, ValueDef};
+
+/// What is known once all directives are specialized.
+pub(crate) struct Known<'a> {
+    /// The memory that directives' results are written to.
+    pub heap: Memory,
+    /// The table index written at each result address.
+    pub results: &'a FxHashMap<u32, u32>,
+    /// The function, and its signature, at each index of table 0
+    /// whose contents cannot change.
+    pub table: &'a [Option<(Func, Signature)>],
+}
+
+/// A value's constness, in the usual optimistic lattice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Lattice {
+    /// No definition reached yet.
+    Unknown,
+    Const(u32),
+    Varying,
+}
+
+impl Lattice {
+    fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Unknown, x) | (x, Lattice::Unknown) => x,
+            (Lattice::Const(a), Lattice::Const(b)) if a == b => Lattice::Const(a),
+            _ => Lattice::Varying,
+        }
+    }
+}
+
+/// Turn indirect calls in `body` whose callee is known into direct
+/// calls. Returns the number of calls devirtualized.
+pub(crate) fn devirtualize(body: &mut FunctionBody, known: &Known) -> usize {
+    let values = propagate(body, known);
+    let mut devirtualized = 0;
+    for block in body.blocks.iter() {
+        for i in 0..body.blocks[block].insts.len() {
+            let inst = body.blocks[block].insts[i];
+            let ValueDef::Operator(
+                Operator::CallIndirect {
+                    sig_index,
+                    table_index,
+                },
+                args,
+                tys,
+            ) = body.values[inst]
+            else {
+                continue;
+            };
+            if table_index != Table::from(0) {
+                continue;
+            }
+            let args = body.arg_pool[args].to_vec();
+            let Some(&index) = args.last() else {
+                continue;
+            };
+            let Lattice::Const(index) = get(&values, body.resolve_alias(index)) else {
+                continue;
+            };
+            let Some(Some((func, sig))) = known.table.get(index as usize) else {
+                continue;
+            };
+            // A call to a function of another signature traps.
+            if *sig != sig_index {
+                continue;
+            }
+            tracing::debug!("devirtualizing {} to {}", inst, func);
+            let args = body
+                .arg_pool
+                .from_iter(args[..args.len() - 1].iter().cloned());
+            body.values[inst] = ValueDef::Operator(
+                Operator::Call {
+                    function_index: *func,
+                },
+                args,
+                tys,
+            );
+            devirtualized += 1;
+        }
+    }
+    devirtualized
+}
+
+fn get(values: &FxHashMap<Value, Lattice>, value: Value) -> Lattice {
+    values.get(&value).copied().unwrap_or(Lattice::Unknown)
+}
+
+/// Lower `value` to its meet with `new`; returns whether it changed.
+fn lower(values: &mut FxHashMap<Value, Lattice>, value: Value, new: Lattice) -> bool {
+    let old = get(values, value);
+    let new = old.meet(new);
+    if new != old {
+        values.insert(value, new);
+    }
+    new != old
+}
+
+/// Sparse conditional constant propagation of i32 values, to a
+/// fixpoint: only edges that can be taken carry block arguments.
+fn propagate(body: &FunctionBody, known: &Known) -> FxHashMap<Value, Lattice> {
+    let mut values: FxHashMap<Value, Lattice> = FxHashMap::default();
+    let mut reachable = FxHashSet::default();
+    reachable.insert(body.entry);
+    for &(_, param) in &body.blocks[body.entry].params {
+        values.insert(param, Lattice::Varying);
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in body.blocks.iter() {
+            if !reachable.contains(&block) {
+                continue;
+            }
+            for &inst in &body.blocks[block].insts {
+                let new = match &body.values[inst] {
+                    ValueDef::Operator(op, args, _) => {
+                        let args = body.arg_pool[*args]
+                            .iter()
+                            .map(|&arg| get(&values, body.resolve_alias(arg)))
+                            .collect::<Vec<_>>();
+                        eval(*op, &args, known)
+                    }
+                    _ => Lattice::Varying,
+                };
+                changed |= lower(&mut values, inst, new);
+            }
+
+            let mut taken = vec![];
+            match &body.blocks[block].terminator {
+                Terminator::Br { target } => taken.push(target),
+                Terminator::CondBr {
+                    cond,
+                    if_true,
+                    if_false,
+                } => match get(&values, body.resolve_alias(*cond)) {
+                    Lattice::Unknown => {}
+                    Lattice::Const(0) => taken.push(if_false),
+                    Lattice::Const(_) => taken.push(if_true),
+                    Lattice::Varying => taken.extend([if_true, if_false]),
+                },
+                Terminator::Select {
+                    value,
+                    targets,
+                    default,
+                } => match get(&values, body.resolve_alias(*value)) {
+                    Lattice::Unknown => {}
+                    Lattice::Const(k) => taken.push(targets.get(k as usize).unwrap_or(default)),
+                    Lattice::Varying => {
+                        taken.push(default);
+                        taken.extend(targets);
+                    }
+                },
+                _ => {}
+            }
+            for target in taken {
+                changed |= reachable.insert(target.block);
+                let params = &body.blocks[target.block].params;
+                for (&arg, &(_, param)) in target.args.iter().zip(params) {
+                    let arg = get(&values, body.resolve_alias(arg));
+                    changed |= lower(&mut values, param, arg);
+                }
+            }
+        }
+    }
+    values
+}
+
+/// Evaluate an operator over the lattice. Loads of directives' results
+/// and numeric operators on `i32` constants fold; other operators are
+/// varying.
+fn eval(op: Operator, args: &[Lattice], known: &Known) -> Lattice {
+    if args.contains(&Lattice::Unknown) {
+        return Lattice::Unknown;
+    }
+    let k = |i: usize| match args.get(i) {
+        Some(&Lattice::Const(k)) => Some(k),
+        _ => None,
+    };
+    let value = match op {
+        Operator::I32Const { value } => Some(value),
+        // A directive's result, written after specialization.
+        Operator::I32Load { memory } if memory.memory == known.heap => k(0)
+            .and_then(|addr| addr.checked_add(memory.offset))
+            .and_then(|addr| known.results.get(&addr).copied()),
+        Operator::Select | Operator::TypedSelect { .. } => match (k(2), args) {
+            (Some(cond), _) => return if cond != 0 { args[0] } else { args[1] },
+            (None, [a, b, _]) => return a.meet(*b),
+            _ => None,
+        },
+        // Only `i32`s are tracked.
+        _ => (0..args.len())
+            .map(|i| k(i).map(WasmVal::I32))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|args| const_eval::eval(op, &args))
+            .and_then(|value| match value {
+                WasmVal::I32(k) => Some(k),
+                _ => None,
+            }),
+    };
+    value.map_or(Lattice::Varying, Lattice::Const)
+}
@@ -1,8 +1,10 @@
 //! Partial-evaluation directives.
 
+use crate::error::WevalError;
 use crate::image::Image;
 use crate::intrinsics::find_global_data_by_exported_func;
 use crate::value::{AbstractValue, MemoryBufferIndex, WasmVal};
+use fxhash::FxHashSet;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use waffle::{Func, Memory, Module};
@@ -40,16 +42,37 @@ pub(crate) struct DirectiveArgs {
 pub(crate) struct MemoryBuffer {
     /// The bytes in memory at this pointer.
     data: Arc<Vec<u8>>,
+    /// For a struct argument, the (offset, length) of each constant
+    /// field; other bytes are read at runtime. `None` if all bytes
+    /// are constant.
+    const_fields: Option<Arc<Vec<(u32, u32)>>>,
 }
 
 impl MemoryBuffer {
+    /// Whether a read of `size` bytes at `offset` has a constant
+    /// value: in a struct argument, whether it lies within the struct
+    /// and one of its constant fields.
+    pub(crate) fn is_const(&self, offset: u32, size: u32) -> bool {
+        let Some(fields) = &self.const_fields else {
+            return true;
+        };
+        let (offset, end) = (u64::from(offset), u64::from(offset) + u64::from(size));
+        end <= self.data.len() as u64
+            && fields.iter().any(|&(field, len)| {
+                u64::from(field) <= offset && end <= u64::from(field) + u64::from(len)
+            })
+    }
+
     pub(crate) fn read_size(&self, offset: u32, size: u32) -> anyhow::Result<u64> {
-        let offset = usize::try_from(offset).unwrap();
-        let size = usize::try_from(size).unwrap();
-        if offset + size > self.data.len() {
-            anyhow::bail!("Out of bounds");
-        }
-        let slice = &self.data[offset..(offset + size)];
+        let (start, end) = (offset as usize, offset as usize + size as usize);
+        let slice = self.data.get(start..end).ok_or_else(|| {
+            anyhow::anyhow!(
+                "{:#x}..{:#x} is outside the {:#x}-byte argument buffer",
+                start,
+                end,
+                self.data.len()
+            )
+        })?;
         Ok(match size {
             1 => u64::from(slice[0]),
             2 => u64::from(u16::from_le_bytes([slice[0], slice[1]])),
@@ -57,12 +80,16 @@ impl MemoryBuffer {
             8 => u64::from_le_bytes([
                 slice[0], slice[1], slice[2], slice[3], slice[4], slice[5], slice[6], slice[7],
             ]),
-            _ => unreachable!(),
+            _ => anyhow::bail!("unsupported read size {}", size),
         })
     }
 }
 
-pub(crate) fn collect(module: &Module, im: &mut Image) -> anyhow::Result<Vec<Directive>> {
+pub(crate) fn collect(
+    module: &Module,
+    im: &mut Image,
+    skip_malformed: bool,
+) -> anyhow::Result<Vec<Directive>> {
     // Is there a function called "weval.pending.head"?  If so, is the
     // function body a simple constant? This provides the address of a
     // doubly-linked list; we process requests and unlink them.
@@ -74,24 +101,48 @@ pub(crate) fn collect(module: &Module, im: &mut Image) -> anyhow::Result<Vec<Dir
         }
     };
 
-    log::info!("weval request list head at {:#x}", pending_head_addr);
+    tracing::info!("weval request list head at {:#x}", pending_head_addr);
 
     let heap = match im.main_heap {
         Some(heap) => heap,
         None => return Ok(vec![]),
     };
 
+    let malformed = |head: u32, e: anyhow::Error| {
+        anyhow::Error::new(WevalError::MalformedRequest(format!(
+            "malformed weval request at address {:#x}: {}",
+            head, e
+        )))
+    };
+
     let mut head = im.read_u32(heap, pending_head_addr)?;
+    let mut seen = FxHashSet::default();
     let mut directives = vec![];
     while head != 0 {
-        directives.push(decode_weval_req(im, heap, head)?);
-        let next = im.read_u32(heap, head)?;
-        let prev = im.read_u32(heap, head + 4)?;
+        // A corrupted link can't be skipped: we don't know where the
+        // rest of the list is.
+        if !seen.insert(head) {
+            return Err(malformed(head, anyhow::anyhow!("request list has a cycle")));
+        }
+        let (next, prev) = read_links(im, heap, head).map_err(|e| malformed(head, e))?;
+        match decode_weval_req(im, heap, head).and_then(|directive| {
+            check_args(module, &directive)?;
+            Ok(directive)
+        }) {
+            Ok(directive) => directives.push(directive),
+            Err(e) if skip_malformed => {
+                tracing::warn!("{}; skipping it", malformed(head, e));
+            }
+            Err(e) => return Err(malformed(head, e)),
+        }
         if next != 0 {
-            im.write_u32(heap, next + 4, prev)?;
+            req_field(next, 4)
+                .and_then(|addr| im.write_u32(heap, addr, prev))
+                .map_err(|e| malformed(next, e))?;
         }
         if prev != 0 {
-            im.write_u32(heap, prev, next)?;
+            im.write_u32(heap, prev, next)
+                .map_err(|e| malformed(prev, e))?;
         } else {
             im.write_u32(heap, pending_head_addr, next)?;
         }
@@ -103,19 +154,67 @@ pub(crate) fn collect(module: &Module, im: &mut Image) -> anyhow::Result<Vec<Dir
     Ok(directives)
 }
 
-fn decode_weval_req(im: &Image, heap: Memory, head: u32) -> anyhow::Result<Directive> {
+/// The `next` and `prev` links of the request at `head`.
+fn read_links(im: &Image, heap: Memory, head: u32) -> anyhow::Result<(u32, u32)> {
+    let next = im.read_u32(heap, head)?;
+    let prev = im.read_u32(heap, req_field(head, 4)?)?;
+    Ok((next, prev))
+}
+
+/// The address of the field at `offset` in the request at `head`.
+fn req_field(head: u32, offset: u32) -> anyhow::Result<u32> {
+    head.checked_add(offset)
+        .ok_or_else(|| anyhow::anyhow!("request extends past the end of memory"))
+}
+
+/// Check that a request's arguments decode and match its function's
+/// parameters, so that a truncated or stale request fails here rather
+/// than specializing on garbage.
+fn check_args(module: &Module, directive: &Directive) -> anyhow::Result<()> {
+    let args = DirectiveArgs::decode(&directive.args[..])?;
+    let sig = &module.signatures[module.funcs[directive.func].sig()];
+    let expected = sig.params.len() + directive.num_globals as usize;
+    if args.const_params.len() != expected {
+        anyhow::bail!(
+            "{} arguments given for {} parameters and {} specialization globals of {}",
+            args.const_params.len(),
+            sig.params.len(),
+            directive.num_globals,
+            module.funcs[directive.func].name()
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn decode_weval_req(im: &Image, heap: Memory, head: u32) -> anyhow::Result<Directive> {
     // Keep these offsets in sync with the struct definition in
     // `include/weval.h`.
-    let user_id = im.read_u32(heap, head + 8)?;
-    let num_globals = im.read_u32(heap, head + 12)?;
-    let func_table_index = im.read_u32(heap, head + 16)?;
-    let func = im.func_ptr(func_table_index)?;
-    let arg_ptr = im.read_u32(heap, head + 20)?;
-    let arg_len = im.read_u32(heap, head + 24)?;
-    let func_index_out_addr = im.read_u32(heap, head + 28)?;
-    let args = im.read_slice(heap, arg_ptr, arg_len)?.to_vec();
+    let field = |offset| -> anyhow::Result<u32> {
+        let addr = req_field(head, offset)?;
+        im.read_u32(heap, addr)
+            .map_err(|_| anyhow::anyhow!("field at {:#x} is outside of memory", addr))
+    };
+    let user_id = field(8)?;
+    let num_globals = field(12)?;
+    let func_table_index = field(16)?;
+    let func = im
+        .func_ptr(func_table_index)
+        .map_err(|e| anyhow::anyhow!("function table index {}: {}", func_table_index, e))?;
+    let arg_ptr = field(20)?;
+    let arg_len = field(24)?;
+    let func_index_out_addr = field(28)?;
+    let args = im
+        .read_slice(heap, arg_ptr, arg_len)
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "arguments at {:#x} (length {:#x}) are outside of memory",
+                arg_ptr,
+                arg_len
+            )
+        })?
+        .to_vec();
 
-    log::trace!("directive: args {:#x} len {:#x}", arg_ptr, arg_len);
+    tracing::trace!("directive: args {:#x} len {:#x}", arg_ptr, arg_len);
 
     Ok(Directive {
         user_id,
@@ -133,61 +232,98 @@ impl DirectiveArgs {
         let mut const_memory = vec![];
         let mut arg_ptr = 0;
 
-        let read_u32 = |addr| {
-            u32::from_le_bytes([
-                bytes[addr],
-                bytes[addr + 1],
-                bytes[addr + 2],
-                bytes[addr + 3],
-            ])
+        let slice = |addr: usize, len: usize| {
+            addr.checked_add(len)
+                .and_then(|end| bytes.get(addr..end))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "arguments truncated: need {} bytes at offset {}, but have {} in all",
+                        len,
+                        addr,
+                        bytes.len()
+                    )
+                })
         };
-        let read_u64 = |addr| {
-            u64::from_le_bytes([
-                bytes[addr],
-                bytes[addr + 1],
-                bytes[addr + 2],
-                bytes[addr + 3],
-                bytes[addr + 4],
-                bytes[addr + 5],
-                bytes[addr + 6],
-                bytes[addr + 7],
-            ])
+        let read_u32 = |addr| -> anyhow::Result<u32> {
+            Ok(u32::from_le_bytes(slice(addr, 4)?.try_into().unwrap()))
+        };
+        let read_u64 = |addr| -> anyhow::Result<u64> {
+            Ok(u64::from_le_bytes(slice(addr, 8)?.try_into().unwrap()))
         };
 
         let mut i = 0;
         while arg_ptr < bytes.len() {
-            let is_specialized = read_u32(arg_ptr);
-            let ty = read_u32(arg_ptr + 4);
+            let is_specialized = read_u32(arg_ptr)?;
+            let ty = read_u32(arg_ptr + 4)?;
             let (value, mem, arg_len) = if is_specialized != 0 {
                 match ty {
                     0 => (
-                        AbstractValue::Concrete(WasmVal::I32(read_u32(arg_ptr + 8))),
+                        AbstractValue::Concrete(WasmVal::I32(read_u32(arg_ptr + 8)?)),
                         None,
                         16,
                     ),
                     1 => (
-                        AbstractValue::Concrete(WasmVal::I64(read_u64(arg_ptr + 8))),
+                        AbstractValue::Concrete(WasmVal::I64(read_u64(arg_ptr + 8)?)),
                         None,
                         16,
                     ),
                     2 => (
-                        AbstractValue::Concrete(WasmVal::F32(read_u32(arg_ptr + 8))),
+                        AbstractValue::Concrete(WasmVal::F32(read_u32(arg_ptr + 8)?)),
                         None,
                         16,
                     ),
                     3 => (
-                        AbstractValue::Concrete(WasmVal::F64(read_u64(arg_ptr + 8))),
+                        AbstractValue::Concrete(WasmVal::F64(read_u64(arg_ptr + 8)?)),
                         None,
                         16,
                     ),
                     4 => {
-                        let len = read_u32(arg_ptr + 8);
-                        let padded_len = read_u32(arg_ptr + 12);
+                        let len = read_u32(arg_ptr + 8)? as usize;
+                        let padded_len = read_u32(arg_ptr + 12)? as usize;
+                        if len > padded_len {
+                            anyhow::bail!(
+                                "argument {}: buffer length {} exceeds its padded length {}",
+                                i,
+                                len,
+                                padded_len
+                            );
+                        }
+                        let data = MemoryBuffer {
+                            data: Arc::new(slice(arg_ptr + 16, len)?.to_vec()),
+                            const_fields: None,
+                        };
+                        (
+                            AbstractValue::ConcreteMemory(MemoryBufferIndex(i), 0),
+                            Some(data),
+                            16 + padded_len,
+                        )
+                    }
+                    5 => {
+                        // A field table (count, reserved word, then an
+                        // offset and length per field), then the data.
+                        let len = read_u32(arg_ptr + 8)? as usize;
+                        let padded_len = read_u32(arg_ptr + 12)? as usize;
+                        let num_fields = read_u32(arg_ptr + 16)? as usize;
+                        let table_len = 8 + 8 * num_fields;
+                        if table_len + len > padded_len {
+                            anyhow::bail!(
+                                "argument {}: {} fields and {} bytes of struct data exceed \
+                                 its padded length {}",
+                                i,
+                                num_fields,
+                                len,
+                                padded_len
+                            );
+                        }
+                        let fields = (0..num_fields)
+                            .map(|j| {
+                                let field = arg_ptr + 24 + 8 * j;
+                                Ok((read_u32(field)?, read_u32(field + 4)?))
+                            })
+                            .collect::<anyhow::Result<Vec<_>>>()?;
                         let data = MemoryBuffer {
-                            data: Arc::new(
-                                bytes[arg_ptr + 16..(arg_ptr + 16 + usize::try_from(len).unwrap())]
-                                    .to_vec(),
-                            ),
+                            data: Arc::new(slice(arg_ptr + 16 + table_len, len)?.to_vec()),
+                            const_fields: Some(Arc::new(fields)),
                         };
                         (
                             AbstractValue::ConcreteMemory(MemoryBufferIndex(i), 0),
@@ -200,9 +336,10 @@ impl DirectiveArgs {
             } else {
                 (AbstractValue::Runtime(None), None, 16)
             };
+            slice(arg_ptr, arg_len)?;
             const_params.push(value);
             const_memory.push(mem);
-            arg_ptr += usize::try_from(arg_len).unwrap();
+            arg_ptr += arg_len;
             i += 1;
         }
 
@@ -0,0 +1,519 @@
+//! Automatic detection of interpreter dispatch loops.
+//!
+//! Specializing an interpreter loop per bytecode PC normally takes
+//! `weval_push_context()`, `weval_update_context()` and
+//! `weval_pop_context()` calls around it. With `--auto-dispatch
+//! FUNC:PARAM`, weval finds the loop itself in the generic body of
+//! FUNC and adds them, for functions that do not use contexts already.
+//!
+//! A dispatch loop is a loop whose body ends in a `br_table` keyed on a
+//! load from the bytecode buffer, or, for a subroutine-threaded
+//! interpreter, calls an opcode handler through a `call_indirect` with
+//! such a load as its table index. The buffer is FUNC's parameter number
+//! PARAM; the PC is the one block parameter of the loop header that
+//! the load's address depends on (through adds, subtracts, multiplies
+//! and shifts). The address must also depend on the buffer, either
+//! directly (`buffer[pc]`) or through the PC's initial value (a PC
+//! that starts as a pointer into the buffer).
+//!
+//! The contexts are pushed with the PC on the edges entering the loop,
+//! updated with the next PC on its back edges, and popped on the edges
+//! leaving it. The PC must be constant when specializing, as with
+//! explicit intrinsics; if not, the directive fails and its function
+//! stays generic.
+//!
+//! A function may have several dispatch loops, one after another (a
+//! fast loop handing over to a slow one) or nested (a slow-path loop
+//! entered from a handler, returning to the main loop). Each is
+//! instrumented with its own PC: a nested loop pushes its context on
+//! top of the enclosing loop's, and pops back to it on exit, so the
+//! enclosing loop carries on at its own PC.
+//!
+//! With a constant PC, the opcode loaded is constant too, so a
+//! `call_indirect` into a table that nothing modifies becomes a direct
+//! call to the handler when specializing.
+
+use crate::intrinsics::Intrinsics;
+use fxhash::{FxHashMap, FxHashSet};
+use waffle::cfg::CFGInfo;
+use waffle::entity::EntityRef;
+use waffle::{
+    Block, Func, FunctionBody, Module, Operator, Table, Terminator, Type, Value, ValueDef,
+};
+
+/// How deep to look into an address expression.
+pub(crate) const MAX_ADDRESS_DEPTH: usize = 16;
+
+/// Parse an `--auto-dispatch` argument, `FUNC:PARAM`.
+pub(crate) fn parse_spec(s: &str) -> anyhow::Result<(String, usize)> {
+    match s.rsplit_once(':') {
+        Some((func, param)) if !func.is_empty() => Ok((func.to_owned(), param.parse()?)),
+        _ => anyhow::bail!("must be of the form FUNC:PARAM"),
+    }
+}
+
+/// Find the functions named in `--auto-dispatch` arguments, by name
+/// (from the name section), export name or index.
+pub(crate) fn resolve(
+    module: &Module,
+    specs: &[(String, usize)],
+) -> anyhow::Result<FxHashMap<Func, usize>> {
+    let mut funcs = FxHashMap::default();
+    for (name, param) in specs {
+        let func = crate::intrinsics::find_func(module, name)
+            .ok_or_else(|| anyhow::anyhow!("--auto-dispatch: no function named `{}`", name))?;
+        let params = module.signatures[module.funcs[func].sig()].params.len();
+        anyhow::ensure!(
+            *param < params,
+            "--auto-dispatch: function `{}` has {} parameters, no parameter {}",
+            name,
+            params,
+            param
+        );
+        funcs.insert(func, *param);
+    }
+    Ok(funcs)
+}
+
+/// Give the context intrinsics that the module does not import
+/// placeholder function indices, which only ever appear in the
+/// instrumented generic bodies: the calls are elided when
+/// specializing, and the bodies themselves are not emitted.
+pub(crate) fn add_placeholder_intrinsics(intrinsics: &mut Intrinsics) {
+    let placeholder = |n: usize| Some(Func::new(u32::MAX as usize - 1 - n));
+    intrinsics.push_context = intrinsics.push_context.or_else(|| placeholder(0));
+    intrinsics.update_context = intrinsics.update_context.or_else(|| placeholder(1));
+    intrinsics.pop_context = intrinsics.pop_context.or_else(|| placeholder(2));
+}
+
+/// Whether `func` already calls any context intrinsic.
+pub(crate) fn uses_contexts(func: &FunctionBody, intrinsics: &Intrinsics) -> bool {
+    let contexts = [
+        intrinsics.push_context,
+        intrinsics.update_context,
+        intrinsics.pop_context,
+    ];
+    func.blocks.values().any(|block| {
+        block.insts.iter().any(|&inst| match &func.values[inst] {
+            ValueDef::Operator(Operator::Call { function_index }, ..) => {
+                contexts.contains(&Some(*function_index))
+            }
+            _ => false,
+        })
+    })
+}
+
+pub(crate) struct DispatchLoop {
+    pub header: Block,
+    /// Index of the PC among the header's block parameters.
+    pub pc: usize,
+    /// Whether the PC is a pointer into the buffer rather than an
+    /// index.
+    pub pc_in_buffer: bool,
+    /// The block that dispatches on the opcode: the one ending in the
+    /// `br_table`, or containing the `call_indirect`.
+    pub dispatch: Block,
+    /// The table of handler functions, if the loop dispatches through
+    /// a `call_indirect` rather than a `br_table`.
+    pub handler_table: Option<Table>,
+    /// Block parameters in the loop that merge next PCs on their way
+    /// to the back edges, as (block, index).
+    pub pc_merges: Vec<(Block, usize)>,
+    pub body: FxHashSet<Block>,
+}
+
+/// Add context intrinsics around the dispatch loops in `func` keyed on
+/// its parameter number `buffer_param`. Returns the number of loops
+/// instrumented.
+pub(crate) fn instrument(
+    func: &mut FunctionBody,
+    buffer_param: usize,
+    intrinsics: &Intrinsics,
+) -> usize {
+    func.recompute_edges();
+    let cfg = CFGInfo::new(func);
+    let loops = find_loops(func, &cfg, buffer_param);
+
+    // Decide what to call on each edge before splitting any, so that
+    // the new edge blocks are not mistaken for loop exits.
+    let mut edges = vec![];
+    for (from, block) in func.blocks.entries() {
+        if cfg.rpo_pos[from].is_none() {
+            continue;
+        }
+        for (succ_idx, &to) in block.succs.iter().enumerate() {
+            let mut calls = vec![];
+            for l in &loops {
+                if l.body.contains(&from) && !l.body.contains(&to) {
+                    calls.push((intrinsics.pop_context, None));
+                }
+            }
+            for l in loops.iter().filter(|l| l.header == to) {
+                if l.body.contains(&from) {
+                    calls.push((intrinsics.update_context, Some(l.pc)));
+                } else {
+                    calls.push((intrinsics.push_context, Some(l.pc)));
+                }
+            }
+            // Update the context before next PCs are merged, too: each
+            // path is then specialized for its own constant PC.
+            for l in &loops {
+                for &(block, idx) in &l.pc_merges {
+                    if block == to {
+                        calls.push((intrinsics.update_context, Some(idx)));
+                    }
+                }
+            }
+            if !calls.is_empty() {
+                edges.push((from, succ_idx, to, calls));
+            }
+        }
+    }
+    for (from, succ_idx, to, calls) in edges {
+        let edge_block = func.split_edge(from, to, succ_idx);
+        func.blocks[edge_block].desc =
+            format!("Dispatch-loop context change on {} -> {}", from, to);
+        for (function_index, pc) in calls {
+            let function_index = function_index.expect("context intrinsics are set");
+            let args: Vec<Value> = pc
+                .map(|pc| func.blocks[edge_block].params[pc].1)
+                .into_iter()
+                .collect();
+            func.add_op(
+                edge_block,
+                Operator::Call { function_index },
+                &args[..],
+                &[],
+            );
+        }
+    }
+    func.recompute_edges();
+    loops.len()
+}
+
+/// Find the dispatch loops in `func` keyed on its parameter number
+/// `buffer_param`, outermost first: a loop nested in another comes
+/// after it.
+pub(crate) fn find_loops(
+    func: &FunctionBody,
+    cfg: &CFGInfo,
+    buffer_param: usize,
+) -> Vec<DispatchLoop> {
+    let Some(&(_, buffer)) = func.blocks[func.entry].params.get(buffer_param) else {
+        return vec![];
+    };
+
+    // Loop headers come before their loops' bodies in RPO, so outer
+    // loops are found first.
+    let mut loops: Vec<DispatchLoop> = vec![];
+    for &header in cfg.rpo.values() {
+        let latches: Vec<Block> = func.blocks[header]
+            .preds
+            .iter()
+            .copied()
+            .filter(|&pred| cfg.dominates(header, pred))
+            .collect();
+        if latches.is_empty() {
+            continue;
+        }
+        let body = natural_loop(func, cfg, header, &latches);
+        if let Some((pc, dispatch, handler_table)) = find_pc(func, header, &body, buffer) {
+            tracing::debug!("dispatch loop at {}: PC is block param {}", header, pc);
+            loops.push(DispatchLoop {
+                header,
+                pc,
+                pc_in_buffer: starts_in_buffer(func, header, &body, pc, buffer),
+                dispatch,
+                handler_table,
+                pc_merges: vec![],
+                body,
+            });
+        }
+    }
+
+    // A loop's PC passes unchanged through the loops nested in it, so
+    // their blocks are no merges of it.
+    let merges: Vec<_> = loops
+        .iter()
+        .map(|l| {
+            let nested: Vec<&FxHashSet<Block>> = loops
+                .iter()
+                .filter(|n| n.header != l.header && l.body.contains(&n.header))
+                .map(|n| &n.body)
+                .collect();
+            pc_merges(func, l.header, &l.body, l.pc, &nested)
+        })
+        .collect();
+    for (l, merges) in loops.iter_mut().zip(merges) {
+        l.pc_merges = merges;
+    }
+    loops
+}
+
+/// The reachable blocks of the natural loop with the given header
+/// and latches.
+fn natural_loop(
+    func: &FunctionBody,
+    cfg: &CFGInfo,
+    header: Block,
+    latches: &[Block],
+) -> FxHashSet<Block> {
+    let mut body = FxHashSet::default();
+    body.insert(header);
+    let mut stack = latches.to_vec();
+    while let Some(block) = stack.pop() {
+        if cfg.rpo_pos[block].is_some() && body.insert(block) {
+            stack.extend(func.blocks[block].preds.iter().copied());
+        }
+    }
+    body
+}
+
+/// If the loop dispatches on a load from the buffer, the index of
+/// its PC among the header's block parameters, the dispatching block,
+/// and the handler table for a `call_indirect` dispatch.
+fn find_pc(
+    func: &FunctionBody,
+    header: Block,
+    body: &FxHashSet<Block>,
+    buffer: Value,
+) -> Option<(usize, Block, Option<Table>)> {
+    let header_param = |value: Value| match &func.values[value] {
+        ValueDef::BlockParam(block, idx, Type::I32) if *block == header => Some(*idx as usize),
+        _ => None,
+    };
+    // A header parameter that the back edges pass through unchanged
+    // (e.g. the buffer itself) is not the PC.
+    let invariant = |idx: usize| {
+        let param = func.blocks[header].params[idx].1;
+        incoming(func, header, body, idx, true)
+            .into_iter()
+            .all(|arg| is_same_value(func, arg, param, MAX_ADDRESS_DEPTH))
+    };
+
+    // The PC that the opcode `value` is loaded with.
+    let keyed_pc = |value: Value| {
+        let addr = match &func.values[func.resolve_alias(value)] {
+            ValueDef::Operator(op, args, _) if is_load(op) => func.arg_pool[*args][0],
+            _ => return None,
+        };
+        let mut leaves = vec![];
+        address_leaves(func, addr, MAX_ADDRESS_DEPTH, &mut leaves);
+        let mut pcs = vec![];
+        let mut on_buffer = false;
+        for &leaf in &leaves {
+            if leaf == buffer {
+                on_buffer = true;
+            } else if let Some(idx) = header_param(leaf) {
+                if invariant(idx) {
+                    on_buffer |= starts_in_buffer(func, header, body, idx, buffer);
+                } else if !pcs.contains(&idx) {
+                    pcs.push(idx);
+                }
+            }
+        }
+        let &[pc] = &pcs[..] else {
+            return None;
+        };
+        (on_buffer || starts_in_buffer(func, header, body, pc, buffer)).then_some(pc)
+    };
+
+    // In block order, for a deterministic choice.
+    func.blocks
+        .iter()
+        .filter(|block| body.contains(block))
+        .find_map(|block| {
+            if let Terminator::Select { value, targets, .. } = &func.blocks[block].terminator {
+                if targets.len() >= 2 {
+                    if let Some(pc) = keyed_pc(*value) {
+                        return Some((pc, block, None));
+                    }
+                }
+            }
+            func.blocks[block].insts.iter().find_map(|&inst| {
+                let ValueDef::Operator(Operator::CallIndirect { table_index, .. }, args, _) =
+                    &func.values[inst]
+                else {
+                    return None;
+                };
+                let &index = func.arg_pool[*args].last()?;
+                keyed_pc(index).map(|pc| (pc, block, Some(*table_index)))
+            })
+        })
+}
+
+/// The block parameters in the loop, outside the `nested` loops in
+/// it, through which next PCs reach the back edges.
+fn pc_merges(
+    func: &FunctionBody,
+    header: Block,
+    body: &FxHashSet<Block>,
+    pc: usize,
+    nested: &[&FxHashSet<Block>],
+) -> Vec<(Block, usize)> {
+    let mut merges = vec![];
+    let mut visited = FxHashSet::default();
+    let mut stack = incoming(func, header, body, pc, true);
+    while let Some(value) = stack.pop() {
+        let value = func.resolve_alias(value);
+        let ValueDef::BlockParam(block, idx, _) = &func.values[value] else {
+            continue;
+        };
+        let (block, idx) = (*block, *idx as usize);
+        if block == header || !body.contains(&block) || !visited.insert((block, idx)) {
+            continue;
+        }
+        if !nested.iter().any(|nested| nested.contains(&block)) {
+            merges.push((block, idx));
+        }
+        for &pred in &func.blocks[block].preds {
+            func.blocks[pred].terminator.visit_targets(|target| {
+                if target.block == block {
+                    stack.push(target.args[idx]);
+                }
+            });
+        }
+    }
+    merges
+}
+
+/// The values passed to the header's parameter `idx` on the loop's
+/// back edges, or on the edges entering it.
+fn incoming(
+    func: &FunctionBody,
+    header: Block,
+    body: &FxHashSet<Block>,
+    idx: usize,
+    back_edges: bool,
+) -> Vec<Value> {
+    let mut preds: Vec<Block> = func.blocks[header].preds.clone();
+    preds.sort();
+    preds.dedup();
+    let mut args = vec![];
+    for pred in preds {
+        if body.contains(&pred) != back_edges {
+            continue;
+        }
+        func.blocks[pred].terminator.visit_targets(|target| {
+            if target.block == header {
+                args.push(target.args[idx]);
+            }
+        });
+    }
+    args
+}
+
+/// Whether the header's parameter `idx` enters the loop as an address
+/// in the buffer on every entry edge.
+fn starts_in_buffer(
+    func: &FunctionBody,
+    header: Block,
+    body: &FxHashSet<Block>,
+    idx: usize,
+    buffer: Value,
+) -> bool {
+    let starts = incoming(func, header, body, idx, false);
+    !starts.is_empty()
+        && starts.into_iter().all(|start| {
+            let mut leaves = vec![];
+            address_leaves(func, start, MAX_ADDRESS_DEPTH, &mut leaves);
+            // The buffer may reach a later loop through the header
+            // parameters of an earlier one.
+            leaves
+                .into_iter()
+                .any(|leaf| is_same_value(func, leaf, buffer, MAX_ADDRESS_DEPTH))
+        })
+}
+
+/// Whether `value` is always `target`: an alias of it, or a block
+/// parameter that receives it on every edge (or itself, around a
+/// loop).
+fn is_same_value(func: &FunctionBody, value: Value, target: Value, depth: usize) -> bool {
+    same_value(func, value, target, depth, &mut FxHashSet::default())
+}
+
+fn same_value(
+    func: &FunctionBody,
+    value: Value,
+    target: Value,
+    depth: usize,
+    visited: &mut FxHashSet<Value>,
+) -> bool {
+    let value = func.resolve_alias(value);
+    // A parameter met again is assumed the same; any other input
+    // decides.
+    if value == target || !visited.insert(value) {
+        return true;
+    }
+    let ValueDef::BlockParam(block, idx, _) = &func.values[value] else {
+        return false;
+    };
+    if depth == 0 || func.blocks[*block].preds.is_empty() {
+        return false;
+    }
+    let mut same = true;
+    for &pred in &func.blocks[*block].preds {
+        func.blocks[pred].terminator.visit_targets(|t| {
+            if t.block == *block {
+                same &= same_value(func, t.args[*idx as usize], target, depth - 1, visited);
+            }
+        });
+    }
+    same
+}
+
+pub(crate) fn is_load(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Load { .. }
+            | Operator::I32Load8U { .. }
+            | Operator::I32Load8S { .. }
+            | Operator::I32Load16U { .. }
+            | Operator::I32Load16S { .. }
+    )
+}
+
+/// Whether `a` and `b` compute the same value by the same pure
+/// operators from the same inputs, looking at most `depth` deep.
+pub(crate) fn is_same_expr(func: &FunctionBody, a: Value, b: Value, depth: usize) -> bool {
+    let (a, b) = (func.resolve_alias(a), func.resolve_alias(b));
+    if a == b {
+        return true;
+    }
+    match (&func.values[a], &func.values[b]) {
+        (ValueDef::Operator(op_a, args_a, _), ValueDef::Operator(op_b, args_b, _))
+            if depth > 0 && op_a == op_b && op_a.is_pure() =>
+        {
+            let (args_a, args_b) = (&func.arg_pool[*args_a], &func.arg_pool[*args_b]);
+            args_a.len() == args_b.len()
+                && args_a
+                    .iter()
+                    .zip(args_b)
+                    .all(|(&a, &b)| is_same_expr(func, a, b, depth - 1))
+        }
+        _ => false,
+    }
+}
+
+/// The values that an address is computed from by integer arithmetic.
+pub(crate) fn address_leaves(
+    func: &FunctionBody,
+    value: Value,
+    depth: usize,
+    leaves: &mut Vec<Value>,
+) {
+    let value = func.resolve_alias(value);
+    match &func.values[value] {
+        ValueDef::Operator(
+            Operator::I32Add | Operator::I32Sub | Operator::I32Mul | Operator::I32Shl,
+            args,
+            _,
+        ) if depth > 0 => {
+            for &arg in &func.arg_pool[*args] {
+                address_leaves(func, arg, depth - 1, leaves);
+            }
+        }
+        ValueDef::Operator(Operator::I32Const { .. }, ..) => {}
+        _ => leaves.push(value),
+    }
+}
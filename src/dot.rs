@@ -0,0 +1,133 @@
+//! Graphviz (DOT) output of context trees and specialized CFGs.
+//!
+//! These are debugging aids emitted alongside the textual IR when
+//! `--output-ir-dot` is given: one graph showing how the context
+//! tree unfolded for a directive, and one showing the specialized
+//! CFG with each block colored by the context it was specialized
+//! in.
+
+use crate::state::{Context, ContextElem, Contexts};
+use fxhash::FxHashMap;
+use std::fmt::Write;
+use waffle::entity::{EntityRef, PerEntity};
+use waffle::{Block, FunctionBody};
+
+/// Number of colors in the Brewer `set312` scheme we use to
+/// distinguish contexts.
+const NUM_COLORS: usize = 12;
+
+fn context_color(ctx: Context) -> String {
+    if ctx.is_invalid() {
+        "white".to_owned()
+    } else {
+        format!("/set312/{}", (ctx.index() % NUM_COLORS) + 1)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The innermost element of a context, for people to read.
+pub(crate) fn context_elem(contexts: &Contexts, ctx: Context) -> String {
+    match contexts.leaf_element(ctx) {
+        ContextElem::Root => "root".to_owned(),
+        ContextElem::Loop(pc) => format!("PC {:#x}", pc),
+        ContextElem::Cold => "cold PCs".to_owned(),
+        ContextElem::Specialized(value, k) => format!("{} = {}", value, k),
+    }
+}
+
+fn context_label(contexts: &Contexts, ctx: Context) -> String {
+    let leaf = context_elem(contexts, ctx);
+    match contexts.context_bucket[ctx] {
+        Some(bucket) => format!("{}\\n{}\\nbucket {}", ctx, leaf, bucket),
+        None => format!("{}\\n{}", ctx, leaf),
+    }
+}
+
+/// Render the context tree, annotating each context with the number
+/// of specialized blocks created in it.
+pub(crate) fn context_tree(
+    name: &str,
+    contexts: &Contexts,
+    block_rev_map: &PerEntity<Block, (Context, Block)>,
+    func: &FunctionBody,
+) -> String {
+    let mut blocks_per_ctx: FxHashMap<Context, usize> = FxHashMap::default();
+    for block in func.blocks.iter() {
+        let (ctx, _) = block_rev_map[block];
+        if ctx.is_valid() {
+            *blocks_per_ctx.entry(ctx).or_default() += 1;
+        }
+    }
+
+    let mut s = String::new();
+    writeln!(&mut s, "digraph \"{}\" {{", escape(name)).unwrap();
+    writeln!(&mut s, "  node [shape=box, style=filled];").unwrap();
+    for ctx in contexts.iter() {
+        writeln!(
+            &mut s,
+            "  {} [label=\"{}\\n{} blocks\", fillcolor=\"{}\"];",
+            ctx,
+            context_label(contexts, ctx),
+            blocks_per_ctx.get(&ctx).copied().unwrap_or(0),
+            context_color(ctx),
+        )
+        .unwrap();
+        let parent = contexts.parent(ctx);
+        if parent.is_valid() {
+            writeln!(&mut s, "  {} -> {};", parent, ctx).unwrap();
+        }
+    }
+    writeln!(&mut s, "}}").unwrap();
+    s
+}
+
+/// Render the reachable part of a specialized function body's CFG,
+/// with blocks colored by the context in which they were specialized.
+pub(crate) fn specialized_cfg(
+    name: &str,
+    contexts: &Contexts,
+    block_rev_map: &PerEntity<Block, (Context, Block)>,
+    func: &FunctionBody,
+) -> String {
+    let (_, _, reachable) = crate::stats::count_reachable_blocks_and_insts(func);
+    let mut reachable = reachable.into_iter().collect::<Vec<_>>();
+    reachable.sort();
+
+    let mut s = String::new();
+    writeln!(&mut s, "digraph \"{}\" {{", escape(name)).unwrap();
+    writeln!(&mut s, "  node [shape=box, style=filled];").unwrap();
+    for &block in &reachable {
+        let (ctx, orig_block) = block_rev_map[block];
+        let label = if ctx.is_valid() {
+            format!(
+                "{}\\norig {}\\n{}\\n{} insts",
+                block,
+                orig_block,
+                context_label(contexts, ctx),
+                func.blocks[block].insts.len()
+            )
+        } else if block == func.entry {
+            format!("{}\\npre-entry", block)
+        } else {
+            format!("{}\\n{} insts", block, func.blocks[block].insts.len())
+        };
+        writeln!(
+            &mut s,
+            "  {} [label=\"{}\", fillcolor=\"{}\"];",
+            block,
+            label,
+            context_color(ctx)
+        )
+        .unwrap();
+    }
+    for &block in &reachable {
+        func.blocks[block].terminator.visit_successors(|succ| {
+            writeln!(&mut s, "  {} -> {};", block, succ).unwrap();
+        });
+    }
+    writeln!(&mut s, "}}").unwrap();
+    s
+}
@@ -0,0 +1,168 @@
+//! Analysis results as Datalog relations (`--emit-facts`).
+//!
+//! For tools that study weval's partial evaluation without
+//! instrumenting it, this writes what the evaluator computed for each
+//! directive, as it stands when evaluation finishes (before the passes
+//! that clean up specialized code). Each relation goes in a
+//! tab-separated `NAME.facts` file, one row per line, as Soufflé reads
+//! them (CSV tools can too), and `schema.dl` declares them all:
+//!
+//! - `directive(d, func)`: the directive with user ID `d` specializes
+//!   the generic function `func`. Every other relation starts with the
+//!   directive's user ID.
+//! - `context(d, ctx, elem)` and `context_parent(d, ctx, parent)`: the
+//!   context tree, as in the IR output's DOT files.
+//! - `block(d, ctx, generic, specialized)`: the specialized block for a
+//!   generic block in a context.
+//! - `value(d, ctx, generic, specialized, abs)`: the specialized value
+//!   for a generic value in a context, and its abstract value.
+//! - `overlay(d, block, slot, value, abs)`: the state on entry to a
+//!   specialized block: specialization registers and the virtualized
+//!   stack and locals (`Register(0)`, `StackData(1)`, `LocalAddr(2)`,
+//!   ...), each with the specialized value holding it (or `merge`), and
+//!   globals (`global0`, ..., with `-` for the value), each with its
+//!   abstract value.
+//! - `call(d, ctx, inst, kind, callee)`: what became of a generic call
+//!   instruction in a context: `direct`, `const-result` (a direct call
+//!   whose result is known), `devirtualized` (an indirect call made
+//!   direct) or `indirect`, with the callee, or `-`.
+//!
+//! Functions, values, blocks and abstract values are written as in the
+//! IR output (`--output-ir`). Directives taken from the cache are not
+//! evaluated, so have no facts.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Each relation, with its columns' names and Soufflé types.
+const SCHEMA: &[(&str, &[(&str, &str)])] = &[
+    ("directive", &[("d", "unsigned"), ("func", "symbol")]),
+    (
+        "context",
+        &[("d", "unsigned"), ("ctx", "unsigned"), ("elem", "symbol")],
+    ),
+    (
+        "context_parent",
+        &[
+            ("d", "unsigned"),
+            ("ctx", "unsigned"),
+            ("parent", "unsigned"),
+        ],
+    ),
+    (
+        "block",
+        &[
+            ("d", "unsigned"),
+            ("ctx", "unsigned"),
+            ("generic", "symbol"),
+            ("specialized", "symbol"),
+        ],
+    ),
+    (
+        "value",
+        &[
+            ("d", "unsigned"),
+            ("ctx", "unsigned"),
+            ("generic", "symbol"),
+            ("specialized", "symbol"),
+            ("abs", "symbol"),
+        ],
+    ),
+    (
+        "overlay",
+        &[
+            ("d", "unsigned"),
+            ("block", "symbol"),
+            ("slot", "symbol"),
+            ("value", "symbol"),
+            ("abs", "symbol"),
+        ],
+    ),
+    (
+        "call",
+        &[
+            ("d", "unsigned"),
+            ("ctx", "unsigned"),
+            ("inst", "symbol"),
+            ("kind", "symbol"),
+            ("callee", "symbol"),
+        ],
+    ),
+];
+
+/// The rows of each relation for one directive.
+#[derive(Default)]
+pub(crate) struct Relations {
+    rows: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl Relations {
+    /// Add a row to `relation`, whose columns must match `SCHEMA`.
+    pub(crate) fn push(&mut self, relation: &'static str, columns: &[&dyn Display]) {
+        debug_assert!(SCHEMA
+            .iter()
+            .any(|&(name, cols)| name == relation && cols.len() == columns.len()));
+        let row = columns
+            .iter()
+            .map(|column| column.to_string().replace(['\t', '\n'], " "))
+            .collect::<Vec<_>>()
+            .join("\t");
+        self.rows.entry(relation).or_default().push(row);
+    }
+}
+
+/// The `--emit-facts` directory, and the rows gathered for it from
+/// directives specialized in parallel.
+pub(crate) struct FactsOutput {
+    dir: PathBuf,
+    rows: Mutex<BTreeMap<&'static str, Vec<String>>>,
+}
+
+impl FactsOutput {
+    pub(crate) fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow::anyhow!("--emit-facts: creating {}: {}", dir.display(), e))?;
+        Ok(FactsOutput {
+            dir,
+            rows: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub(crate) fn add(&self, relations: Relations) {
+        let mut rows = self.rows.lock().unwrap();
+        for (relation, new) in relations.rows {
+            rows.entry(relation).or_default().extend(new);
+        }
+    }
+
+    /// Write every relation, sorted so that the files do not depend on
+    /// the order directives finished in, and the schema.
+    pub(crate) fn write(self) -> anyhow::Result<()> {
+        let mut rows = self.rows.into_inner().unwrap();
+        let mut schema = String::new();
+        for &(relation, columns) in SCHEMA {
+            let columns = columns
+                .iter()
+                .map(|(name, ty)| format!("{}: {}", name, ty))
+                .collect::<Vec<_>>();
+            schema.push_str(&format!(
+                ".decl {}({})\n.input {}\n",
+                relation,
+                columns.join(", "),
+                relation
+            ));
+            let mut rows = rows.remove(relation).unwrap_or_default();
+            rows.sort();
+            rows.dedup();
+            let mut contents = rows.join("\n");
+            if !contents.is_empty() {
+                contents.push('\n');
+            }
+            std::fs::write(self.dir.join(format!("{}.facts", relation)), contents)?;
+        }
+        std::fs::write(self.dir.join("schema.dl"), schema)?;
+        Ok(())
+    }
+}
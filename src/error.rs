@@ -0,0 +1,115 @@
+//! Typed errors, for tooling that wraps weval.
+//!
+//! Errors travel as `anyhow::Error`, gathering context on the way up;
+//! the site that knows what kind of failure it is attaches a
+//! `WevalError`, and `code` and `to_json` find it again in the chain.
+//! Errors without one (I/O, malformed input modules, ...) have the
+//! code `error`.
+//!
+//! `--error-json` writes a failed run's error in this form, and `weval
+//! serve` includes it as the `data` of its JSON-RPC error responses.
+
+use crate::verify::VerifyError;
+use serde_json::{json, Value as Json};
+
+#[derive(Debug)]
+pub(crate) enum WevalError {
+    /// The module's `weval` imports don't match the intrinsics this
+    /// weval provides.
+    IntrinsicMismatch(String),
+    /// A directive the run depends on could not be specialized.
+    DirectiveFailed {
+        user_id: u32,
+        func: String,
+        message: String,
+    },
+    /// An entry in the module's weval request list is malformed.
+    MalformedRequest(String),
+    /// A weval intrinsic failed while specializing: an assertion
+    /// (e.g. `weval_assert_const32()`) did not hold, or an intrinsic
+    /// was given arguments it cannot use.
+    IntrinsicFailed(String),
+    /// The module uses something weval doesn't support.
+    Unsupported(String),
+    /// A memory or size budget was exceeded.
+    BudgetExceeded(String),
+    /// weval broke one of its own invariants: a bug in weval.
+    InternalInvariant(String),
+    /// The run succeeded, but broke the exit-status policy (e.g.
+    /// `--fail-on-warning`).
+    PolicyViolation(String),
+}
+
+impl WevalError {
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            WevalError::IntrinsicMismatch(_) => "intrinsic-mismatch",
+            WevalError::DirectiveFailed { .. } => "directive-failed",
+            WevalError::MalformedRequest(_) => "malformed-request",
+            WevalError::IntrinsicFailed(_) => "intrinsic-failed",
+            WevalError::Unsupported(_) => "unsupported",
+            WevalError::BudgetExceeded(_) => "budget-exceeded",
+            WevalError::InternalInvariant(_) => "internal-invariant",
+            WevalError::PolicyViolation(_) => "policy-violation",
+        }
+    }
+}
+
+impl std::fmt::Display for WevalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WevalError::IntrinsicMismatch(message)
+            | WevalError::MalformedRequest(message)
+            | WevalError::IntrinsicFailed(message)
+            | WevalError::Unsupported(message)
+            | WevalError::BudgetExceeded(message)
+            | WevalError::InternalInvariant(message)
+            | WevalError::PolicyViolation(message) => write!(f, "{}", message),
+            WevalError::DirectiveFailed {
+                user_id,
+                func,
+                message,
+            } => write!(
+                f,
+                "specializing `{}` (user ID {}) failed: {}",
+                func, user_id, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WevalError {}
+
+/// The code of `e`: that of the first `WevalError` in its chain, with
+/// a failed IR verification an internal invariant.
+pub(crate) fn code(e: &anyhow::Error) -> &'static str {
+    e.chain()
+        .find_map(|cause| {
+            if let Some(e) = cause.downcast_ref::<WevalError>() {
+                Some(e.code())
+            } else if cause.is::<VerifyError>() {
+                Some("internal-invariant")
+            } else {
+                None
+            }
+        })
+        .unwrap_or("error")
+}
+
+/// `e` as JSON: its code, the full message, and the message of each
+/// cause, outermost first, plus the directive for a failed one.
+pub(crate) fn to_json(e: &anyhow::Error) -> Json {
+    let mut json = json!({
+        "code": code(e),
+        "message": format!("{:#}", e),
+        "causes": e.chain().map(|cause| cause.to_string()).collect::<Vec<_>>(),
+    });
+    let directive = e.chain().find_map(|cause| match cause.downcast_ref() {
+        Some(WevalError::DirectiveFailed { user_id, func, .. }) => Some((user_id, func)),
+        _ => None,
+    });
+    if let Some((user_id, func)) = directive {
+        json["directive"] = json!({ "user_id": user_id, "func": func });
+    }
+    json
+}
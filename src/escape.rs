@@ -7,12 +7,835 @@
 //! stack-pointer manipulation. This turns out to be useful when
 //! weval'ing ICs when partial evaluation has removed all uses of some
 //! dynamic on-stack data structure, like an opcode reader.
+//!
+//! Before that, the analysis is done per field of the frame: a field
+//! (a constant offset from the stack pointer on entry, accessed with
+//! plain loads and stores of one type) whose address does not escape
+//! is promoted to SSA values, and its loads and stores removed.
+//! Fields accessed with narrow loads and stores (`bool`, `char` and
+//! `short` locals spilled by the compiler) are promoted too: a narrow
+//! load of one becomes a mask or sign-extension of the value last
+//! stored. Once every field is promoted the frame itself may go away.
+//!
+//! An address that escapes (passed to a call, stored to memory,
+//! returned, etc.) may be used to reach any part of the frame, even
+//! below it (as C's `container_of` does), so it keeps every field in
+//! memory. For small callees, though, we compute a summary of how each
+//! pointer argument is used; a callee that only loads and stores
+//! through it, within a known range, does not capture it, so the call
+//! affects only the fields in that range.
+//!
+//! The analysis records why each part of the frame stays in memory
+//! in an [`EscapeReport`], which is logged, dumped with the IR, and
+//! used to check `weval_assert_not_escaped()` assertions.
 
-use std::collections::HashSet;
+use fxhash::FxHashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use waffle::cfg::CFGInfo;
-use waffle::entity::EntityRef;
+use waffle::entity::{EntityRef, PerEntity};
 use waffle::pool::ListRef;
-use waffle::{FunctionBody, Operator, Terminator, Type, Value, ValueDef};
+use waffle::{
+    Block, Func, FuncDecl, FunctionBody, MemoryArg, Module, Operator, Terminator, Type, Value,
+    ValueDef,
+};
+
+/// At most this many fields are promoted per function (one bit each
+/// in the initialization analysis).
+const MAX_PROMOTED_FIELDS: usize = 64;
+
+/// Largest function body, in bytes of bytecode, for which we compute
+/// an escape summary.
+const MAX_SUMMARY_BODY_SIZE: usize = 512;
+
+/// How a function uses a pointer passed as one of its arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ArgSummary {
+    /// Not used at all.
+    Unused,
+    /// Used only as the address of loads and stores, which access
+    /// bytes in the given range relative to the pointer; never
+    /// captured.
+    Accessed(i64, i64),
+    /// May be captured: stored, returned, passed on, compared, etc.
+    Captured,
+}
+
+/// Escape summaries of small functions, per parameter.
+pub(crate) type EscapeSummaries = HashMap<Func, Vec<ArgSummary>>;
+
+/// What the escape analysis found for one function body.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct EscapeReport {
+    /// Why the frame stays in memory: the offset from the stack
+    /// pointer on entry of each escaping address, or `None` if
+    /// unknown, with a description.
+    pub escapes: Vec<(Option<i64>, String)>,
+    /// Offsets of the fields promoted to SSA values.
+    pub promoted: Vec<i64>,
+    /// Whether all shadow-stack operations were removed.
+    pub frame_removed: bool,
+    /// Offset from the stack pointer on entry of each stack-derived
+    /// value, if constant.
+    pub offsets: HashMap<Value, Option<i64>>,
+}
+
+impl EscapeReport {
+    /// Check that the address `ptr` does not escape, returning the
+    /// reasons it does otherwise.
+    pub(crate) fn check_not_escaped(&self, ptr: Value) -> Result<(), String> {
+        if self.frame_removed {
+            return Ok(());
+        }
+        let offset = match self.offsets.get(&ptr) {
+            None => return Err(format!("{} is not derived from the stack pointer", ptr)),
+            Some(None) => return Err(format!("{} is at an unknown offset in the frame", ptr)),
+            Some(Some(offset)) => *offset,
+        };
+        // Any escaping address may be used to reach this one.
+        let reasons = self
+            .escapes
+            .iter()
+            .map(|(_, reason)| reason.as_str())
+            .collect::<Vec<_>>();
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} (offset {}) escapes: {}",
+                ptr,
+                offset,
+                reasons.join("; ")
+            ))
+        }
+    }
+}
+
+impl std::fmt::Display for EscapeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.frame_removed {
+            writeln!(f, "frame removed")?;
+        }
+        writeln!(f, "promoted fields: {:?}", self.promoted)?;
+        for (offset, reason) in &self.escapes {
+            match offset {
+                Some(offset) => writeln!(f, "escape at offset {}: {}", offset, reason)?,
+                None => writeln!(f, "escape at unknown offset: {}", reason)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Size of the access made by a load or store, for those we can
+/// summarize.
+pub(crate) fn access_size(op: &Operator) -> Option<i64> {
+    match op {
+        Operator::I32Load8S { .. }
+        | Operator::I32Load8U { .. }
+        | Operator::I64Load8S { .. }
+        | Operator::I64Load8U { .. }
+        | Operator::I32Store8 { .. }
+        | Operator::I64Store8 { .. } => Some(1),
+        Operator::I32Load16S { .. }
+        | Operator::I32Load16U { .. }
+        | Operator::I64Load16S { .. }
+        | Operator::I64Load16U { .. }
+        | Operator::I32Store16 { .. }
+        | Operator::I64Store16 { .. } => Some(2),
+        Operator::I32Load { .. }
+        | Operator::F32Load { .. }
+        | Operator::I64Load32S { .. }
+        | Operator::I64Load32U { .. }
+        | Operator::I32Store { .. }
+        | Operator::F32Store { .. }
+        | Operator::I64Store32 { .. } => Some(4),
+        Operator::I64Load { .. }
+        | Operator::F64Load { .. }
+        | Operator::I64Store { .. }
+        | Operator::F64Store { .. } => Some(8),
+        Operator::V128Load { .. } | Operator::V128Store { .. } => Some(16),
+        _ => None,
+    }
+}
+
+fn summarize_arg(body: &FunctionBody, cfg: &CFGInfo, param: Value) -> ArgSummary {
+    // Offset from the argument of each value derived from it.
+    let mut offsets: HashMap<Value, i64> = HashMap::new();
+    offsets.insert(param, 0);
+    let mut range: Option<(i64, i64)> = None;
+
+    let const_arg = |value: Value| match &body.values[body.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(i64::from(*value as i32)),
+        _ => None,
+    };
+
+    for &block in cfg.rpo.values() {
+        for &inst in &body.blocks[block].insts {
+            let (op, args) = match &body.values[inst] {
+                ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+                ValueDef::Alias(val) => {
+                    if let Some(&offset) = offsets.get(val) {
+                        offsets.insert(inst, offset);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            let tainted = args
+                .iter()
+                .map(|arg| offsets.get(arg).copied())
+                .collect::<Vec<_>>();
+            if tainted.iter().all(|offset| offset.is_none()) {
+                continue;
+            }
+            match (op, &tainted[..]) {
+                (Operator::I32Add, &[Some(base), None])
+                | (Operator::I32Sub, &[Some(base), None]) => {
+                    let Some(k) = const_arg(args[1]) else {
+                        return ArgSummary::Captured;
+                    };
+                    let offset = match op {
+                        Operator::I32Add => base + k,
+                        _ => base - k,
+                    };
+                    offsets.insert(inst, offset);
+                }
+                (Operator::I32Add, &[None, Some(base)]) => {
+                    let Some(k) = const_arg(args[0]) else {
+                        return ArgSummary::Captured;
+                    };
+                    offsets.insert(inst, base + k);
+                }
+                (op, tainted) if op.is_load() || op.is_store() => {
+                    // Only as the address, never as a stored value.
+                    let (Some(base), Some(size)) = (tainted[0], access_size(op)) else {
+                        return ArgSummary::Captured;
+                    };
+                    if tainted[1..].iter().any(|offset| offset.is_some()) {
+                        return ArgSummary::Captured;
+                    }
+                    let mut memory = None;
+                    op.clone().update_memory_arg(|arg| memory = Some(*arg));
+                    let memory = memory.unwrap();
+                    if memory.memory.index() != 0 {
+                        return ArgSummary::Captured;
+                    }
+                    let lo = base + i64::from(memory.offset);
+                    let hi = lo + size;
+                    range = Some(match range {
+                        Some((min, max)) => (std::cmp::min(min, lo), std::cmp::max(max, hi)),
+                        None => (lo, hi),
+                    });
+                }
+                _ => return ArgSummary::Captured,
+            }
+        }
+
+        let mut captured = false;
+        let term = &body.blocks[block].terminator;
+        match term {
+            Terminator::CondBr { cond, .. } | Terminator::Select { value: cond, .. } => {
+                captured |= offsets.contains_key(cond);
+            }
+            Terminator::Return { values } => {
+                captured |= values.iter().any(|value| offsets.contains_key(value));
+            }
+            _ => {}
+        }
+        term.visit_targets(|target| {
+            captured |= target.args.iter().any(|arg| offsets.contains_key(arg));
+        });
+        if captured {
+            return ArgSummary::Captured;
+        }
+    }
+
+    match range {
+        Some((lo, hi)) => ArgSummary::Accessed(lo, hi),
+        None => ArgSummary::Unused,
+    }
+}
+
+/// Compute escape summaries for the module's small functions, and
+/// its pure imports (which use no pointer).
+pub(crate) fn summarize(module: &Module, pure_imports: &FxHashSet<Func>) -> EscapeSummaries {
+    let mut summaries: EscapeSummaries = module
+        .funcs
+        .entries()
+        .filter_map(|(func, decl)| {
+            match decl {
+                FuncDecl::Lazy(_, _, body) if body.range().len() <= MAX_SUMMARY_BODY_SIZE => {}
+                _ => return None,
+            }
+            let body = module.clone_and_expand_body(func).ok()?;
+            let cfg = CFGInfo::new(&body);
+            let summary = body.blocks[body.entry]
+                .params
+                .iter()
+                .map(|&(ty, param)| match ty {
+                    Type::I32 => summarize_arg(&body, &cfg, param),
+                    _ => ArgSummary::Unused,
+                })
+                .collect::<Vec<_>>();
+            tracing::trace!("escape summary for {}: {:?}", func, summary);
+            Some((func, summary))
+        })
+        .collect();
+    for &func in pure_imports {
+        let params = module.signatures[module.funcs[func].sig()].params.len();
+        summaries.insert(func, vec![ArgSummary::Unused; params]);
+    }
+    summaries
+}
+
+/// A plain full-width load or store: whether it is a store, the value
+/// type, and the memory argument.
+/// A plain load or store: whether it is a store, the type of the
+/// value loaded or stored, and the access's width in bytes.
+fn plain_access(op: &Operator) -> Option<(bool, Type, i64, &MemoryArg)> {
+    let (is_store, ty, memory) = match op {
+        Operator::I32Load { memory }
+        | Operator::I32Load8S { memory }
+        | Operator::I32Load8U { memory }
+        | Operator::I32Load16S { memory }
+        | Operator::I32Load16U { memory } => (false, Type::I32, memory),
+        Operator::I64Load { memory }
+        | Operator::I64Load8S { memory }
+        | Operator::I64Load8U { memory }
+        | Operator::I64Load16S { memory }
+        | Operator::I64Load16U { memory }
+        | Operator::I64Load32S { memory }
+        | Operator::I64Load32U { memory } => (false, Type::I64, memory),
+        Operator::F32Load { memory } => (false, Type::F32, memory),
+        Operator::F64Load { memory } => (false, Type::F64, memory),
+        Operator::I32Store { memory }
+        | Operator::I32Store8 { memory }
+        | Operator::I32Store16 { memory } => (true, Type::I32, memory),
+        Operator::I64Store { memory }
+        | Operator::I64Store8 { memory }
+        | Operator::I64Store16 { memory }
+        | Operator::I64Store32 { memory } => (true, Type::I64, memory),
+        Operator::F32Store { memory } => (true, Type::F32, memory),
+        Operator::F64Store { memory } => (true, Type::F64, memory),
+        _ => return None,
+    };
+    Some((is_store, ty, access_size(op)?, memory))
+}
+
+/// How a narrow load of a promoted field computes its result from the
+/// value last stored to the field: an operator applied to that value,
+/// and a constant second operand for it, if any.
+fn narrow_load(op: &Operator) -> Option<(Operator, Option<Operator>)> {
+    Some(match op {
+        Operator::I32Load8S { .. } => (Operator::I32Extend8S, None),
+        Operator::I32Load16S { .. } => (Operator::I32Extend16S, None),
+        Operator::I32Load8U { .. } => (Operator::I32And, Some(Operator::I32Const { value: 0xff })),
+        Operator::I32Load16U { .. } => {
+            (Operator::I32And, Some(Operator::I32Const { value: 0xffff }))
+        }
+        Operator::I64Load8S { .. } => (Operator::I64Extend8S, None),
+        Operator::I64Load16S { .. } => (Operator::I64Extend16S, None),
+        Operator::I64Load32S { .. } => (Operator::I64Extend32S, None),
+        Operator::I64Load8U { .. } => (Operator::I64And, Some(Operator::I64Const { value: 0xff })),
+        Operator::I64Load16U { .. } => {
+            (Operator::I64And, Some(Operator::I64Const { value: 0xffff }))
+        }
+        Operator::I64Load32U { .. } => (
+            Operator::I64And,
+            Some(Operator::I64Const { value: 0xffff_ffff }),
+        ),
+        _ => return None,
+    })
+}
+
+fn zero_operator(ty: Type) -> Operator {
+    match ty {
+        Type::I32 => Operator::I32Const { value: 0 },
+        Type::I64 => Operator::I64Const { value: 0 },
+        Type::F32 => Operator::F32Const { value: 0 },
+        Type::F64 => Operator::F64Const { value: 0 },
+        _ => unreachable!(),
+    }
+}
+
+/// Result of the per-field analysis of the shadow-stack frame.
+struct Fields {
+    /// Promotable fields: offset from the stack pointer on entry,
+    /// type, and width in bytes.
+    fields: Vec<(i64, Type, i64)>,
+    /// Loads and stores of promotable fields, with the field index.
+    accesses: HashMap<Value, usize>,
+    /// Per block, the fields (as a bitmask) stored on every path to
+    /// the block's entry.
+    init: PerEntity<Block, u64>,
+}
+
+/// Find the fields of the shadow-stack frame that can be promoted.
+///
+/// We handle only a single read of the stack pointer, with constant
+/// offsets from it; any other arithmetic on a stack address (e.g.
+/// aligning it) gives an unknown offset, and using such an address
+/// gives up on the frame. A field must lie within the frame (between
+/// the lowest value set into the stack pointer and its value on
+/// entry), must not overlap any access of another type or width, and
+/// must be stored before it is loaded on every path. An escaping
+/// address (passed to a call, stored to memory, returned, etc.) may be
+/// used to access anything in the frame, above or below it, so it
+/// prevents promotion of every field.
+fn promotable_fields(
+    func: &FunctionBody,
+    cfg: &CFGInfo,
+    summaries: &EscapeSummaries,
+    report: &mut EscapeReport,
+) -> Option<Fields> {
+    let EscapeReport {
+        escapes, offsets, ..
+    } = report;
+    // Blockparams that also receive non-stack-derived values.
+    let mut mixed: HashSet<Value> = HashSet::new();
+    let mut roots = 0;
+    let mut sp_min: Option<i64> = None;
+    // (inst, block, offset, store?, type, width).
+    let mut accesses = vec![];
+    // Ranges accessed by callees through pointers they don't capture.
+    let mut callee_accesses: Vec<(i64, i64)> = vec![];
+
+    let const_arg = |value: Value| match &func.values[func.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(i64::from(*value as i32)),
+        _ => None,
+    };
+
+    for (block_rpo, &block) in cfg.rpo.entries() {
+        for &(_, param) in &func.blocks[block].params {
+            if mixed.contains(&param) {
+                if let Some(offset) = offsets.get_mut(&param) {
+                    *offset = None;
+                }
+            }
+        }
+
+        for &inst in &func.blocks[block].insts {
+            let (op, args) = match &func.values[inst] {
+                ValueDef::Operator(op, args, _) => (op, &func.arg_pool[*args]),
+                &ValueDef::PickOutput(val, _, _) | &ValueDef::Alias(val) => {
+                    if let Some(&offset) = offsets.get(&val) {
+                        offsets.insert(inst, offset);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            match op {
+                &Operator::GlobalGet { global_index } if global_index.index() == 0 => {
+                    roots += 1;
+                    offsets.insert(inst, Some(0));
+                    continue;
+                }
+                _ => {}
+            }
+            if !args.iter().any(|arg| offsets.contains_key(arg)) {
+                continue;
+            }
+            let offset = |i: usize| offsets.get(&args[i]).copied();
+            match op {
+                Operator::I32Add | Operator::I32Sub => {
+                    let result = match (offset(0), offset(1)) {
+                        (Some(Some(base)), None) => const_arg(args[1]).map(|k| match op {
+                            Operator::I32Add => base + k,
+                            _ => base - k,
+                        }),
+                        (None, Some(Some(base))) if *op == Operator::I32Add => {
+                            const_arg(args[0]).map(|k| base + k)
+                        }
+                        _ => None,
+                    };
+                    offsets.insert(inst, result);
+                }
+                &Operator::GlobalSet { global_index } if global_index.index() == 0 => {
+                    let Some(Some(new_sp)) = offset(0) else {
+                        escapes.push((
+                            None,
+                            format!("{}: stack pointer set to unknown value", inst),
+                        ));
+                        return None;
+                    };
+                    sp_min = Some(std::cmp::min(sp_min.unwrap_or(0), new_sp));
+                }
+                op => match plain_access(op) {
+                    Some((is_store, ty, width, memory))
+                        if memory.memory.index() == 0 && offset(0).is_some() =>
+                    {
+                        let Some(Some(addr)) = offset(0) else {
+                            escapes.push((None, format!("{}: access at unknown offset", inst)));
+                            return None;
+                        };
+                        accesses.push((
+                            inst,
+                            block,
+                            addr + i64::from(memory.offset),
+                            is_store,
+                            ty,
+                            width,
+                        ));
+                        if is_store {
+                            if let Some(value) = offset(1) {
+                                escapes
+                                    .push((value, format!("{}: address stored to memory", inst)));
+                                value?;
+                            }
+                        }
+                    }
+                    _ if op.is_pure() => {
+                        // Derived from a stack address, but not at a
+                        // known offset: any use of it gives up.
+                        offsets.insert(inst, None);
+                    }
+                    _ => {
+                        let summary = match op {
+                            Operator::Call { function_index } => summaries.get(function_index),
+                            _ => None,
+                        };
+                        let reason = match op {
+                            Operator::Call { function_index } => {
+                                format!(
+                                    "{}: passed to {}, which may capture it",
+                                    inst, function_index
+                                )
+                            }
+                            op => format!("{}: used by {:?}", inst, op),
+                        };
+                        for i in 0..args.len() {
+                            if let Some(value) = offset(i) {
+                                match summary.map(|summary| summary[i]) {
+                                    Some(ArgSummary::Unused) => continue,
+                                    Some(ArgSummary::Accessed(lo, hi)) => {
+                                        let Some(value) = value else {
+                                            escapes.push((
+                                                None,
+                                                format!("{}: passed at unknown offset", inst),
+                                            ));
+                                            return None;
+                                        };
+                                        callee_accesses.push((value + lo, value + hi));
+                                        continue;
+                                    }
+                                    _ => {}
+                                }
+                                tracing::trace!(
+                                    "field at {:?} escapes due to inst {}",
+                                    value,
+                                    inst
+                                );
+                                escapes.push((value, reason.clone()));
+                                value?;
+                            }
+                        }
+                    }
+                },
+            }
+        }
+
+        let mut term_escapes = |value: &Value, reason: &str| -> Option<()> {
+            if let Some(&offset) = offsets.get(value) {
+                escapes.push((offset, format!("{} in {}", reason, block)));
+                offset?;
+            }
+            Some(())
+        };
+        match &func.blocks[block].terminator {
+            Terminator::CondBr { cond, .. } | Terminator::Select { value: cond, .. } => {
+                term_escapes(cond, "used as branch condition")?;
+            }
+            Terminator::Return { values } => {
+                for value in values {
+                    term_escapes(value, "returned")?;
+                }
+            }
+            _ => {}
+        }
+
+        let mut backedge = None;
+        func.blocks[block].terminator.visit_targets(|target| {
+            for (arg, &(_, param)) in target
+                .args
+                .iter()
+                .zip(func.blocks[target.block].params.iter())
+            {
+                match offsets.get(arg).copied() {
+                    Some(offset) => {
+                        let target_rpo = cfg.rpo_pos[target.block].unwrap();
+                        if target_rpo.index() <= block_rpo.index() {
+                            backedge = Some(target.block);
+                        }
+                        let merged = match offsets.get(&param) {
+                            Some(&prev) if prev != offset => None,
+                            _ => offset,
+                        };
+                        offsets.insert(param, merged);
+                    }
+                    None => {
+                        mixed.insert(param);
+                    }
+                }
+            }
+        });
+        if let Some(target) = backedge {
+            escapes.push((
+                None,
+                format!("carried around loop backedge from {} to {}", block, target),
+            ));
+            return None;
+        }
+    }
+
+    if roots > 1 {
+        escapes.push((None, format!("stack pointer read {} times", roots)));
+    }
+    if roots != 1 || !escapes.is_empty() {
+        return None;
+    }
+    let sp_min = sp_min?;
+
+    // Group accesses by offset, and keep those fields accessed
+    // uniformly, within the frame, and not overlapping any other
+    // access.
+    // Per offset, the uniform (type, width) if any, and the largest
+    // width accessed.
+    let mut shapes: BTreeMap<i64, (Option<(Type, i64)>, i64)> = BTreeMap::new();
+    for &(_, _, offset, _, ty, width) in &accesses {
+        let (shape, max_width) = shapes.entry(offset).or_insert((Some((ty, width)), width));
+        if *shape != Some((ty, width)) {
+            *shape = None;
+        }
+        *max_width = std::cmp::max(*max_width, width);
+    }
+    let mut fields = vec![];
+    let mut prev_end = i64::MIN;
+    let ranges = shapes
+        .iter()
+        .map(|(&offset, &(_, max_width))| (offset, offset + max_width))
+        .collect::<Vec<_>>();
+    for (i, (&offset, &(shape, _))) in shapes.iter().enumerate() {
+        let end = ranges[i].1;
+        let overlaps_next = ranges.get(i + 1).is_some_and(|&(next, _)| next < end);
+        let overlaps_prev = prev_end > offset;
+        let overlaps_callee = callee_accesses
+            .iter()
+            .any(|&(lo, hi)| lo < end && offset < hi);
+        prev_end = std::cmp::max(prev_end, end);
+        match shape {
+            Some((ty, width))
+                if !overlaps_prev
+                    && !overlaps_next
+                    && !overlaps_callee
+                    && offset >= sp_min
+                    && end <= 0
+                    && fields.len() < MAX_PROMOTED_FIELDS =>
+            {
+                fields.push((offset, ty, width));
+            }
+            _ => {}
+        }
+    }
+    if fields.is_empty() {
+        return None;
+    }
+    let field_index = fields
+        .iter()
+        .enumerate()
+        .map(|(i, &(offset, _, _))| (offset, i))
+        .collect::<HashMap<_, _>>();
+    let mut field_accesses = accesses
+        .iter()
+        .filter_map(|&(inst, _, offset, _, _, _)| Some((inst, *field_index.get(&offset)?)))
+        .collect::<HashMap<_, _>>();
+
+    // Forward must-analysis: which fields are stored on every path
+    // to each block?
+    let mut stored: PerEntity<Block, u64> = PerEntity::default();
+    for &(inst, block, _, is_store, _, _) in &accesses {
+        if let (true, Some(&field)) = (is_store, field_accesses.get(&inst)) {
+            stored[block] |= 1 << field;
+        }
+    }
+    let mut init: PerEntity<Block, u64> = PerEntity::default();
+    for &block in cfg.rpo.values() {
+        if block != func.entry {
+            init[block] = u64::MAX;
+        }
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in cfg.rpo.values().skip(1) {
+            let new = cfg.preds[block]
+                .iter()
+                .filter(|pred| cfg.rpo_pos[**pred].is_some())
+                .fold(u64::MAX, |acc, &pred| acc & (init[pred] | stored[pred]));
+            changed |= new != init[block];
+            init[block] = new;
+        }
+    }
+
+    // Drop fields that may be loaded before being stored.
+    let mut uninit_loads = 0u64;
+    for &block in cfg.rpo.values() {
+        let mut cur = init[block];
+        for inst in &func.blocks[block].insts {
+            if let Some(&field) = field_accesses.get(inst) {
+                if accesses_store(func, *inst) {
+                    cur |= 1 << field;
+                } else if cur & (1 << field) == 0 {
+                    uninit_loads |= 1 << field;
+                }
+            }
+        }
+    }
+    field_accesses.retain(|_, field| uninit_loads & (1 << *field) == 0);
+
+    Some(Fields {
+        fields,
+        accesses: field_accesses,
+        init,
+    })
+}
+
+fn accesses_store(func: &FunctionBody, inst: Value) -> bool {
+    match &func.values[inst] {
+        ValueDef::Operator(op, _, _) => {
+            plain_access(op).is_some_and(|(is_store, _, _, _)| is_store)
+        }
+        _ => false,
+    }
+}
+
+/// Promote non-escaping fields of the shadow-stack frame to SSA
+/// values: loads become aliases of the last stored value (or, for
+/// narrow loads, its mask or sign-extension), stores are removed, and
+/// blocks with several predecessors get a blockparam per field
+/// (redundant ones are cleaned up by later optimization).
+fn promote_fields(
+    func: &mut FunctionBody,
+    cfg: &CFGInfo,
+    summaries: &EscapeSummaries,
+    report: &mut EscapeReport,
+) {
+    let Some(Fields {
+        fields,
+        accesses,
+        init,
+    }) = promotable_fields(func, cfg, summaries, report)
+    else {
+        return;
+    };
+    let promoted = accesses
+        .values()
+        .fold(0u64, |acc, field| acc | (1 << field));
+    report.promoted = fields
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| promoted & (1 << i) != 0)
+        .map(|(_, &(offset, _, _))| offset)
+        .collect();
+    tracing::trace!(
+        "promoting shadow-stack fields {:?} (mask {:#x})",
+        fields,
+        promoted
+    );
+
+    // Placeholder values for fields not yet stored; never read.
+    let mut undef: HashMap<Type, Value> = HashMap::new();
+    for &(_, ty, _) in &fields {
+        if let std::collections::hash_map::Entry::Vacant(v) = undef.entry(ty) {
+            let tys = func.single_type_list(ty);
+            let value = func.add_value(ValueDef::Operator(
+                zero_operator(ty),
+                ListRef::default(),
+                tys,
+            ));
+            func.blocks[func.entry].insts.insert(0, value);
+            v.insert(value);
+        }
+    }
+    let undef_values = fields
+        .iter()
+        .map(|(_, ty, _)| undef[ty])
+        .collect::<Vec<_>>();
+
+    let mut params: PerEntity<Block, Vec<(usize, Value)>> = PerEntity::default();
+    let mut exits: PerEntity<Block, Vec<Value>> = PerEntity::default();
+    for &block in cfg.rpo.values() {
+        let preds = &cfg.preds[block];
+        let single_pred = (preds.len() == 1 && cfg.rpo_pos[preds[0]].is_some()).then(|| preds[0]);
+        let mut cur = undef_values.clone();
+        for (field, &(_, ty, _)) in fields.iter().enumerate() {
+            if block == func.entry || promoted & init[block] & (1 << field) == 0 {
+                continue;
+            }
+            cur[field] = match single_pred {
+                Some(pred) => exits[pred][field],
+                None => {
+                    let param = func.add_blockparam(block, ty);
+                    params[block].push((field, param));
+                    param
+                }
+            };
+        }
+
+        let insts = std::mem::take(&mut func.blocks[block].insts);
+        let mut new_insts = Vec::with_capacity(insts.len());
+        for inst in insts {
+            match accesses.get(&inst) {
+                Some(&field) if accesses_store(func, inst) => {
+                    let ValueDef::Operator(_, args, _) = &func.values[inst] else {
+                        unreachable!()
+                    };
+                    cur[field] = func.arg_pool[*args][1];
+                }
+                Some(&field) => {
+                    let ValueDef::Operator(op, _, tys) = func.values[inst] else {
+                        unreachable!()
+                    };
+                    func.values[inst] = match narrow_load(&op) {
+                        Some((op, None)) => {
+                            let args = func.arg_pool.single(cur[field]);
+                            ValueDef::Operator(op, args, tys)
+                        }
+                        Some((op, Some(mask))) => {
+                            let mask =
+                                func.add_value(ValueDef::Operator(mask, ListRef::default(), tys));
+                            new_insts.push(mask);
+                            let args = func.arg_pool.double(cur[field], mask);
+                            ValueDef::Operator(op, args, tys)
+                        }
+                        None => ValueDef::Alias(cur[field]),
+                    };
+                    if !matches!(func.values[inst], ValueDef::Alias(_)) {
+                        new_insts.push(inst);
+                    }
+                }
+                None => new_insts.push(inst),
+            }
+        }
+        func.blocks[block].insts = new_insts;
+        exits[block] = cur;
+    }
+
+    for block in func.blocks.iter() {
+        let exit = if cfg.rpo_pos[block].is_some() {
+            std::mem::take(&mut exits[block])
+        } else {
+            undef_values.clone()
+        };
+        let mut terminator = std::mem::take(&mut func.blocks[block].terminator);
+        terminator.update_targets(|target| {
+            for &(field, _) in &params[target.block] {
+                target.args.push(exit[field]);
+            }
+        });
+        func.blocks[block].terminator = terminator;
+    }
+}
 
 enum EscapeAnalysisResult {
     Escapes,
@@ -28,28 +851,28 @@ fn shadow_stack_escapes(func: &FunctionBody, cfg: &CFGInfo) -> EscapeAnalysisRes
                 | &ValueDef::Operator(Operator::GlobalSet { global_index }, _, _)
                     if global_index.index() == 0 =>
                 {
-                    log::trace!("tainted because global.get/set: {}", inst);
+                    tracing::trace!("tainted because global.get/set: {}", inst);
                     tainted.insert(inst);
                 }
                 &ValueDef::Operator(Operator::I32Add, args, _)
                 | &ValueDef::Operator(Operator::I32Sub, args, _) => {
                     let args = &func.arg_pool[args];
                     if args.iter().any(|arg| tainted.contains(arg)) {
-                        log::trace!("tainted because of arg: {}", inst);
+                        tracing::trace!("tainted because of arg: {}", inst);
                         tainted.insert(inst);
                     }
                 }
                 &ValueDef::Operator(_, args, _) => {
                     let args = &func.arg_pool[args];
                     if args.iter().any(|arg| tainted.contains(arg)) {
-                        log::trace!("shadow stack escape due to inst {}", inst);
+                        tracing::trace!("shadow stack escape due to inst {}", inst);
                         return EscapeAnalysisResult::Escapes;
                     }
                 }
                 &ValueDef::PickOutput(val, _, _) | &ValueDef::Alias(val)
                     if tainted.contains(&val) =>
                 {
-                    log::trace!(
+                    tracing::trace!(
                         "taint on {} propagates to {} because of alias or pick",
                         val,
                         inst
@@ -63,7 +886,7 @@ fn shadow_stack_escapes(func: &FunctionBody, cfg: &CFGInfo) -> EscapeAnalysisRes
         match &func.blocks[block].terminator {
             &Terminator::CondBr { cond, .. } | &Terminator::Select { value: cond, .. } => {
                 if tainted.contains(&cond) {
-                    log::trace!(
+                    tracing::trace!(
                         "taint on input to conditional branch causes escape: {}",
                         cond
                     );
@@ -72,7 +895,7 @@ fn shadow_stack_escapes(func: &FunctionBody, cfg: &CFGInfo) -> EscapeAnalysisRes
             }
             &Terminator::Return { ref values } => {
                 if values.iter().any(|v| tainted.contains(v)) {
-                    log::trace!("taint on return value causes escape");
+                    tracing::trace!("taint on return value causes escape");
                     return EscapeAnalysisResult::Escapes;
                 }
             }
@@ -88,7 +911,7 @@ fn shadow_stack_escapes(func: &FunctionBody, cfg: &CFGInfo) -> EscapeAnalysisRes
                 if tainted.contains(arg) {
                     let target_rpo = cfg.rpo_pos[target.block].unwrap();
                     if target_rpo.index() <= block_rpo.index() {
-                        log::trace!(
+                        tracing::trace!(
                             "taint traveling on backedge from {} to {} ({} to {}) causes escape",
                             arg,
                             param,
@@ -109,9 +932,30 @@ fn shadow_stack_escapes(func: &FunctionBody, cfg: &CFGInfo) -> EscapeAnalysisRes
     EscapeAnalysisResult::NonEscaping(tainted)
 }
 
-pub(crate) fn remove_shadow_stack_if_non_escaping(func: &mut FunctionBody, cfg: &CFGInfo) {
+/// Run the analysis alone, leaving the frame in memory, for
+/// `--preserve-traps`: removing its loads and stores would remove
+/// their traps.
+pub(crate) fn analyze(
+    func: &FunctionBody,
+    cfg: &CFGInfo,
+    summaries: &EscapeSummaries,
+) -> EscapeReport {
+    let mut report = EscapeReport::default();
+    promotable_fields(func, cfg, summaries, &mut report);
+    tracing::debug!("escape analysis:\n{}", report);
+    report
+}
+
+pub(crate) fn remove_shadow_stack_if_non_escaping(
+    func: &mut FunctionBody,
+    cfg: &CFGInfo,
+    summaries: &EscapeSummaries,
+) -> EscapeReport {
+    let mut report = EscapeReport::default();
+    promote_fields(func, cfg, summaries, &mut report);
     if let EscapeAnalysisResult::NonEscaping(values_to_remove) = shadow_stack_escapes(func, &cfg) {
-        log::trace!("removing shadow stack operations: {:?}", values_to_remove);
+        tracing::trace!("removing shadow stack operations: {:?}", values_to_remove);
+        report.frame_removed = true;
         let ty_u32 = func.type_pool.single(Type::I32);
         let const_zero = func.values.push(ValueDef::Operator(
             Operator::I32Const { value: 0 },
@@ -131,4 +975,6 @@ pub(crate) fn remove_shadow_stack_if_non_escaping(func: &mut FunctionBody, cfg:
             });
         }
     }
+    tracing::debug!("escape analysis:\n{}", report);
+    report
 }
@@ -1,25 +1,37 @@
 //! Partial evaluation.
 
 use crate::cache::{Cache, CacheData};
+use crate::checkpoint::Checkpoint;
+use crate::const_eval;
 use crate::directive::{Directive, DirectiveArgs};
+use crate::emit_facts::{FactsOutput, Relations};
+use crate::error::WevalError;
+use crate::hooks::FunctionPass;
 use crate::image::Image;
 use crate::intrinsics::{find_global_data_by_exported_func, Intrinsics};
-use crate::liveness::Liveness;
+use crate::liveness::{LiveRegs, Liveness, PressureHint};
+use crate::pc_profile::HotPcs;
+use crate::progress::{DirectiveLine, Progress};
 use crate::state::*;
-use crate::stats::SpecializationStats;
-use crate::value::{AbstractValue, WasmVal};
+use crate::stats::{DirectiveOutcome, DirectiveResult, SpecializationStats, SpecializedFuncSize};
+use crate::value::{AbstractValue, ConstOrigin, MemoryBufferIndex, WasmVal};
 use fxhash::FxHashMap as HashMap;
 use fxhash::FxHashSet as HashSet;
 use rayon::prelude::*;
 use std::borrow::Cow;
-use std::collections::{hash_map::Entry as HashEntry, BTreeSet, VecDeque};
-use std::sync::Mutex;
+use std::collections::{hash_map::Entry as HashEntry, BTreeMap, BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use waffle::{
     cfg::CFGInfo, entity::EntityRef, entity::PerEntity, pool::ListRef, Block, BlockDef,
-    BlockTarget, FuncDecl, FunctionBody, Memory, MemoryArg, Module, Operator, Signature, SourceLoc,
-    Table, Terminator, Type, Value, ValueDef,
+    BlockTarget, Func, FuncDecl, FunctionBody, Global, Memory, MemoryArg, Module, Operator,
+    Signature, SourceLoc, Table, Terminator, Type, Value, ValueDef,
 };
 
+/// Per context and original call instruction, the kind of call it
+/// became and its callee, for `--emit-facts`.
+type CallDecisions = HashMap<(Context, Value), (&'static str, Option<Func>)>;
+
 struct Evaluator<'a> {
     /// Module.
     module: &'a Module<'a>,
@@ -31,10 +43,15 @@ struct Evaluator<'a> {
     directive_args: DirectiveArgs,
     /// Intrinsic function indices.
     intrinsics: &'a Intrinsics,
+    /// Facts about the module and the set of directives.
+    facts: &'a ModuleFacts,
     /// Memory image.
     image: &'a Image,
     /// Domtree for function body.
     cfg: &'a CFGInfo,
+    /// Specialization registers live at each block of the original
+    /// function body.
+    live_regs: &'a PerEntity<Block, LiveRegs>,
     /// State of SSA values and program points:
     /// - per context:
     ///   - per SSA number, an abstract value
@@ -60,49 +77,445 @@ struct Evaluator<'a> {
     queue_set: HashSet<(Block, Context)>,
     /// Stats accumulated during specialization.
     stats: SpecializationStats,
+    /// References to other directives' results, to patch at
+    /// emission.
+    symbolic_sites: Vec<SymbolicSite>,
+    /// `weval_assert_not_escaped()` calls, per (specialized block,
+    /// original inst): the specialized pointer and line number.
+    escape_asserts: HashMap<(Block, Value), (Value, u32)>,
+    /// Stores that write the virtual stack and locals back to memory.
+    flush_stores: HashSet<Value>,
+    /// Last state met into a block's entry along each (from, to)
+    /// edge between specialized blocks, so that re-propagation only
+    /// visits what changed since.
+    edge_states: HashMap<(Block, Block), ProgPointState>,
+    /// This directive's share of the memory budget, if there is one.
+    memory: Option<Reservation<'a>>,
+    /// This directive's line in the TUI, if there is one.
+    progress_line: Option<&'a DirectiveLine>,
+    /// How many contexts to create before continuing in generic
+    /// dispatch at new PCs, if limited.
+    max_contexts: Option<usize>,
+    /// How each call was specialized, if `--emit-facts` wants to know.
+    call_decisions: Option<CallDecisions>,
+    /// The options for the run.
+    options: &'a EvalOptions,
+    /// Original instructions with folds the engine may compute
+    /// differently.
+    nondeterministic_folds: HashSet<Value>,
+    /// In debug builds, the last output of each transfer function,
+    /// to check that re-evaluation only moves it down the lattice:
+    /// the abstract value and origin of each generic value per
+    /// context (and, for a blockparam, per specialized predecessor).
+    last_defs: HashMap<(Context, Value, Option<Block>), (AbstractValue, ConstOrigin)>,
+    /// In debug builds, the last exit state of each generic block
+    /// per context, likewise.
+    last_exits: HashMap<(Context, Block), ProgPointState>,
+}
+
+/// The evaluator's maps and worklists, kept per worker thread and
+/// reused across directives so that their allocations are too. All
+/// are empty between directives.
+#[derive(Default)]
+struct Scratch {
+    block_map: HashMap<(Context, Block), Block>,
+    value_map: HashMap<(Context, Value), Value>,
+    value_dep_blocks: HashMap<(Context, Value), BTreeSet<Block>>,
+    reg_map: HashMap<(Context, Block, RegSlot), Value>,
+    queue: VecDeque<(Block, Context, Block)>,
+    queue_set: HashSet<(Block, Context)>,
+    escape_asserts: HashMap<(Block, Value), (Value, u32)>,
+    flush_stores: HashSet<Value>,
+    edge_states: HashMap<(Block, Block), ProgPointState>,
+}
+
+impl Scratch {
+    /// Take back the buffers of an evaluator that is done, cleared.
+    fn recycle(&mut self, evaluator: &mut Evaluator) {
+        self.block_map = std::mem::take(&mut evaluator.block_map);
+        self.block_map.clear();
+        self.value_map = std::mem::take(&mut evaluator.value_map);
+        self.value_map.clear();
+        self.value_dep_blocks = std::mem::take(&mut evaluator.value_dep_blocks);
+        self.value_dep_blocks.clear();
+        self.reg_map = std::mem::take(&mut evaluator.reg_map);
+        self.reg_map.clear();
+        self.queue = std::mem::take(&mut evaluator.queue);
+        self.queue.clear();
+        self.queue_set = std::mem::take(&mut evaluator.queue_set);
+        self.queue_set.clear();
+        self.escape_asserts = std::mem::take(&mut evaluator.escape_asserts);
+        self.escape_asserts.clear();
+        self.flush_stores = std::mem::take(&mut evaluator.flush_stores);
+        self.flush_stores.clear();
+        self.edge_states = std::mem::take(&mut evaluator.edge_states);
+        self.edge_states.clear();
+    }
+}
+
+/// A ceiling on the estimated memory used by all specializations in
+/// flight at once.
+pub(crate) struct MemoryBudget {
+    limit: usize,
+    in_use: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    fn reserve(&self) -> Reservation<'_> {
+        Reservation {
+            budget: self,
+            bytes: 0,
+        }
+    }
+}
+
+/// One evaluator's share of a `MemoryBudget`, given back when
+/// dropped.
+struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl<'a> Reservation<'a> {
+    /// Resize this share to `bytes`, failing without change if that
+    /// would take the total over the limit.
+    fn resize(&mut self, bytes: usize) -> anyhow::Result<()> {
+        if bytes > self.bytes {
+            let grow = bytes - self.bytes;
+            let total = self.budget.in_use.fetch_add(grow, Ordering::Relaxed) + grow;
+            if total > self.budget.limit {
+                self.budget.in_use.fetch_sub(grow, Ordering::Relaxed);
+                anyhow::bail!(WevalError::BudgetExceeded(format!(
+                    "memory limit exceeded: specialization state of this directive \
+                     reached ~{:.2} GiB, bringing all in-flight specializations to \
+                     ~{:.2} GiB (limit {:.2} GiB); leaving the function generic",
+                    gib(bytes),
+                    gib(total),
+                    gib(self.budget.limit)
+                )));
+            }
+        } else {
+            self.budget
+                .in_use
+                .fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+fn gib(bytes: usize) -> f64 {
+    bytes as f64 / (1u64 << 30) as f64
+}
+
+/// The result of 64-bit arithmetic on a static-memory address: still
+/// one if it is a 32-bit address.
+fn static_memory_64(addr: u64) -> AbstractValue {
+    match u32::try_from(addr) {
+        Ok(addr) => AbstractValue::StaticMemory(addr),
+        Err(_) => AbstractValue::Concrete(WasmVal::I64(addr)),
+    }
+}
+
+/// A static-memory address as a constant of the same width as `other`,
+/// the other operand of a binary operator.
+fn static_memory_const(addr: u32, other: &AbstractValue) -> WasmVal {
+    match other {
+        AbstractValue::Concrete(WasmVal::I64(_)) => WasmVal::I64(u64::from(addr)),
+        _ => WasmVal::I32(addr),
+    }
+}
+
+/// Facts about the whole module and set of directives, computed once
+/// before specializing any function.
+struct ModuleFacts {
+    /// Tables whose contents are never modified after instantiation.
+    const_tables: HashSet<Table>,
+    /// Globals whose image value is their value during
+    /// specialization: immutable, or written only at initialization.
+    const_globals: HashSet<Global>,
+    /// The output address of every directive, with the generic
+    /// function it specializes.
+    specialization_outputs: HashMap<u32, Func>,
+    /// How small functions use pointers passed to them.
+    escape_summaries: crate::escape::EscapeSummaries,
+    /// Small functions that never read memory, and pure imports.
+    memory_free_funcs: HashSet<Func>,
+    /// Imports declared pure.
+    pure_imports: HashSet<Func>,
+    /// Small functions that always return the same constant, such as
+    /// the exit handler of a subroutine-threaded interpreter.
+    const_result_funcs: HashMap<Func, WasmVal>,
+}
+
+/// A place in a specialized function body that refers to the result
+/// of a directive, patched once that result is known during emission.
+/// Until then, the body keeps the original code, which reads the
+/// result from memory at runtime.
+#[derive(Clone, Copy, Debug)]
+enum SymbolicSite {
+    /// A load of the table index produced by the directive with this
+    /// output address; becomes a constant.
+    FuncIndex(Value, u32),
+    /// An indirect call through that table index; becomes a direct
+    /// call.
+    Call(Value, u32),
+}
+
+/// Where and how to write IR and other debugging output.
+#[derive(Clone, Debug)]
+pub(crate) struct IrOutput {
+    /// Directory to write output files into.
+    pub dir: std::path::PathBuf,
+    /// Also write Graphviz DOT files for each directive's context tree
+    /// and specialized CFG.
+    pub dot: bool,
+    /// Only write output for directives on these functions, if any
+    /// are given.
+    pub funcs: Vec<Func>,
+    /// Only write output for directives with these user IDs, if any
+    /// are given.
+    pub user_ids: Vec<u32>,
+    /// Only write the final specialized IR: no generic IR or escape
+    /// reports.
+    pub final_only: bool,
+}
+
+impl IrOutput {
+    /// Whether to write output for `directive`.
+    fn wants(&self, directive: &Directive) -> bool {
+        (self.funcs.is_empty() || self.funcs.contains(&directive.func))
+            && (self.user_ids.is_empty() || self.user_ids.contains(&directive.user_id))
+    }
+}
+
+/// How to specialize, for every directive in a run.
+pub(crate) struct EvalOptions {
+    /// Where to write IR and other debugging output, if anywhere.
+    pub output_ir: Option<IrOutput>,
+    /// Functions whose dispatch loops to detect and specialize per
+    /// PC, with the parameter holding the bytecode.
+    pub auto_dispatch: HashMap<Func, usize>,
+    /// Specialize only the dispatch of those loops, calling outlined
+    /// opcode handlers.
+    pub dispatch_only: bool,
+    /// PCs to specialize dispatch loops for, if not all.
+    pub hot_pcs: Option<HotPcs>,
+    /// Outline code that specializations have in common, if given
+    /// thresholds.
+    pub outline_common: Option<crate::dedup::Options>,
+    /// Refer to other directives' results by their specialized
+    /// functions, as if results never change once written.
+    pub link_results: bool,
+    /// Call specializations directly once results are known.
+    pub devirtualize_results: bool,
+    /// Abandon specializations growing more than this many times
+    /// larger than their generic function.
+    pub max_growth: Option<f64>,
+    /// Skip cleanups and limit contexts, for quick builds.
+    pub fast: bool,
+    /// Where to gather the evaluator's results as Datalog facts.
+    pub facts_output: Option<FactsOutput>,
+    /// Imports declared pure.
+    pub pure_imports: HashSet<Func>,
+    /// The input's start function, if Wizening ran it and removed the
+    /// start section, so that it has already run in the snapshot.
+    pub wizened_start: Option<Func>,
+    /// Check IR invariants after every pass.
+    pub verify: bool,
+    /// Keep operators that may trap.
+    pub preserve_traps: bool,
+    /// Leave folds the engine may compute differently to the engine.
+    pub deterministic_folds: bool,
+    /// The memory budget shared by directives in flight, if any.
+    pub memory: Option<MemoryBudget>,
+    /// Library users' passes over each specialized function.
+    pub function_passes: Vec<Arc<dyn FunctionPass>>,
+}
+
+/// What the specialization of every directive reads.
+struct Shared<'a> {
+    module: &'a Module<'a>,
+    image: &'a Image,
+    intrinsics: &'a Intrinsics,
+    facts: &'a ModuleFacts,
+    options: &'a EvalOptions,
+}
+
+/// A generic function prepared for specialization, and the stats of
+/// its specializations.
+struct Generic {
+    body: FunctionBody,
+    cfg: CFGInfo,
+    live_regs: PerEntity<Block, LiveRegs>,
+    stats: Mutex<SpecializationStats>,
+}
+
+/// One debugging-output file for a specialized function, written as
+/// `{kind}_{generic}_to_{specialized}.{ext}` in the output directory.
+struct IrDump {
+    kind: &'static str,
+    ext: &'static str,
+    contents: String,
 }
 
 pub(crate) struct PartialEvalResult<'a> {
     pub module: Module<'a>,
     pub global_base: usize,
     pub stats: Vec<SpecializationStats>,
+    pub sizes: Vec<SpecializedFuncSize>,
+    pub outcomes: Vec<DirectiveOutcome>,
 }
 
 /// Partially evaluates according to the given directives. Returns
 /// clone of original module, with tracing added.
+#[tracing::instrument(skip_all, fields(directives = directives.len()))]
 pub(crate) fn partially_evaluate<'a>(
     mut module: Module<'a>,
     im: &mut Image,
     directives: &[Directive],
-    mut progress: Option<indicatif::ProgressBar>,
-    output_ir: Option<std::path::PathBuf>,
+    progress: Option<Progress>,
+    options: &EvalOptions,
     cache: &Cache,
+    checkpoint: Option<&Checkpoint>,
 ) -> anyhow::Result<PartialEvalResult<'a>> {
-    let intrinsics = Intrinsics::find(&module);
-    log::trace!("intrinsics: {:?}", intrinsics);
+    let EvalOptions {
+        ref output_ir,
+        ref auto_dispatch,
+        dispatch_only,
+        ref hot_pcs,
+        outline_common,
+        link_results,
+        devirtualize_results,
+        fast,
+        ref pure_imports,
+        verify,
+        ..
+    } = *options;
+    let mut intrinsics = Intrinsics::find(&module);
+    if !auto_dispatch.is_empty() {
+        crate::dispatch::add_placeholder_intrinsics(&mut intrinsics);
+    }
+    tracing::trace!("intrinsics: {:?}", intrinsics);
+
+    // Functions whose dispatch loops we instrument, and all functions
+    // under a PC profile, specialize differently, so their results are
+    // cached apart.
+    let hot_pcs_key = hot_pcs.as_ref().map(HotPcs::fingerprint);
+    let cache_key = |directive: &Directive| -> anyhow::Result<Vec<u8>> {
+        let mut key = bincode::serialize(directive)?;
+        if let Some(&param) = auto_dispatch.get(&directive.func) {
+            key.extend_from_slice(b"auto-dispatch");
+            key.extend_from_slice(&(param as u32).to_le_bytes());
+            if dispatch_only {
+                key.extend_from_slice(b"dispatch-only");
+            }
+        }
+        if let Some(hot_pcs) = &hot_pcs_key {
+            key.extend_from_slice(b"hot-pcs");
+            key.extend_from_slice(hot_pcs);
+        }
+        if fast {
+            key.extend_from_slice(b"fast");
+        }
+        if link_results {
+            key.extend_from_slice(b"link-results");
+        }
+        Ok(key)
+    };
+
+    // Outline the handlers of every `--auto-dispatch` function, named
+    // in this run's directives or not, so that the handlers' indices
+    // do not depend on which directives the cache fulfills.
+    let mut outlined = HashMap::default();
+    if dispatch_only {
+        let mut funcs = auto_dispatch.iter().collect::<Vec<_>>();
+        funcs.sort();
+        for (&func, &param) in funcs {
+            let mut f = module.clone_and_expand_body(func)?;
+            let n = crate::outline::outline_handlers(&mut module, func, &mut f, param);
+            tracing::info!("{}: outlined {} handlers", func, n);
+            if n == 0 {
+                crate::policy::warn(format!(
+                    "--dispatch-only: no handlers outlined in `{}`",
+                    module.funcs[func].name()
+                ));
+            }
+            outlined.insert(func, f);
+        }
+    }
 
-    // Sort directives by out-address, and remove duplicates.
+    // Sort directives by out-address, and remove duplicates. Those
+    // from the command line have no out-address, and are all kept.
     let mut directives = directives.to_vec();
     directives.sort_by_key(|d| d.func_index_out_addr);
-    directives.dedup_by_key(|d| d.func_index_out_addr);
+    directives.dedup_by(|a, b| {
+        a.func_index_out_addr != 0 && a.func_index_out_addr == b.func_index_out_addr
+    });
 
-    if let Some(p) = progress.as_mut() {
+    let facts = ModuleFacts {
+        const_tables: find_const_tables(&module)?,
+        const_globals: find_const_globals(&module, options.wizened_start)?,
+        specialization_outputs: directives
+            .iter()
+            .filter(|d| d.func_index_out_addr != 0)
+            .map(|d| (d.func_index_out_addr, d.func))
+            .collect(),
+        escape_summaries: crate::escape::summarize(&module, pure_imports),
+        memory_free_funcs: crate::flush::memory_free_funcs(&module)
+            .union(pure_imports)
+            .copied()
+            .collect(),
+        pure_imports: pure_imports.clone(),
+        const_result_funcs: find_const_result_funcs(&module),
+    };
+    tracing::trace!("constant tables: {:?}", facts.const_tables);
+    tracing::trace!("constant globals: {:?}", facts.const_globals);
+    tracing::trace!("constant-result functions: {:?}", facts.const_result_funcs);
+
+    if let Some(p) = progress.as_ref() {
         p.set_length(directives.len() as u64);
     }
 
     // Result of compilation.
-    let mut bodies: Vec<(Cow<Directive>, FuncDecl, String, bool)> = vec![];
+    let mut bodies: Vec<EmittedFunc> = vec![];
 
-    // Filter out directives that can be directly fulfilled by the cache.
+    // Filter out directives that can be directly fulfilled by the
+    // cache, or by a checkpoint when resuming (which then goes into
+    // the cache too).
     let mut cache_ctx = cache.thread()?;
     let mut remaining_directives = vec![];
+    let mut resumed = 0;
     for directive in directives {
-        let key = bincode::serialize(&directive).unwrap();
-        if let Some(data) = cache_ctx.lookup(&key)? {
+        let key = cache_key(&directive)?;
+        let mut data = cache_ctx.lookup(&key)?;
+        if data.is_none() {
+            if let Some(checkpointed) = checkpoint.map(|c| c.load(&key)).transpose()?.flatten() {
+                cache_ctx.insert(&key, checkpointed.clone())?;
+                data = Some(checkpointed);
+                resumed += 1;
+            }
+        }
+        if let Some(data) = data {
             bodies.push((
                 Cow::Owned(directive),
                 FuncDecl::Compiled(Signature::new(data.sig as usize), data.name, data.body),
-                String::new(),
+                None,
+                vec![],
+                vec![],
                 true,
             ));
 
@@ -114,8 +527,14 @@ pub(crate) fn partially_evaluate<'a>(
         }
     }
     directives = remaining_directives;
+    if resumed > 0 {
+        eprintln!(
+            "Resuming: {} directives specialized from checkpoints",
+            resumed
+        );
+    }
 
-    if let Some(p) = progress.as_mut() {
+    if let Some(p) = progress.as_ref() {
         p.tick();
     }
 
@@ -123,10 +542,19 @@ pub(crate) fn partially_evaluate<'a>(
     let mut funcs = HashMap::default();
     for directive in &directives {
         if !funcs.contains_key(&directive.func) {
-            let mut f = module.clone_and_expand_body(directive.func)?;
+            let mut f = match outlined.remove(&directive.func) {
+                Some(f) => f,
+                None => module.clone_and_expand_body(directive.func)?,
+            };
 
-            if let Some(path) = &output_ir {
-                let mut generic_ir_file = path.clone();
+            let wants_generic = output_ir.as_ref().filter(|o| {
+                !o.final_only
+                    && directives
+                        .iter()
+                        .any(|d| d.func == directive.func && o.wants(d))
+            });
+            if let Some(output_ir) = wants_generic {
+                let mut generic_ir_file = output_ir.dir.clone();
                 generic_ir_file.push(&format!("generic_{}.txt", directive.func));
                 std::fs::write(
                     &generic_ir_file,
@@ -137,6 +565,21 @@ pub(crate) fn partially_evaluate<'a>(
 
             let stats = Mutex::new(SpecializationStats::new(directive.func, &f));
 
+            if let Some(&param) = auto_dispatch.get(&directive.func) {
+                if crate::dispatch::uses_contexts(&f, &intrinsics) {
+                    tracing::info!(
+                        "{}: uses context intrinsics, not detecting dispatch loops",
+                        directive.func
+                    );
+                } else if crate::dispatch::instrument(&mut f, param, &intrinsics) == 0 {
+                    crate::policy::warn(format!(
+                        "--auto-dispatch: no dispatch loop keyed on parameter {} found in `{}`",
+                        param,
+                        module.funcs[directive.func].name()
+                    ));
+                }
+            }
+
             split_blocks_at_intrinsic_calls(&mut f, &intrinsics);
 
             f.recompute_edges();
@@ -144,30 +587,77 @@ pub(crate) fn partially_evaluate<'a>(
             let cut_blocks = find_cut_blocks(&f, &cfg, &intrinsics);
 
             f.convert_to_max_ssa(Some(cut_blocks));
+            let live_regs = crate::liveness::live_regs(&f, &cfg, &intrinsics);
 
-            funcs.insert(directive.func, (f, cfg, stats));
+            funcs.insert(
+                directive.func,
+                Generic {
+                    body: f,
+                    cfg,
+                    live_regs,
+                    stats,
+                },
+            );
         }
     }
 
     let global_base = module.globals.len();
 
+    let shared = Shared {
+        module: &module,
+        image: im,
+        intrinsics: &intrinsics,
+        facts: &facts,
+        options,
+    };
     let progress_ref = progress.as_ref();
+    let outcomes = Mutex::new(vec![]);
+    let outcome = |directive: &Directive, result| DirectiveOutcome {
+        user_id: directive.user_id,
+        func: directive.func,
+        func_index_out_addr: directive.func_index_out_addr,
+        result,
+    };
+    let parent_span = tracing::Span::current();
     bodies.extend(
         directives
             .par_iter()
-            .flat_map(|directive| {
-                let (generic, cfg, stats) = funcs.get(&directive.func).unwrap();
+            .map_init(Scratch::default, |scratch, directive| {
+                let _span = tracing::info_span!(
+                    parent: &parent_span,
+                    "directive",
+                    user_id = directive.user_id,
+                    func = %directive.func,
+                )
+                .entered();
+                let generic = funcs.get(&directive.func).unwrap();
+                let progress_line = progress_ref.and_then(|p| {
+                    p.start_directive(format!(
+                        "{} (user ID {})",
+                        module.funcs[directive.func].name(),
+                        directive.user_id
+                    ))
+                });
                 let result = match partially_evaluate_func(
-                    &module,
+                    &shared,
                     generic,
-                    cfg,
-                    im,
-                    &intrinsics,
                     directive,
+                    progress_line.as_ref(),
+                    scratch,
                 ) {
                     Ok(result) => result,
+                    // A broken invariant is weval's bug, not the
+                    // directive's: stop rather than fall back.
+                    Err(e) if e.is::<crate::verify::VerifyError>() => return Some(Err(e)),
                     Err(e) => {
-                        log::warn!("Failed to evaluate function: {e:?}");
+                        tracing::warn!("Failed to evaluate function: {e:?}");
+                        outcomes.lock().unwrap().push(outcome(
+                            directive,
+                            DirectiveResult::Failed {
+                                code: crate::error::code(&e),
+                                message: format!("{e:?}"),
+                            },
+                        ));
                         return None;
                     }
                 };
@@ -175,55 +665,91 @@ pub(crate) fn partially_evaluate<'a>(
                 if let Some(p) = progress_ref {
                     p.inc(1);
                 }
-                if let Some((body, sig, name, spec_stats)) = result {
-                    stats.lock().unwrap().add_specialization(&spec_stats);
-                    let ir = if output_ir.is_some() {
-                        use std::fmt::Write;
-                        let cfg = CFGInfo::new(&body);
-                        let liveness = Liveness::new(&body, &cfg);
-                        let mut s = String::new();
-                        writeln!(&mut s, "# Liveness:").unwrap();
-                        for (block, _) in body.blocks.entries() {
-                            let mut live = liveness.block_start[block]
-                                .iter()
-                                .cloned()
-                                .collect::<Vec<_>>();
-                            live.sort();
-                            writeln!(&mut s, "# {}: {:?}", block, live).unwrap();
-                        }
-                        writeln!(&mut s, "").unwrap();
-                        writeln!(&mut s, "{}", body.display_verbose("", Some(&module))).unwrap();
-                        s
-                    } else {
-                        String::new()
-                    };
-                    let decl = {
-                        let body = match body.compile() {
-                            Ok(body) => body,
-                            Err(e) => return Some(Err(e)),
+                if let Some((body, sig, name, spec_stats, pressure, sites, ir)) = result {
+                    generic
+                        .stats
+                        .lock()
+                        .unwrap()
+                        .add_specialization(&spec_stats);
+                    // Bodies with symbolic sites are compiled once
+                    // those are resolved, below, as are all bodies when
+                    // outlining code they have in common or
+                    // devirtualizing calls through results.
+                    let decl =
+                        if sites.is_empty() && outline_common.is_none() && !devirtualize_results {
+                            let body = match pass("compile", || body.compile()) {
+                                Ok(body) => body.into_raw_body(),
+                                Err(e) => return Some(Err(e)),
+                            };
+                            if let Some(checkpoint) = checkpoint {
+                                let data = CacheData {
+                                    sig: sig.index() as u32,
+                                    name: name.clone(),
+                                    body: body.clone(),
+                                };
+                                if let Err(e) = cache_key(directive)
+                                    .and_then(|key| checkpoint.save(&key, &data))
+                                {
+                                    return Some(Err(e));
+                                }
+                            }
+                            FuncDecl::Compiled(sig, name, body)
+                        } else {
+                            FuncDecl::Body(sig, name, body)
                         };
-                        FuncDecl::Compiled(sig, name, body.into_raw_body())
-                    };
-                    Some(Ok((Cow::Borrowed(directive), decl, ir, false)))
+                    Some(Ok((
+                        Cow::Borrowed(directive),
+                        decl,
+                        pressure,
+                        sites,
+                        ir,
+                        false,
+                    )))
                 } else {
-                    log::warn!("Failed to weval for directive {:?}", directive);
+                    tracing::warn!("Failed to weval for directive {:?}", directive);
+                    outcomes
+                        .lock()
+                        .unwrap()
+                        .push(outcome(directive, DirectiveResult::Abandoned));
                     None
                 }
             })
+            .flatten()
             .collect::<anyhow::Result<Vec<_>>>()?,
     );
 
-    if let Some(p) = progress.as_mut() {
-        p.finish_and_clear();
+    if let Some(p) = progress.as_ref() {
+        p.finish();
         eprintln!("Inserting results into cache...");
     }
 
+    // The expanded generic bodies are no longer needed; keep only
+    // their stats.
+    let mut stats = funcs
+        .drain()
+        .map(|(_, generic)| generic.stats.into_inner().unwrap())
+        .collect::<Vec<_>>();
+    stats.sort_by_key(|stats| stats.generic);
+
     // Compute memory updates.
+    let mut outcomes = outcomes.into_inner().unwrap();
     let mut mem_updates = HashMap::default();
-    for (directive, decl, ir, cache_hit) in bodies {
-        // Add to cache.
-        if !cache_hit && cache.can_insert() {
-            let key = bincode::serialize(&directive)?;
+    let mut sizes = vec![];
+    let mut generic_sizes = HashMap::default();
+    let mut produced = HashMap::default();
+    let mut deferred = vec![];
+    let generic_table_len = module
+        .tables
+        .get(Table::from(0))
+        .and_then(|table| table.func_elements.as_ref())
+        .map_or(0, |elems| elems.len());
+    for (directive, decl, pressure, sites, ir, cache_hit) in bodies {
+        // Add to cache. Bodies that refer to other directives' results,
+        // or to code outlined from other specializations, depend on
+        // more than their own directive, so are not cached.
+        let deferred_body = matches!(decl, FuncDecl::Body(..));
+        if !cache_hit && !deferred_body && cache.can_insert() {
+            let key = cache_key(&directive)?;
             let (sig, name, body) = match &decl {
                 FuncDecl::Compiled(sig, name, body) => (sig, name, body),
                 _ => unreachable!(),
@@ -236,31 +762,156 @@ pub(crate) fn partially_evaluate<'a>(
             cache_ctx.insert(&key, data)?;
         }
 
+        // Record the size of the emitted body.
+        let generic_bytes = *generic_sizes
+            .entry(directive.func)
+            .or_insert_with(|| func_body_size(&module.funcs[directive.func]));
+        let specialized_bytes = func_body_size(&decl);
+
         // Add function to module.
         let func = module.funcs.push(decl);
-        // Append to table.
-        let func_table = &mut module.tables[Table::from(0)];
-        let table_idx = {
-            let func_table_elts = func_table.func_elements.as_mut().unwrap();
-            let table_idx = func_table_elts.len();
-            func_table_elts.push(func);
-            table_idx
-        } as u64;
-        func_table.initial = std::cmp::max(func_table.initial, table_idx + 1);
-        if func_table.max.is_some() && table_idx >= func_table.max.unwrap() {
-            func_table.max = Some(table_idx + 1);
+        sizes.push(SpecializedFuncSize {
+            generic: directive.func,
+            specialized: func,
+            user_id: directive.user_id,
+            func_index_out_addr: directive.func_index_out_addr,
+            generic_bytes,
+            specialized_bytes,
+            cache_hit,
+            pressure,
+        });
+        outcomes.push(outcome(
+            &directive,
+            if cache_hit {
+                DirectiveResult::Cached
+            } else {
+                DirectiveResult::Specialized
+            },
+        ));
+
+        if let Some(output_ir) = &output_ir {
+            for dump in ir {
+                let mut ir_file = output_ir.dir.clone();
+                ir_file.push(&format!(
+                    "{}_{}_to_{}.{}",
+                    dump.kind, directive.func, func, dump.ext
+                ));
+                std::fs::write(&ir_file, dump.contents).unwrap();
+            }
         }
-        log::info!("New func index {} -> table index {}", func, table_idx);
 
-        if let Some(path) = &output_ir {
-            let mut specialized_ir_file = path.clone();
-            specialized_ir_file.push(&format!("specialized_{}_to_{}.txt", directive.func, func));
-            std::fs::write(&specialized_ir_file, ir).unwrap();
+        // Append to table, and update memory image with an output
+        // function index. (Directives from the command line have no
+        // output address: their results are exported instead.)
+        if directive.func_index_out_addr != 0 {
+            let func_table = &mut module.tables[Table::from(0)];
+            let table_idx = {
+                let func_table_elts = func_table.func_elements.as_mut().unwrap();
+                let table_idx = func_table_elts.len();
+                func_table_elts.push(func);
+                table_idx
+            } as u64;
+            func_table.initial = std::cmp::max(func_table.initial, table_idx + 1);
+            if func_table.max.is_some() && table_idx >= func_table.max.unwrap() {
+                func_table.max = Some(table_idx + 1);
+            }
+            tracing::info!("New func index {} -> table index {}", func, table_idx);
+
+            tracing::info!(" -> writing to 0x{:x}", directive.func_index_out_addr);
+            mem_updates.insert(directive.func_index_out_addr, table_idx);
+            produced.insert(directive.func_index_out_addr, (func, table_idx as u32));
+        }
+        if deferred_body {
+            deferred.push((func, sites, sizes.len() - 1));
         }
+    }
+
+    // With all results known, the functions in table 0 that cannot
+    // change: all of them if the table is constant, and otherwise the
+    // specializations weval appended.
+    let table = match module.tables.get(Table::from(0)) {
+        Some(table) if devirtualize_results => table
+            .func_elements
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, &func)| {
+                (func.is_valid()
+                    && (i >= generic_table_len || facts.const_tables.contains(&Table::from(0))))
+                .then(|| (func, module.funcs[func].sig()))
+            })
+            .collect::<Vec<_>>(),
+        _ => vec![],
+    };
+    let results = produced
+        .iter()
+        .map(|(&addr, &(_, table_idx))| (addr, table_idx))
+        .collect::<HashMap<_, _>>();
+    let known = crate::devirt::Known {
+        heap: im.main_heap()?,
+        results: &results,
+        table: &table[..],
+    };
 
-        // Update memory image with an output function index.
-        log::info!(" -> writing to 0x{:x}", directive.func_index_out_addr);
-        mem_updates.insert(directive.func_index_out_addr, table_idx);
+    // Resolve references to other directives' results, now that all
+    // specialized functions have indices, devirtualize calls through
+    // them, outline common code, and compile those bodies in parallel.
+    let mut deferred = deferred
+        .into_iter()
+        .map(|(func, sites, size_idx)| {
+            match std::mem::replace(&mut module.funcs[func], FuncDecl::None) {
+                FuncDecl::Body(sig, name, mut body) => {
+                    resolve_symbolic_sites(&mut body, &sites, &produced);
+                    if devirtualize_results {
+                        let n = crate::devirt::devirtualize(&mut body, &known);
+                        if n > 0 {
+                            tracing::info!("{}: devirtualized {} calls", func, n);
+                        }
+                    }
+                    (func, sig, name, body, size_idx)
+                }
+                _ => unreachable!(),
+            }
+        })
+        .collect::<Vec<_>>();
+    if verify {
+        let pass = if devirtualize_results {
+            "devirtualize"
+        } else {
+            "resolve_symbolic_sites"
+        };
+        for (_, _, name, body, _) in &deferred {
+            crate::verify::check(&module, body, pass, name)?;
+        }
+    }
+    if let Some(opts) = outline_common {
+        let mut generics = sizes.iter().map(|size| size.generic).collect::<Vec<_>>();
+        generics.sort();
+        generics.dedup();
+        for generic in generics {
+            let mut bodies = deferred
+                .iter_mut()
+                .filter(|(.., size_idx)| sizes[*size_idx].generic == generic)
+                .map(|(_, _, _, body, _)| body)
+                .collect::<Vec<_>>();
+            crate::dedup::outline(&mut module, generic, &mut bodies, opts);
+        }
+        if verify {
+            for (_, _, name, body, _) in &deferred {
+                crate::verify::check(&module, body, "outline_common", name)?;
+            }
+        }
+    }
+    let compiled = deferred
+        .into_par_iter()
+        .map(|(func, sig, name, body, size_idx)| {
+            let body = body.compile()?.into_raw_body();
+            Ok((func, FuncDecl::Compiled(sig, name, body), size_idx))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for (func, decl, size_idx) in compiled {
+        sizes[size_idx].specialized_bytes = func_body_size(&decl);
+        module.funcs[func] = decl;
     }
 
     // Update memory.
@@ -271,38 +922,372 @@ pub(crate) fn partially_evaluate<'a>(
 
     // Update the `weval_is_wevaled` flag, if it exists and is exported.
     if let Some(is_wevaled) = find_global_data_by_exported_func(&module, "weval.is.wevaled") {
-        log::info!("updating `is_wevaled` flag at {:#x} to 1", is_wevaled);
+        tracing::info!("updating `is_wevaled` flag at {:#x} to 1", is_wevaled);
         im.write_u32(heap, is_wevaled, 1)?;
     }
 
-    let mut stats = funcs
-        .drain()
-        .map(|(_, (_, _, stats))| stats.into_inner().unwrap())
-        .collect::<Vec<_>>();
-    stats.sort_by_key(|stats| stats.generic);
+    outcomes.sort_by_key(|outcome| outcome.func_index_out_addr);
 
     Ok(PartialEvalResult {
         module,
         global_base,
         stats,
+        sizes,
+        outcomes,
     })
 }
 
-fn partially_evaluate_func(
+/// Patch references to directives' results in a specialized body.
+/// References to directives that produced no function (e.g. because
+/// specialization failed) keep the original runtime code.
+fn resolve_symbolic_sites(
+    body: &mut FunctionBody,
+    sites: &[SymbolicSite],
+    produced: &HashMap<u32, (Func, u32)>,
+) {
+    for &site in sites {
+        match site {
+            SymbolicSite::FuncIndex(value, addr) => {
+                if let Some(&(_, table_idx)) = produced.get(&addr) {
+                    tracing::debug!("resolving {} to table index {}", value, table_idx);
+                    let tys = match &body.values[value] {
+                        ValueDef::Operator(_, _, tys) => *tys,
+                        _ => continue,
+                    };
+                    body.values[value] = ValueDef::Operator(
+                        Operator::I32Const { value: table_idx },
+                        ListRef::default(),
+                        tys,
+                    );
+                }
+            }
+            SymbolicSite::Call(value, addr) => {
+                if let Some(&(func, _)) = produced.get(&addr) {
+                    tracing::debug!("resolving call {} to {}", value, func);
+                    let (args, tys) = match &body.values[value] {
+                        ValueDef::Operator(Operator::CallIndirect { .. }, args, tys) => {
+                            (*args, *tys)
+                        }
+                        _ => continue,
+                    };
+                    let args = body.arg_pool[args].to_vec();
+                    let args = body
+                        .arg_pool
+                        .from_iter(args[..args.len() - 1].iter().cloned());
+                    body.values[value] = ValueDef::Operator(
+                        Operator::Call {
+                            function_index: func,
+                        },
+                        args,
+                        tys,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Find tables whose contents cannot change after instantiation:
+/// those that are neither imported nor exported, and that are never
+/// the target of a table-mutating instruction.
+fn find_const_tables(module: &Module) -> anyhow::Result<HashSet<Table>> {
+    use waffle::wasmparser::Operator as WasmOp;
+
+    let mut tables = module
+        .tables
+        .iter()
+        .filter(|&table| module.tables[table].func_elements.is_some())
+        .collect::<HashSet<_>>();
+    for import in &module.imports {
+        if let waffle::ImportKind::Table(table) = import.kind {
+            tables.remove(&table);
+        }
+    }
+    for export in &module.exports {
+        if let waffle::ExportKind::Table(table) = export.kind {
+            tables.remove(&table);
+        }
+    }
+
+    for decl in module.funcs.values() {
+        match decl {
+            FuncDecl::Lazy(_, _, body) => {
+                for op in body.get_operators_reader()? {
+                    match op? {
+                        WasmOp::TableSet { table }
+                        | WasmOp::TableGrow { table }
+                        | WasmOp::TableFill { table }
+                        | WasmOp::TableInit { table, .. }
+                        | WasmOp::TableCopy {
+                            dst_table: table, ..
+                        } => {
+                            tables.remove(&Table::from(table));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            FuncDecl::Body(_, _, body) => {
+                for def in body.values.values() {
+                    if let ValueDef::Operator(
+                        Operator::TableSet { table_index } | Operator::TableGrow { table_index },
+                        ..,
+                    ) = def
+                    {
+                        tables.remove(table_index);
+                    }
+                }
+            }
+            FuncDecl::Compiled(..) => {
+                // Can't cheaply inspect; assume anything may change.
+                tables.clear();
+            }
+            FuncDecl::Import(..) | FuncDecl::None => {}
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Find globals whose value in the image holds whenever a specialized
+/// function runs: those with an initial value that are immutable, or
+/// that are mutable but neither imported nor exported and never set
+/// except by a start function that Wizening already ran.
+fn find_const_globals(
     module: &Module,
-    generic: &FunctionBody,
-    cfg: &CFGInfo,
-    image: &Image,
-    intrinsics: &Intrinsics,
+    wizened_start: Option<Func>,
+) -> anyhow::Result<HashSet<Global>> {
+    use waffle::wasmparser::Operator as WasmOp;
+
+    let mut globals = module
+        .globals
+        .entries()
+        .filter(|(_, data)| data.value.is_some())
+        .map(|(global, _)| global)
+        .collect::<HashSet<_>>();
+    for import in &module.imports {
+        if let waffle::ImportKind::Global(global) = import.kind {
+            globals.remove(&global);
+        }
+    }
+    for export in &module.exports {
+        if let waffle::ExportKind::Global(global) = export.kind {
+            if module.globals[global].mutable {
+                globals.remove(&global);
+            }
+        }
+    }
+
+    // A start function runs after the image's values were taken,
+    // unless Wizening already ran it and dropped the start section.
+    // Its writes don't count then, if nothing can call it again.
+    let mut start_sets = HashSet::default();
+    let mut start_reachable = wizened_start.is_none_or(|start| {
+        module
+            .exports
+            .iter()
+            .any(|export| matches!(export.kind, waffle::ExportKind::Func(f) if f == start))
+            || module
+                .tables
+                .values()
+                .any(|table| table.func_elements.iter().flatten().any(|&f| f == start))
+    });
+    for (func, decl) in module.funcs.entries() {
+        let mut set = |global: Global| {
+            if Some(func) == wizened_start {
+                start_sets.insert(global);
+            } else {
+                globals.remove(&global);
+            }
+        };
+        match decl {
+            FuncDecl::Lazy(_, _, body) => {
+                for op in body.get_operators_reader()? {
+                    match op? {
+                        WasmOp::GlobalSet { global_index } => set(Global::from(global_index)),
+                        WasmOp::Call { function_index }
+                        | WasmOp::RefFunc { function_index }
+                        | WasmOp::ReturnCall { function_index } => {
+                            start_reachable |= wizened_start == Some(Func::from(function_index));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            FuncDecl::Body(_, _, body) => {
+                for def in body.values.values() {
+                    match def {
+                        ValueDef::Operator(Operator::GlobalSet { global_index }, ..) => {
+                            set(*global_index)
+                        }
+                        ValueDef::Operator(Operator::Call { function_index: f }, ..)
+                        | ValueDef::Operator(Operator::RefFunc { func_index: f }, ..) => {
+                            start_reachable |= wizened_start == Some(*f);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            FuncDecl::Compiled(..) => {
+                // Can't cheaply inspect; assume only immutable
+                // globals are constant.
+                globals.retain(|&global| !module.globals[global].mutable);
+                start_reachable = true;
+            }
+            FuncDecl::Import(..) | FuncDecl::None => {}
+        }
+    }
+    if start_reachable {
+        globals.retain(|global| !start_sets.contains(global));
+    }
+
+    Ok(globals)
+}
+
+/// Largest function body, in bytes of bytecode, that we scan to see
+/// whether it returns a constant.
+const MAX_CONST_RESULT_BODY_SIZE: usize = 512;
+
+/// How many block parameters to look through for a returned constant.
+const MAX_CONST_RESULT_DEPTH: usize = 8;
+
+/// Find small functions with one result that every return gives the
+/// same constant value. A call to one still runs, for its effects, but
+/// its result is known when specializing.
+fn find_const_result_funcs(module: &Module) -> HashMap<Func, WasmVal> {
+    module
+        .funcs
+        .entries()
+        .filter_map(|(func, decl)| {
+            match decl {
+                FuncDecl::Lazy(_, _, body) if body.range().len() <= MAX_CONST_RESULT_BODY_SIZE => {}
+                _ => return None,
+            }
+            if module.signatures[decl.sig()].returns.len() != 1 {
+                return None;
+            }
+            let mut body = module.clone_and_expand_body(func).ok()?;
+            body.recompute_edges();
+            let mut result = None;
+            for block in body.blocks.values() {
+                let Terminator::Return { values } = &block.terminator else {
+                    continue;
+                };
+                let value = const_value(&body, values[0], MAX_CONST_RESULT_DEPTH)?;
+                if result.is_some_and(|result| result != value) {
+                    return None;
+                }
+                result = Some(value);
+            }
+            Some((func, result?))
+        })
+        .collect()
+}
+
+/// The constant that `value` always is, looking through up to `depth`
+/// block parameters whose incoming arguments all agree.
+fn const_value(body: &FunctionBody, value: Value, depth: usize) -> Option<WasmVal> {
+    match &body.values[body.resolve_alias(value)] {
+        ValueDef::Operator(op, _, _) => WasmVal::try_from(*op).ok(),
+        &ValueDef::BlockParam(block, idx, _) if depth > 0 => {
+            let mut args = vec![];
+            for &pred in &body.blocks[block].preds {
+                body.blocks[pred].terminator.visit_targets(|target| {
+                    if target.block == block {
+                        args.push(target.args[idx as usize]);
+                    }
+                });
+            }
+            let mut result = None;
+            for arg in args {
+                let value = const_value(body, arg, depth - 1)?;
+                if result.is_some_and(|result| result != value) {
+                    return None;
+                }
+                result = Some(value);
+            }
+            result
+        }
+        _ => None,
+    }
+}
+
+/// Run one pass over a specialized function body within its own span.
+fn pass<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
+    tracing::debug_span!("pass", name).in_scope(f)
+}
+
+/// Size in bytes of a function body's bytecode (including locals), if
+/// it is in bytecode form.
+fn func_body_size(decl: &FuncDecl) -> usize {
+    match decl {
+        FuncDecl::Lazy(_, _, body) => body.range().len(),
+        FuncDecl::Compiled(_, _, body) => body.len(),
+        _ => 0,
+    }
+}
+
+/// A function to emit for a directive: its declaration, references to
+/// other directives' results to resolve, debugging output, and
+/// whether it came from the cache.
+type EmittedFunc<'a, 'b> = (
+    Cow<'b, Directive>,
+    FuncDecl<'a>,
+    Option<PressureHint>,
+    Vec<SymbolicSite>,
+    Vec<IrDump>,
+    bool,
+);
+
+/// A specialized function body, its signature and name, stats,
+/// references to other directives' results to resolve at emission,
+/// and any debugging output produced along the way.
+type SpecializedFunc = (
+    FunctionBody,
+    Signature,
+    String,
+    SpecializationStats,
+    Option<PressureHint>,
+    Vec<SymbolicSite>,
+    Vec<IrDump>,
+);
+
+fn partially_evaluate_func(
+    shared: &Shared,
+    generic: &Generic,
     directive: &Directive,
-) -> anyhow::Result<Option<(FunctionBody, Signature, String, SpecializationStats)>> {
+    progress_line: Option<&DirectiveLine>,
+    scratch: &mut Scratch,
+) -> anyhow::Result<Option<SpecializedFunc>> {
+    let &Shared {
+        module,
+        image,
+        intrinsics,
+        facts,
+        options,
+    } = shared;
+    let Generic {
+        body: generic,
+        cfg,
+        live_regs,
+        ..
+    } = generic;
+    let EvalOptions {
+        verify,
+        preserve_traps,
+        max_growth,
+        fast,
+        ..
+    } = *options;
+    let output_ir = options.output_ir.as_ref().filter(|o| o.wants(directive));
+    let facts_output = options.facts_output.as_ref();
     let directive_args = DirectiveArgs::decode(&directive.args[..])?;
     let orig_name = module.funcs[directive.func].name();
     let sig = module.funcs[directive.func].sig();
 
-    log::info!("Specializing: {:?}", directive);
-    log::info!("Args: {:?}", directive_args);
-    log::debug!("body:\n{}", generic.display("| ", Some(module)));
+    tracing::info!("Specializing: {:?}", directive);
+    tracing::info!("Args: {:?}", directive_args);
+    tracing::debug!("body:\n{}", generic.display("| ", Some(module)));
 
     // Build the evaluator.
     let func = FunctionBody::new(module, sig);
@@ -312,21 +1297,37 @@ fn partially_evaluate_func(
         directive,
         directive_args,
         intrinsics,
+        facts,
         image,
         cfg,
+        live_regs,
         state: FunctionState::new(),
         func,
-        block_map: HashMap::default(),
+        block_map: std::mem::take(&mut scratch.block_map),
         block_rev_map: PerEntity::default(),
-        value_map: HashMap::default(),
-        value_dep_blocks: HashMap::default(),
-        reg_map: HashMap::default(),
-        queue: VecDeque::new(),
-        queue_set: HashSet::default(),
+        value_map: std::mem::take(&mut scratch.value_map),
+        value_dep_blocks: std::mem::take(&mut scratch.value_dep_blocks),
+        reg_map: std::mem::take(&mut scratch.reg_map),
+        queue: std::mem::take(&mut scratch.queue),
+        queue_set: std::mem::take(&mut scratch.queue_set),
         stats: SpecializationStats::default(),
+        symbolic_sites: vec![],
+        escape_asserts: std::mem::take(&mut scratch.escape_asserts),
+        flush_stores: std::mem::take(&mut scratch.flush_stores),
+        edge_states: std::mem::take(&mut scratch.edge_states),
+        memory: options.memory.as_ref().map(MemoryBudget::reserve),
+        progress_line,
+        // `--specialize-func` results must not read their buffer, so
+        // have every PC specialized.
+        max_contexts: (fast && directive.func_index_out_addr != 0).then_some(FAST_MAX_CONTEXTS),
+        call_decisions: facts_output.map(|_| HashMap::default()),
+        options,
+        nondeterministic_folds: HashSet::default(),
+        last_defs: HashMap::default(),
+        last_exits: HashMap::default(),
     };
-    let (ctx, entry_state) = evaluator.state.init(image);
-    log::trace!("after init_args, state is {:?}", evaluator.state);
+    let (ctx, entry_state) = evaluator.state.init(image, &facts.const_globals);
+    tracing::trace!("after init_args, state is {:?}", evaluator.state);
 
     let specialized_entry = evaluator.create_block(evaluator.generic.entry, ctx, entry_state);
     evaluator
@@ -344,36 +1345,210 @@ fn partially_evaluate_func(
     let pre_entry = evaluator.create_pre_entry(specialized_entry);
     evaluator.func.entry = pre_entry;
 
-    let success = evaluator.evaluate()?;
+    let success = pass("evaluate", || evaluator.evaluate())?;
     if !success {
+        scratch.recycle(&mut evaluator);
         return Ok(None);
     }
+    evaluator.stats.nondeterministic_folds = evaluator.nondeterministic_folds.len();
+    if let Some(facts_output) = facts_output {
+        facts_output.add(evaluator.facts());
+    }
+    if evaluator.intrinsics.context_bucket.is_none() {
+        let contexts = &evaluator.state.contexts;
+        let mut buckets = BTreeMap::<u32, BTreeSet<u32>>::new();
+        for context in contexts.iter() {
+            if let (ContextElem::Loop(pc), Some(bucket)) = (
+                contexts.leaf_element(context),
+                contexts.context_bucket[context],
+            ) {
+                buckets.entry(bucket).or_default().insert(pc);
+            }
+        }
+        if !buckets.is_empty() {
+            evaluator
+                .stats
+                .inferred_buckets
+                .push((directive.user_id, buckets));
+        }
+    }
 
     let name = format!("{} (specialized)", orig_name);
+    let verify_after = |pass: &'static str, func: &FunctionBody| -> anyhow::Result<()> {
+        if verify {
+            let func_name = format!("{}, user ID {}", name, directive.user_id);
+            crate::verify::check(module, func, pass, &func_name)?;
+        }
+        Ok(())
+    };
+    verify_after("evaluate", &evaluator.func)?;
     let cfg = CFGInfo::new(&evaluator.func);
-    crate::escape::remove_shadow_stack_if_non_escaping(&mut evaluator.func, &cfg);
-    evaluator.func.optimize(&waffle::OptOptions {
+    let func = &mut evaluator.func;
+    let opts = waffle::OptOptions {
         gvn: false,
         cprop: false,
         redundant_blockparams: true,
+    };
+    let escape_report = pass("escape", || {
+        if preserve_traps {
+            crate::escape::analyze(func, &cfg, &facts.escape_summaries)
+        } else {
+            crate::escape::remove_shadow_stack_if_non_escaping(func, &cfg, &facts.escape_summaries)
+        }
     });
-    crate::constant_offsets::run(&mut evaluator.func, &cfg);
-    waffle::passes::resolve_aliases::run(&mut evaluator.func);
-    evaluator.func.optimize(&waffle::OptOptions {
-        gvn: false,
-        cprop: false,
-        redundant_blockparams: true,
+    for (&(block, _), &(ptr, line)) in &evaluator.escape_asserts {
+        if cfg.rpo_pos[block].is_none() {
+            continue;
+        }
+        if let Err(e) = escape_report.check_not_escaped(ptr) {
+            anyhow::bail!(WevalError::IntrinsicFailed(format!(
+                "weval_assert_not_escaped() failed: line {}: {}",
+                line, e
+            )));
+        }
+    }
+    verify_after("escape", func)?;
+    pass("optimize", || func.optimize(&opts));
+    verify_after("optimize", func)?;
+    // `--fast` skips the cleanups that only make the code smaller or
+    // faster.
+    if !fast {
+        pass("constant_offsets", || {
+            crate::constant_offsets::run(func, &cfg, preserve_traps)
+        });
+        verify_after("constant_offsets", func)?;
+    }
+    pass("resolve_aliases", || {
+        waffle::passes::resolve_aliases::run(func)
+    });
+    verify_after("resolve_aliases", func)?;
+    // A flush store overwritten later may still be the one that traps.
+    if !preserve_traps && !fast {
+        evaluator.stats.flush_stores_elided = pass("flush", || {
+            crate::flush::run(
+                func,
+                &cfg,
+                &evaluator.flush_stores,
+                &facts.memory_free_funcs,
+            )
+        });
+        verify_after("flush", func)?;
+        pass("optimize", || func.optimize(&opts));
+        verify_after("optimize", func)?;
+    }
+    // The passes since `evaluate` may have left blocks unreachable.
+    let cfg = CFGInfo::new(func);
+    if !fast {
+        evaluator.stats.switch_chains = pass("switch", || crate::switch::run(func, &cfg));
+        verify_after("switch", func)?;
+    }
+    pass("dce", || {
+        crate::dce::run(func, &cfg, &facts.pure_imports, preserve_traps)
+    });
+    verify_after("dce", func)?;
+    if !fast {
+        // After DCE, so that only live instructions are hoisted.
+        let cfg = CFGInfo::new(func);
+        evaluator.stats.licm_hoisted = pass("licm", || {
+            crate::licm::run(func, &cfg, &facts.pure_imports, preserve_traps)
+        });
+        verify_after("licm", func)?;
+    }
+    for hook in &options.function_passes {
+        tracing::debug_span!("pass", name = hook.name())
+            .in_scope(|| hook.run(module, func))
+            .map_err(|e| e.context(format!("pass `{}` failed", hook.name())))?;
+        verify_after("library pass", func)?;
+    }
+    // Emission restores reducibility by duplicating code; say so when
+    // it has to.
+    let cfg = CFGInfo::new(func);
+    evaluator.stats.irreducible_edges = crate::reducibility::irreducible_edges(func, &cfg);
+    if evaluator.stats.irreducible_edges > 0 {
+        crate::policy::warn(format!(
+            "specialization of `{}` with user ID {} has {} irreducible control-flow edges; \
+             blocks will be duplicated at emission to make it reducible",
+            orig_name, directive.user_id, evaluator.stats.irreducible_edges
+        ));
+    }
+
+    // `--fast` collects no stats, nor register-pressure estimates.
+    let pressure = (!fast).then(|| {
+        accumulate_stats_from_func(
+            &mut evaluator.stats,
+            &evaluator.func,
+            &evaluator.state.origins,
+        )
     });
-    crate::dce::run(&mut evaluator.func, &cfg);
+    if let Some(factor) = max_growth {
+        let (_, insts, _) = crate::stats::count_reachable_blocks_and_insts(&evaluator.func);
+        let (_, generic_insts, _) = crate::stats::count_reachable_blocks_and_insts(generic);
+        if insts as f64 > factor * generic_insts as f64 {
+            tracing::info!(
+                " -> {} instructions, over {}x the generic function's {}",
+                insts,
+                factor,
+                generic_insts
+            );
+            scratch.recycle(&mut evaluator);
+            return Ok(None);
+        }
+    }
 
-    accumulate_stats_from_func(&mut evaluator.stats, &evaluator.func);
+    let mut ir = vec![];
+    if output_ir.is_some() {
+        ir.push(IrDump {
+            kind: "specialized",
+            ext: "txt",
+            contents: evaluator.annotated_ir(),
+        });
+    }
+    if output_ir.is_some_and(|o| !o.final_only) {
+        ir.push(IrDump {
+            kind: "escape",
+            ext: "txt",
+            contents: escape_report.to_string(),
+        });
+    }
+    if output_ir.map(|o| o.dot).unwrap_or(false) {
+        ir.push(IrDump {
+            kind: "contexts",
+            ext: "dot",
+            contents: crate::dot::context_tree(
+                &name,
+                &evaluator.state.contexts,
+                &evaluator.block_rev_map,
+                &evaluator.func,
+            ),
+        });
+        ir.push(IrDump {
+            kind: "specialized",
+            ext: "dot",
+            contents: crate::dot::specialized_cfg(
+                &name,
+                &evaluator.state.contexts,
+                &evaluator.block_rev_map,
+                &evaluator.func,
+            ),
+        });
+    }
 
-    log::info!("Specialization of {:?} done", directive);
-    log::debug!(
+    scratch.recycle(&mut evaluator);
+
+    tracing::info!("Specialization of {:?} done", directive);
+    tracing::debug!(
         "Adding func:\n{}",
         evaluator.func.display_verbose("| ", Some(module))
     );
-    Ok(Some((evaluator.func, sig, name, evaluator.stats)))
+    Ok(Some((
+        evaluator.func,
+        sig,
+        name,
+        evaluator.stats,
+        pressure,
+        evaluator.symbolic_sites,
+        ir,
+    )))
 }
 
 // Split at every `weval_specialize_value()` call and
@@ -389,13 +1564,13 @@ fn split_blocks_at_intrinsic_calls(func: &mut FunctionBody, intrinsics: &Intrins
                 if Some(*function_index) == intrinsics.specialize_value
                     || Some(*function_index) == intrinsics.pop_context
                 {
-                    log::trace!("Splitting at weval intrinsic for inst {}", inst);
+                    tracing::trace!("Splitting at weval intrinsic for inst {}", inst);
 
                     // Split the block here!  Split *after* the call
                     // (the `i + 1`).
                     let split_insts = func.blocks[block].insts.split_off(i + 1);
                     let new_block = func.blocks.push(BlockDef::default());
-                    log::trace!(" -> new block: {}", new_block);
+                    tracing::trace!(" -> new block: {}", new_block);
                     func.blocks[new_block].insts = split_insts;
                     let term = std::mem::take(&mut func.blocks[block].terminator);
                     func.blocks[new_block].terminator = term;
@@ -416,7 +1591,7 @@ fn split_blocks_at_intrinsic_calls(func: &mut FunctionBody, intrinsics: &Intrins
         }
     }
 
-    log::trace!("After splitting:\n{}\n", func.display_verbose("| ", None));
+    tracing::trace!("After splitting:\n{}\n", func.display_verbose("| ", None));
 }
 
 fn find_cut_blocks(
@@ -473,7 +1648,7 @@ fn find_cut_blocks(
 
             let changed = new != current;
             highest_same_ctx_ancestor[succ] = new;
-            log::trace!("highest same-context ancestor for {}: {}", succ, new);
+            tracing::trace!("highest same-context ancestor for {}: {}", succ, new);
             if changed {
                 if queue_set.insert(succ) {
                     queue.push(succ);
@@ -482,7 +1657,7 @@ fn find_cut_blocks(
         });
     }
 
-    log::trace!("cut blocks = {:?}", blocks);
+    tracing::trace!("cut blocks = {:?}", blocks);
     blocks
 }
 
@@ -497,17 +1672,43 @@ fn meet_ancestors(cfg: &CFGInfo, a: Block, b: Block) -> Block {
     }
 }
 
-fn accumulate_stats_from_func(stats: &mut SpecializationStats, func: &FunctionBody) {
+fn accumulate_stats_from_func(
+    stats: &mut SpecializationStats,
+    func: &FunctionBody,
+    origins: &PerEntity<Value, ConstOrigin>,
+) -> PressureHint {
     let (blocks, insts, reachable_blocks) = crate::stats::count_reachable_blocks_and_insts(func);
     stats.specialized_blocks += blocks;
     stats.specialized_insts += insts;
 
+    // Count materialized constants by whether they hold module-wide.
+    for &block in &reachable_blocks {
+        for &inst in &func.blocks[block].insts {
+            if let ValueDef::Operator(op, _, _) = &func.values[inst] {
+                if WasmVal::try_from(*op).is_ok() || matches!(op, Operator::RefFunc { .. }) {
+                    match origins[inst].for_use() {
+                        ConstOrigin::Module => stats.module_consts += 1,
+                        _ => stats.directive_consts += 1,
+                    }
+                }
+            }
+        }
+    }
+
     // Compute liveness over all blocks and find the live-over-edge count.
     let cfg = CFGInfo::new(func);
     let liveness = Liveness::new(func, &cfg);
+    let mut pressure = PressureHint {
+        blocks: reachable_blocks.len() as u32,
+        ..PressureHint::default()
+    };
     for &block in &reachable_blocks {
-        stats.live_value_at_block_start += liveness.block_start[block].len();
+        let live = liveness.block_start[block].len();
+        stats.live_value_at_block_start += live;
+        pressure.max_live = std::cmp::max(pressure.max_live, live as u32);
+        pressure.total_live += live as u32;
     }
+    pressure
 }
 
 fn const_operator(ty: Type, value: WasmVal) -> Option<Operator> {
@@ -520,6 +1721,26 @@ fn const_operator(ty: Type, value: WasmVal) -> Option<Operator> {
     }
 }
 
+/// How many definitions of an address `const_load_error` lists.
+const MAX_ADDR_CHAIN: usize = 8;
+
+/// The `i`th argument of a call to `weval_{intrinsic}()`, which the
+/// guest must pass as a constant.
+fn const_u32_arg(
+    abs: &[AbstractValue],
+    i: usize,
+    intrinsic: &str,
+    what: &str,
+) -> anyhow::Result<u32> {
+    abs[i].as_const_u32().ok_or_else(|| {
+        WevalError::IntrinsicFailed(format!(
+            "weval_{}(): {} is not a constant: {:?}",
+            intrinsic, what, abs[i]
+        ))
+        .into()
+    })
+}
+
 fn store_operator(ty: Type) -> Option<Operator> {
     let memory = MemoryArg {
         memory: Memory::new(0),
@@ -557,6 +1778,9 @@ enum EvalResult {
     Alias(AbstractValue, Value),
     Normal(AbstractValue),
     NewBlock(Block, AbstractValue, Value),
+    /// Emit a different operator with the given arguments in place
+    /// of the original one.
+    Rewrite(Operator, ListRef<Value>, AbstractValue),
 }
 impl EvalResult {
     fn is_handled(&self) -> bool {
@@ -569,36 +1793,99 @@ impl EvalResult {
 
 const MAX_BLOCKS: usize = 100_000;
 const MAX_VALUES: usize = 1_000_000;
+/// Contexts to create with `--fast`: enough for the loops of a small
+/// function to be specialized, not for large ones to be slow.
+const FAST_MAX_CONTEXTS: usize = 256;
+/// How many blocks to evaluate between memory-budget checks.
+const MEMORY_CHECK_INTERVAL: usize = 256;
 
 impl<'a> Evaluator<'a> {
     fn evaluate(&mut self) -> anyhow::Result<bool> {
+        let mut evaluated = 0;
         while let Some((orig_block, ctx, new_block)) = self.queue.pop_back() {
             if self.func.blocks.len() > MAX_BLOCKS || self.func.values.len() > MAX_VALUES {
-                log::info!(
+                tracing::info!(
                     " -> too many blocks or values: {} blocks {} values",
                     self.func.blocks.len(),
                     self.func.values.len()
                 );
                 return Ok(false);
             }
+            evaluated += 1;
+            if evaluated % MEMORY_CHECK_INTERVAL == 0 {
+                self.check_memory()?;
+                self.show_progress();
+            }
             self.queue_set.remove(&(orig_block, ctx));
             self.evaluate_block(orig_block, ctx, new_block)?;
         }
+        self.check_memory()?;
         self.finalize()?;
         Ok(true)
     }
 
+    fn check_memory(&mut self) -> anyhow::Result<()> {
+        if let Some(mut memory) = self.memory.take() {
+            let result = memory.resize(self.approx_memory());
+            self.memory = Some(memory);
+            result?;
+        }
+        Ok(())
+    }
+
+    fn show_progress(&self) {
+        if let Some(line) = self.progress_line {
+            line.update(
+                self.func.blocks.len(),
+                self.func.values.len(),
+                self.approx_memory(),
+            );
+        }
+    }
+
+    /// A rough estimate of the memory held by this evaluation: the
+    /// specialized body, per-block states, contexts and value maps.
+    fn approx_memory(&self) -> usize {
+        use std::mem::size_of;
+        let blocks: usize = self
+            .func
+            .blocks
+            .iter()
+            .map(|block| {
+                size_of::<BlockDef>()
+                    + self.func.blocks[block].insts.len() * size_of::<Value>()
+                    + 2 * size_of::<ProgPointState>()
+                    + self.state.block_entry[block].approx_bytes()
+                    + self.state.block_exit[block].approx_bytes()
+            })
+            .sum();
+        let values = self.func.values.len()
+            * (size_of::<ValueDef>() + size_of::<AbstractValue>() + size_of::<ConstOrigin>());
+        let edges: usize = self
+            .edge_states
+            .values()
+            .map(|state| size_of::<ProgPointState>() + state.approx_bytes())
+            .sum();
+        let maps = self.block_map.len() * size_of::<((Context, Block), Block)>()
+            + self.value_map.len() * size_of::<((Context, Value), Value)>()
+            + self.reg_map.len() * size_of::<((Context, Block, RegSlot), Value)>();
+        let contexts = self.state.contexts.len() * 2 * size_of::<(Context, ContextElem)>();
+        blocks + values + edges + maps + contexts
+    }
+
     fn evaluate_block(
         &mut self,
         orig_block: Block,
         ctx: Context,
         new_block: Block,
     ) -> anyhow::Result<()> {
+        let _span = tracing::trace_span!("context", %ctx, block = %orig_block).entered();
+
         // Clear the block body each time we rebuild it -- we may be
         // recomputing a specialization with an existing output.
         self.func.blocks[new_block].insts.clear();
 
-        log::trace!(
+        tracing::trace!(
             "evaluate_block: orig {} ctx {} new {}",
             orig_block,
             ctx,
@@ -613,7 +1900,7 @@ impl<'a> Evaluator<'a> {
             pending_specialize: None,
             flow: self.state.block_entry[new_block].clone(),
         };
-        log::trace!(" -> state = {:?}", state);
+        tracing::trace!(" -> state = {:?}", state);
 
         state.flow.update_at_block_entry(
             &mut self.reg_map,
@@ -622,7 +1909,7 @@ impl<'a> Evaluator<'a> {
                     .entry((ctx, orig_block, regslot))
                     .or_insert_with(|| {
                         let param = self.func.add_placeholder(ty);
-                        log::trace!(
+                        tracing::trace!(
                             "new blockparam {} of ty {:?} for reg slot {:?} on block {} (ctx {} orig {})",
                             param,
                             ty,
@@ -650,6 +1937,7 @@ impl<'a> Evaluator<'a> {
             })?;
 
         // Store the exit state at this point for later use.
+        self.check_exit_monotonic(orig_block, ctx, &state.flow);
         self.state.block_exit[new_block] = state.flow.clone();
 
         self.evaluate_term(orig_block, &mut state, new_block);
@@ -667,7 +1955,7 @@ impl<'a> Evaluator<'a> {
         new_block: Block,
         orig_val: Value,
     ) -> (Value, AbstractValue) {
-        log::trace!(
+        tracing::trace!(
             "using value {} at block {} in context {}",
             orig_val,
             orig_block,
@@ -681,9 +1969,9 @@ impl<'a> Evaluator<'a> {
                     .insert(new_block);
             }
             let abs = &self.state.values[val];
-            log::trace!(" -> found abstract  value {:?} at context {}", abs, context);
-            log::trace!(" -> runtime value {}", val);
-            return (val, abs.clone());
+            tracing::trace!(" -> found abstract  value {:?} at context {}", abs, context);
+            tracing::trace!(" -> runtime value {}", val);
+            return (val, *abs);
         }
         panic!(
             "Could not find value for {} in context {}",
@@ -698,20 +1986,22 @@ impl<'a> Evaluator<'a> {
         orig_val: Value,
         val: Value,
         abs: AbstractValue,
+        origin: ConstOrigin,
     ) -> bool {
-        log::debug!(
-            "defining val {} in block {} context {} with specialized val {} abs {:?}",
+        tracing::debug!(
+            "defining val {} in block {} context {} with specialized val {} abs {:?} ({:?})",
             orig_val,
             block,
             context,
             val,
-            abs
+            abs,
+            origin
         );
         self.value_map.insert((context, orig_val), val);
         let val_abs = &mut self.state.values[val];
         let updated = AbstractValue::meet(val_abs, &abs);
         let changed = updated != *val_abs;
-        log::debug!(
+        tracing::debug!(
             " -> meet: cur {:?} input {:?} result {:?} (changed: {})",
             val_abs,
             abs,
@@ -720,6 +2010,11 @@ impl<'a> Evaluator<'a> {
         );
         *val_abs = updated;
 
+        let val_origin = &mut self.state.origins[val];
+        let updated_origin = val_origin.meet(origin);
+        let changed = changed || updated_origin != *val_origin;
+        *val_origin = updated_origin;
+
         if changed {
             if let Some(deps) = self.value_dep_blocks.get(&(context, orig_val)) {
                 for &new_block in deps {
@@ -751,16 +2046,19 @@ impl<'a> Evaluator<'a> {
         // Reused below for each instruction.
         let mut arg_abs_values = vec![];
 
-        log::trace!("evaluate_block_body: {}: state {:?}", orig_block, state);
+        tracing::trace!("evaluate_block_body: {}: state {:?}", orig_block, state);
 
         for &inst in &self.generic.blocks[orig_block].insts {
             let input_ctx = state.context;
-            log::trace!(
+            tracing::trace!(
                 "inst {} in context {} -> {:?}",
                 inst,
                 input_ctx,
                 self.generic.values[inst]
             );
+            // Origin of the result's knowledge, from its inputs and
+            // the operator; refined below once the result is known.
+            let mut inst_origin = ConstOrigin::Module;
             if let Some((result_value, result_abs)) = match &self.generic.values[inst] {
                 ValueDef::Alias(_) => {
                     // Don't generate any new code; uses will be
@@ -785,28 +2083,47 @@ impl<'a> Evaluator<'a> {
                     arg_abs_values.clear();
                     let mut arg_values = self.func.arg_pool.allocate(args.len(), Value::invalid());
                     for (i, &arg) in args_slice.iter().enumerate() {
-                        log::trace!(" * arg {}", arg);
+                        tracing::trace!(" * arg {}", arg);
                         let arg = self.generic.resolve_alias(arg);
-                        log::trace!(" -> resolves to arg {}", arg);
+                        tracing::trace!(" -> resolves to arg {}", arg);
                         let (val, abs) = self.use_value(state.context, orig_block, new_block, arg);
+                        inst_origin = inst_origin.meet(self.state.origins[val].for_use());
+                        if let AbstractValue::ConcreteMemory(..) = &abs {
+                            inst_origin = ConstOrigin::Directive;
+                        }
                         arg_abs_values.push(abs);
                         self.func.arg_pool[arg_values][i] = val;
                     }
+                    // Calls (including intrinsics) and specialization
+                    // globals yield knowledge about this directive only.
+                    if op.is_call() || matches!(op, Operator::GlobalGet { .. }) {
+                        inst_origin = ConstOrigin::Directive;
+                    }
                     let loc = self.generic.source_locs[inst];
 
                     // Eval the transfer-function for this operator.
-                    let result = self.abstract_eval(
-                        orig_block,
-                        new_block,
-                        inst,
-                        *op,
-                        loc,
-                        /* abstract values = */ &arg_abs_values[..],
-                        /* new values = */ arg_values,
-                        /* orig_values = */ args_slice,
-                        tys_slice,
-                        state,
-                    )?;
+                    let result = self
+                        .abstract_eval(
+                            orig_block,
+                            new_block,
+                            inst,
+                            *op,
+                            loc,
+                            /* abstract values = */ &arg_abs_values[..],
+                            /* new values = */ arg_values,
+                            /* orig_values = */ args_slice,
+                            tys_slice,
+                            state,
+                        )
+                        .map_err(|e| {
+                            e.context(format!(
+                                "{} = {} in block {} of {}",
+                                inst,
+                                op,
+                                orig_block,
+                                self.module.funcs[self.directive.func].name()
+                            ))
+                        })?;
                     // Transcribe either the original operation, or a
                     // constant, to the output.
 
@@ -839,9 +2156,27 @@ impl<'a> Evaluator<'a> {
                                 ))
                             }
                         }
+                        EvalResult::Normal(AbstractValue::FuncRef(func))
+                            if matches!(op, Operator::TableGet { .. }) =>
+                        {
+                            // The function is in a table, so it is
+                            // declared for `ref.func`.
+                            Some((
+                                ValueDef::Operator(
+                                    Operator::RefFunc { func_index: func },
+                                    ListRef::default(),
+                                    specialized_tys,
+                                ),
+                                AbstractValue::FuncRef(func),
+                            ))
+                        }
                         EvalResult::Normal(AbstractValue::StaticMemory(addr)) if tys.len() == 1 => {
-                            let const_op =
-                                const_operator(tys_slice[0], WasmVal::I32(addr)).unwrap();
+                            // A 64-bit pointer if extended to i64.
+                            let k = match tys_slice[0] {
+                                Type::I64 => WasmVal::I64(u64::from(addr)),
+                                _ => WasmVal::I32(addr),
+                            };
+                            let const_op = const_operator(tys_slice[0], k).unwrap();
                             Some((
                                 ValueDef::Operator(const_op, ListRef::default(), specialized_tys),
                                 AbstractValue::StaticMemory(addr),
@@ -859,6 +2194,9 @@ impl<'a> Evaluator<'a> {
                             new_block = block;
                             Some((ValueDef::Alias(value), av))
                         }
+                        EvalResult::Rewrite(op, args, av) => {
+                            Some((ValueDef::Operator(op, args, specialized_tys), av))
+                        }
                     }
                 }
                 _ => unreachable!(
@@ -867,11 +2205,30 @@ impl<'a> Evaluator<'a> {
                 ),
             } {
                 let result_value = self.func.add_value(result_value);
+                if let Some(site) =
+                    self.symbolic_site(result_value, &result_abs, &arg_abs_values[..])
+                {
+                    self.symbolic_sites.push(site);
+                }
                 self.value_map.insert((input_ctx, inst), result_value);
                 self.func.append_to_block(new_block, result_value);
                 self.func.source_locs[result_value] = self.generic.source_locs[inst];
 
-                self.def_value(orig_block, input_ctx, inst, result_value, result_abs);
+                let origin = match (&self.func.values[result_value], &result_abs) {
+                    (_, AbstractValue::Runtime(_)) => ConstOrigin::Module,
+                    (_, AbstractValue::SpecializedFuncIndex(_)) => ConstOrigin::Directive,
+                    (&ValueDef::Alias(val), _) => self.state.origins[val].for_use(),
+                    _ => inst_origin,
+                };
+                self.check_def_monotonic(orig_block, input_ctx, inst, None, &result_abs, origin);
+                self.def_value(
+                    orig_block,
+                    input_ctx,
+                    inst,
+                    result_value,
+                    result_abs,
+                    origin,
+                );
             }
         }
 
@@ -880,21 +2237,132 @@ impl<'a> Evaluator<'a> {
 
     fn meet_into_block_entry(
         &mut self,
-        _block: Block,
-        _context: Context,
+        from: Block,
+        block: Block,
+        context: Context,
         new_block: Block,
         state: &ProgPointState,
     ) -> bool {
         let mut state = state.clone();
         state.update_across_edge();
+        self.drop_dead_regs(block, &mut state);
+
+        // The state along an edge is the output of the source block's
+        // transfer function, so must only move down the lattice.
+        if cfg!(debug_assertions) {
+            if let Some(Err(slot)) = self
+                .edge_states
+                .get(&(from, new_block))
+                .map(|prev| state.check_below(prev))
+            {
+                panic!(
+                    "Non-monotonic state on edge from block {} to block {} (orig {}) in context {} ({}): {}",
+                    from,
+                    new_block,
+                    block,
+                    context,
+                    self.context_desc(context),
+                    slot
+                );
+            }
+        }
+        let old = cfg!(debug_assertions).then(|| self.state.block_entry[new_block].clone());
+        let changed = match self.edge_states.get(&(from, new_block)) {
+            Some(prev) => self.state.block_entry[new_block].meet_with_changes(&state, prev),
+            None => self.state.block_entry[new_block].meet_with(&state),
+        };
+        if let Some(old) = old {
+            let mut full = old.clone();
+            full.meet_with(&state);
+            assert_eq!(
+                full, self.state.block_entry[new_block],
+                "sparse meet into block {} diverged from full meet",
+                new_block
+            );
+        }
+        self.edge_states.insert((from, new_block), state);
+        changed
+    }
+
+    /// Drop specialization registers that are not live at `block`,
+    /// so we don't carry them as blockparams into its specializations.
+    fn drop_dead_regs(&self, block: Block, state: &mut ProgPointState) {
+        if let Some(live) = &self.live_regs[block] {
+            state.regs.retain(|slot, _| match slot {
+                RegSlot::Register(idx) => live.contains(idx),
+                _ => true,
+            });
+        }
+    }
+
+    /// In debug builds, check that the abstract value computed for
+    /// `orig_val` in `context` (arriving from the specialized block
+    /// `from`, for a blockparam) is at or below the last one computed
+    /// for it. Meeting into the specialized value cannot move it up
+    /// regardless, so this is what catches a transfer function that
+    /// is not monotonic, which can keep the fixpoint from terminating.
+    fn check_def_monotonic(
+        &mut self,
+        block: Block,
+        context: Context,
+        orig_val: Value,
+        from: Option<Block>,
+        abs: &AbstractValue,
+        origin: ConstOrigin,
+    ) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        if let Some((old_abs, old_origin)) = self
+            .last_defs
+            .insert((context, orig_val, from), (*abs, origin))
+        {
+            // A runtime value's origin claims nothing.
+            let origin_below =
+                matches!(abs, AbstractValue::Runtime(_)) || origin.is_below(old_origin);
+            if !(abs.is_below(&old_abs) && origin_below) {
+                panic!(
+                    "Non-monotonic transfer for {} in block {} context {} ({}){}: \
+                     {:?} ({:?}) -> {:?} ({:?})",
+                    orig_val,
+                    block,
+                    context,
+                    self.context_desc(context),
+                    from.map(|from| format!(" from block {}", from))
+                        .unwrap_or_default(),
+                    old_abs,
+                    old_origin,
+                    abs,
+                    origin
+                );
+            }
+        }
+    }
 
-        self.state.block_entry[new_block].meet_with(&state)
+    /// In debug builds, check that the exit state computed for
+    /// `block` in `context` is at or below the last one.
+    fn check_exit_monotonic(&mut self, block: Block, context: Context, state: &ProgPointState) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        if let Some(old) = self.last_exits.insert((context, block), state.clone()) {
+            if let Err(slot) = state.check_below(&old) {
+                panic!(
+                    "Non-monotonic exit state of block {} in context {} ({}): {}",
+                    block,
+                    context,
+                    self.context_desc(context),
+                    slot
+                );
+            }
+        }
     }
 
     fn context_desc(&self, ctx: Context) -> String {
         match self.state.contexts.leaf_element(ctx) {
             ContextElem::Root => "root".to_owned(),
             ContextElem::Loop(pc) => format!("PC {:?}", pc),
+            ContextElem::Cold => "cold PCs".to_owned(),
             ContextElem::Specialized(index, val) => format!("Specialization of {}: {}", index, val),
         }
     }
@@ -906,6 +2374,7 @@ impl<'a> Evaluator<'a> {
         mut state: ProgPointState,
     ) -> Block {
         state.update_across_edge();
+        self.drop_dead_regs(orig_block, &mut state);
         let block = self.func.add_block();
         self.func.blocks[block].desc = format!(
             "Orig {} ctx {} ({})",
@@ -913,7 +2382,7 @@ impl<'a> Evaluator<'a> {
             context,
             self.context_desc(context)
         );
-        log::debug!(
+        tracing::debug!(
             "create_block: orig_block {} context {} -> {}",
             orig_block,
             context,
@@ -924,7 +2393,7 @@ impl<'a> Evaluator<'a> {
             .reserve(self.generic.blocks[orig_block].params.len());
         for &(ty, param) in &self.generic.blocks[orig_block].params {
             let new_param = self.func.add_blockparam(block, ty);
-            log::trace!(" -> blockparam {} maps to {}", param, new_param);
+            tracing::trace!(" -> blockparam {} maps to {}", param, new_param);
             self.value_map.insert((context, param), new_param);
         }
         self.block_map.insert((context, orig_block), block);
@@ -937,20 +2406,20 @@ impl<'a> Evaluator<'a> {
         &mut self,
         state: &PointState,
         orig_block: Block,
-        _new_block: Block,
+        new_block: Block,
         target: Block,
         target_context: Context,
     ) -> Block {
-        log::debug!(
+        tracing::debug!(
             "targeting block {} from {}, in context {}",
             target,
             orig_block,
             state.context
         );
 
-        log::trace!(" -> new context {}", target_context);
+        tracing::trace!(" -> new context {}", target_context);
 
-        log::trace!(
+        tracing::trace!(
             "target_block: from orig {} ctx {} to {} ctx {}",
             orig_block,
             state.context,
@@ -961,7 +2430,7 @@ impl<'a> Evaluator<'a> {
         match self.block_map.entry((target_context, target)) {
             HashEntry::Vacant(_) => {
                 let block = self.create_block(target, target_context, state.flow.clone());
-                log::trace!(" -> created block {}", block);
+                tracing::trace!(" -> created block {}", block);
                 self.block_map.insert((target_context, target), block);
                 self.queue_set.insert((target, target_context));
                 self.queue.push_back((target, target_context, block));
@@ -969,15 +2438,16 @@ impl<'a> Evaluator<'a> {
             }
             HashEntry::Occupied(o) => {
                 let target_specialized = *o.get();
-                log::trace!(" -> already existing block {}", target_specialized);
+                tracing::trace!(" -> already existing block {}", target_specialized);
                 let changed = self.meet_into_block_entry(
+                    new_block,
                     target,
                     target_context,
                     target_specialized,
                     &state.flow,
                 );
                 if changed {
-                    log::trace!("   -> changed");
+                    tracing::trace!("   -> changed");
                     if self.queue_set.insert((target, target_context)) {
                         self.queue
                             .push_back((target, target_context, target_specialized));
@@ -999,7 +2469,7 @@ impl<'a> Evaluator<'a> {
         let n_args = self.generic.blocks[orig_block].params.len();
         let mut args = Vec::with_capacity(n_args);
         let mut abs_args = Vec::with_capacity(n_args);
-        log::trace!(
+        tracing::trace!(
             "evaluate target: block {} context {} to {:?}",
             orig_block,
             state.context,
@@ -1012,7 +2482,7 @@ impl<'a> Evaluator<'a> {
         for &arg in &target.args {
             let arg = self.generic.resolve_alias(arg);
             let (val, abs) = self.use_value(state.context, orig_block, new_block, arg);
-            log::trace!(
+            tracing::trace!(
                 "blockparam: block {} context {}: arg {} has val {} abs {:?}",
                 orig_block,
                 state.context,
@@ -1027,37 +2497,48 @@ impl<'a> Evaluator<'a> {
         // Parallel-move semantics: read all uses above, then write
         // all defs below.
         let mut changed = false;
-        for (blockparam, abs) in self.generic.blocks[target.block]
+        for ((blockparam, abs), &arg) in self.generic.blocks[target.block]
             .params
             .iter()
             .map(|(_, val)| *val)
             .zip(abs_args.iter())
+            .zip(args.iter())
         {
             let &val = self.value_map.get(&(target_ctx, blockparam)).unwrap();
+            let mut origin = self.state.origins[arg].for_use();
 
             let abs = if let ContextElem::Specialized(index, val) =
                 self.state.contexts.leaf_element(target_ctx)
             {
                 if index == blockparam {
-                    log::trace!(
+                    tracing::trace!(
                         "Specialized context into block {} context {}: index {} becomes val {}",
                         target_block,
                         target_ctx,
                         index,
                         val
                     );
+                    origin = ConstOrigin::Directive;
                     AbstractValue::Concrete(WasmVal::I32(val))
                 } else {
-                    abs.clone()
+                    *abs
                 }
             } else {
-                abs.clone()
+                *abs
             };
 
-            log::debug!(
+            tracing::debug!(
                 "blockparam: updating with new def: block {} context {} param {} val {} abstract {:?}",
                 target.block, target_ctx, blockparam, val, abs);
-            changed |= self.def_value(orig_block, target_ctx, blockparam, val, abs);
+            self.check_def_monotonic(
+                orig_block,
+                target_ctx,
+                blockparam,
+                Some(new_block),
+                &abs,
+                origin,
+            );
+            changed |= self.def_value(orig_block, target_ctx, blockparam, val, abs, origin);
         }
 
         // If blockparam inputs changed, re-enqueue target for evaluation.
@@ -1072,7 +2553,7 @@ impl<'a> Evaluator<'a> {
     }
 
     fn evaluate_term(&mut self, orig_block: Block, state: &mut PointState, new_block: Block) {
-        log::trace!(
+        tracing::trace!(
             "evaluating terminator: block {} context {} specialized block {}: {:?}",
             orig_block,
             state.context,
@@ -1132,7 +2613,7 @@ impl<'a> Evaluator<'a> {
             }
             &Terminator::Br { ref target } => {
                 if let Some((index, lo, hi)) = state.pending_specialize.take() {
-                    log::trace!(
+                    tracing::trace!(
                         "Branch to target {} with PendingSpecialize on {}",
                         target.block,
                         index
@@ -1146,7 +2627,7 @@ impl<'a> Evaluator<'a> {
                                 Some(new_context),
                                 ContextElem::Specialized(target_specialized_value, i),
                             );
-                            log::trace!(" -> created new context {} for index {}", c, i);
+                            tracing::trace!(" -> created new context {} for index {}", c, i);
                             self.evaluate_block_target(orig_block, new_block, state, c, target)
                         })
                         .collect();
@@ -1178,13 +2659,19 @@ impl<'a> Evaluator<'a> {
                 assert!(!state.pending_specialize.is_some());
                 let (value, abs_value) =
                     self.use_value(state.context, orig_block, new_block, value);
-                if let Some(selector) = abs_value.as_const_u32() {
-                    let selector = selector as usize;
-                    let target = if selector < targets.len() {
-                        &targets[selector]
-                    } else {
-                        default
-                    };
+                let target_index = |selector: u32| std::cmp::min(selector as usize, targets.len());
+                let selected = match &abs_value {
+                    AbstractValue::ConstSet(vals) => {
+                        let first = target_index(vals.get(0).integer_value().unwrap() as u32);
+                        vals.iter()
+                            .all(|val| target_index(val.integer_value().unwrap() as u32) == first)
+                            .then_some(first)
+                    }
+                    _ => abs_value.as_const_u32().map(target_index),
+                };
+                if let Some(selected) = selected {
+                    let target = targets.get(selected).unwrap_or(default);
+                    self.infer_context_bucket(state.context, targets, target.block);
                     Terminator::Br {
                         target: self.evaluate_block_target(
                             orig_block,
@@ -1251,7 +2738,7 @@ impl<'a> Evaluator<'a> {
         tys: &[Type],
         state: &mut PointState,
     ) -> anyhow::Result<EvalResult> {
-        log::debug!(
+        tracing::debug!(
             "abstract eval of {} {}: op {:?} abs {:?} state {:?}",
             orig_block,
             orig_inst,
@@ -1272,22 +2759,65 @@ impl<'a> Evaluator<'a> {
             values,
             orig_values,
             state,
-        );
+        )?;
         if intrinsic_result.is_handled() {
-            log::debug!(" -> intrinsic: {:?}", intrinsic_result);
+            tracing::debug!(" -> intrinsic: {:?}", intrinsic_result);
             return Ok(intrinsic_result);
         }
 
         let reg_result =
             self.abstract_eval_regs(orig_inst, new_block, op, abs, values, tys, state)?;
         if reg_result.is_handled() {
-            log::debug!(" -> specialization regs: {:?}", reg_result);
+            tracing::debug!(" -> specialization regs: {:?}", reg_result);
             return Ok(reg_result);
         }
 
+        if let Some(func) = self.devirtualize(op, abs) {
+            tracing::debug!(" -> devirtualized call to {}", func);
+            self.record_call(state.context, orig_inst, "devirtualized", Some(func));
+            let args = self.func.arg_pool[values].to_vec();
+            let args = self
+                .func
+                .arg_pool
+                .from_iter(args[..args.len() - 1].iter().cloned());
+            return Ok(EvalResult::Rewrite(
+                Operator::Call {
+                    function_index: func,
+                },
+                args,
+                self.call_result(func, orig_inst),
+            ));
+        }
+        if let Operator::Call { function_index } = op {
+            if self.facts.const_result_funcs.contains_key(&function_index) {
+                self.record_call(
+                    state.context,
+                    orig_inst,
+                    "const-result",
+                    Some(function_index),
+                );
+                // Keep the call, with its known result.
+                return Ok(EvalResult::Rewrite(
+                    op,
+                    values,
+                    self.call_result(function_index, orig_inst),
+                ));
+            }
+        }
+
         let ret = if op.is_call() {
-            log::debug!(" -> call");
+            tracing::debug!(" -> call");
+            match op {
+                Operator::Call { function_index } => {
+                    self.record_call(state.context, orig_inst, "direct", Some(function_index))
+                }
+                _ => self.record_call(state.context, orig_inst, "indirect", None),
+            }
             AbstractValue::Runtime(Some(orig_inst))
+        } else if let Some(ret) =
+            self.abstract_eval_const_sets(orig_inst, op, abs, orig_values, state)?
+        {
+            ret
         } else {
             match abs.len() {
                 0 => self.abstract_eval_nullary(orig_inst, op, state),
@@ -1298,7 +2828,7 @@ impl<'a> Evaluator<'a> {
             }
         };
 
-        log::debug!(" -> result: {:?}", ret);
+        tracing::debug!(" -> result: {:?}", ret);
         Ok(EvalResult::Normal(ret))
     }
 
@@ -1313,101 +2843,117 @@ impl<'a> Evaluator<'a> {
         values: ListRef<Value>,
         orig_values: &[Value],
         state: &mut PointState,
-    ) -> EvalResult {
-        match op {
+    ) -> anyhow::Result<EvalResult> {
+        Ok(match op {
             Operator::Call { function_index } => {
                 if Some(function_index) == self.intrinsics.push_context {
-                    let pc = abs[0]
-                        .as_const_u32_or_mem_offset()
-                        .expect("PC should not be a runtime value");
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
+                    let elem = self.loop_context_elem(
+                        instantaneous_context,
+                        instantaneous_context,
+                        &abs[0],
+                        "push",
+                    )?;
                     let child = self
                         .state
                         .contexts
-                        .create(Some(instantaneous_context), ContextElem::Loop(pc));
+                        .create(Some(instantaneous_context), elem.clone());
                     state.pending_context = Some(child);
-                    log::trace!("push context (pc {:?}): now {}", pc, child);
+                    tracing::trace!("push context ({:?}): now {}", elem, child);
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.pop_context {
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
                     let parent = self.state.contexts.pop_one_loop(instantaneous_context);
                     state.pending_context = Some(parent);
-                    log::trace!("pop context: now {}", parent);
+                    tracing::trace!("pop context: now {}", parent);
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.update_context {
-                    log::trace!("update context at {}: PC is {:?}", orig_values[0], abs[0]);
+                    tracing::trace!("update context at {}: PC is {:?}", orig_values[0], abs[0]);
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
                     let parent = self.state.contexts.pop_one_loop(instantaneous_context);
-                    let pending_context = if let Some(pc) = abs[0].as_const_u32_or_mem_offset() {
-                        Some(
-                            self.state
-                                .contexts
-                                .create(Some(parent), ContextElem::Loop(pc)),
-                        )
-                    } else {
-                        panic!("PC is a runtime value: {:?}", abs[0]);
-                    };
-                    log::trace!("update context: now {:?}", pending_context);
+                    let elem =
+                        self.loop_context_elem(instantaneous_context, parent, &abs[0], "update")?;
+                    let pending_context = Some(self.state.contexts.create(Some(parent), elem));
+                    tracing::trace!("update context: now {:?}", pending_context);
                     state.pending_context = pending_context;
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.context_bucket {
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
-                    let bucket = abs[0].as_const_u32().unwrap();
+                    let bucket = const_u32_arg(abs, 0, "context_bucket", "bucket")?;
                     self.state.contexts.context_bucket[instantaneous_context] = Some(bucket);
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.specialize_value {
-                    let lo = abs[1].as_const_u32().unwrap();
-                    let hi = abs[2].as_const_u32().unwrap();
-                    log::trace!(
+                    let lo = const_u32_arg(abs, 1, "specialize_value", "lower bound")?;
+                    let hi = const_u32_arg(abs, 2, "specialize_value", "upper bound")?;
+                    tracing::trace!(
                         "Creating pending-specialize state for index {} lo {} hi {}",
                         orig_inst,
                         lo,
                         hi
                     );
                     state.pending_specialize = Some((orig_inst, lo, hi));
-                    EvalResult::Alias(abs[0].clone(), self.func.arg_pool[values][0])
+                    EvalResult::Alias(abs[0], self.func.arg_pool[values][0])
                 } else if Some(function_index) == self.intrinsics.abort_specialization {
                     let line_num = abs[0].as_const_u32().unwrap_or(0);
                     let fatal = abs[1].as_const_u32().unwrap_or(0);
-                    log::trace!("abort-specialization point: line {}", line_num);
+                    tracing::trace!("abort-specialization point: line {}", line_num);
                     if fatal != 0 {
-                        panic!("Specialization reached a point it shouldn't have!");
+                        anyhow::bail!(WevalError::IntrinsicFailed(format!(
+                            "weval_abort_specialization(): specialization reached line {}, \
+                             which it shouldn't have",
+                            line_num
+                        )));
                     }
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.trace_line {
                     let line_num = abs[0].as_const_u32().unwrap_or(0);
-                    log::debug!("trace: line number {}: current context {} at block {}, pending context {:?}",
+                    tracing::debug!("trace: line number {}: current context {} at block {}, pending context {:?}",
                                 line_num, state.context, orig_block, state.pending_context);
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.assert_const32 {
-                    log::trace!("assert_const32: abs {:?} line {:?}", abs[0], abs[1]);
+                    tracing::trace!("assert_const32: abs {:?} line {:?}", abs[0], abs[1]);
                     if abs[0].as_const_u32_or_mem_offset().is_none() {
-                        panic!(
-                            "weval_assert_const32() failed: {:?}: line {:?}",
-                            abs[0], abs[1]
-                        );
+                        anyhow::bail!(WevalError::IntrinsicFailed(format!(
+                            "weval_assert_const32() failed: line {}: {:?}",
+                            abs[1].as_const_u32().unwrap_or(0),
+                            abs[0]
+                        )));
                     }
                     EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.assert_not_escaped {
+                    // Checked against the escape analysis once the
+                    // function is fully specialized.
+                    let ptr = self.func.arg_pool[values][0];
+                    let line = abs[1].as_const_u32().unwrap_or(0);
+                    self.escape_asserts
+                        .insert((new_block, orig_inst), (ptr, line));
+                    EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.print {
-                    let message_ptr = abs[0].as_const_u32().unwrap();
-                    let message = self
-                        .image
-                        .read_str(self.image.main_heap.unwrap(), message_ptr)
-                        .unwrap();
-                    let line = abs[1].as_const_u32().unwrap();
-                    let val = abs[2].clone();
-                    log::info!("print: line {}: {}: {:?}", line, message, val);
+                    let message_ptr = const_u32_arg(abs, 0, "print", "message")?;
+                    let message = self.image.read_str(self.image.main_heap()?, message_ptr)?;
+                    let line = const_u32_arg(abs, 1, "print", "line")?;
+                    let val = abs[2];
+                    tracing::info!("print: line {}: {}: {:?}", line, message, val);
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.read_specialization_global {
-                    let index = abs[0].as_const_u32().unwrap() as usize;
+                    let index = const_u32_arg(abs, 0, "read_specialization_global", "index")?;
                     let value = self.func.add_op(
                         new_block,
                         Operator::I64Const { value: 0 },
                         &[],
                         &[Type::I64],
                     );
-                    let state = self.state.specialization_globals[index].clone();
-                    log::trace!(
+                    let state = *self
+                        .state
+                        .specialization_globals
+                        .get(index as usize)
+                        .ok_or_else(|| {
+                            WevalError::IntrinsicFailed(format!(
+                                "weval_read_specialization_global(): no specialization global {}",
+                                index
+                            ))
+                        })?;
+                    tracing::trace!(
                         "read_specialization_global: index {}: state = {:?}",
                         index,
                         state
@@ -1416,31 +2962,31 @@ impl<'a> Evaluator<'a> {
                 } else if Some(function_index) == self.intrinsics.push_stack {
                     let stackptr = self.func.arg_pool[values][0];
                     let value = self.func.arg_pool[values][1];
-                    log::trace!(
+                    tracing::trace!(
                         "push_stack: value {}, current stack is {:?}",
                         value,
                         state.flow.stack,
                     );
-                    log::trace!("push_stack: value {} stackptr {}", value, stackptr);
+                    tracing::trace!("push_stack: value {} stackptr {}", value, stackptr);
                     state.flow.stack.insert(
                         0,
                         (
                             RegValue::Value {
                                 data: stackptr,
                                 ty: Type::I32,
-                                abs: abs[0].clone(),
+                                abs: abs[0],
                             },
                             RegValue::Value {
                                 data: value,
                                 ty: Type::I64,
-                                abs: abs[1].clone(),
+                                abs: abs[1],
                             },
                         ),
                     );
                     self.stats.virtstack_writes += 1;
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.pop_stack {
-                    log::trace!("pop_stack: current stack is {:?}", state.flow.stack);
+                    tracing::trace!("pop_stack: current stack is {:?}", state.flow.stack);
                     self.stats.virtstack_reads += 1;
                     if state.flow.stack.len() > 0 {
                         let (_, reg) = state.flow.stack.remove(0);
@@ -1457,7 +3003,7 @@ impl<'a> Evaluator<'a> {
                                 memory: MemoryArg {
                                     align: 1,
                                     offset: 0,
-                                    memory: self.image.main_heap().unwrap(),
+                                    memory: self.image.main_heap()?,
                                 },
                             },
                             &[ptr],
@@ -1467,8 +3013,8 @@ impl<'a> Evaluator<'a> {
                         EvalResult::Alias(AbstractValue::Runtime(None), load)
                     }
                 } else if Some(function_index) == self.intrinsics.read_stack {
-                    let idx = abs[1].as_const_u32().unwrap();
-                    log::trace!(
+                    let idx = const_u32_arg(abs, 1, "read_stack", "index")?;
+                    tracing::trace!(
                         "read_stack: index {}, current stack is {:?}",
                         idx,
                         state.flow.stack
@@ -1476,7 +3022,7 @@ impl<'a> Evaluator<'a> {
                     self.stats.virtstack_reads += 1;
                     if let Some((_, data)) = state.flow.stack.get(idx as usize) {
                         let (value, abs) = match data {
-                            RegValue::Value { data, abs, .. } => (*data, abs.clone()),
+                            RegValue::Value { data, abs, .. } => (*data, *abs),
                             _ => unreachable!(),
                         };
                         EvalResult::Alias(abs, value)
@@ -1488,7 +3034,7 @@ impl<'a> Evaluator<'a> {
                                 memory: MemoryArg {
                                     align: 1,
                                     offset: 0,
-                                    memory: self.image.main_heap().unwrap(),
+                                    memory: self.image.main_heap()?,
                                 },
                             },
                             &[ptr],
@@ -1499,9 +3045,9 @@ impl<'a> Evaluator<'a> {
                     }
                 } else if Some(function_index) == self.intrinsics.write_stack {
                     let stackptr = self.func.arg_pool[values][0];
-                    let idx = abs[1].as_const_u32().unwrap();
+                    let idx = const_u32_arg(abs, 1, "write_stack", "index")?;
                     let value = self.func.arg_pool[values][2];
-                    log::trace!(
+                    tracing::trace!(
                         "write_stack: index {}, value {}, current stack is {:?}",
                         idx,
                         value,
@@ -1509,17 +3055,17 @@ impl<'a> Evaluator<'a> {
                     );
                     let addr_value = RegValue::Value {
                         data: stackptr,
-                        abs: abs[0].clone(),
+                        abs: abs[0],
                         ty: Type::I32,
                     };
                     let data_value = RegValue::Value {
                         data: value,
-                        abs: abs[2].clone(),
+                        abs: abs[2],
                         ty: Type::I64,
                     };
                     self.stats.virtstack_writes += 1;
                     if let Some((addr, data)) = state.flow.stack.get_mut(idx as usize) {
-                        log::trace!("write_stack: value {} stackptr {}", value, stackptr);
+                        tracing::trace!("write_stack: value {} stackptr {}", value, stackptr);
                         *addr = addr_value;
                         *data = data_value;
                     } else if idx == 0 && state.flow.stack.is_empty() {
@@ -1531,7 +3077,7 @@ impl<'a> Evaluator<'a> {
                                 memory: MemoryArg {
                                     align: 1,
                                     offset: 0,
-                                    memory: self.image.main_heap().unwrap(),
+                                    memory: self.image.main_heap()?,
                                 },
                             },
                             &[stackptr, value],
@@ -1541,50 +3087,52 @@ impl<'a> Evaluator<'a> {
                     }
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.sync_stack {
-                    log::trace!("sync_stack current stack is {:?}", state.flow.stack);
+                    tracing::trace!("sync_stack current stack is {:?}", state.flow.stack);
 
                     for (addr, data) in state.flow.stack.drain(..) {
                         let addr = addr.value().unwrap();
                         let data = data.value().unwrap();
-                        log::trace!("sync_stack: value {} stackptr {}", addr, data);
-                        self.func.add_op(
+                        tracing::trace!("sync_stack: value {} stackptr {}", addr, data);
+                        let store = self.func.add_op(
                             new_block,
                             Operator::I64Store {
                                 memory: MemoryArg {
                                     align: 1,
                                     offset: 0,
-                                    memory: self.image.main_heap().unwrap(),
+                                    memory: self.image.main_heap()?,
                                 },
                             },
                             &[addr, data],
                             &[],
                         );
                         self.stats.virtstack_writes_mem += 1;
+                        self.flush_stores.insert(store);
                     }
 
                     for (_, (addr, data)) in std::mem::take(&mut state.flow.locals) {
                         let addr = addr.value().unwrap();
                         let data = data.value().unwrap();
-                        log::trace!("sync_stack: local addr {} data {}", addr, data);
-                        self.func.add_op(
+                        tracing::trace!("sync_stack: local addr {} data {}", addr, data);
+                        let store = self.func.add_op(
                             new_block,
                             Operator::I64Store {
                                 memory: MemoryArg {
                                     align: 1,
                                     offset: 0,
-                                    memory: self.image.main_heap().unwrap(),
+                                    memory: self.image.main_heap()?,
                                 },
                             },
                             &[addr, data],
                             &[],
                         );
                         self.stats.local_writes_mem += 1;
+                        self.flush_stores.insert(store);
                     }
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.read_local {
                     self.stats.local_reads += 1;
                     let ptr = self.func.arg_pool[values][0];
-                    let idx = abs[1].as_const_u32().unwrap();
+                    let idx = const_u32_arg(abs, 1, "read_local", "index")?;
                     match state.flow.locals.get(&idx) {
                         None => {
                             let load = self.func.add_op(
@@ -1593,7 +3141,7 @@ impl<'a> Evaluator<'a> {
                                     memory: MemoryArg {
                                         align: 1,
                                         offset: 0,
-                                        memory: self.image.main_heap().unwrap(),
+                                        memory: self.image.main_heap()?,
                                     },
                                 },
                                 &[ptr],
@@ -1603,26 +3151,26 @@ impl<'a> Evaluator<'a> {
                             EvalResult::Alias(AbstractValue::Runtime(None), load)
                         }
                         Some((_, RegValue::Value { data, abs, .. })) => {
-                            EvalResult::Alias(abs.clone(), *data)
+                            EvalResult::Alias(*abs, *data)
                         }
                         _ => unreachable!(),
                     }
                 } else if Some(function_index) == self.intrinsics.write_local {
                     self.stats.local_writes += 1;
                     let ptr = self.func.arg_pool[values][0];
-                    let idx = abs[1].as_const_u32().unwrap();
+                    let idx = const_u32_arg(abs, 1, "write_local", "index")?;
                     let data = self.func.arg_pool[values][2];
                     state.flow.locals.insert(
                         idx,
                         (
                             RegValue::Value {
                                 data: ptr,
-                                abs: abs[0].clone(),
+                                abs: abs[0],
                                 ty: Type::I32,
                             },
                             RegValue::Value {
                                 data,
-                                abs: abs[2].clone(),
+                                abs: abs[2],
                                 ty: Type::I64,
                             },
                         ),
@@ -1633,7 +3181,7 @@ impl<'a> Evaluator<'a> {
                 }
             }
             _ => EvalResult::Unhandled,
-        }
+        })
     }
 
     fn abstract_eval_regs(
@@ -1650,54 +3198,194 @@ impl<'a> Evaluator<'a> {
             Operator::Call { function_index }
                 if Some(function_index) == self.intrinsics.read_reg =>
             {
-                let idx = abs[0].as_const_u64().expect("Non-constant register number");
-                log::trace!("load from specialization reg {}", idx);
+                let idx = abs[0].as_const_u64().ok_or_else(|| {
+                    WevalError::IntrinsicFailed(format!(
+                        "weval_read_reg(): register number is not a constant: {:?}",
+                        abs[0]
+                    ))
+                })?;
+                tracing::trace!("load from specialization reg {}", idx);
                 let slot = RegSlot::Register(idx as u32);
                 match state.flow.regs.get(&slot) {
                     Some(RegValue::Value { data, abs, .. }) => {
-                        log::trace!(" -> have value {} with abs {:?}", data, abs);
-                        return Ok(EvalResult::Alias(abs.clone(), *data));
+                        tracing::trace!(" -> have value {} with abs {:?}", data, abs);
+                        return Ok(EvalResult::Alias(*abs, *data));
                     }
                     Some(v) => {
-                        anyhow::bail!(
-                            "Specialization register {} in bad state {:?} at read",
-                            idx,
-                            v
-                        );
+                        anyhow::bail!(WevalError::IntrinsicFailed(format!(
+                            "weval_read_reg(): register {} in bad state {:?}",
+                            idx, v
+                        )));
                     }
                     None => {
-                        anyhow::bail!("Specialization register {} not set", idx);
+                        anyhow::bail!(WevalError::IntrinsicFailed(format!(
+                            "weval_read_reg(): register {} not set",
+                            idx
+                        )));
                     }
                 }
             }
-            Operator::Call { function_index }
-                if Some(function_index) == self.intrinsics.write_reg =>
-            {
-                let idx = abs[0].as_const_u64().expect("Non-constant register number");
-                let data = self.func.arg_pool[vals][1];
-                log::trace!(
-                    "store to specialization reg {} value {} abs {:?}",
-                    idx,
-                    data,
-                    abs[1]
-                );
-                let slot = RegSlot::Register(idx as u32);
-                state.flow.regs.insert(
-                    slot,
-                    RegValue::Value {
-                        data,
-                        ty: Type::I64,
-                        abs: abs[1].clone(),
-                    },
-                );
+            Operator::Call { function_index }
+                if Some(function_index) == self.intrinsics.write_reg =>
+            {
+                let idx = abs[0].as_const_u64().ok_or_else(|| {
+                    WevalError::IntrinsicFailed(format!(
+                        "weval_write_reg(): register number is not a constant: {:?}",
+                        abs[0]
+                    ))
+                })?;
+                let data = self.func.arg_pool[vals][1];
+                tracing::trace!(
+                    "store to specialization reg {} value {} abs {:?}",
+                    idx,
+                    data,
+                    abs[1]
+                );
+                let slot = RegSlot::Register(idx as u32);
+                state.flow.regs.insert(
+                    slot,
+                    RegValue::Value {
+                        data,
+                        ty: Type::I64,
+                        abs: abs[1],
+                    },
+                );
+
+                // Elide the store.
+                return Ok(EvalResult::Elide);
+            }
+            _ => {}
+        }
+
+        Ok(EvalResult::Unhandled)
+    }
+
+    /// If a newly emitted value refers to a directive's result, return
+    /// the site to patch at emission.
+    fn symbolic_site(
+        &self,
+        value: Value,
+        abs: &AbstractValue,
+        arg_abs: &[AbstractValue],
+    ) -> Option<SymbolicSite> {
+        if let &AbstractValue::SpecializedFuncIndex(addr) = abs {
+            return Some(SymbolicSite::FuncIndex(value, addr));
+        }
+        // Weval appends specialized functions to table 0, and they
+        // have the same signature as their generic function.
+        match (&self.func.values[value], arg_abs.last()) {
+            (
+                &ValueDef::Operator(
+                    Operator::CallIndirect {
+                        sig_index,
+                        table_index,
+                    },
+                    ..,
+                ),
+                Some(&AbstractValue::SpecializedFuncIndex(addr)),
+            ) if table_index == Table::from(0) => {
+                let generic = self.facts.specialization_outputs[&addr];
+                (self.module.funcs[generic].sig() == sig_index)
+                    .then_some(SymbolicSite::Call(value, addr))
+            }
+            _ => None,
+        }
+    }
+
+    /// If `op` is an indirect call whose callee is known, return the
+    /// callee. The callee must have exactly the expected signature,
+    /// since otherwise the indirect call would trap.
+    fn devirtualize(&self, op: Operator, abs: &[AbstractValue]) -> Option<Func> {
+        let (sig, func) = match (op, abs.last()?) {
+            (Operator::CallRef { sig_index }, AbstractValue::FuncRef(func)) => (sig_index, *func),
+            (
+                Operator::CallIndirect {
+                    sig_index,
+                    table_index,
+                },
+                AbstractValue::Concrete(WasmVal::I32(index)),
+            ) => (sig_index, self.const_table_elem(table_index, *index)?),
+            _ => return None,
+        };
+        (self.module.funcs[func].sig() == sig).then_some(func)
+    }
+
+    /// The result of a call to `func`: constant if it always returns
+    /// the same value.
+    fn call_result(&self, func: Func, orig_inst: Value) -> AbstractValue {
+        match self.facts.const_result_funcs.get(&func) {
+            Some(&value) => AbstractValue::Concrete(value),
+            None => AbstractValue::Runtime(Some(orig_inst)),
+        }
+    }
+
+    /// The function at the given index of a table whose contents are
+    /// fixed, if there is one.
+    fn const_table_elem(&self, table: Table, index: u32) -> Option<Func> {
+        if !self.facts.const_tables.contains(&table) {
+            return None;
+        }
+        let elems = self.module.tables[table].func_elements.as_ref()?;
+        let func = *elems.get(index as usize)?;
+        func.is_valid().then_some(func)
+    }
+
+    /// Evaluate a pure operator with at least one constant-set input
+    /// by evaluating it over every combination of constant inputs,
+    /// and merging the results.
+    fn abstract_eval_const_sets(
+        &mut self,
+        orig_inst: Value,
+        op: Operator,
+        abs: &[AbstractValue],
+        orig_values: &[Value],
+        state: &mut PointState,
+    ) -> anyhow::Result<Option<AbstractValue>> {
+        if !op.is_pure()
+            || abs.is_empty()
+            || abs.len() > 3
+            || !abs.iter().any(|a| matches!(a, AbstractValue::ConstSet(_)))
+            || abs.iter().any(|a| a.const_set_elems().is_empty())
+        {
+            return Ok(None);
+        }
+
+        let mut result = AbstractValue::Top;
+        let mut indices = vec![0; abs.len()];
+        loop {
+            let args = abs
+                .iter()
+                .zip(indices.iter())
+                .map(|(a, &i)| AbstractValue::Concrete(a.const_set_elems().get(i)))
+                .collect::<Vec<_>>();
+            let ret = match args.len() {
+                1 => self.abstract_eval_unary(orig_inst, op, &args[0], orig_values[0], state)?,
+                2 => self.abstract_eval_binary(orig_inst, op, &args[0], &args[1]),
+                3 => self.abstract_eval_ternary(orig_inst, op, &args[0], &args[1], &args[2]),
+                _ => unreachable!(),
+            };
+            if !matches!(ret, AbstractValue::Concrete(_)) {
+                return Ok(Some(AbstractValue::Runtime(Some(orig_inst))));
+            }
+            result = AbstractValue::meet(&result, &ret);
+            if let AbstractValue::Runtime(_) = result {
+                return Ok(Some(AbstractValue::Runtime(Some(orig_inst))));
+            }
 
-                // Elide the store.
-                return Ok(EvalResult::Elide);
+            // Advance to the next combination of inputs.
+            let mut i = 0;
+            while i < indices.len() {
+                indices[i] += 1;
+                if indices[i] < abs[i].const_set_elems().len() {
+                    break;
+                }
+                indices[i] = 0;
+                i += 1;
+            }
+            if i == indices.len() {
+                return Ok(Some(result));
             }
-            _ => {}
         }
-
-        Ok(EvalResult::Unhandled)
     }
 
     fn abstract_eval_nullary(
@@ -1717,6 +3405,7 @@ impl<'a> Evaluator<'a> {
             | Operator::I64Const { .. }
             | Operator::F32Const { .. }
             | Operator::F64Const { .. } => AbstractValue::Concrete(WasmVal::try_from(op).unwrap()),
+            Operator::RefFunc { func_index } => AbstractValue::FuncRef(func_index),
             _ => AbstractValue::Runtime(Some(orig_inst)),
         }
     }
@@ -1731,75 +3420,68 @@ impl<'a> Evaluator<'a> {
     ) -> anyhow::Result<AbstractValue> {
         match (op, x) {
             (Operator::GlobalSet { global_index }, av) => {
-                state.flow.globals.insert(global_index, av.clone());
+                state.flow.globals.insert(global_index, *av);
                 Ok(AbstractValue::Runtime(Some(orig_inst)))
             }
-            (Operator::I32Eqz, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(if *k == 0 {
-                    1
-                } else {
-                    0
-                })))
+            // The result of another directive, written at emission,
+            // with `--link-results`.
+            (
+                Operator::I32Load { memory },
+                AbstractValue::Concrete(WasmVal::I32(addr)) | AbstractValue::StaticMemory(addr),
+            ) if self.options.link_results
+                && addr
+                    .checked_add(memory.offset)
+                    .is_some_and(|addr| self.facts.specialization_outputs.contains_key(&addr)) =>
+            {
+                Ok(AbstractValue::SpecializedFuncIndex(addr + memory.offset))
             }
-            (Operator::I64Eqz, AbstractValue::Concrete(WasmVal::I64(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(if *k == 0 {
-                    1
-                } else {
-                    0
-                })))
+            (Operator::TableGet { table_index }, AbstractValue::Concrete(WasmVal::I32(k))) => {
+                Ok(match self.const_table_elem(table_index, *k) {
+                    Some(func) => AbstractValue::FuncRef(func),
+                    None => AbstractValue::Runtime(Some(orig_inst)),
+                })
             }
-            (Operator::I32Extend8S, AbstractValue::Concrete(WasmVal::I32(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I32(*k as i8 as i32 as u32)),
-            ),
-            (Operator::I32Extend16S, AbstractValue::Concrete(WasmVal::I32(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I32(*k as i16 as i32 as u32)),
-            ),
-            (Operator::I64Extend8S, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(*k as i8 as i64 as u64)),
-            ),
-            (Operator::I64Extend16S, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(*k as i16 as i64 as u64)),
-            ),
-            (Operator::I64Extend32S, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(*k as i32 as i64 as u64)),
-            ),
-            (Operator::I32Clz, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(k.leading_zeros())))
+            (Operator::RefIsNull, AbstractValue::FuncRef(..)) => {
+                Ok(AbstractValue::Concrete(WasmVal::I32(0)))
             }
-            (Operator::I64Clz, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(k.leading_zeros() as u64)),
-            ),
-            (Operator::I32Ctz, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(k.trailing_zeros())))
+            (Operator::I32Eqz, AbstractValue::ConcreteMemory(..)) => {
+                Ok(AbstractValue::Concrete(WasmVal::I32(0)))
             }
-            (Operator::I64Ctz, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(k.trailing_zeros() as u64)),
-            ),
-            (Operator::I32Popcnt, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(k.count_ones())))
+            (Operator::I32WrapI64, AbstractValue::ConcreteMemory(buf, off)) => {
+                Ok(AbstractValue::ConcreteMemory(*buf, *off))
             }
-            (Operator::I64Popcnt, AbstractValue::Concrete(WasmVal::I64(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I64(k.count_ones() as u64)))
+            (Operator::I64ExtendI32U, AbstractValue::ConcreteMemory(buf, off)) => {
+                Ok(AbstractValue::ConcreteMemory(*buf, *off))
             }
-            (Operator::I32WrapI64, AbstractValue::Concrete(WasmVal::I64(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(*k as u32)))
+            // A static-memory address stays one through pointer-sized
+            // (64-bit) arithmetic, so that e.g. a PC computed in i64
+            // and truncated is still known.
+            (Operator::I32WrapI64 | Operator::I64ExtendI32U, AbstractValue::StaticMemory(addr)) => {
+                Ok(AbstractValue::StaticMemory(*addr))
             }
-            (Operator::I32WrapI64, AbstractValue::ConcreteMemory(buf, off)) => {
-                Ok(AbstractValue::ConcreteMemory(buf.clone(), *off))
+            (Operator::I64ExtendI32S, AbstractValue::StaticMemory(addr)) if *addr < 0x8000_0000 => {
+                Ok(AbstractValue::StaticMemory(*addr))
             }
-            (Operator::I64ExtendI32S, AbstractValue::Concrete(WasmVal::I32(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(*k as i32 as i64 as u64)),
-            ),
-            (Operator::I64ExtendI32U, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I64(*k as u64)))
+
+            // Loads of a struct argument's non-constant fields happen
+            // at runtime.
+            (op, AbstractValue::ConcreteMemory(buf, offset))
+                if !self.is_const_memory_load(op, *buf, *offset) =>
+            {
+                Ok(AbstractValue::Runtime(Some(orig_inst)))
             }
 
-            (Operator::I32Load { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I32Load8U { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I32Load8S { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I32Load16U { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I32Load16S { memory }, AbstractValue::ConcreteMemory(buf, offset)) => {
-                log::trace!(
+            (Operator::I32Load { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I32Load8U { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I32Load8S { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I32Load16U { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I32Load16S { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I32Load { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I32Load8U { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I32Load8S { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I32Load16U { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I32Load16S { memory }, AbstractValue::StaticMemory(..)) => {
+                tracing::trace!(
                     "load of addr {:?} offset {} (orig value {}) with const_memory tag",
                     x,
                     memory.offset,
@@ -1822,25 +3504,28 @@ impl<'a> Evaluator<'a> {
                     _ => unreachable!(),
                 };
 
-                let offset = offset
-                    .checked_add(memory.offset)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid offset"))?;
-                let mem = self.directive_args.const_memory[buf.0 as usize]
-                    .as_ref()
-                    .unwrap();
-                let val = mem.read_size(offset, size)?;
+                let val = self
+                    .read_const_memory(x, memory.offset, size)
+                    .map_err(|e| self.const_load_error(e, x, memory.offset, orig_x_val))?;
                 let val = AbstractValue::Concrete(WasmVal::I32(conv(val)));
-                log::trace!(" -> produces {:?}", val);
+                tracing::trace!(" -> produces {:?}", val);
                 Ok(val)
             }
 
-            (Operator::I64Load { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I64Load8U { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I64Load8S { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I64Load16U { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I64Load16S { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I64Load32U { memory }, AbstractValue::ConcreteMemory(buf, offset))
-            | (Operator::I64Load32S { memory }, AbstractValue::ConcreteMemory(buf, offset)) => {
+            (Operator::I64Load { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I64Load8U { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I64Load8S { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I64Load16U { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I64Load16S { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I64Load32U { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I64Load32S { memory }, AbstractValue::ConcreteMemory(..))
+            | (Operator::I64Load { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I64Load8U { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I64Load8S { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I64Load16U { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I64Load16S { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I64Load32U { memory }, AbstractValue::StaticMemory(..))
+            | (Operator::I64Load32S { memory }, AbstractValue::StaticMemory(..)) => {
                 let size = match op {
                     Operator::I64Load { .. } => 8,
                     Operator::I64Load8U { .. } => 1,
@@ -1862,32 +3547,250 @@ impl<'a> Evaluator<'a> {
                     _ => unreachable!(),
                 };
 
-                let offset = offset
-                    .checked_add(memory.offset)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid offset"))?;
+                let val = self
+                    .read_const_memory(x, memory.offset, size)
+                    .map_err(|e| self.const_load_error(e, x, memory.offset, orig_x_val))?;
+                let val = AbstractValue::Concrete(WasmVal::I64(conv(val)));
+                tracing::trace!(" -> produces {:?}", val);
+                Ok(val)
+            }
+
+            (op, AbstractValue::Concrete(k)) => Ok(self.fold(orig_inst, op, &[*k])),
 
+            // A static-memory address is an ordinary constant to
+            // any other pure operator, as an i32 or (having been
+            // extended) an i64.
+            (op, AbstractValue::StaticMemory(addr)) if op.is_pure() => {
+                match self.fold(orig_inst, op, &[WasmVal::I32(*addr)]) {
+                    AbstractValue::Runtime(_) => {
+                        Ok(self.fold(orig_inst, op, &[WasmVal::I64(u64::from(*addr))]))
+                    }
+                    result => Ok(result),
+                }
+            }
+
+            // TODO: SIMD
+            _ => Ok(AbstractValue::Runtime(Some(orig_inst))),
+        }
+    }
+
+    /// Unless the module groups PCs itself with `context.bucket`, put
+    /// the PC context `context` in a bucket by the handler the first
+    /// constant `br_table` in it dispatches to: the index of the first
+    /// table entry that goes to `handler`'s block (or the table's
+    /// length, for the default), so that opcodes with one handler
+    /// share a bucket.
+    fn infer_context_bucket(&mut self, context: Context, targets: &[BlockTarget], handler: Block) {
+        let contexts = &mut self.state.contexts;
+        if self.intrinsics.context_bucket.is_some()
+            || contexts.context_bucket[context].is_some()
+            || !matches!(contexts.leaf_element(context), ContextElem::Loop(_))
+        {
+            return;
+        }
+        let bucket = targets
+            .iter()
+            .position(|target| target.block == handler)
+            .unwrap_or(targets.len());
+        tracing::trace!("inferred bucket {} for context {}", bucket, context);
+        contexts.context_bucket[context] = Some(bucket as u32);
+    }
+
+    /// The context element for a loop entered or continued at `pc`
+    /// from `context`, as a child of `parent`: the PC itself, unless it
+    /// or the loop is cold, or there are too many contexts already.
+    /// Hot PCs must be constant.
+    fn loop_context_elem(
+        &self,
+        context: Context,
+        parent: Context,
+        pc: &AbstractValue,
+        intrinsic: &str,
+    ) -> anyhow::Result<ContextElem> {
+        // Code in a cold loop stays generic until it leaves the loop.
+        if self.state.contexts.in_cold_loop(context) {
+            return Ok(ContextElem::Cold);
+        }
+        let Some(k) = pc.as_const_u32_or_mem_offset() else {
+            anyhow::bail!(WevalError::IntrinsicFailed(format!(
+                "PC at {}.context is a runtime value: {:?}",
+                intrinsic, pc
+            )));
+        };
+        let hot = self.options.hot_pcs.as_ref().is_none_or(|hot_pcs| {
+            hot_pcs.is_hot(k, || {
+                let addr = match pc {
+                    AbstractValue::ConcreteMemory(..) => *pc,
+                    _ => AbstractValue::StaticMemory(k),
+                };
+                self.read_const_memory(&addr, 0, 1).ok().map(|op| op as u8)
+            })
+        });
+        let elem = ContextElem::Loop(k);
+        if !hot {
+            tracing::debug!("cold PC {:#x}: continuing in generic dispatch", k);
+            Ok(ContextElem::Cold)
+        } else if self.max_contexts.is_some_and(|max| {
+            self.state.contexts.len() >= max && !self.state.contexts.exists(parent, &elem)
+        }) {
+            tracing::debug!(
+                "context limit at PC {:#x}: continuing in generic dispatch",
+                k
+            );
+            Ok(ContextElem::Cold)
+        } else {
+            Ok(elem)
+        }
+    }
+
+    /// Whether `op`, if a load from a directive's argument buffer at
+    /// `offset`, reads constant bytes.
+    fn is_const_memory_load(&self, op: Operator, buf: MemoryBufferIndex, offset: u32) -> bool {
+        let (memory, size) = match op {
+            Operator::I32Load { memory }
+            | Operator::I64Load32U { memory }
+            | Operator::I64Load32S { memory } => (memory, 4),
+            Operator::I32Load8U { memory }
+            | Operator::I32Load8S { memory }
+            | Operator::I64Load8U { memory }
+            | Operator::I64Load8S { memory } => (memory, 1),
+            Operator::I32Load16U { memory }
+            | Operator::I32Load16S { memory }
+            | Operator::I64Load16U { memory }
+            | Operator::I64Load16S { memory } => (memory, 2),
+            Operator::I64Load { memory } => (memory, 8),
+            _ => return true,
+        };
+        let Some(offset) = offset.checked_add(memory.offset) else {
+            return false;
+        };
+        self.directive_args.const_memory[buf.0 as usize]
+            .as_ref()
+            .is_none_or(|mem| mem.is_const(offset, size))
+    }
+
+    /// Read `size` bytes of constant memory at the given address
+    /// (a pointer into a directive's argument buffer or into static
+    /// memory in the image) plus `offset`.
+    fn read_const_memory(
+        &self,
+        addr: &AbstractValue,
+        offset: u32,
+        size: u32,
+    ) -> anyhow::Result<u64> {
+        match addr {
+            AbstractValue::ConcreteMemory(buf, buf_offset) => {
+                let offset = buf_offset
+                    .checked_add(offset)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid offset"))?;
                 let mem = self.directive_args.const_memory[buf.0 as usize]
                     .as_ref()
                     .unwrap();
-                let val = mem.read_size(offset, size)?;
-                let val = AbstractValue::Concrete(WasmVal::I64(conv(val)));
-                log::trace!(" -> produces {:?}", val);
-                Ok(val)
+                mem.read_size(offset, size)
+            }
+            AbstractValue::StaticMemory(addr) => {
+                let addr = addr
+                    .checked_add(offset)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid offset"))?;
+                self.image
+                    .read_size(self.image.main_heap()?, addr, size as u8)
             }
+            _ => unreachable!(),
+        }
+    }
 
-            (Operator::I32Load { memory }, AbstractValue::StaticMemory(addr)) => {
-                let addr = addr.checked_add(memory.offset).unwrap();
-                let val = self.image.read_u32(self.image.main_heap()?, addr)?;
-                Ok(AbstractValue::Concrete(WasmVal::I32(val)))
+    /// Describe a failed read of constant memory at `addr` plus
+    /// `offset`: the address, how the generic function computed it
+    /// from `orig_addr`, and the directive.
+    fn const_load_error(
+        &self,
+        e: anyhow::Error,
+        addr: &AbstractValue,
+        offset: u32,
+        orig_addr: Value,
+    ) -> anyhow::Error {
+        let addr = match addr {
+            AbstractValue::ConcreteMemory(buf, buf_offset) => {
+                format!("argument buffer {} + {:#x}", buf.0, buf_offset)
+            }
+            AbstractValue::StaticMemory(addr) => format!("{:#x}", addr),
+            _ => format!("{:?}", addr),
+        };
+        // The definitions the address depends on, breadth-first,
+        // skipping constants (which show up as operands).
+        let mut chain = vec![];
+        let mut queue = VecDeque::from([self.generic.resolve_alias(orig_addr)]);
+        let mut seen = HashSet::default();
+        while let Some(value) = queue.pop_front() {
+            if chain.len() == MAX_ADDR_CHAIN {
+                chain.push("...".to_owned());
+                break;
+            }
+            if !seen.insert(value) {
+                continue;
             }
-            (Operator::I64Load { memory }, AbstractValue::StaticMemory(addr)) => {
-                let addr = addr.checked_add(memory.offset).unwrap();
-                let val = self.image.read_u64(self.image.main_heap()?, addr)?;
-                Ok(AbstractValue::Concrete(WasmVal::I64(val)))
+            match &self.generic.values[value] {
+                ValueDef::Operator(op, args, _) => {
+                    let args = &self.generic.arg_pool[*args];
+                    chain.push(format!(
+                        "{} = {} {}",
+                        value,
+                        op,
+                        args.iter()
+                            .map(|arg| arg.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ));
+                    queue.extend(
+                        args.iter()
+                            .map(|&arg| self.generic.resolve_alias(arg))
+                            .filter(|&arg| {
+                                !matches!(
+                                    self.generic.values[arg],
+                                    ValueDef::Operator(op, ..) if WasmVal::try_from(op).is_ok()
+                                )
+                            }),
+                    );
+                }
+                ValueDef::BlockParam(block, i, _) => {
+                    chain.push(format!("{} = param {} of {}", value, i, block));
+                }
+                _ => {}
             }
+        }
+        let offset = match offset {
+            0 => String::new(),
+            offset => format!(" + {:#x}", offset),
+        };
+        e.context(format!(
+            "constant load of {}{} for directive with user ID {}; address computed by:\n  {}",
+            addr,
+            offset,
+            self.directive.user_id,
+            chain.join("\n  ")
+        ))
+    }
 
-            // TODO: FP and SIMD
-            _ => Ok(AbstractValue::Runtime(Some(orig_inst))),
+    /// Fold the numeric operator `op` over constants, if it does not
+    /// trap, noting folds the engine may compute differently, and
+    /// leaving those to the engine with `--deterministic-folds`.
+    fn fold(&mut self, orig_inst: Value, op: Operator, args: &[WasmVal]) -> AbstractValue {
+        match const_eval::eval(op, args) {
+            Some(result) if const_eval::is_nondeterministic(op, result) => {
+                tracing::debug!(
+                    "{} ({}) folds to NaN, whose bits the engine may choose differently",
+                    orig_inst,
+                    op
+                );
+                self.nondeterministic_folds.insert(orig_inst);
+                if self.options.deterministic_folds {
+                    AbstractValue::Runtime(Some(orig_inst))
+                } else {
+                    AbstractValue::Concrete(result)
+                }
+            }
+            Some(result) => AbstractValue::Concrete(result),
+            None => AbstractValue::Runtime(Some(orig_inst)),
         }
     }
 
@@ -1900,230 +3803,7 @@ impl<'a> Evaluator<'a> {
     ) -> AbstractValue {
         match (x, y) {
             (AbstractValue::Concrete(v1), AbstractValue::Concrete(v2)) => {
-                match (op, v1, v2) {
-                    // 32-bit comparisons.
-                    (Operator::I32Eq, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 == k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32Ne, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 != k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32LtS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i32) < (*k2 as i32) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I32LtU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 < k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32GtS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i32) > (*k2 as i32) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I32GtU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 > k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32LeS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i32) <= (*k2 as i32) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I32LeU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 <= k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32GeS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i32) >= (*k2 as i32) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I32GeU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 >= k2 { 1 } else { 0 }))
-                    }
-
-                    // 64-bit comparisons.
-                    (Operator::I64Eq, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 == k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64Ne, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 != k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64LtS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i64) < (*k2 as i64) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I64LtU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 < k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64GtS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i64) > (*k2 as i64) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I64GtU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 > k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64LeS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i64) <= (*k2 as i64) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I64LeU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 <= k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64GeS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i64) >= (*k2 as i64) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I64GeU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 >= k2 { 1 } else { 0 }))
-                    }
-
-                    // 32-bit integer arithmetic.
-                    (Operator::I32Add, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_add(*k2)))
-                    }
-                    (Operator::I32Sub, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_sub(*k2)))
-                    }
-                    (Operator::I32Mul, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_mul(*k2)))
-                    }
-                    (Operator::I32DivU, WasmVal::I32(k1), WasmVal::I32(k2)) if *k2 != 0 => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_div(*k2)))
-                    }
-                    (Operator::I32DivS, WasmVal::I32(k1), WasmVal::I32(k2))
-                        if *k2 != 0 && (*k1 != 0x8000_0000 || *k2 != 0xffff_ffff) =>
-                    {
-                        AbstractValue::Concrete(WasmVal::I32(
-                            (*k1 as i32).wrapping_div(*k2 as i32) as u32
-                        ))
-                    }
-                    (Operator::I32RemU, WasmVal::I32(k1), WasmVal::I32(k2)) if *k2 != 0 => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_rem(*k2)))
-                    }
-                    (Operator::I32RemS, WasmVal::I32(k1), WasmVal::I32(k2))
-                        if *k2 != 0 && (*k1 != 0x8000_0000 || *k2 != 0xffff_ffff) =>
-                    {
-                        AbstractValue::Concrete(WasmVal::I32(
-                            (*k1 as i32).wrapping_rem(*k2 as i32) as u32
-                        ))
-                    }
-                    (Operator::I32And, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1 & k2))
-                    }
-                    (Operator::I32Or, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1 | k2))
-                    }
-                    (Operator::I32Xor, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1 ^ k2))
-                    }
-                    (Operator::I32Shl, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_shl(k2 & 0x1f)))
-                    }
-                    (Operator::I32ShrU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_shr(k2 & 0x1f)))
-                    }
-                    (Operator::I32ShrS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(
-                            (*k1 as i32).wrapping_shr(*k2 & 0x1f) as u32
-                        ))
-                    }
-                    (Operator::I32Rotl, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        let amt = k2 & 0x1f;
-                        let result = k1.wrapping_shl(amt) | k1.wrapping_shr(32 - amt);
-                        AbstractValue::Concrete(WasmVal::I32(result))
-                    }
-                    (Operator::I32Rotr, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        let amt = k2 & 0x1f;
-                        let result = k1.wrapping_shr(amt) | k1.wrapping_shl(32 - amt);
-                        AbstractValue::Concrete(WasmVal::I32(result))
-                    }
-
-                    // 64-bit integer arithmetic.
-                    (Operator::I64Add, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_add(*k2)))
-                    }
-                    (Operator::I64Sub, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_sub(*k2)))
-                    }
-                    (Operator::I64Mul, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_mul(*k2)))
-                    }
-                    (Operator::I64DivU, WasmVal::I64(k1), WasmVal::I64(k2)) if *k2 != 0 => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_div(*k2)))
-                    }
-                    (Operator::I64DivS, WasmVal::I64(k1), WasmVal::I64(k2))
-                        if *k2 != 0
-                            && (*k1 != 0x8000_0000_0000_0000 || *k2 != 0xffff_ffff_ffff_ffff) =>
-                    {
-                        AbstractValue::Concrete(WasmVal::I64(
-                            (*k1 as i64).wrapping_div(*k2 as i64) as u64
-                        ))
-                    }
-                    (Operator::I64RemU, WasmVal::I64(k1), WasmVal::I64(k2)) if *k2 != 0 => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_rem(*k2)))
-                    }
-                    (Operator::I64RemS, WasmVal::I64(k1), WasmVal::I64(k2))
-                        if *k2 != 0
-                            && (*k1 != 0x8000_0000_0000_0000 || *k2 != 0xffff_ffff_ffff_ffff) =>
-                    {
-                        AbstractValue::Concrete(WasmVal::I64(
-                            (*k1 as i64).wrapping_rem(*k2 as i64) as u64
-                        ))
-                    }
-                    (Operator::I64And, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(*k1 & *k2))
-                    }
-                    (Operator::I64Or, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(*k1 | *k2))
-                    }
-                    (Operator::I64Xor, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(*k1 ^ *k2))
-                    }
-                    (Operator::I64Shl, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_shl((*k2 & 0x3f) as u32)))
-                    }
-                    (Operator::I64ShrU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_shr((*k2 & 0x3f) as u32)))
-                    }
-                    (Operator::I64ShrS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(
-                            (*k1 as i64).wrapping_shr((*k2 & 0x3f) as u32) as u64,
-                        ))
-                    }
-                    (Operator::I64Rotl, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        let amt = (*k2 & 0x3f) as u32;
-                        let result = k1.wrapping_shl(amt) | k1.wrapping_shr(64 - amt);
-                        AbstractValue::Concrete(WasmVal::I64(result))
-                    }
-                    (Operator::I64Rotr, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        let amt = (*k2 & 0x3f) as u32;
-                        let result = k1.wrapping_shr(amt) | k1.wrapping_shl(64 - amt);
-                        AbstractValue::Concrete(WasmVal::I64(result))
-                    }
-
-                    // TODO: FP and SIMD ops.
-                    _ => AbstractValue::Runtime(Some(orig_inst)),
-                }
+                self.fold(orig_inst, op, &[*v1, *v2])
             }
 
             // ptr OP const | const OP ptr (commutative cases)
@@ -2135,7 +3815,7 @@ impl<'a> Evaluator<'a> {
                 AbstractValue::Concrete(WasmVal::I32(k)),
                 AbstractValue::ConcreteMemory(buf, offset),
             ) if op == Operator::I32Add => {
-                AbstractValue::ConcreteMemory(buf.clone(), offset.wrapping_add(*k))
+                AbstractValue::ConcreteMemory(*buf, offset.wrapping_add(*k))
             }
             (AbstractValue::StaticMemory(addr), AbstractValue::Concrete(WasmVal::I32(k)))
             | (AbstractValue::Concrete(WasmVal::I32(k)), AbstractValue::StaticMemory(addr))
@@ -2149,7 +3829,42 @@ impl<'a> Evaluator<'a> {
                 AbstractValue::ConcreteMemory(buf, offset),
                 AbstractValue::Concrete(WasmVal::I32(k)),
             ) if op == Operator::I32Sub => {
-                AbstractValue::ConcreteMemory(buf.clone(), offset.wrapping_sub(*k))
+                AbstractValue::ConcreteMemory(*buf, offset.wrapping_sub(*k))
+            }
+            (AbstractValue::StaticMemory(addr), AbstractValue::Concrete(WasmVal::I32(k)))
+                if op == Operator::I32Sub =>
+            {
+                AbstractValue::StaticMemory(addr.wrapping_sub(*k))
+            }
+
+            // 64-bit pointers into known memory.
+            (
+                AbstractValue::ConcreteMemory(buf, offset),
+                AbstractValue::Concrete(WasmVal::I64(k)),
+            )
+            | (
+                AbstractValue::Concrete(WasmVal::I64(k)),
+                AbstractValue::ConcreteMemory(buf, offset),
+            ) if op == Operator::I64Add => {
+                AbstractValue::ConcreteMemory(*buf, offset.wrapping_add(*k as u32))
+            }
+            (
+                AbstractValue::ConcreteMemory(buf, offset),
+                AbstractValue::Concrete(WasmVal::I64(k)),
+            ) if op == Operator::I64Sub => {
+                AbstractValue::ConcreteMemory(*buf, offset.wrapping_sub(*k as u32))
+            }
+
+            (AbstractValue::StaticMemory(addr), AbstractValue::Concrete(WasmVal::I64(k)))
+            | (AbstractValue::Concrete(WasmVal::I64(k)), AbstractValue::StaticMemory(addr))
+                if op == Operator::I64Add =>
+            {
+                static_memory_64(u64::from(*addr).wrapping_add(*k))
+            }
+            (AbstractValue::StaticMemory(addr), AbstractValue::Concrete(WasmVal::I64(k)))
+                if op == Operator::I64Sub =>
+            {
+                static_memory_64(u64::from(*addr).wrapping_sub(*k))
             }
 
             // ptr OP ptr
@@ -2159,6 +3874,58 @@ impl<'a> Evaluator<'a> {
             ) if op == Operator::I32Sub && buf1 == buf2 => {
                 AbstractValue::Concrete(WasmVal::I32(offset1.wrapping_sub(*offset2)))
             }
+            (
+                AbstractValue::ConcreteMemory(buf1, offset1),
+                AbstractValue::ConcreteMemory(buf2, offset2),
+            ) if op == Operator::I64Sub && buf1 == buf2 => AbstractValue::Concrete(WasmVal::I64(
+                u64::from(*offset1).wrapping_sub(u64::from(*offset2)),
+            )),
+            // Pointers into the same buffer compare by offset. Pointers
+            // into distinct buffers are left alone: a directive may
+            // pass the same or overlapping memory as two arguments.
+            (
+                AbstractValue::ConcreteMemory(buf1, offset1),
+                AbstractValue::ConcreteMemory(buf2, offset2),
+            ) if buf1 == buf2 => {
+                let result = match op {
+                    Operator::I32Eq | Operator::I64Eq => Some(offset1 == offset2),
+                    Operator::I32Ne | Operator::I64Ne => Some(offset1 != offset2),
+                    Operator::I32LtU | Operator::I64LtU => Some(offset1 < offset2),
+                    Operator::I32LeU | Operator::I64LeU => Some(offset1 <= offset2),
+                    Operator::I32GtU | Operator::I64GtU => Some(offset1 > offset2),
+                    Operator::I32GeU | Operator::I64GeU => Some(offset1 >= offset2),
+                    _ => None,
+                };
+                match result {
+                    Some(result) => AbstractValue::Concrete(WasmVal::I32(result as u32)),
+                    None => AbstractValue::Runtime(Some(orig_inst)),
+                }
+            }
+
+            // Null checks on pointers into known memory.
+            (AbstractValue::ConcreteMemory(..), AbstractValue::Concrete(WasmVal::I32(0)))
+            | (AbstractValue::Concrete(WasmVal::I32(0)), AbstractValue::ConcreteMemory(..))
+                if op == Operator::I32Eq || op == Operator::I32Ne =>
+            {
+                AbstractValue::Concrete(WasmVal::I32((op == Operator::I32Ne) as u32))
+            }
+
+            // Otherwise, a static-memory address is an ordinary
+            // constant (e.g. for masking or shifting, or for
+            // differences between two static addresses), of the other
+            // operand's width.
+            (AbstractValue::StaticMemory(addr), y) => self.abstract_eval_binary(
+                orig_inst,
+                op,
+                &AbstractValue::Concrete(static_memory_const(*addr, y)),
+                y,
+            ),
+            (x, AbstractValue::StaticMemory(addr)) => self.abstract_eval_binary(
+                orig_inst,
+                op,
+                x,
+                &AbstractValue::Concrete(static_memory_const(*addr, x)),
+            ),
 
             _ => AbstractValue::Runtime(Some(orig_inst)),
         }
@@ -2176,18 +3943,101 @@ impl<'a> Evaluator<'a> {
             (Operator::Select, AbstractValue::Concrete(v))
             | (Operator::TypedSelect { .. }, AbstractValue::Concrete(v)) => {
                 if v.is_truthy() {
-                    x.clone()
+                    *x
                 } else {
-                    y.clone()
+                    *y
+                }
+            }
+            (Operator::Select, cond @ AbstractValue::ConstSet(_))
+            | (Operator::TypedSelect { .. }, cond @ AbstractValue::ConstSet(_)) => {
+                match cond.as_const_truthy() {
+                    Some(true) => *x,
+                    Some(false) => *y,
+                    None => AbstractValue::Runtime(Some(orig_inst)),
                 }
             }
             // Concrete-memory symbolic pointers are always truthy.
             (Operator::Select, AbstractValue::ConcreteMemory(..))
-            | (Operator::TypedSelect { .. }, AbstractValue::ConcreteMemory(..)) => x.clone(),
+            | (Operator::TypedSelect { .. }, AbstractValue::ConcreteMemory(..)) => *x,
+            // With a runtime condition, the result is still anything
+            // both sides agree on.
+            (Operator::Select, _) | (Operator::TypedSelect { .. }, _) => {
+                match AbstractValue::meet(x, y) {
+                    AbstractValue::Top | AbstractValue::Runtime(_) => {
+                        AbstractValue::Runtime(Some(orig_inst))
+                    }
+                    av => av,
+                }
+            }
             _ => AbstractValue::Runtime(Some(orig_inst)),
         }
     }
 
+    fn record_call(
+        &mut self,
+        context: Context,
+        orig_inst: Value,
+        kind: &'static str,
+        callee: Option<Func>,
+    ) {
+        if let Some(decisions) = &mut self.call_decisions {
+            decisions.insert((context, orig_inst), (kind, callee));
+        }
+    }
+
+    /// The `--emit-facts` relations for this directive, as evaluated.
+    fn facts(&self) -> Relations {
+        let d = self.directive.user_id;
+        let mut relations = Relations::default();
+        relations.push("directive", &[&d, &self.directive.func]);
+        let contexts = &self.state.contexts;
+        for ctx in contexts.iter() {
+            let elem = crate::dot::context_elem(contexts, ctx);
+            relations.push("context", &[&d, &ctx.index(), &elem]);
+            let parent = contexts.parent(ctx);
+            if parent.is_valid() {
+                relations.push("context_parent", &[&d, &ctx.index(), &parent.index()]);
+            }
+        }
+        for (&(ctx, generic), &block) in &self.block_map {
+            relations.push("block", &[&d, &ctx.index(), &generic, &block]);
+            let entry = &self.state.block_entry[block];
+            let mut overlay = |slot: &dyn std::fmt::Display, value: &RegValue| {
+                let data = value
+                    .value()
+                    .map_or_else(|| "merge".to_owned(), |v| v.to_string());
+                let abs = format!("{:?}", value.abs());
+                relations.push("overlay", &[&d, &block, slot, &data, &abs]);
+            };
+            for (slot, value) in &entry.regs {
+                overlay(&format!("{:?}", slot), value);
+            }
+            for (i, (addr, data)) in entry.stack.iter().enumerate() {
+                overlay(&format!("{:?}", RegSlot::StackAddr(i as u32)), addr);
+                overlay(&format!("{:?}", RegSlot::StackData(i as u32)), data);
+            }
+            for (&i, (addr, data)) in &entry.locals {
+                overlay(&format!("{:?}", RegSlot::LocalAddr(i)), addr);
+                overlay(&format!("{:?}", RegSlot::LocalData(i)), data);
+            }
+            for (global, abs) in &entry.globals {
+                let abs = format!("{:?}", abs);
+                relations.push("overlay", &[&d, &block, global, &"-", &abs]);
+            }
+        }
+        for (&(ctx, generic), &value) in &self.value_map {
+            let abs = format!("{:?}", self.state.values[value]);
+            relations.push("value", &[&d, &ctx.index(), &generic, &value, &abs]);
+        }
+        if let Some(decisions) = &self.call_decisions {
+            for (&(ctx, inst), &(kind, callee)) in decisions {
+                let callee = callee.map_or_else(|| "-".to_owned(), |f| f.to_string());
+                relations.push("call", &[&d, &ctx.index(), &inst, &kind, &callee]);
+            }
+        }
+        relations
+    }
+
     fn add_blockparam_reg_args(&mut self) -> anyhow::Result<()> {
         // Examine regs in block input state of each
         // specialized block, and create blockparams for all values
@@ -2275,13 +4125,13 @@ impl<'a> Evaluator<'a> {
             for i in succ_min_depth..pred_depth {
                 let addr = pred_state.stack[i].0.value().unwrap();
                 let data = pred_state.stack[i].1.value().unwrap();
-                log::trace!(
+                tracing::trace!(
                     "spilling {} back to real stack memory: addr {} data {}",
                     i,
                     addr,
                     data
                 );
-                self.func.add_op(
+                let store = self.func.add_op(
                     block,
                     Operator::I64Store {
                         memory: MemoryArg {
@@ -2293,6 +4143,7 @@ impl<'a> Evaluator<'a> {
                     &[addr, data],
                     &[],
                 );
+                self.flush_stores.insert(store);
             }
 
             let locals_to_sync = pred_state
@@ -2310,13 +4161,13 @@ impl<'a> Evaluator<'a> {
                 let (addr, data) = pred_state.locals.get(&local).unwrap();
                 let addr = addr.value().unwrap();
                 let data = data.value().unwrap();
-                log::trace!(
+                tracing::trace!(
                     "spilling local {} back to real locals memory: addr {} data {}",
                     local,
                     addr,
                     data
                 );
-                self.func.add_op(
+                let store = self.func.add_op(
                     block,
                     Operator::I64Store {
                         memory: MemoryArg {
@@ -2328,6 +4179,7 @@ impl<'a> Evaluator<'a> {
                     &[addr, data],
                     &[],
                 );
+                self.flush_stores.insert(store);
             }
         }
     }
@@ -2389,6 +4241,72 @@ impl<'a> Evaluator<'a> {
         pre_entry
     }
 
+    /// Render the specialized body for `--output-ir`, annotated with
+    /// liveness, the flow-sensitive state at each block entry, and the
+    /// final abstract value of each value.
+    fn annotated_ir(&self) -> String {
+        use std::fmt::Write;
+        let cfg = CFGInfo::new(&self.func);
+        let liveness = Liveness::new(&self.func, &cfg);
+        let mut s = String::new();
+        writeln!(&mut s, "# Liveness:").unwrap();
+        for (block, _) in self.func.blocks.entries() {
+            let mut live = liveness.block_start[block]
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>();
+            live.sort();
+            writeln!(&mut s, "# {}: {:?}", block, live).unwrap();
+        }
+        writeln!(&mut s).unwrap();
+
+        writeln!(&mut s, "# Block-entry state:").unwrap();
+        for &block in cfg.rpo.values() {
+            writeln!(
+                &mut s,
+                "# {}: {}",
+                block,
+                self.state.block_entry[block].summary()
+            )
+            .unwrap();
+        }
+        writeln!(&mut s).unwrap();
+
+        writeln!(&mut s, "# Abstract values:").unwrap();
+        for &block in cfg.rpo.values() {
+            let block_def = &self.func.blocks[block];
+            let values = block_def
+                .params
+                .iter()
+                .map(|&(_, param)| param)
+                .chain(block_def.insts.iter().cloned());
+            for value in values {
+                match &self.state.values[value] {
+                    AbstractValue::Top => {}
+                    abs @ AbstractValue::Runtime(_) => {
+                        writeln!(&mut s, "# {}: {:?}", value, abs).unwrap()
+                    }
+                    abs => {
+                        let origin = match self.state.origins[value].for_use() {
+                            ConstOrigin::Module => "module",
+                            _ => "directive",
+                        };
+                        writeln!(&mut s, "# {}: {:?} [{}]", value, abs, origin).unwrap()
+                    }
+                }
+            }
+        }
+        writeln!(&mut s).unwrap();
+
+        writeln!(
+            &mut s,
+            "{}",
+            self.func.display_verbose("", Some(self.module))
+        )
+        .unwrap();
+        s
+    }
+
     fn finalize(&mut self) -> anyhow::Result<()> {
         self.func.recompute_edges();
 
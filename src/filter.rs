@@ -1,7 +1,8 @@
 //! Final filter pass to remove intrinsics imports and calls to intrinsics.
 //!
 //! Needs to do a few things:
-//! - Remove any imports from a "weval" module.
+//! - Remove any imports from a "weval" module, unless keeping them so
+//!   that the output can be wevaled again.
 //! - Track how removing those imports renumbers other import and
 //!   function indices, and rewrite function indices in the code (`call`
 //!   instructions) and in table initializers.
@@ -12,8 +13,30 @@
 //!   - If a return value, then the first arg is returned. Assert that types
 //!     match accordingly. Generate a drop (`0x1a`) for all remaining args.
 //!   - Otherwise, if any args, generate drops for all args.
-
-use fxhash::FxHashMap;
+//! - Add two globals for the `{read,write}.global.{0,1}` intrinsics, if
+//!   any are removed.
+//! - Remove any `build_id` custom section; the input's ID does not
+//!   identify the output.
+//! - Optionally, append a `weval.pressure` custom section with
+//!   register-pressure hints for specialized functions, by their final
+//!   function indices. It holds a count, then per function the function
+//!   index, the number of blocks, the largest number of values live at
+//!   a block start, and the sum over blocks of values live at their
+//!   start, all as unsigned LEB128s. Hints from an earlier weval run
+//!   (in the input's section) are carried over.
+//! - Compact the locals of specialized functions (see
+//!   `compact_locals`), rewriting their `local.*` instructions.
+//!
+//! Renumbering mistakes here silently call the wrong host function, so
+//! the output is then cross-checked against the input: it must have
+//! the input's imports, less the removed intrinsics, in the same order
+//! and with the same types, and every `call`, `return_call` and
+//! `ref.func` in every function must refer to the same import (by
+//! name) or defined function as in the input.
+
+use crate::error::WevalError;
+use crate::liveness::PressureHint;
+use fxhash::{FxHashMap, FxHashSet};
 use waffle::wasmparser::{
     ElementItems, ElementKind, ExternalKind, KnownCustom, Parser, Payload, TypeRef, ValType,
 };
@@ -38,8 +61,26 @@ impl FuncRemap {
 struct Rewrite {
     func_remap: FxHashMap<u32, FuncRemap>,
     func_types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    pressure: Vec<(u32, PressureHint)>,
+    keep_intrinsics: bool,
+    /// Functions whose locals to compact, by original index.
+    compact: FxHashSet<u32>,
+    /// The type of each function defined in the module.
+    defined_func_types: Vec<u32>,
+    /// The original index of the first function defined in the module.
+    first_defined_func: u32,
+}
+
+/// Whether an intrinsic is polyfilled with the globals the filter adds.
+fn uses_weval_globals(name: &str) -> bool {
+    matches!(
+        name,
+        "read.global.0" | "read.global.1" | "write.global.0" | "write.global.1"
+    )
 }
 
+const PRESSURE_SECTION_NAME: &str = "weval.pressure";
+
 fn gen_replacement_bytecode(
     args: &[ValType],
     results: &[ValType],
@@ -114,7 +155,29 @@ fn parser_to_encoder_ty(ty: wasmparser::ValType) -> wasm_encoder::ValType {
 }
 
 impl Rewrite {
-    pub(crate) fn process(mut self, module: &[u8]) -> anyhow::Result<Vec<u8>> {
+    /// Add the hints in an input's `weval.pressure` section for
+    /// functions that have none from this run.
+    fn read_pressure_section(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut reader =
+            wasmparser::BinaryReader::new(data, 0, wasmparser::WasmFeatures::default());
+        for _ in 0..reader.read_var_u32()? {
+            let func = reader.read_var_u32()?;
+            let hint = PressureHint {
+                blocks: reader.read_var_u32()?,
+                max_live: reader.read_var_u32()?,
+                total_live: reader.read_var_u32()?,
+            };
+            if !self.pressure.iter().any(|&(f, _)| f == func) {
+                self.pressure.push((func, hint));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn process(
+        mut self,
+        module: &[u8],
+    ) -> anyhow::Result<(Vec<u8>, FxHashMap<u32, u32>)> {
         let parser = Parser::new(0);
         let mut out = wasm_encoder::Module::new();
         let mut orig_func_idx = 0;
@@ -123,11 +186,20 @@ impl Rewrite {
         let mut num_funcs_emitted = 0;
         let mut out_code_section = wasm_encoder::CodeSection::new();
         let mut weval_globals = 0;
+        let mut add_weval_globals = false;
 
-        // Scan globals section once to count globals so that we know
-        // the indices of the new globals that we add.
+        // Scan the imports and globals once to count globals so that we
+        // know the indices of the new globals that we add, if any.
         for payload in parser.clone().parse_all(module) {
             match payload? {
+                Payload::ImportSection(imports) if !self.keep_intrinsics => {
+                    for import in imports {
+                        let import = import?;
+                        if import.module == "weval" && uses_weval_globals(import.name) {
+                            add_weval_globals = true;
+                        }
+                    }
+                }
                 Payload::GlobalSection(globals) => {
                     weval_globals += globals.count();
                     break;
@@ -166,7 +238,7 @@ impl Rewrite {
                                 let orig_idx = orig_func_idx;
                                 orig_func_idx += 1;
 
-                                if import.module == "weval" {
+                                if import.module == "weval" && !self.keep_intrinsics {
                                     // Omit the import, and add a rewriting to the func_remap info.
                                     let (args, results) = &self.func_types[fty as usize];
                                     let bytecode = gen_replacement_bytecode(
@@ -189,7 +261,10 @@ impl Rewrite {
                                     out_func_idx += 1;
                                 }
                             }
-                            ty => anyhow::bail!("import type {:?} not supported", ty),
+                            ty => anyhow::bail!(WevalError::Unsupported(format!(
+                                "import type {:?} not supported",
+                                ty
+                            ))),
                         }
                     }
 
@@ -243,7 +318,7 @@ impl Rewrite {
                         out_globals.global(ty, &init_expr);
                     }
 
-                    for _ in 0..2 {
+                    for _ in 0..if add_weval_globals { 2 } else { 0 } {
                         out_globals.global(
                             wasm_encoder::GlobalType {
                                 val_type: wasm_encoder::ValType::I64,
@@ -259,8 +334,9 @@ impl Rewrite {
                 }
 
                 Payload::FunctionSection(funcs) => {
+                    self.first_defined_func = orig_func_idx;
                     for fty in funcs {
-                        let _fty = fty?;
+                        self.defined_func_types.push(fty?);
                         let orig_idx = orig_func_idx;
                         orig_func_idx += 1;
                         let out_idx = out_func_idx;
@@ -360,18 +436,23 @@ impl Rewrite {
                                                 const_expr =
                                                     Some(wasm_encoder::ConstExpr::i32_const(value));
                                             } else {
-                                                anyhow::bail!("More than one i32const in active table elem expr");
+                                                anyhow::bail!(WevalError::Unsupported(
+                                                    "more than one i32const in active table elem expr".to_owned()
+                                                ));
                                             }
                                         }
                                         wasmparser::Operator::End => {}
-                                        _ => anyhow::bail!(
+                                        _ => anyhow::bail!(WevalError::Unsupported(
                                             "unexpected operator in active table elem expr"
-                                        ),
+                                                .to_owned()
+                                        )),
                                     }
                                 }
                                 out_elements.active(table_index, &const_expr.unwrap(), out_items);
                             }
-                            _ => panic!("Unsupported element kind for element section"),
+                            _ => anyhow::bail!(WevalError::Unsupported(
+                                "element kind in element section".to_owned()
+                            )),
                         }
                     }
 
@@ -391,11 +472,33 @@ impl Rewrite {
                     // as the functions don't actually exist at
                     // runtime post-wevaling.)
 
+                    let orig_idx = self.first_defined_func + num_funcs_emitted;
+                    let compaction = if self.compact.contains(&orig_idx) {
+                        let fty = self.defined_func_types[num_funcs_emitted as usize];
+                        let num_params = self.func_types[fty as usize].0.len() as u32;
+                        let compaction = crate::compact_locals::compact(&code, num_params)?;
+                        tracing::debug!(
+                            "func {}: compacted {} locals to {}",
+                            orig_idx,
+                            compaction.before,
+                            compaction.decls.iter().map(|&(n, _)| n).sum::<u32>()
+                        );
+                        Some(compaction)
+                    } else {
+                        None
+                    };
+
                     let mut locals = vec![];
-                    for local in code.get_locals_reader()? {
-                        let (count, ty) = local?;
-                        let ty = parser_to_encoder_ty(ty);
-                        locals.push((count, ty));
+                    if let Some(compaction) = &compaction {
+                        for &(count, ty) in &compaction.decls {
+                            locals.push((count, parser_to_encoder_ty(ty)));
+                        }
+                    } else {
+                        for local in code.get_locals_reader()? {
+                            let (count, ty) = local?;
+                            let ty = parser_to_encoder_ty(ty);
+                            locals.push((count, ty));
+                        }
                     }
 
                     let mut func = wasm_encoder::Function::new(locals);
@@ -438,6 +541,27 @@ impl Rewrite {
                                 }
                                 true
                             }
+                            wasmparser::Operator::LocalGet { local_index }
+                                if compaction.is_some() =>
+                            {
+                                let local = compaction.as_ref().unwrap().local(local_index);
+                                func.instruction(&wasm_encoder::Instruction::LocalGet(local));
+                                true
+                            }
+                            wasmparser::Operator::LocalSet { local_index }
+                                if compaction.is_some() =>
+                            {
+                                let local = compaction.as_ref().unwrap().local(local_index);
+                                func.instruction(&wasm_encoder::Instruction::LocalSet(local));
+                                true
+                            }
+                            wasmparser::Operator::LocalTee { local_index }
+                                if compaction.is_some() =>
+                            {
+                                let local = compaction.as_ref().unwrap().local(local_index);
+                                func.instruction(&wasm_encoder::Instruction::LocalTee(local));
+                                true
+                            }
                             wasmparser::Operator::RefFunc { function_index }
                                 if self
                                     .func_remap
@@ -446,7 +570,9 @@ impl Rewrite {
                                     .as_index()
                                     .is_err() =>
                             {
-                                anyhow::bail!("ref.func taken of intrinsic");
+                                anyhow::bail!(WevalError::Unsupported(
+                                    "ref.func taken of intrinsic".to_owned()
+                                ));
                             }
                             _ => false,
                         };
@@ -489,7 +615,13 @@ impl Rewrite {
                         out.section(&names);
                         false
                     }
-                    _ => true,
+                    // Rewritten below, with this run's hints.
+                    _ if reader.name() == PRESSURE_SECTION_NAME => {
+                        self.read_pressure_section(reader.data())?;
+                        false
+                    }
+                    // The caller writes a new build ID, if any.
+                    _ => reader.name() != crate::build_id::SECTION_NAME,
                 },
                 _ => true,
             };
@@ -503,11 +635,171 @@ impl Rewrite {
             }
         }
 
-        Ok(out.finish())
+        if !self.pressure.is_empty() {
+            use wasm_encoder::Encode;
+            let mut data = vec![];
+            (self.pressure.len() as u32).encode(&mut data);
+            for &(func, hint) in &self.pressure {
+                self.func_remap
+                    .get(&func)
+                    .unwrap()
+                    .as_index()?
+                    .encode(&mut data);
+                hint.blocks.encode(&mut data);
+                hint.max_live.encode(&mut data);
+                hint.total_live.encode(&mut data);
+            }
+            out.section(&wasm_encoder::CustomSection {
+                name: PRESSURE_SECTION_NAME.into(),
+                data: data.into(),
+            });
+        }
+
+        let func_indices = self
+            .func_remap
+            .iter()
+            .filter_map(|(&orig, remap)| match remap {
+                FuncRemap::Index(new) => Some((orig, *new)),
+                FuncRemap::InlinedBytecode(_) => None,
+            })
+            .collect();
+        Ok((out.finish(), func_indices))
     }
 }
 
-pub(crate) fn filter(module: &[u8]) -> anyhow::Result<Vec<u8>> {
-    let rewrite = Rewrite::default();
-    rewrite.process(module)
+/// Filter the module, appending register-pressure hints for the given
+/// functions (by index before filtering), if any, compacting the
+/// locals of the given functions (likewise), and keeping the
+/// intrinsics if asked to. Also returns the new index of every
+/// function that was kept.
+pub(crate) fn filter(
+    module: &[u8],
+    pressure: Vec<(u32, PressureHint)>,
+    compact: FxHashSet<u32>,
+    keep_intrinsics: bool,
+) -> anyhow::Result<(Vec<u8>, FxHashMap<u32, u32>)> {
+    let rewrite = Rewrite {
+        pressure,
+        compact,
+        keep_intrinsics,
+        ..Rewrite::default()
+    };
+    let (out, func_indices) = rewrite.process(module)?;
+    check_imports(module, &out, keep_intrinsics)?;
+    Ok((out, func_indices))
+}
+
+/// What a `call`, `return_call` or `ref.func` refers to, independent of
+/// function numbering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Callee<'a> {
+    Import(&'a str, &'a str),
+    /// A function defined in the module, by its index among those.
+    Defined(u32),
+    /// A removed intrinsic.
+    Intrinsic,
+}
+
+/// The function imports of a module, with their types, and the callees
+/// of each function defined in it, in order. (The filter rejects other
+/// imports.)
+#[derive(Default)]
+struct CallSites<'a> {
+    imports: Vec<(&'a str, &'a str, u32)>,
+    funcs: Vec<Vec<Callee<'a>>>,
+}
+
+impl<'a> CallSites<'a> {
+    /// Read the call sites of `module`, treating intrinsic imports as
+    /// removed unless keeping them.
+    fn read(module: &'a [u8], keep_intrinsics: bool) -> anyhow::Result<Self> {
+        let mut sites = Self::default();
+        let mut func_imports = vec![];
+        for payload in Parser::new(0).parse_all(module) {
+            match payload? {
+                Payload::ImportSection(imports) => {
+                    for import in imports {
+                        let import = import?;
+                        let TypeRef::Func(ty) = import.ty else {
+                            continue;
+                        };
+                        if import.module == "weval" && !keep_intrinsics {
+                            func_imports.push(Callee::Intrinsic);
+                        } else {
+                            func_imports.push(Callee::Import(import.module, import.name));
+                            sites.imports.push((import.module, import.name, ty));
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(code) => {
+                    let mut callees = vec![];
+                    for op in code.get_operators_reader()? {
+                        let (wasmparser::Operator::Call { function_index }
+                        | wasmparser::Operator::ReturnCall { function_index }
+                        | wasmparser::Operator::RefFunc { function_index }) = op?
+                        else {
+                            continue;
+                        };
+                        let callee = match func_imports.get(function_index as usize) {
+                            Some(&callee) => callee,
+                            None => Callee::Defined(function_index - func_imports.len() as u32),
+                        };
+                        if callee != Callee::Intrinsic {
+                            callees.push(callee);
+                        }
+                    }
+                    sites.funcs.push(callees);
+                }
+                _ => {}
+            }
+        }
+        Ok(sites)
+    }
+}
+
+/// Check that filtering `input` into `output` kept its other imports
+/// and the targets of all its calls.
+fn check_imports(input: &[u8], output: &[u8], keep_intrinsics: bool) -> anyhow::Result<()> {
+    let before = CallSites::read(input, keep_intrinsics)?;
+    let after = CallSites::read(output, true)?;
+    let invariant = |message: String| WevalError::InternalInvariant(message);
+    if before.imports.len() != after.imports.len() {
+        anyhow::bail!(invariant(format!(
+            "filter kept {} of {} imports to keep",
+            after.imports.len(),
+            before.imports.len()
+        )));
+    }
+    for (i, (b, a)) in before.imports.iter().zip(&after.imports).enumerate() {
+        if b != a {
+            anyhow::bail!(invariant(format!(
+                "filter changed import {} from {:?} to {:?}",
+                i, b, a
+            )));
+        }
+    }
+    if before.funcs.len() != after.funcs.len() {
+        anyhow::bail!(invariant(format!(
+            "filter changed the number of function bodies from {} to {}",
+            before.funcs.len(),
+            after.funcs.len()
+        )));
+    }
+    for (i, (b, a)) in before.funcs.iter().zip(&after.funcs).enumerate() {
+        if let Some((site, (b, a))) = b.iter().zip(a).enumerate().find(|(_, (b, a))| b != a) {
+            anyhow::bail!(invariant(format!(
+                "filter changed the target of call {} in defined function {} from {:?} to {:?}",
+                site, i, b, a
+            )));
+        }
+        if b.len() != a.len() {
+            anyhow::bail!(invariant(format!(
+                "filter changed the number of calls in defined function {} from {} to {}",
+                i,
+                b.len(),
+                a.len()
+            )));
+        }
+    }
+    Ok(())
 }
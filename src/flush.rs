@@ -0,0 +1,189 @@
+//! Elision of dead flush stores.
+//!
+//! Syncing the virtual stack and locals (at `weval_sync_stack()` and
+//! on edges to blocks with a shallower virtual stack) writes every
+//! entry back to memory. Often a slot is written again before anything
+//! can observe the first write, e.g. when the sync precedes a call to
+//! a helper that does not read memory and the next sync rewrites the
+//! same slots. This pass removes such flush stores, using a backward
+//! analysis of which slots are certainly overwritten before being
+//! read.
+//!
+//! A slot is the eight bytes at an address value plus a constant
+//! offset. A load through the same address value at a disjoint offset
+//! does not read it; any other load, a call to a function that may
+//! read memory, or a return (the caller may read the frame) may.
+
+use fxhash::FxHashSet;
+use waffle::cfg::CFGInfo;
+use waffle::entity::PerEntity;
+use waffle::{
+    Block, Func, FuncDecl, FunctionBody, Memory, MemoryArg, Module, Operator, SideEffect,
+    Terminator, Value, ValueDef,
+};
+
+/// Largest function body, in bytes of bytecode, that we scan to see
+/// whether it reads memory.
+const MAX_SCANNED_BODY_SIZE: usize = 512;
+
+/// A slot: memory, address value and offset.
+type Slot = (Memory, Value, u32);
+
+/// Slots certainly overwritten before being read, or `None` for all
+/// slots (nothing is ever read, e.g. before an `unreachable`).
+type Overwritten = Option<FxHashSet<Slot>>;
+
+fn memory_arg(op: &Operator) -> Option<MemoryArg> {
+    let mut memory = None;
+    let mut op = *op;
+    op.update_memory_arg(|arg| memory = Some(*arg));
+    memory
+}
+
+fn may_read_memory(op: &Operator) -> bool {
+    op.effects()
+        .iter()
+        .any(|effect| matches!(effect, SideEffect::ReadMem | SideEffect::All))
+}
+
+/// Small functions that never read memory (and make no calls).
+pub(crate) fn memory_free_funcs(module: &Module) -> FxHashSet<Func> {
+    module
+        .funcs
+        .entries()
+        .filter_map(|(func, decl)| {
+            match decl {
+                FuncDecl::Lazy(_, _, body) if body.range().len() <= MAX_SCANNED_BODY_SIZE => {}
+                _ => return None,
+            }
+            let body = module.clone_and_expand_body(func).ok()?;
+            let reads = body.blocks.values().any(|block| {
+                block.insts.iter().any(|&inst| match &body.values[inst] {
+                    ValueDef::Operator(op, _, _) => may_read_memory(op),
+                    _ => false,
+                })
+            });
+            (!reads).then_some(func)
+        })
+        .collect()
+}
+
+/// The slot a store writes, if it writes a whole slot.
+fn store_slot(func: &FunctionBody, inst: Value) -> Option<Slot> {
+    match &func.values[inst] {
+        ValueDef::Operator(Operator::I64Store { memory }, args, _) => Some((
+            memory.memory,
+            func.resolve_alias(func.arg_pool[*args][0]),
+            memory.offset,
+        )),
+        _ => None,
+    }
+}
+
+/// Update `overwritten` backward over one instruction.
+fn transfer(
+    func: &FunctionBody,
+    inst: Value,
+    memory_free: &FxHashSet<Func>,
+    overwritten: &mut Overwritten,
+) {
+    let ValueDef::Operator(op, args, _) = &func.values[inst] else {
+        return;
+    };
+    match op {
+        Operator::I64Store { .. } => {
+            if let (Some(set), Some(slot)) = (overwritten, store_slot(func, inst)) {
+                set.insert(slot);
+            }
+        }
+        Operator::Call { function_index } if memory_free.contains(function_index) => {}
+        op if op.is_load() => {
+            let (memory, size) = match (memory_arg(op), crate::escape::access_size(op)) {
+                (Some(memory), Some(size)) => (memory, size as u32),
+                _ => {
+                    *overwritten = Some(FxHashSet::default());
+                    return;
+                }
+            };
+            let addr = func.resolve_alias(func.arg_pool[*args][0]);
+            let set = overwritten.get_or_insert_with(FxHashSet::default);
+            set.retain(|&(slot_memory, slot_addr, slot_offset)| {
+                slot_memory != memory.memory
+                    || (slot_addr == addr
+                        && (slot_offset.saturating_add(8) <= memory.offset
+                            || memory.offset.saturating_add(size) <= slot_offset))
+            });
+        }
+        op if may_read_memory(op) => {
+            *overwritten = Some(FxHashSet::default());
+        }
+        _ => {}
+    }
+}
+
+fn meet(a: &Overwritten, b: &Overwritten) -> Overwritten {
+    match (a, b) {
+        (None, x) | (x, None) => x.clone(),
+        (Some(a), Some(b)) => Some(a.intersection(b).copied().collect()),
+    }
+}
+
+fn block_end(
+    func: &FunctionBody,
+    block_start: &PerEntity<Block, Overwritten>,
+    block: Block,
+) -> Overwritten {
+    if let Terminator::Return { .. } = &func.blocks[block].terminator {
+        return Some(FxHashSet::default());
+    }
+    let mut end = None;
+    func.blocks[block].terminator.visit_successors(|succ| {
+        end = meet(&end, &block_start[succ]);
+    });
+    end
+}
+
+/// Remove the stores in `flush_stores` whose slot is overwritten
+/// before it can be read. Returns the number of stores removed.
+pub(crate) fn run(
+    func: &mut FunctionBody,
+    cfg: &CFGInfo,
+    flush_stores: &FxHashSet<Value>,
+    memory_free: &FxHashSet<Func>,
+) -> usize {
+    // Backward fixpoint over reachable blocks: slots overwritten on
+    // every path from the start of each block.
+    let mut block_start: PerEntity<Block, Overwritten> = PerEntity::default();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in cfg.rpo.values().rev() {
+            let mut overwritten = block_end(func, &block_start, block);
+            for &inst in func.blocks[block].insts.iter().rev() {
+                transfer(func, inst, memory_free, &mut overwritten);
+            }
+            if overwritten != block_start[block] {
+                block_start[block] = overwritten;
+                changed = true;
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for &block in cfg.rpo.values() {
+        let mut overwritten = block_end(func, &block_start, block);
+        let mut dead = FxHashSet::default();
+        for &inst in func.blocks[block].insts.iter().rev() {
+            if let (true, Some(slot)) = (flush_stores.contains(&inst), store_slot(func, inst)) {
+                if overwritten.as_ref().is_none_or(|set| set.contains(&slot)) {
+                    tracing::trace!("flush store {} to {:?} is dead", inst, slot);
+                    dead.insert(inst);
+                }
+            }
+            transfer(func, inst, memory_free, &mut overwritten);
+        }
+        removed += dead.len();
+        func.blocks[block].insts.retain(|inst| !dead.contains(inst));
+    }
+    removed
+}
@@ -0,0 +1,484 @@
+//! Differential fuzzing of specialized modules.
+//!
+//! `weval fuzz` checks that a specialized module behaves like the
+//! generic module it was made from. For each entry point, it calls the
+//! entry with random arguments in a fresh instance of each module, and
+//! compares what the calls return (or that both trap, the same way)
+//! and, unless `--results-only`, the memory afterward. Bytes whose
+//! initial contents differ between the two modules, such as the
+//! pending-request list and the results weval wrote, are not compared.
+//! A call that runs out of fuel in either module proves nothing and is
+//! skipped. On a mismatch, the arguments are shrunk to simpler ones
+//! that still mismatch, and reported.
+//!
+//! Entry points are named with `--entry`, or declared by the guest
+//! with `WEVAL_FUZZ_ENTRY` in `weval.h`, which exports them as
+//! `weval.fuzz.NAME`. They may only take and return numbers. Imports
+//! from `weval` resolve to the stubs used for Wizening; calls to any
+//! other import trap.
+
+use std::path::Path;
+use structopt::StructOpt;
+
+/// Options for `weval fuzz`.
+#[derive(Clone, Debug, StructOpt)]
+pub struct FuzzOptions {
+    /// An entry point to fuzz (default: every export named
+    /// `weval.fuzz.*`).
+    #[structopt(long = "entry", value_name = "EXPORT")]
+    entries: Vec<String>,
+
+    /// How many inputs to try per entry point.
+    #[structopt(long = "iterations", default_value = "1000")]
+    iterations: usize,
+
+    /// Seed for the random inputs; the same seed tries the same
+    /// inputs.
+    #[structopt(long = "seed", default_value = "0")]
+    seed: u64,
+
+    /// Fuel (roughly, Wasm operators) for each call; a call that runs
+    /// out is skipped.
+    #[structopt(long = "fuel", default_value = "10000000")]
+    fuel: u64,
+
+    /// Compare only what entry points return (or how they trap), not
+    /// memory.
+    #[structopt(long = "results-only")]
+    results_only: bool,
+}
+
+/// How many rounds of shrinking to try at most.
+const MAX_SHRINK_ROUNDS: usize = 1000;
+
+/// A numeric argument or result. Floats are kept as bits, so that
+/// NaNs compare equal to themselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Num {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+impl std::fmt::Display for Num {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Num::I32(x) => write!(f, "{}i32", x),
+            Num::I64(x) => write!(f, "{}i64", x),
+            Num::F32(x) => write!(f, "{}f32", f32::from_bits(x)),
+            Num::F64(x) => write!(f, "{}f64", f64::from_bits(x)),
+        }
+    }
+}
+
+#[cfg(feature = "fuzz")]
+impl Num {
+    fn to_val(self) -> wasmtime::Val {
+        match self {
+            Num::I32(x) => wasmtime::Val::I32(x),
+            Num::I64(x) => wasmtime::Val::I64(x),
+            Num::F32(x) => wasmtime::Val::F32(x),
+            Num::F64(x) => wasmtime::Val::F64(x),
+        }
+    }
+
+    fn from_val(val: &wasmtime::Val) -> Option<Num> {
+        match *val {
+            wasmtime::Val::I32(x) => Some(Num::I32(x)),
+            wasmtime::Val::I64(x) => Some(Num::I64(x)),
+            wasmtime::Val::F32(x) => Some(Num::F32(x)),
+            wasmtime::Val::F64(x) => Some(Num::F64(x)),
+            _ => None,
+        }
+    }
+
+    fn zero(ty: &wasmtime::ValType) -> Option<Num> {
+        match ty {
+            wasmtime::ValType::I32 => Some(Num::I32(0)),
+            wasmtime::ValType::I64 => Some(Num::I64(0)),
+            wasmtime::ValType::F32 => Some(Num::F32(0)),
+            wasmtime::ValType::F64 => Some(Num::F64(0)),
+            _ => None,
+        }
+    }
+
+    /// A random value of the same type: often a small number or an
+    /// edge case, which guests branch on most.
+    fn random(self, rng: &mut Rng) -> Num {
+        let bits = match rng.next() % 4 {
+            0 => rng.next() % 16,
+            1 => [
+                0,
+                1,
+                u64::MAX,
+                1 << 31,
+                (1 << 31) - 1,
+                1 << 63,
+                (1 << 63) - 1,
+            ][(rng.next() % 7) as usize],
+            _ => rng.next(),
+        };
+        match self {
+            Num::I32(_) => Num::I32(bits as i32),
+            Num::I64(_) => Num::I64(bits as i64),
+            Num::F32(_) if bits < 16 => Num::F32((bits as f32).to_bits()),
+            Num::F32(_) => Num::F32(bits as u32),
+            Num::F64(_) if bits < 16 => Num::F64((bits as f64).to_bits()),
+            Num::F64(_) => Num::F64(bits),
+        }
+    }
+
+    /// Simpler values to try in place of this one, simplest first.
+    fn shrink(self) -> Vec<Num> {
+        let ints = |x: i64| {
+            let mut out = vec![0];
+            if x < 0 {
+                out.push(-x);
+            }
+            out.push(x / 2);
+            out.push(x - x.signum());
+            out.retain(|&y| y != x && y.unsigned_abs() <= x.unsigned_abs());
+            out.dedup();
+            out
+        };
+        match self {
+            Num::I32(x) => ints(x as i64)
+                .into_iter()
+                .map(|y| y as i32)
+                .filter(|&y| y != x)
+                .map(Num::I32)
+                .collect(),
+            Num::I64(x) => ints(x).into_iter().map(Num::I64).collect(),
+            Num::F32(x) => {
+                let x = f32::from_bits(x);
+                [0.0, x.trunc(), x / 2.0]
+                    .into_iter()
+                    .filter(|&y| y.is_finite() && y.abs() < x.abs())
+                    .map(|y| Num::F32(y.to_bits()))
+                    .collect()
+            }
+            Num::F64(x) => {
+                let x = f64::from_bits(x);
+                [0.0, x.trunc(), x / 2.0]
+                    .into_iter()
+                    .filter(|&y| y.is_finite() && y.abs() < x.abs())
+                    .map(|y| Num::F64(y.to_bits()))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// A small, seedable PRNG (splitmix64), so that runs are repeatable.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// How a call of an entry point ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Outcome {
+    Returned(Vec<Num>),
+    Trapped(String),
+    OutOfFuel,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Outcome::Returned(results) => {
+                let results = results.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+                write!(f, "returned [{}]", results.join(", "))
+            }
+            Outcome::Trapped(trap) => write!(f, "trapped: {}", trap),
+            Outcome::OutOfFuel => write!(f, "ran out of fuel"),
+        }
+    }
+}
+
+/// Runs entry points of a generic module and its specialization side
+/// by side.
+#[cfg(feature = "fuzz")]
+pub(crate) struct Harness {
+    engine: wasmtime::Engine,
+    generic: wasmtime::Module,
+    specialized: wasmtime::Module,
+    stubs: wasmtime::Module,
+    opts: FuzzOptions,
+    /// Byte offsets where the two modules' initial memories differ.
+    initial_diffs: fxhash::FxHashSet<usize>,
+}
+
+#[cfg(feature = "fuzz")]
+impl Harness {
+    pub(crate) fn new(
+        generic: &[u8],
+        specialized: &[u8],
+        stubs: &[u8],
+        opts: &FuzzOptions,
+    ) -> anyhow::Result<Harness> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config)?;
+        let compile = |what: &str, bytes: &[u8]| {
+            wasmtime::Module::new(&engine, bytes)
+                .map_err(|e| anyhow::anyhow!("compiling the {} module: {:#}", what, e))
+        };
+        let mut harness = Harness {
+            generic: compile("generic", generic)?,
+            specialized: compile("specialized", specialized)?,
+            stubs: compile("stubs", stubs)?,
+            engine,
+            opts: opts.clone(),
+            initial_diffs: Default::default(),
+        };
+        if !opts.results_only {
+            let generic = harness.initial_memory(&harness.generic)?;
+            let specialized = harness.initial_memory(&harness.specialized)?;
+            harness.initial_diffs = generic
+                .iter()
+                .zip(&specialized)
+                .enumerate()
+                .filter(|(_, (a, b))| a != b)
+                .map(|(i, _)| i)
+                .collect();
+        }
+        Ok(harness)
+    }
+
+    /// The entry points the guest declares with `WEVAL_FUZZ_ENTRY`.
+    pub(crate) fn declared_entries(&self) -> Vec<String> {
+        self.generic
+            .exports()
+            .filter(|export| export.name().starts_with("weval.fuzz."))
+            .filter(|export| export.ty().func().is_some())
+            .map(|export| export.name().to_owned())
+            .collect()
+    }
+
+    /// Zero arguments for `entry`, checking that both modules export it
+    /// with the same numeric signature.
+    pub(crate) fn zero_args(&self, entry: &str) -> anyhow::Result<Vec<Num>> {
+        let ty = |module: &wasmtime::Module, what: &str| {
+            module
+                .get_export(entry)
+                .and_then(|ty| ty.func().cloned())
+                .ok_or_else(|| anyhow::anyhow!("the {} module has no function `{}`", what, entry))
+        };
+        let generic = ty(&self.generic, "generic")?;
+        let specialized = ty(&self.specialized, "specialized")?;
+        anyhow::ensure!(
+            generic == specialized,
+            "`{}` has type {:?} in the generic module but {:?} in the specialized module",
+            entry,
+            generic,
+            specialized
+        );
+        anyhow::ensure!(
+            generic.results().all(|ty| Num::zero(&ty).is_some()),
+            "`{}` returns non-numeric values, which cannot be compared",
+            entry
+        );
+        generic
+            .params()
+            .map(|ty| {
+                Num::zero(&ty).ok_or_else(|| {
+                    anyhow::anyhow!("`{}` takes a parameter of type {}, not a number", entry, ty)
+                })
+            })
+            .collect()
+    }
+
+    /// Call `entry` with `args` in both modules. Returns a description
+    /// of how they differ, if they do.
+    pub(crate) fn check(&self, entry: &str, args: &[Num]) -> anyhow::Result<Option<String>> {
+        let (generic, generic_memory) = self.call(&self.generic, entry, args)?;
+        let (specialized, specialized_memory) = self.call(&self.specialized, entry, args)?;
+        if generic == Outcome::OutOfFuel || specialized == Outcome::OutOfFuel {
+            return Ok(None);
+        }
+        if generic != specialized {
+            return Ok(Some(format!(
+                "the generic module {} but the specialized module {}",
+                generic, specialized
+            )));
+        }
+        if self.opts.results_only {
+            return Ok(None);
+        }
+        if generic_memory.len() != specialized_memory.len() {
+            return Ok(Some(format!(
+                "memory ends up {} bytes long in the generic module but {} in the specialized module",
+                generic_memory.len(),
+                specialized_memory.len()
+            )));
+        }
+        let diff = generic_memory
+            .iter()
+            .zip(&specialized_memory)
+            .enumerate()
+            .find(|&(i, (a, b))| a != b && !self.initial_diffs.contains(&i));
+        Ok(diff.map(|(i, (a, b))| {
+            format!(
+                "memory differs at {:#x}: {:#04x} in the generic module, {:#04x} in the specialized module",
+                i, a, b
+            )
+        }))
+    }
+
+    fn instantiate(
+        &self,
+        module: &wasmtime::Module,
+    ) -> anyhow::Result<(wasmtime::Store<()>, wasmtime::Instance)> {
+        let mut store = wasmtime::Store::new(&self.engine, ());
+        store.set_fuel(self.opts.fuel)?;
+        let mut linker = wasmtime::Linker::new(&self.engine);
+        let stubs = linker.instantiate(&mut store, &self.stubs)?;
+        linker.instance(&mut store, "weval", stubs)?;
+        linker.define_unknown_imports_as_traps(module)?;
+        let instance = linker.instantiate(&mut store, module)?;
+        Ok((store, instance))
+    }
+
+    fn memory(store: &mut wasmtime::Store<()>, instance: &wasmtime::Instance) -> Vec<u8> {
+        let memory = instance
+            .exports(&mut *store)
+            .find_map(|export| export.into_memory());
+        memory.map_or(vec![], |memory| memory.data(&*store).to_vec())
+    }
+
+    fn initial_memory(&self, module: &wasmtime::Module) -> anyhow::Result<Vec<u8>> {
+        let (mut store, instance) = self.instantiate(module)?;
+        Ok(Self::memory(&mut store, &instance))
+    }
+
+    fn call(
+        &self,
+        module: &wasmtime::Module,
+        entry: &str,
+        args: &[Num],
+    ) -> anyhow::Result<(Outcome, Vec<u8>)> {
+        let (mut store, instance) = self.instantiate(module)?;
+        let func = instance
+            .get_func(&mut store, entry)
+            .ok_or_else(|| anyhow::anyhow!("no function `{}`", entry))?;
+        let args = args.iter().map(|arg| arg.to_val()).collect::<Vec<_>>();
+        let mut results = func
+            .ty(&store)
+            .results()
+            .map(|ty| Num::zero(&ty).map_or(wasmtime::Val::I32(0), Num::to_val))
+            .collect::<Vec<_>>();
+        let outcome = match func.call(&mut store, &args, &mut results) {
+            Ok(()) => Outcome::Returned(results.iter().filter_map(Num::from_val).collect()),
+            Err(e) => match e.downcast_ref::<wasmtime::Trap>() {
+                Some(wasmtime::Trap::OutOfFuel) => Outcome::OutOfFuel,
+                Some(trap) => Outcome::Trapped(trap.to_string()),
+                None => Outcome::Trapped(format!("{:#}", e)),
+            },
+        };
+        let memory = if self.opts.results_only {
+            vec![]
+        } else {
+            Self::memory(&mut store, &instance)
+        };
+        Ok((outcome, memory))
+    }
+}
+
+/// Shrink `args`, which make `entry` mismatch, one argument at a time
+/// to the simplest values that still do.
+#[cfg(feature = "fuzz")]
+fn shrink(
+    harness: &Harness,
+    entry: &str,
+    mut args: Vec<Num>,
+    mut mismatch: String,
+) -> anyhow::Result<(Vec<Num>, String)> {
+    for _ in 0..MAX_SHRINK_ROUNDS {
+        let mut progress = false;
+        'args: for i in 0..args.len() {
+            for candidate in args[i].shrink() {
+                let mut smaller = args.clone();
+                smaller[i] = candidate;
+                if let Some(m) = harness.check(entry, &smaller)? {
+                    args = smaller;
+                    mismatch = m;
+                    progress = true;
+                    break 'args;
+                }
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+    Ok((args, mismatch))
+}
+
+/// Run `weval fuzz`: call each entry point `--iterations` times with
+/// random arguments in both modules, failing at the first mismatch.
+#[cfg(feature = "fuzz")]
+pub(crate) fn fuzz(
+    generic: &Path,
+    specialized: &Path,
+    stubs: &[u8],
+    opts: &FuzzOptions,
+) -> anyhow::Result<()> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| anyhow::anyhow!("reading {}: {}", path.display(), e))
+    };
+    let harness = Harness::new(&read(generic)?, &read(specialized)?, stubs, opts)?;
+    let entries = if opts.entries.is_empty() {
+        harness.declared_entries()
+    } else {
+        opts.entries.clone()
+    };
+    anyhow::ensure!(
+        !entries.is_empty(),
+        "no entry points: pass `--entry`, or declare them with `WEVAL_FUZZ_ENTRY`"
+    );
+
+    let mut rng = Rng(opts.seed);
+    for entry in &entries {
+        let zero = harness.zero_args(entry)?;
+        for i in 0..opts.iterations {
+            // Try all-zero arguments first: they are the simplest.
+            let args = if i == 0 {
+                zero.clone()
+            } else {
+                zero.iter().map(|arg| arg.random(&mut rng)).collect()
+            };
+            let Some(mismatch) = harness.check(entry, &args)? else {
+                continue;
+            };
+            let (args, mismatch) = shrink(&harness, entry, args, mismatch)?;
+            let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            anyhow::bail!(
+                "`{}` mismatches with arguments ({}): {}",
+                entry,
+                args.join(", "),
+                mismatch
+            );
+        }
+        eprintln!("{}: {} inputs, no mismatches", entry, opts.iterations);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "fuzz"))]
+pub(crate) fn fuzz(
+    _generic: &Path,
+    _specialized: &Path,
+    _stubs: &[u8],
+    _opts: &FuzzOptions,
+) -> anyhow::Result<()> {
+    anyhow::bail!("weval was built without fuzzing support (the `fuzz` feature)")
+}
@@ -0,0 +1,388 @@
+//! Golden IR snapshots of specialized functions.
+//!
+//! Interpreter projects want to know when a change (to the
+//! interpreter, or to weval) makes weval's output worse. With
+//! `--check-ir-against DIR`, the IR of every specialized function in
+//! the final output is compared against a snapshot in DIR, committed
+//! alongside the project's tests, and any difference fails the run;
+//! `--update-ir` writes the snapshots instead.
+//!
+//! Snapshots are named after what produced them, not function
+//! indices: `GENERIC.USER_ID.ir`, for the generic function's name and
+//! the directive's user ID (with `.N` appended when several directives
+//! share both). Within a snapshot, blocks are numbered in reverse
+//! postorder, values by their position in their block (`bB.pP` for
+//! block parameters, `bB.I` for instructions), and callees by name, so
+//! that renumbering alone is not a difference. Snapshots are compared
+//! block by block, and each difference reported with its block.
+
+use fxhash::FxHashMap;
+use std::fmt::Write;
+use std::path::Path;
+use waffle::cfg::CFGInfo;
+use waffle::entity::EntityRef;
+use waffle::{BlockTarget, Func, FunctionBody, Module, Operator, Terminator, Value, ValueDef};
+
+/// How many differences to report per function.
+const MAX_DIFFS: usize = 10;
+
+/// The extension of snapshot files.
+const EXT: &str = "ir";
+
+/// A block of a snapshot, rendered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Block {
+    /// `blockN(params):`
+    header: String,
+    insts: Vec<String>,
+    terminator: String,
+}
+
+/// A specialized function's IR, in canonical form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Snapshot {
+    blocks: Vec<Block>,
+}
+
+impl Snapshot {
+    fn new(module: &Module, body: &FunctionBody) -> Snapshot {
+        let cfg = CFGInfo::new(body);
+        let mut names: FxHashMap<Value, String> = FxHashMap::default();
+        for (i, &block) in cfg.rpo.values().enumerate() {
+            for (p, &(_, param)) in body.blocks[block].params.iter().enumerate() {
+                names.insert(param, format!("b{}.p{}", i, p));
+            }
+            for (n, &inst) in body.blocks[block].insts.iter().enumerate() {
+                names.insert(inst, format!("b{}.{}", i, n));
+            }
+        }
+        let name = |value: Value| {
+            names
+                .get(&body.resolve_alias(value))
+                .cloned()
+                .unwrap_or_else(|| "?".to_owned())
+        };
+        let list = |values: &[Value]| {
+            values
+                .iter()
+                .map(|&v| name(v))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let target = |target: &BlockTarget| {
+            let index = cfg.rpo_pos[target.block].map_or(usize::MAX, |pos| pos.index());
+            format!("block{}({})", index, list(&target.args))
+        };
+
+        let mut blocks = vec![];
+        for (i, &block) in cfg.rpo.values().enumerate() {
+            let def = &body.blocks[block];
+            let params = def
+                .params
+                .iter()
+                .enumerate()
+                .map(|(p, (ty, _))| format!("b{}.p{}: {}", i, p, ty))
+                .collect::<Vec<_>>();
+            let header = format!("block{}({}):", i, params.join(", "));
+            let insts = def
+                .insts
+                .iter()
+                .map(|&inst| {
+                    let rhs = match &body.values[inst] {
+                        ValueDef::Operator(op, args, tys) => {
+                            let tys = body.type_pool[*tys]
+                                .iter()
+                                .map(|ty| ty.to_string())
+                                .collect::<Vec<_>>();
+                            let op = format!(
+                                "{}({})",
+                                operator(module, op),
+                                list(&body.arg_pool[*args])
+                            );
+                            if tys.is_empty() {
+                                op
+                            } else {
+                                format!("{} = {}", tys.join(", "), op)
+                            }
+                        }
+                        ValueDef::PickOutput(value, index, ty) => {
+                            format!("{} = pick {}, {}", ty, name(*value), index)
+                        }
+                        other => format!("{:?}", other),
+                    };
+                    format!("{}: {}", name(inst), rhs)
+                })
+                .collect();
+            let terminator = match &def.terminator {
+                Terminator::Br { target: t } => format!("br {}", target(t)),
+                Terminator::CondBr {
+                    cond,
+                    if_true,
+                    if_false,
+                } => format!(
+                    "if {}, {}, {}",
+                    name(*cond),
+                    target(if_true),
+                    target(if_false)
+                ),
+                Terminator::Select {
+                    value,
+                    targets,
+                    default,
+                } => format!(
+                    "select {}, [{}], {}",
+                    name(*value),
+                    targets.iter().map(target).collect::<Vec<_>>().join(", "),
+                    target(default)
+                ),
+                Terminator::Return { values } => format!("return {}", list(values)),
+                Terminator::Unreachable => "unreachable".to_owned(),
+                Terminator::None => "no_terminator".to_owned(),
+            };
+            blocks.push(Block {
+                header,
+                insts,
+                terminator,
+            });
+        }
+        Snapshot { blocks }
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Snapshot> {
+        let mut blocks: Vec<Block> = vec![];
+        for (i, line) in text.lines().enumerate() {
+            if line.starts_with(';') || line.trim().is_empty() {
+                continue;
+            }
+            match line.strip_prefix("  ") {
+                Some(inst) => match blocks.last_mut() {
+                    Some(block) => block.insts.push(inst.to_owned()),
+                    None => anyhow::bail!("line {}: instruction outside a block", i + 1),
+                },
+                None => blocks.push(Block {
+                    header: line.to_owned(),
+                    insts: vec![],
+                    terminator: String::new(),
+                }),
+            }
+        }
+        for block in &mut blocks {
+            block.terminator = block
+                .insts
+                .pop()
+                .ok_or_else(|| anyhow::anyhow!("`{}` has no terminator", block.header))?;
+        }
+        Ok(Snapshot { blocks })
+    }
+
+    /// The differences from `expected`, in the order of the blocks.
+    fn diff(&self, expected: &Snapshot) -> Vec<String> {
+        let mut diffs = vec![];
+        if self.blocks.len() != expected.blocks.len() {
+            diffs.push(format!(
+                "{} blocks, expected {}",
+                self.blocks.len(),
+                expected.blocks.len()
+            ));
+        }
+        for (i, (actual, expected)) in self.blocks.iter().zip(&expected.blocks).enumerate() {
+            if actual.header != expected.header {
+                diffs.push(format!(
+                    "block{}: parameters `{}`, expected `{}`",
+                    i, actual.header, expected.header
+                ));
+            }
+            // Report the first difference in the instructions, which
+            // often causes the rest.
+            let first = actual
+                .insts
+                .iter()
+                .zip(&expected.insts)
+                .position(|(a, e)| a != e);
+            match first {
+                Some(n) => diffs.push(format!(
+                    "block{}: instruction {} is `{}`, expected `{}`",
+                    i, n, actual.insts[n], expected.insts[n]
+                )),
+                None if actual.insts.len() != expected.insts.len() => diffs.push(format!(
+                    "block{}: {} instructions, expected {}",
+                    i,
+                    actual.insts.len(),
+                    expected.insts.len()
+                )),
+                None => {}
+            }
+            if actual.terminator != expected.terminator {
+                diffs.push(format!(
+                    "block{}: terminator `{}`, expected `{}`",
+                    i, actual.terminator, expected.terminator
+                ));
+            }
+        }
+        diffs
+    }
+}
+
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for block in &self.blocks {
+            writeln!(f, "{}", block.header)?;
+            for inst in &block.insts {
+                writeln!(f, "  {}", inst)?;
+            }
+            writeln!(f, "  {}", block.terminator)?;
+        }
+        Ok(())
+    }
+}
+
+/// An operator, with functions named rather than numbered.
+fn operator(module: &Module, op: &Operator) -> String {
+    let func = |func: Func| match module.funcs.get(func).map(|decl| decl.name()) {
+        Some(name) if !name.is_empty() => name.to_owned(),
+        _ => func.to_string(),
+    };
+    match *op {
+        Operator::Call { function_index } => format!("call<{}>", func(function_index)),
+        Operator::RefFunc { func_index } => format!("ref_func<{}>", func(func_index)),
+        Operator::CallIndirect {
+            sig_index,
+            table_index,
+        } => {
+            let sig = &module.signatures[sig_index];
+            let types = |tys: &[waffle::Type]| {
+                tys.iter()
+                    .map(|ty| ty.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            format!(
+                "call_indirect<({}) -> ({}), {}>",
+                types(&sig.params),
+                types(&sig.returns),
+                table_index
+            )
+        }
+        _ => op.to_string(),
+    }
+}
+
+/// The snapshot name for each directive: `GENERIC.USER_ID`, with `.N`
+/// appended to repeats, in a form usable as a file name.
+pub(crate) fn names<'a>(directives: impl Iterator<Item = (&'a str, u32)>) -> Vec<String> {
+    let mut seen: FxHashMap<String, usize> = FxHashMap::default();
+    directives
+        .map(|(generic, user_id)| {
+            let generic = generic
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' => c,
+                    _ => '_',
+                })
+                .collect::<String>();
+            let name = format!("{}.{}", generic, user_id);
+            let count = seen.entry(name.clone()).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                name
+            } else {
+                format!("{}.{}", name, *count - 1)
+            }
+        })
+        .collect()
+}
+
+fn snapshot_path(dir: &Path, name: &str) -> std::path::PathBuf {
+    dir.join(format!("{}.{}", name, EXT))
+}
+
+/// Compare the specialized functions `funcs`, by snapshot name and
+/// function index, of the final module `bytes` against their snapshots
+/// in `dir`, or write the snapshots if `update`.
+pub(crate) fn check(
+    dir: &Path,
+    bytes: &[u8],
+    funcs: &[(String, u32)],
+    update: bool,
+) -> anyhow::Result<()> {
+    let module = Module::from_wasm_bytes(bytes, &Default::default())?;
+    let mut actual = vec![];
+    for (name, index) in funcs {
+        let body = module.clone_and_expand_body(Func::new(*index as usize))?;
+        actual.push((name, Snapshot::new(&module, &body)));
+    }
+
+    let mut stale = vec![];
+    if dir.exists() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == EXT)
+                && !funcs
+                    .iter()
+                    .any(|(name, _)| path == snapshot_path(dir, name))
+            {
+                stale.push(path);
+            }
+        }
+    }
+    stale.sort();
+
+    if update {
+        std::fs::create_dir_all(dir)?;
+        for (name, snapshot) in &actual {
+            let path = snapshot_path(dir, name);
+            std::fs::write(
+                &path,
+                format!("; weval IR snapshot: {}\n{}", name, snapshot),
+            )
+            .map_err(|e| anyhow::anyhow!("writing {}: {}", path.display(), e))?;
+        }
+        for path in &stale {
+            std::fs::remove_file(path)?;
+        }
+        eprintln!("Updated {} IR snapshots in {}", actual.len(), dir.display());
+        return Ok(());
+    }
+
+    let mut report = String::new();
+    for (name, snapshot) in &actual {
+        let path = snapshot_path(dir, name);
+        let expected = match std::fs::read_to_string(&path) {
+            Ok(text) => Snapshot::parse(&text)
+                .map_err(|e| anyhow::anyhow!("parsing {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                writeln!(&mut report, "{}: no snapshot", name).unwrap();
+                continue;
+            }
+            Err(e) => anyhow::bail!("reading {}: {}", path.display(), e),
+        };
+        let diffs = snapshot.diff(&expected);
+        for diff in diffs.iter().take(MAX_DIFFS) {
+            writeln!(&mut report, "{}: {}", name, diff).unwrap();
+        }
+        if diffs.len() > MAX_DIFFS {
+            writeln!(
+                &mut report,
+                "{}: ... and {} more differences",
+                name,
+                diffs.len() - MAX_DIFFS
+            )
+            .unwrap();
+        }
+    }
+    for path in &stale {
+        writeln!(
+            &mut report,
+            "{}: no longer produced",
+            path.file_stem().unwrap_or_default().to_string_lossy()
+        )
+        .unwrap();
+    }
+    anyhow::ensure!(
+        report.is_empty(),
+        "specialized IR differs from the snapshots in {} (rerun with --update-ir to accept):\n{}",
+        dir.display(),
+        report.trim_end()
+    );
+    Ok(())
+}
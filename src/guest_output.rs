@@ -0,0 +1,115 @@
+//! Capture of guest output during Wizening.
+//!
+//! Wizer gives the guest the process's own stdout and stderr (there
+//! is no way to hand it other streams), so when initialization fails,
+//! whatever the guest printed is interleaved with weval's output, or
+//! lost in CI logs. While Wizening, we instead point file descriptors
+//! 1 and 2 at temporary files. If Wizening succeeds, the output is
+//! written back out as if it had not been captured; if it fails, the
+//! output is attached to the error.
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+
+/// Only the last this-many bytes of each stream are kept in an error.
+const MAX_OUTPUT_IN_ERROR: usize = 16 * 1024;
+
+/// Output the guest wrote to stdout and stderr.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GuestOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl GuestOutput {
+    /// Write the output to the process's stdout and stderr.
+    pub(crate) fn replay(&self) -> std::io::Result<()> {
+        std::io::stdout().write_all(&self.stdout)?;
+        std::io::stderr().write_all(&self.stderr)
+    }
+
+    /// Attach the output, if any, to an error.
+    pub(crate) fn attach(&self, error: anyhow::Error) -> anyhow::Error {
+        if self.stdout.is_empty() && self.stderr.is_empty() {
+            return error;
+        }
+        let mut msg = "Wizening failed; guest output:".to_owned();
+        for (name, bytes) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            if bytes.is_empty() {
+                continue;
+            }
+            let tail = &bytes[bytes.len().saturating_sub(MAX_OUTPUT_IN_ERROR)..];
+            msg.push_str(&format!("\n--- {} ---\n", name));
+            if tail.len() < bytes.len() {
+                msg.push_str(&format!("[{} bytes omitted]\n", bytes.len() - tail.len()));
+            }
+            msg.push_str(String::from_utf8_lossy(tail).trim_end());
+        }
+        error.context(msg)
+    }
+}
+
+/// An unnamed temporary file.
+fn temp_file(name: &str) -> std::io::Result<File> {
+    let path = std::env::temp_dir().join(format!("weval-{}-{}", std::process::id(), name));
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+fn read_all(mut file: File) -> std::io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    file.rewind()?;
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Run `f` with stdout and stderr captured.
+#[cfg(unix)]
+pub(crate) fn capture<T>(f: impl FnOnce() -> T) -> std::io::Result<(T, GuestOutput)> {
+    use std::os::unix::io::AsRawFd;
+
+    fn check(ret: libc::c_int) -> std::io::Result<libc::c_int> {
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    let stdout = temp_file("stdout")?;
+    let stderr = temp_file("stderr")?;
+    std::io::stdout().flush()?;
+    std::io::stderr().flush()?;
+    // SAFETY: we only duplicate and replace the standard descriptors,
+    // and restore them before returning.
+    let result = unsafe {
+        let saved_stdout = check(libc::dup(1))?;
+        let saved_stderr = check(libc::dup(2))?;
+        check(libc::dup2(stdout.as_raw_fd(), 1))?;
+        check(libc::dup2(stderr.as_raw_fd(), 2))?;
+        let result = f();
+        let _ = std::io::stdout().flush();
+        let _ = std::io::stderr().flush();
+        check(libc::dup2(saved_stdout, 1))?;
+        check(libc::dup2(saved_stderr, 2))?;
+        libc::close(saved_stdout);
+        libc::close(saved_stderr);
+        result
+    };
+    let output = GuestOutput {
+        stdout: read_all(stdout)?,
+        stderr: read_all(stderr)?,
+    };
+    Ok((result, output))
+}
+
+/// Run `f`; output is not captured on this platform.
+#[cfg(not(unix))]
+pub(crate) fn capture<T>(f: impl FnOnce() -> T) -> std::io::Result<(T, GuestOutput)> {
+    Ok((f(), GuestOutput::default()))
+}
@@ -0,0 +1,82 @@
+//! Passes that library users add to weval's pipeline.
+//!
+//! [`crate::specialize`] runs them at three points: over the parsed
+//! input module before directive collection, over each specialized
+//! function after weval's own cleanups, and over the output module
+//! before it is encoded. A pass that fails fails the run, except a
+//! function pass: its directive then falls back to the generic
+//! function, as when specialization fails.
+
+use std::sync::Arc;
+use waffle::{FunctionBody, Module};
+
+/// A pass over a whole module.
+pub trait ModulePass: Send + Sync {
+    /// The pass's name, for tracing and errors.
+    fn name(&self) -> &str;
+
+    /// Rewrite `module` in place.
+    fn run(&self, module: &mut Module<'_>) -> anyhow::Result<()>;
+}
+
+/// A pass over one specialized function body.
+///
+/// Calls and table-index loads through other directives' results are
+/// patched after every function is specialized, by value. A pass may
+/// remove or replace such a value, keeping the runtime lookup, but
+/// must not change the value's operator in place.
+pub trait FunctionPass: Send + Sync {
+    /// The pass's name, for tracing and errors.
+    fn name(&self) -> &str;
+
+    /// Rewrite `func`, a specialization in `module`, in place.
+    /// `module` does not yet contain the other specializations.
+    fn run(&self, module: &Module<'_>, func: &mut FunctionBody) -> anyhow::Result<()>;
+}
+
+/// The passes to run at each point of the pipeline, in order.
+#[derive(Clone, Default)]
+pub struct Passes {
+    /// Run over the parsed input module, before directives are
+    /// collected from its memory image.
+    pub before_directives: Vec<Arc<dyn ModulePass>>,
+    /// Run over each specialized function.
+    pub after_specialize: Vec<Arc<dyn FunctionPass>>,
+    /// Run over the output module, before it is encoded.
+    pub before_emit: Vec<Arc<dyn ModulePass>>,
+}
+
+impl Passes {
+    /// Run `passes` over `module`, in order.
+    pub(crate) fn run_module(
+        passes: &[Arc<dyn ModulePass>],
+        module: &mut Module<'_>,
+    ) -> anyhow::Result<()> {
+        for pass in passes {
+            tracing::debug_span!("pass", name = pass.name())
+                .in_scope(|| pass.run(module))
+                .map_err(|e| e.context(format!("pass `{}` failed", pass.name())))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Passes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = |passes: Vec<&str>| passes.join(", ");
+        f.debug_struct("Passes")
+            .field(
+                "before_directives",
+                &names(self.before_directives.iter().map(|p| p.name()).collect()),
+            )
+            .field(
+                "after_specialize",
+                &names(self.after_specialize.iter().map(|p| p.name()).collect()),
+            )
+            .field(
+                "before_emit",
+                &names(self.before_emit.iter().map(|p| p.name()).collect()),
+            )
+            .finish()
+    }
+}
@@ -0,0 +1,364 @@
+//! Host-driven specialization.
+//!
+//! A module built with `weval.h` asks for its own specializations. With
+//! `--specialize-func FUNC --const-arg N=VALUE`, the host asks instead,
+//! so that weval can specialize modules that cannot be rebuilt: FUNC
+//! is specialized with the given parameters constant, and the result
+//! exported (under `--specialized-export`, by default `FUNC.specialized`)
+//! through a wrapper that takes only the remaining parameters.
+//!
+//! `N=@FILE` makes parameter N a pointer to a buffer holding FILE's
+//! contents, e.g. the bytecode of an interpreter function: loads
+//! through it fold to constants. The buffer exists only while
+//! specializing, so the wrapper passes a null pointer, and weval fails
+//! if the specialized function still reads the pointer. Interpreters
+//! that do not use weval's context intrinsics need `--auto-dispatch`
+//! to specialize per PC and fold their bytecode loads. With `--osr-pc`,
+//! entries into that loop mid-function are exported too (see
+//! `crate::osr`).
+
+use crate::directive::Directive;
+use crate::error::WevalError;
+use crate::eval::PartialEvalResult;
+use crate::stats::DirectiveResult;
+use crate::value::WasmVal;
+use std::path::PathBuf;
+use waffle::wasmparser::{self, BinaryReader, WasmFeatures};
+use waffle::{
+    Export, ExportKind, Func, FuncDecl, FunctionBody, Module, Operator, SignatureData, Terminator,
+    Type, ValueDef,
+};
+
+/// A `--const-arg` value, before its parameter's type is known.
+#[derive(Clone, Debug)]
+pub enum ConstArg {
+    Value(String),
+    File(PathBuf),
+}
+
+/// Parse a `--const-arg` argument, `N=VALUE` or `N=@FILE`.
+pub(crate) fn parse_const_arg(s: &str) -> anyhow::Result<(usize, ConstArg)> {
+    match s.split_once('=') {
+        Some((param, value)) if !value.is_empty() => {
+            let arg = match value.strip_prefix('@') {
+                Some(path) => ConstArg::File(path.into()),
+                None => ConstArg::Value(value.to_owned()),
+            };
+            Ok((param.parse()?, arg))
+        }
+        _ => anyhow::bail!("must be of the form N=VALUE or N=@FILE"),
+    }
+}
+
+/// A parameter of the function to specialize.
+#[derive(Clone, Debug)]
+enum Param {
+    Runtime,
+    Const(WasmVal),
+    /// A pointer to a buffer with these contents.
+    Buffer(Vec<u8>),
+}
+
+/// A specialization requested on the command line.
+pub(crate) struct Request {
+    pub directive: Directive,
+    params: Vec<Param>,
+    export: String,
+}
+
+impl Request {
+    pub(crate) fn new(
+        module: &Module,
+        func_name: &str,
+        const_args: &[(usize, ConstArg)],
+        export: Option<String>,
+    ) -> anyhow::Result<Request> {
+        let func = crate::intrinsics::find_func(module, func_name).ok_or_else(|| {
+            anyhow::anyhow!("--specialize-func: no function named `{}`", func_name)
+        })?;
+        anyhow::ensure!(
+            !matches!(module.funcs[func], FuncDecl::Import(..)),
+            "--specialize-func: `{}` is an import",
+            func_name
+        );
+        let tys = &module.signatures[module.funcs[func].sig()].params;
+
+        let mut params = vec![Param::Runtime; tys.len()];
+        for (param, arg) in const_args {
+            let ty = *tys.get(*param).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "--const-arg: `{}` has {} parameters, no parameter {}",
+                    func_name,
+                    tys.len(),
+                    param
+                )
+            })?;
+            anyhow::ensure!(
+                matches!(params[*param], Param::Runtime),
+                "--const-arg: parameter {} given twice",
+                param
+            );
+            params[*param] = match arg {
+                ConstArg::Value(value) => Param::Const(parse_value(value, ty).map_err(|e| {
+                    anyhow::anyhow!("--const-arg: parameter {} ({}): {}", param, ty, e)
+                })?),
+                ConstArg::File(path) => {
+                    anyhow::ensure!(
+                        ty == Type::I32,
+                        "--const-arg: parameter {} is {}, not an i32 buffer pointer",
+                        param,
+                        ty
+                    );
+                    let data = std::fs::read(path).map_err(|e| {
+                        anyhow::anyhow!("--const-arg: reading {}: {}", path.display(), e)
+                    })?;
+                    Param::Buffer(data)
+                }
+            };
+        }
+
+        let export = export.unwrap_or_else(|| format!("{}.specialized", func_name));
+        Request::with_params(func, 0, params, export)
+    }
+
+    /// A request for the OSR entry `osr` (see `crate::osr`) into this
+    /// request's function at `pc`, taking `state` loop-state
+    /// parameters. Its directive's user ID is the PC.
+    pub(crate) fn osr(&self, osr: Func, pc: u32, state: usize) -> anyhow::Result<Request> {
+        let mut params = self.params.clone();
+        params.push(Param::Const(WasmVal::I32(pc)));
+        params.extend(std::iter::repeat_n(Param::Runtime, state));
+        let export = format!("{}.osr{}", self.export, pc);
+        Request::with_params(osr, pc, params, export)
+    }
+
+    fn with_params(
+        func: Func,
+        user_id: u32,
+        params: Vec<Param>,
+        export: String,
+    ) -> anyhow::Result<Request> {
+        // Encode the arguments as a directive made with `weval.h`
+        // would (see `DirectiveArgs::decode`).
+        let mut bytes = vec![];
+        for (i, param) in params.iter().enumerate() {
+            let (is_specialized, ty, value) = match param {
+                Param::Runtime => (0u32, 0u32, 0u64),
+                Param::Const(WasmVal::I32(value)) => (1, 0, u64::from(*value)),
+                Param::Const(WasmVal::I64(value)) => (1, 1, *value),
+                Param::Const(WasmVal::F32(bits)) => (1, 2, u64::from(*bits)),
+                Param::Const(WasmVal::F64(bits)) => (1, 3, *bits),
+                Param::Const(WasmVal::V128(_)) => unreachable!(),
+                Param::Buffer(_) => (1, 4, 0),
+            };
+            bytes.extend_from_slice(&is_specialized.to_le_bytes());
+            bytes.extend_from_slice(&ty.to_le_bytes());
+            match param {
+                Param::Buffer(data) => {
+                    let len = u32::try_from(data.len())?;
+                    let padded_len = len.checked_add(7).ok_or_else(|| {
+                        anyhow::anyhow!("--const-arg: buffer for parameter {} is too large", i)
+                    })? & !7;
+                    bytes.extend_from_slice(&len.to_le_bytes());
+                    bytes.extend_from_slice(&padded_len.to_le_bytes());
+                    bytes.extend_from_slice(data);
+                    bytes.resize(bytes.len() + (padded_len - len) as usize, 0);
+                }
+                _ => bytes.extend_from_slice(&value.to_le_bytes()),
+            }
+        }
+
+        Ok(Request {
+            directive: Directive {
+                user_id,
+                func,
+                args: bytes,
+                num_globals: 0,
+                // The result goes to an export rather than to memory.
+                func_index_out_addr: 0,
+            },
+            params,
+            export,
+        })
+    }
+
+    /// Export the specialized function through a wrapper that supplies
+    /// the constant parameters.
+    pub(crate) fn export(&self, result: &mut PartialEvalResult) -> anyhow::Result<()> {
+        let generic = self.directive.func;
+        let user_id = self.directive.user_id;
+        let outcome = result.outcomes.iter().find(|outcome| {
+            outcome.func == generic
+                && outcome.user_id == user_id
+                && outcome.func_index_out_addr == 0
+        });
+        let message = match outcome.map(|outcome| &outcome.result) {
+            Some(DirectiveResult::Abandoned) => Some("exceeded size limits".to_owned()),
+            Some(DirectiveResult::Failed { message, .. }) => Some(message.clone()),
+            _ => None,
+        };
+        if let Some(message) = message {
+            return Err(anyhow::Error::new(WevalError::DirectiveFailed {
+                user_id,
+                func: result.module.funcs[generic].name().to_owned(),
+                message,
+            })
+            .context("--specialize-func"));
+        }
+        let specialized = result
+            .sizes
+            .iter()
+            .find(|size| {
+                size.generic == generic && size.user_id == user_id && size.func_index_out_addr == 0
+            })
+            .map(|size| size.specialized)
+            .ok_or_else(|| anyhow::anyhow!("--specialize-func: no specialized function"))?;
+        let module = &mut result.module;
+
+        for (i, _) in self
+            .params
+            .iter()
+            .enumerate()
+            .filter(|(_, param)| matches!(param, Param::Buffer(_)))
+        {
+            if let FuncDecl::Compiled(_, _, body) = &module.funcs[specialized] {
+                anyhow::ensure!(
+                    !reads_param(body, i as u32)?,
+                    "--specialize-func: the specialized `{}` still reads the buffer pointer \
+                     in parameter {} (is its dispatch loop specialized per PC? see \
+                     --auto-dispatch)",
+                    module.funcs[generic].name(),
+                    i
+                );
+            }
+        }
+
+        let generic_sig = module.signatures[module.funcs[generic].sig()].clone();
+        let sig = module.signatures.push(SignatureData {
+            params: generic_sig
+                .params
+                .iter()
+                .zip(&self.params)
+                .filter(|(_, param)| matches!(param, Param::Runtime))
+                .map(|(&ty, _)| ty)
+                .collect(),
+            returns: generic_sig.returns.clone(),
+        });
+        let mut body = FunctionBody::new(module, sig);
+        let entry = body.entry;
+        let mut runtime_args = body.blocks[entry]
+            .params
+            .iter()
+            .map(|&(_, value)| value)
+            .collect::<Vec<_>>()
+            .into_iter();
+        let mut args = vec![];
+        for (&ty, param) in generic_sig.params.iter().zip(&self.params) {
+            let op = match param {
+                Param::Runtime => {
+                    args.push(runtime_args.next().unwrap());
+                    continue;
+                }
+                Param::Const(WasmVal::I32(value)) => Operator::I32Const { value: *value },
+                Param::Const(WasmVal::I64(value)) => Operator::I64Const { value: *value },
+                Param::Const(WasmVal::F32(value)) => Operator::F32Const { value: *value },
+                Param::Const(WasmVal::F64(value)) => Operator::F64Const { value: *value },
+                Param::Const(WasmVal::V128(_)) => unreachable!(),
+                Param::Buffer(_) => Operator::I32Const { value: 0 },
+            };
+            args.push(body.add_op(entry, op, &[], &[ty]));
+        }
+        let call = body.add_op(
+            entry,
+            Operator::Call {
+                function_index: specialized,
+            },
+            &args[..],
+            &generic_sig.returns[..],
+        );
+        let values = match generic_sig.returns.len() {
+            1 => vec![call],
+            _ => generic_sig
+                .returns
+                .iter()
+                .enumerate()
+                .map(|(i, &ty)| {
+                    let pick = body.add_value(ValueDef::PickOutput(call, i as u32, ty));
+                    body.append_to_block(entry, pick);
+                    pick
+                })
+                .collect(),
+        };
+        body.set_terminator(entry, Terminator::Return { values });
+
+        let wrapper = module
+            .funcs
+            .push(FuncDecl::Body(sig, self.export.clone(), body));
+        module.exports.retain(|export| export.name != self.export);
+        module.exports.push(Export {
+            name: self.export.clone(),
+            kind: ExportKind::Func(wrapper),
+        });
+        tracing::info!("exporting {} as `{}`", specialized, self.export);
+        Ok(())
+    }
+}
+
+fn parse_value(s: &str, ty: Type) -> anyhow::Result<WasmVal> {
+    let int = |s: &str| -> anyhow::Result<i128> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        let magnitude = match digits.strip_prefix("0x") {
+            Some(hex) => i128::from_str_radix(hex, 16)?,
+            None => digits.parse()?,
+        };
+        Ok(if negative { -magnitude } else { magnitude })
+    };
+    Ok(match ty {
+        Type::I32 => {
+            let value = int(s)?;
+            anyhow::ensure!(
+                (i128::from(i32::MIN)..=i128::from(u32::MAX)).contains(&value),
+                "{} is out of range",
+                s
+            );
+            WasmVal::I32(value as u32)
+        }
+        Type::I64 => {
+            let value = int(s)?;
+            anyhow::ensure!(
+                (i128::from(i64::MIN)..=i128::from(u64::MAX)).contains(&value),
+                "{} is out of range",
+                s
+            );
+            WasmVal::I64(value as u64)
+        }
+        Type::F32 => WasmVal::F32(s.parse::<f32>()?.to_bits()),
+        Type::F64 => WasmVal::F64(s.parse::<f64>()?.to_bits()),
+        _ => anyhow::bail!("cannot give a constant of this type"),
+    })
+}
+
+/// Whether a compiled body may read parameter `param`: whether its
+/// local is read before any write to it, in code order. (The backend
+/// may reuse a parameter's local once the parameter is dead.)
+fn reads_param(body: &[u8], param: u32) -> anyhow::Result<bool> {
+    let body = wasmparser::FunctionBody::new(BinaryReader::new(body, 0, WasmFeatures::default()));
+    for op in body.get_operators_reader()? {
+        match op? {
+            wasmparser::Operator::LocalGet { local_index } if local_index == param => {
+                return Ok(true)
+            }
+            wasmparser::Operator::LocalSet { local_index }
+            | wasmparser::Operator::LocalTee { local_index }
+                if local_index == param =>
+            {
+                return Ok(false)
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
@@ -70,17 +70,200 @@ fn maybe_mem_image(mem: &MemoryData, snapshot_bytes: Option<&[u8]>) -> Option<Me
     Some(MemImage { image })
 }
 
+/// Bytes closer than this are reported as one divergent range.
+const DIFF_MERGE_GAP: usize = 16;
+
+/// Byte ranges in which two memory images differ, with the shorter
+/// one taken as extended with zeroes, and ranges closer than
+/// `DIFF_MERGE_GAP` merged.
+pub(crate) fn diff_ranges(a: &MemImage, b: &MemImage) -> Vec<(usize, usize)> {
+    let len = std::cmp::max(a.len(), b.len());
+    let byte = |image: &MemImage, i: usize| image.image.get(i).copied().unwrap_or(0);
+    let mut range: Option<(usize, usize)> = None;
+    let mut ranges = vec![];
+    for i in 0..len {
+        if byte(a, i) == byte(b, i) {
+            continue;
+        }
+        match &mut range {
+            Some((_, end)) if i - *end <= DIFF_MERGE_GAP => *end = i + 1,
+            _ => {
+                ranges.extend(range);
+                range = Some((i, i + 1));
+            }
+        }
+    }
+    ranges.extend(range);
+    ranges
+}
+
+/// Number of changed ranges listed per memory by `describe_changes`.
+const MAX_LISTED_RANGES: usize = 8;
+
+/// Summarize what changed from the image `before` (built from the
+/// original data segments) to the image `after` (the snapshot): per
+/// memory, its size, the number and total size of changed ranges, and
+/// the largest ranges; and the globals whose values changed.
+pub(crate) fn describe_changes(before: &Image, after: &Image) -> Vec<String> {
+    let mut lines = vec![];
+    for (&mem_id, mem_after) in &after.memories {
+        let empty = MemImage { image: vec![] };
+        let mem_before = before.memories.get(&mem_id).unwrap_or(&empty);
+        let mut ranges = diff_ranges(mem_before, mem_after);
+        let spanned: usize = ranges.iter().map(|(start, end)| end - start).sum();
+        lines.push(format!(
+            "{}: {:#x} -> {:#x} bytes ({} -> {} pages); changed ranges span {} bytes in {} ranges",
+            mem_id,
+            mem_before.len(),
+            mem_after.len(),
+            mem_before.len() / WASM_PAGE,
+            mem_after.len() / WASM_PAGE,
+            spanned,
+            ranges.len()
+        ));
+        ranges.sort_by_key(|(start, end)| std::cmp::Reverse(end - start));
+        for &(start, end) in ranges.iter().take(MAX_LISTED_RANGES) {
+            lines.push(format!(
+                "  {:#x}..{:#x} ({} bytes)",
+                start,
+                end,
+                end - start
+            ));
+        }
+        if ranges.len() > MAX_LISTED_RANGES {
+            lines.push(format!(
+                "  ... and {} smaller ranges",
+                ranges.len() - MAX_LISTED_RANGES
+            ));
+        }
+    }
+    for (&global_id, val_after) in &after.globals {
+        match before.globals.get(&global_id) {
+            Some(val_before) if val_before == val_after => {}
+            Some(val_before) => lines.push(format!(
+                "{}: {:?} -> {:?}",
+                global_id, val_before, val_after
+            )),
+            None => lines.push(format!("{}: -> {:?}", global_id, val_after)),
+        }
+    }
+    lines
+}
+
+/// Describe how two images of the same module differ: byte ranges of
+/// memories, memory sizes, and global values.
+pub(crate) fn diff(a: &Image, b: &Image) -> Vec<String> {
+    let mut diffs = vec![];
+    for (&mem_id, mem_a) in &a.memories {
+        let Some(mem_b) = b.memories.get(&mem_id) else {
+            diffs.push(format!("{}: present only in the first image", mem_id));
+            continue;
+        };
+        if mem_a.len() != mem_b.len() {
+            diffs.push(format!(
+                "{}: size {:#x} vs. {:#x}",
+                mem_id,
+                mem_a.len(),
+                mem_b.len()
+            ));
+        }
+        let common = std::cmp::min(mem_a.len(), mem_b.len());
+        for (start, end) in diff_ranges(mem_a, mem_b) {
+            if start >= common {
+                continue;
+            }
+            let sample = start..std::cmp::min(end, std::cmp::min(common, start + 8));
+            diffs.push(format!(
+                "{}: bytes {:#x}..{:#x} differ ({:02x?} vs. {:02x?})",
+                mem_id,
+                start,
+                end,
+                &mem_a.image[sample.clone()],
+                &mem_b.image[sample],
+            ));
+        }
+    }
+    for (&global_id, val_a) in &a.globals {
+        match b.globals.get(&global_id) {
+            Some(val_b) if val_a == val_b => {}
+            Some(val_b) => diffs.push(format!("{}: {:?} vs. {:?}", global_id, val_a, val_b)),
+            None => diffs.push(format!("{}: present only in the first image", global_id)),
+        }
+    }
+    diffs
+}
+
+/// Runs of nonzero bytes outside the original segments that are
+/// closer than this are written as one new segment.
+const SEGMENT_MERGE_GAP: usize = 16;
+/// Past this many segments in one memory, write the whole image as a
+/// single segment instead.
+const MAX_SEGMENTS: usize = 10_000;
+
+/// Write the image back into the module's data segments. The
+/// original segments are kept and patched in place from the image,
+/// and nonzero bytes that no segment covers get new segments, so an
+/// unmodified memory comes out exactly as it came in.
 pub(crate) fn update(module: &mut Module, im: &Image) {
     for (&mem_id, mem) in &im.memories {
-        module.memories[mem_id].segments.clear();
-        module.memories[mem_id].segments.push(MemorySegment {
-            offset: 0,
-            data: mem.image.clone(),
-        });
+        let memory = &mut module.memories[mem_id];
+        let mut covered = vec![];
+        for segment in &mut memory.segments {
+            let range = segment.offset..(segment.offset + segment.data.len());
+            segment.data.copy_from_slice(&mem.image[range.clone()]);
+            covered.push(range);
+        }
+        covered.sort_by_key(|range| range.start);
+
+        let mut new_segments = vec![];
+        let mut pos = 0;
+        for range in covered
+            .into_iter()
+            .chain(std::iter::once(mem.len()..mem.len()))
+        {
+            if range.start > pos {
+                new_segments.extend(nonzero_runs(&mem.image[pos..range.start], pos));
+            }
+            pos = std::cmp::max(pos, range.end);
+        }
+
+        if memory.segments.len() + new_segments.len() > MAX_SEGMENTS {
+            memory.segments = vec![MemorySegment {
+                offset: 0,
+                data: mem.image.clone(),
+            }];
+        } else {
+            memory
+                .segments
+                .extend(new_segments.into_iter().map(|(start, end)| MemorySegment {
+                    offset: start,
+                    data: mem.image[start..end].to_vec(),
+                }));
+        }
+
         let image_pages = mem.image.len() / WASM_PAGE;
-        module.memories[mem_id].initial_pages =
-            std::cmp::max(module.memories[mem_id].initial_pages, image_pages);
+        memory.initial_pages = std::cmp::max(memory.initial_pages, image_pages);
+    }
+}
+
+/// Ranges of nonzero bytes in `bytes`, offset by `base`, with ranges
+/// closer than `SEGMENT_MERGE_GAP` merged.
+fn nonzero_runs(bytes: &[u8], base: usize) -> Vec<(usize, usize)> {
+    let mut runs = vec![];
+    let mut pos = 0;
+    while let Some(start) = bytes[pos..].iter().position(|&b| b != 0) {
+        let start = pos + start;
+        let mut end = start + 1;
+        while let Some(next) = bytes[end..].iter().position(|&b| b != 0) {
+            if next >= SEGMENT_MERGE_GAP {
+                break;
+            }
+            end += next + 1;
+        }
+        runs.push((base + start, base + end));
+        pos = end;
     }
+    runs
 }
 
 impl Image {
@@ -101,55 +284,48 @@ impl Image {
             .ok_or_else(|| anyhow::anyhow!("no main heap"))
     }
 
+    /// The `len` bytes at `addr` in memory `id`, or an error naming
+    /// the range if any of it lies outside the image.
     pub(crate) fn read_slice(&self, id: Memory, addr: u32, len: u32) -> anyhow::Result<&[u8]> {
-        let image = self.memories.get(&id).unwrap();
-        let addr = usize::try_from(addr).unwrap();
-        let len = usize::try_from(len).unwrap();
-        if addr + len >= image.len() {
-            anyhow::bail!("Out of bounds");
-        }
-        Ok(&image.image[addr..(addr + len)])
+        let image = self
+            .memories
+            .get(&id)
+            .ok_or_else(|| anyhow::anyhow!("{} has no image", id))?;
+        let start = addr as usize;
+        let end = start + len as usize;
+        image.image.get(start..end).ok_or_else(|| {
+            anyhow::anyhow!(
+                "{:#x}..{:#x} is outside the {:#x}-byte image of {}",
+                start,
+                end,
+                image.len(),
+                id
+            )
+        })
     }
 
     pub(crate) fn read_u8(&self, id: Memory, addr: u32) -> anyhow::Result<u8> {
-        let image = self.memories.get(&id).unwrap();
-        image
-            .image
-            .get(addr as usize)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Out of bounds"))
+        Ok(self.read_slice(id, addr, 1)?[0])
     }
 
     pub(crate) fn read_u16(&self, id: Memory, addr: u32) -> anyhow::Result<u16> {
-        let image = self.memories.get(&id).unwrap();
-        let addr = addr as usize;
-        if (addr + 2) > image.len() {
-            anyhow::bail!("Out of bounds");
-        }
-        let slice = &image.image[addr..(addr + 2)];
+        let slice = self.read_slice(id, addr, 2)?;
         Ok(u16::from_le_bytes([slice[0], slice[1]]))
     }
 
     pub(crate) fn read_u32(&self, id: Memory, addr: u32) -> anyhow::Result<u32> {
-        let image = self.memories.get(&id).unwrap();
-        let addr = addr as usize;
-        if (addr + 4) > image.len() {
-            anyhow::bail!("Out of bounds");
-        }
-        let slice = &image.image[addr..(addr + 4)];
+        let slice = self.read_slice(id, addr, 4)?;
         Ok(u32::from_le_bytes([slice[0], slice[1], slice[2], slice[3]]))
     }
 
     pub(crate) fn read_u64(&self, id: Memory, addr: u32) -> anyhow::Result<u64> {
-        let low = self.read_u32(id, addr)?;
-        let high = self.read_u32(id, addr + 4)?;
-        Ok((high as u64) << 32 | (low as u64))
+        let slice = self.read_slice(id, addr, 8)?;
+        Ok(u64::from_le_bytes(slice.try_into().unwrap()))
     }
 
     pub(crate) fn read_u128(&self, id: Memory, addr: u32) -> anyhow::Result<u128> {
-        let low = self.read_u64(id, addr)?;
-        let high = self.read_u64(id, addr + 8)?;
-        Ok((high as u128) << 64 | (low as u128))
+        let slice = self.read_slice(id, addr, 16)?;
+        Ok(u128::from_le_bytes(slice.try_into().unwrap()))
     }
 
     pub(crate) fn read_size(&self, id: Memory, addr: u32, size: u8) -> anyhow::Result<u64> {
@@ -158,19 +334,22 @@ impl Image {
             2 => self.read_u16(id, addr).map(|x| x as u64),
             4 => self.read_u32(id, addr).map(|x| x as u64),
             8 => self.read_u64(id, addr),
-            _ => panic!("bad size"),
+            _ => anyhow::bail!("unsupported read size {}", size),
         }
     }
 
-    pub(crate) fn read_str(&self, id: Memory, mut addr: u32) -> anyhow::Result<String> {
+    pub(crate) fn read_str(&self, id: Memory, addr: u32) -> anyhow::Result<String> {
         let mut bytes = vec![];
+        let mut ptr = addr;
         loop {
-            let byte = self.read_u8(id, addr)?;
+            let byte = self.read_u8(id, ptr)?;
             if byte == 0 {
                 break;
             }
             bytes.push(byte);
-            addr += 1;
+            ptr = ptr
+                .checked_add(1)
+                .ok_or_else(|| anyhow::anyhow!("unterminated string at {:#x}", addr))?;
         }
         Ok(std::str::from_utf8(&bytes[..])?.to_owned())
     }
@@ -217,7 +396,7 @@ impl Image {
         image
             .image
             .extend(data.into_iter().chain(std::iter::repeat(0).take(padding)));
-        log::debug!(
+        tracing::debug!(
             "Appending data ({} bytes, {} padding): went from {} bytes to {} bytes",
             data_len,
             padding,
@@ -1,5 +1,6 @@
 //! Discovery of intrinsics.
 
+use waffle::entity::EntityRef;
 use waffle::{ExportKind, Func, ImportKind, Module, Operator, Terminator, Type, ValueDef};
 
 #[derive(Clone, Debug)]
@@ -13,6 +14,7 @@ pub(crate) struct Intrinsics {
     pub abort_specialization: Option<Func>,
     pub trace_line: Option<Func>,
     pub assert_const32: Option<Func>,
+    pub assert_not_escaped: Option<Func>,
     pub specialize_value: Option<Func>,
     pub print: Option<Func>,
     pub read_specialization_global: Option<Func>,
@@ -47,6 +49,12 @@ impl Intrinsics {
                 &[Type::I32, Type::I32],
                 &[],
             ),
+            assert_not_escaped: find_imported_intrinsic(
+                module,
+                "assert.not.escaped",
+                &[Type::I32, Type::I32],
+                &[],
+            ),
             specialize_value: find_imported_intrinsic(
                 module,
                 "specialize.value",
@@ -135,6 +143,26 @@ pub(crate) fn find_exported_func(
         })
 }
 
+/// The function that `name` refers to on the command line: a function
+/// name, an export name or a function index.
+pub(crate) fn find_func(module: &Module, name: &str) -> Option<Func> {
+    module
+        .funcs
+        .entries()
+        .find(|(_, decl)| decl.name() == name)
+        .map(|(func, _)| func)
+        .or_else(|| {
+            module.exports.iter().find_map(|export| match export.kind {
+                ExportKind::Func(func) if export.name == name => Some(func),
+                _ => None,
+            })
+        })
+        .or_else(|| {
+            let index = name.parse::<usize>().ok()?;
+            (index < module.funcs.len()).then(|| Func::new(index))
+        })
+}
+
 pub(crate) fn find_global_data_by_exported_func(module: &Module, name: &str) -> Option<u32> {
     let f = find_exported_func(module, name, &[], &[Type::I32])?;
     let mut body = module.funcs[f].clone();
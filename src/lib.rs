@@ -0,0 +1,153 @@
+//! weval, the WebAssembly partial evaluator, as a library.
+//!
+//! [`specialize`] runs the core of `weval weval` on a module in
+//! memory: it collects the module's specialization requests, partially
+//! evaluates each, and returns the module with the specializations
+//! filled in. Wizening, the on-disk cache and the other command-line
+//! options stay in the `weval` binary. Built with
+//! `--no-default-features`, the library needs no Wasmtime or SQLite
+//! and compiles for `wasm32-wasip1`.
+//!
+//! Some of the analyses and passes weval runs on waffle IR are
+//! exported too, for passes of one's own (see [`Passes`]): value
+//! liveness ([`liveness`]), dead-code elimination ([`dce`]) and
+//! detection of irreducible control flow ([`reducibility`]). They
+//! take waffle's [`waffle::cfg::CFGInfo`] for the function.
+
+#![allow(dead_code)]
+
+mod batch;
+mod build_id;
+mod cache;
+mod candidates;
+mod checkpoint;
+mod cli;
+mod compact_locals;
+mod const_eval;
+mod constant_offsets;
+pub mod dce;
+mod dedup;
+mod devirt;
+mod directive;
+mod dispatch;
+mod dot;
+mod emit_facts;
+mod error;
+mod escape;
+mod eval;
+mod filter;
+mod flush;
+mod fuzz;
+mod golden;
+#[cfg(feature = "wizer")]
+mod guest_output;
+mod hooks;
+mod host;
+mod image;
+mod intrinsics;
+mod licm;
+pub mod liveness;
+mod opcode_stubs;
+mod osr;
+mod outline;
+mod pc_profile;
+mod policy;
+mod precompile;
+mod preset;
+mod profile;
+mod progress;
+mod pure_imports;
+mod query;
+pub mod reducibility;
+mod report;
+mod runtime_hooks;
+mod serve;
+mod snapshot;
+mod state;
+mod stats;
+mod switch;
+mod value;
+mod verify;
+pub use hooks::{FunctionPass, ModulePass, Passes};
+pub use waffle;
+
+/// Options for [`specialize`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct Options {
+    /// Keep operators that may trap, as `--preserve-traps` does.
+    pub preserve_traps: bool,
+    /// Leave folds the engine may compute differently to the engine,
+    /// as `--deterministic-folds` does.
+    pub deterministic_folds: bool,
+    /// Check IR invariants after every pass, as `--verify` does.
+    pub verify: bool,
+    /// Skip cleanups and limit contexts, as `--fast` does.
+    pub fast: bool,
+    /// Passes to add to the pipeline.
+    pub passes: Passes,
+}
+
+/// Specialize a module: the equivalent of `weval weval` without `-w`,
+/// returning the output module's bytes. Every request the module's
+/// memory image lists must be well-formed.
+pub fn specialize(module_bytes: &[u8], opts: &Options) -> anyhow::Result<Vec<u8>> {
+    let hash = cache::compute_hash(module_bytes);
+    let cache = cache::Cache::open(None, None, hash)?;
+
+    let frontend_opts = waffle::FrontendOptions { debug: true };
+    let mut module = waffle::Module::from_wasm_bytes(module_bytes, &frontend_opts)?;
+    Passes::run_module(&opts.passes.before_directives, &mut module)?;
+    let mut im = image::build_image(&module, None)?;
+    let directives = directive::collect(&module, &mut im, false)?;
+
+    let eval_options = eval::EvalOptions {
+        output_ir: None,
+        auto_dispatch: Default::default(),
+        dispatch_only: false,
+        hot_pcs: None,
+        outline_common: None,
+        link_results: false,
+        devirtualize_results: false,
+        max_growth: None,
+        fast: opts.fast,
+        facts_output: None,
+        pure_imports: Default::default(),
+        wizened_start: None,
+        verify: opts.verify,
+        preserve_traps: opts.preserve_traps,
+        deterministic_folds: opts.deterministic_folds,
+        memory: None,
+        function_passes: opts.passes.after_specialize.clone(),
+    };
+    let mut result = eval::partially_evaluate(
+        module,
+        &mut im,
+        &directives[..],
+        None,
+        &eval_options,
+        &cache,
+        None,
+    )?;
+    image::update(&mut result.module, &im);
+    Passes::run_module(&opts.passes.before_emit, &mut result.module)?;
+
+    let specialized = if opts.fast {
+        Default::default()
+    } else {
+        result
+            .sizes
+            .iter()
+            .map(|size| waffle::entity::EntityRef::index(size.specialized) as u32)
+            .collect()
+    };
+    let bytes = result.module.to_wasm_bytes()?;
+    let (bytes, _) = filter::filter(&bytes[..], vec![], specialized, false)?;
+    Ok(bytes)
+}
+
+/// Run the `weval` command line, for the `weval` binary.
+#[doc(hidden)]
+pub fn cli_main() -> anyhow::Result<()> {
+    cli::main()
+}
@@ -0,0 +1,270 @@
+//! Loop-invariant code motion over specialized function bodies.
+//!
+//! Specialization replicates the interpreter loop body once per
+//! bytecode PC, so a loop in the guest becomes a loop over those
+//! copies; address computations on VM state that does not change in
+//! the guest loop, and reloads of that state, are then redone on
+//! every iteration. This pass hoists them into the loop's preheader.
+//!
+//! An instruction is hoisted if all its operands are defined outside
+//! the loop (or were hoisted already), and either it is pure, or it
+//! only reads memory, globals or tables that nothing in the loop
+//! writes (the loop has no calls) and may trap only if its block runs
+//! on every iteration: it dominates every latch and every exit of the
+//! loop. With `--preserve-traps`, nothing that may trap is hoisted.
+//! Calls to imports declared pure are hoisted like pure operators.
+//!
+//! Constants are left where they are, as hoisting them alone only
+//! lengthens live ranges; a hoisted instruction gets its own copy of
+//! any constant operand from the loop.
+
+use fxhash::{FxHashMap, FxHashSet};
+use waffle::cfg::CFGInfo;
+use waffle::{
+    Block, BlockTarget, Func, FunctionBody, Operator, SideEffect, Terminator, Value, ValueDef,
+};
+
+/// A natural loop: its header, preheader and body (including the
+/// header), with its blocks in RPO.
+struct Loop {
+    header: Block,
+    preheader: Block,
+    blocks: Vec<Block>,
+}
+
+/// Hoist loop-invariant instructions into loop preheaders, creating a
+/// preheader for each loop that lacks one. Returns the number of
+/// instructions hoisted. Adds blocks, so `cfg` must be recomputed
+/// afterward.
+pub(crate) fn run(
+    func: &mut FunctionBody,
+    cfg: &CFGInfo,
+    pure_imports: &FxHashSet<Func>,
+    preserve_traps: bool,
+) -> usize {
+    let headers = find_headers(func, cfg);
+    if headers.is_empty() {
+        return 0;
+    }
+    for &header in &headers {
+        add_preheader(func, cfg, header);
+    }
+    func.recompute_edges();
+    let cfg = CFGInfo::new(func);
+
+    let mut def_block = FxHashMap::default();
+    for (block, def) in func.blocks.entries() {
+        for &(_, param) in &def.params {
+            def_block.insert(param, block);
+        }
+        for &inst in &def.insts {
+            def_block.insert(inst, block);
+        }
+    }
+
+    let mut loops = headers
+        .iter()
+        .filter_map(|&header| find_loop(func, &cfg, header))
+        .collect::<Vec<_>>();
+    // Inner loops first, so that what they hoist may be hoisted again
+    // out of the outer loop.
+    loops.sort_by_key(|l| l.blocks.len());
+
+    let mut hoisted = 0;
+    for l in &loops {
+        hoisted += hoist(func, &cfg, l, &mut def_block, pure_imports, preserve_traps);
+    }
+    hoisted
+}
+
+/// Loop headers: targets of a branch from a block they dominate.
+fn find_headers(func: &FunctionBody, cfg: &CFGInfo) -> Vec<Block> {
+    let mut headers = vec![];
+    let mut seen = FxHashSet::default();
+    for &block in cfg.rpo.values() {
+        for &succ in &func.blocks[block].succs {
+            if cfg.dominates(succ, block) && seen.insert(succ) {
+                headers.push(succ);
+            }
+        }
+    }
+    headers
+}
+
+/// Give `header` a preheader, unless its only predecessor from outside
+/// the loop already branches only to it: a new block that all entries
+/// to the loop go through.
+fn add_preheader(func: &mut FunctionBody, cfg: &CFGInfo, header: Block) {
+    let entries = func.blocks[header]
+        .preds
+        .iter()
+        .copied()
+        .filter(|&pred| cfg.rpo_pos[pred].is_some() && !cfg.dominates(header, pred))
+        .collect::<FxHashSet<_>>();
+    if entries.is_empty() {
+        // The function's entry block.
+        return;
+    }
+    if entries.len() == 1 {
+        let pred = *entries.iter().next().unwrap();
+        if matches!(func.blocks[pred].terminator, Terminator::Br { .. }) {
+            return;
+        }
+    }
+
+    let preheader = func.add_block();
+    let params = func.blocks[header]
+        .params
+        .clone()
+        .into_iter()
+        .map(|(ty, _)| func.add_blockparam(preheader, ty))
+        .collect::<Vec<_>>();
+    func.blocks[preheader].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: header,
+            args: params,
+        },
+    };
+    for pred in entries {
+        func.blocks[pred].terminator.update_targets(|target| {
+            if target.block == header {
+                target.block = preheader;
+            }
+        });
+    }
+}
+
+fn find_loop(func: &FunctionBody, cfg: &CFGInfo, header: Block) -> Option<Loop> {
+    let mut body = FxHashSet::from_iter([header]);
+    let mut stack = vec![];
+    let mut preheader = None;
+    for &pred in &func.blocks[header].preds {
+        if cfg.rpo_pos[pred].is_none() {
+            continue;
+        }
+        if cfg.dominates(header, pred) {
+            stack.push(pred);
+        } else if preheader.replace(pred).is_some_and(|other| other != pred) {
+            return None;
+        }
+    }
+    while let Some(block) = stack.pop() {
+        if body.insert(block) {
+            stack.extend(
+                func.blocks[block]
+                    .preds
+                    .iter()
+                    .filter(|&&pred| cfg.rpo_pos[pred].is_some()),
+            );
+        }
+    }
+    let blocks = cfg
+        .rpo
+        .values()
+        .copied()
+        .filter(|block| body.contains(block))
+        .collect();
+    Some(Loop {
+        header,
+        preheader: preheader?,
+        blocks,
+    })
+}
+
+fn hoist(
+    func: &mut FunctionBody,
+    cfg: &CFGInfo,
+    l: &Loop,
+    def_block: &mut FxHashMap<Value, Block>,
+    pure_imports: &FxHashSet<Func>,
+    preserve_traps: bool,
+) -> usize {
+    let effects = |op: &Operator| match op {
+        Operator::Call { function_index } if pure_imports.contains(function_index) => &[],
+        op => op.effects(),
+    };
+    let body = l.blocks.iter().copied().collect::<FxHashSet<_>>();
+    let mut writes = vec![];
+    let mut ends = vec![];
+    for &block in &l.blocks {
+        for &inst in &func.blocks[block].insts {
+            if let ValueDef::Operator(op, ..) = &func.values[inst] {
+                writes.extend(effects(op).iter().copied().filter(|e| {
+                    matches!(
+                        e,
+                        SideEffect::WriteMem
+                            | SideEffect::WriteGlobal
+                            | SideEffect::WriteTable
+                            | SideEffect::All
+                    )
+                }));
+            }
+        }
+        // Latches and exits.
+        if func.blocks[block]
+            .succs
+            .iter()
+            .any(|succ| *succ == l.header || !body.contains(succ))
+        {
+            ends.push(block);
+        }
+    }
+    let may_write = |e| writes.contains(&e) || writes.contains(&SideEffect::All);
+    let in_loop = |def_block: &FxHashMap<Value, Block>, value: &Value| {
+        def_block
+            .get(value)
+            .is_some_and(|block| body.contains(block))
+    };
+
+    let mut hoisted = 0;
+    for &block in &l.blocks {
+        let every_iteration = ends.iter().all(|&end| cfg.dominates(block, end));
+        let insts = std::mem::take(&mut func.blocks[block].insts);
+        let mut kept = vec![];
+        for inst in insts {
+            let ValueDef::Operator(op, args, _) = &func.values[inst] else {
+                kept.push(inst);
+                continue;
+            };
+            let (op, args) = (*op, *args);
+            let invariant = !is_const(func, inst)
+                && func.arg_pool[args]
+                    .iter()
+                    .all(|arg| !in_loop(def_block, arg) || is_const(func, *arg))
+                && effects(&op).iter().all(|e| match e {
+                    SideEffect::Trap => every_iteration && !preserve_traps,
+                    SideEffect::ReadMem => every_iteration && !may_write(SideEffect::WriteMem),
+                    SideEffect::ReadGlobal => !may_write(SideEffect::WriteGlobal),
+                    SideEffect::ReadTable => !may_write(SideEffect::WriteTable),
+                    _ => false,
+                });
+            if !invariant {
+                kept.push(inst);
+                continue;
+            }
+            for i in 0..args.len() {
+                let arg = func.arg_pool[args][i];
+                if in_loop(def_block, &arg) {
+                    let ValueDef::Operator(op, _, tys) = func.values[arg] else {
+                        unreachable!()
+                    };
+                    let copy = func.add_value(ValueDef::Operator(op, Default::default(), tys));
+                    func.blocks[l.preheader].insts.push(copy);
+                    def_block.insert(copy, l.preheader);
+                    func.arg_pool[args][i] = copy;
+                }
+            }
+            tracing::trace!("licm: hoisting {} from {} to {}", inst, block, l.preheader);
+            func.blocks[l.preheader].insts.push(inst);
+            def_block.insert(inst, l.preheader);
+            hoisted += 1;
+        }
+        func.blocks[block].insts = kept;
+    }
+    hoisted
+}
+
+/// Whether `value` is a constant (a pure operator with no operands).
+fn is_const(func: &FunctionBody, value: Value) -> bool {
+    matches!(&func.values[value], ValueDef::Operator(op, args, _) if args.is_empty() && op.is_pure())
+}
@@ -1,20 +1,41 @@
 //! Liveness analysis: analyze the register pressure of original and
-//! specialized functions.
+//! specialized functions, and find which specialization registers
+//! are live in a generic function.
+//!
+//! The register pressure of specialized functions can be emitted as
+//! hints for the engine that compiles them (see `--pressure-hints`).
+//!
+//! [`Liveness`] and [`scan_block_backward`] work on any function body,
+//! and are exported for library users' passes.
 
+use crate::intrinsics::Intrinsics;
 use fxhash::FxHashSet;
-use std::collections::VecDeque;
-use waffle::{cfg::CFGInfo, entity::PerEntity, Block, FunctionBody, Terminator, Value, ValueDef};
+use std::collections::{BTreeSet, VecDeque};
+use waffle::{
+    cfg::CFGInfo, entity::PerEntity, Block, FunctionBody, Operator, Terminator, Value, ValueDef,
+};
 
-pub(crate) type LiveSet = FxHashSet<Value>;
+/// A set of live SSA values.
+pub type LiveSet = FxHashSet<Value>;
 
+/// The SSA values live at the start and end of each block of a
+/// function, with aliases resolved. Blocks from which no `return` or
+/// `unreachable` can be reached have empty sets.
 #[derive(Clone, Debug)]
-pub(crate) struct Liveness<'a> {
+pub struct Liveness<'a> {
+    /// The function analyzed.
     pub func: &'a FunctionBody,
+    /// Values live on entry to each block, not counting its
+    /// parameters.
     pub block_start: PerEntity<Block, LiveSet>,
+    /// Values live on exit from each block.
     pub block_end: PerEntity<Block, LiveSet>,
 }
 
-pub(crate) fn scan_block_backward<T, Use: Fn(&mut T, Value), Def: Fn(&mut T, Value)>(
+/// Visit the instructions of `block` from last to first, calling
+/// `use_func` for each value used (aliases resolved) and `def_func`
+/// for each value defined, the block's parameters last.
+pub fn scan_block_backward<T, Use: Fn(&mut T, Value), Def: Fn(&mut T, Value)>(
     func: &FunctionBody,
     block: Block,
     state: &mut T,
@@ -53,7 +74,8 @@ pub(crate) fn scan_block_backward<T, Use: Fn(&mut T, Value), Def: Fn(&mut T, Val
 }
 
 impl<'a> Liveness<'a> {
-    pub(crate) fn new(func: &'a FunctionBody, cfg: &CFGInfo) -> Liveness<'a> {
+    /// Compute liveness for `func`, whose CFG is `cfg`.
+    pub fn new(func: &'a FunctionBody, cfg: &CFGInfo) -> Liveness<'a> {
         let mut this = Liveness {
             func,
             block_start: PerEntity::default(),
@@ -109,3 +131,97 @@ impl<'a> Liveness<'a> {
         this
     }
 }
+
+/// Register-pressure estimate for a specialized function, in terms
+/// of SSA values live at block starts.
+///
+/// This is per function rather than per block: the blocks of the IR
+/// do not correspond to anything the engine sees once the function is
+/// lowered to structured Wasm.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PressureHint {
+    /// Number of reachable blocks.
+    pub blocks: u32,
+    /// Largest number of values live at the start of any block.
+    pub max_live: u32,
+    /// Sum over all blocks of the values live at their start.
+    pub total_live: u32,
+}
+
+/// Specialization registers that may be read before being written,
+/// or `None` if any may (a register is named by a non-constant).
+pub(crate) type LiveRegs = Option<BTreeSet<u32>>;
+
+fn const_reg(func: &FunctionBody, value: Value) -> Option<u32> {
+    match &func.values[func.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I64Const { value }, _, _) => Some(*value as u32),
+        _ => None,
+    }
+}
+
+fn scan_regs_backward(
+    func: &FunctionBody,
+    block: Block,
+    intrinsics: &Intrinsics,
+    live: &mut LiveRegs,
+) {
+    for &inst in func.blocks[block].insts.iter().rev() {
+        let ValueDef::Operator(Operator::Call { function_index }, args, _) = &func.values[inst]
+        else {
+            continue;
+        };
+        let reg = || const_reg(func, func.arg_pool[*args][0]);
+        if Some(*function_index) == intrinsics.write_reg {
+            if let (Some(live), Some(reg)) = (live.as_mut(), reg()) {
+                live.remove(&reg);
+            }
+        } else if Some(*function_index) == intrinsics.read_reg {
+            match (live.as_mut(), reg()) {
+                (Some(live), Some(reg)) => {
+                    live.insert(reg);
+                }
+                _ => *live = None,
+            }
+        }
+    }
+}
+
+/// Compute the specialization registers live at the start of each
+/// block of a generic function.
+///
+/// This is computed on the generic CFG, so it holds in every context
+/// the block is specialized in, across context transitions: a
+/// register dead at a block is not needed by any code reachable from
+/// any of its specializations, and need not be carried into them.
+pub(crate) fn live_regs(
+    func: &FunctionBody,
+    cfg: &CFGInfo,
+    intrinsics: &Intrinsics,
+) -> PerEntity<Block, LiveRegs> {
+    let mut block_start: PerEntity<Block, LiveRegs> = PerEntity::default();
+    for block in func.blocks.iter() {
+        block_start[block] = Some(BTreeSet::new());
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in cfg.rpo.values().rev() {
+            let mut live = Some(BTreeSet::new());
+            func.blocks[block].terminator.visit_successors(|succ| {
+                live = match (live.take(), &block_start[succ]) {
+                    (Some(mut live), Some(succ_live)) => {
+                        live.extend(succ_live.iter().copied());
+                        Some(live)
+                    }
+                    _ => None,
+                };
+            });
+            scan_regs_backward(func, block, intrinsics, &mut live);
+            if live != block_start[block] {
+                block_start[block] = live;
+                changed = true;
+            }
+        }
+    }
+    block_start
+}
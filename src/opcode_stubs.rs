@@ -0,0 +1,226 @@
+//! Per-opcode stubs for template JITs.
+//!
+//! A template JIT compiles bytecode by splicing together a prebuilt
+//! piece of code per opcode. With `--opcode-stubs`, weval provides
+//! those pieces: each opcode handler in the `--auto-dispatch` loop is
+//! outlined, as for `--dispatch-only`, into a function of its own,
+//! exported as `weval.stub.N` for opcode N (opcodes sharing a handler
+//! share a function). The generic interpreter is left as it is.
+//!
+//! A stub takes the handler's entry state as parameters: values the
+//! handler reads from the dispatch loop's state, from the interpreter
+//! function's parameters, or from the bytecode instruction itself (its
+//! immediates, at fixed offsets from the opcode). It returns as an
+//! outlined handler does: the index of the exit it takes, if it has
+//! more than one, then the values it computes for the exits. A handler
+//! reading anything else, or one that cannot be outlined, gets no stub.
+//!
+//! The `weval.opcode-stubs` custom section describes the stubs. It
+//! holds a count, then per stub: the opcode; the number of parameters,
+//! and for each a kind byte followed by
+//!
+//! - 0 (loop state): the index of the loop header's block parameter;
+//! - 1 (function parameter): the parameter's index;
+//! - 2 (immediate): the offset of its bytes from the opcode's (signed),
+//!   its size in bytes, and 1 if sign-extended, otherwise 0;
+//!
+//! and the number of exits, with a byte for each: 0 if it continues
+//! the dispatch loop, 1 if it leaves it. All numbers are LEB128s.
+
+use crate::dispatch::{self, DispatchLoop};
+use waffle::cfg::CFGInfo;
+use waffle::wasm_encoder::{self, Encode, Section};
+use waffle::{
+    Export, ExportKind, Func, FunctionBody, Module, Operator, Terminator, Value, ValueDef,
+};
+
+pub(crate) const SECTION_NAME: &str = "weval.opcode-stubs";
+pub(crate) const EXPORT_PREFIX: &str = "weval.stub.";
+
+/// Where a stub parameter comes from.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Param {
+    State(u32),
+    Arg(u32),
+    Immediate {
+        offset: i32,
+        size: u32,
+        signed: bool,
+    },
+}
+
+pub(crate) struct Stub {
+    pub(crate) opcode: u32,
+    params: Vec<Param>,
+    /// Per exit, whether it leaves the dispatch loop.
+    exits: Vec<bool>,
+}
+
+/// The bytes a load reads, whether it sign-extends them, and its
+/// offset.
+fn load_layout(op: &Operator) -> Option<(u32, bool, u32)> {
+    Some(match op {
+        Operator::I32Load { memory } | Operator::I64Load32U { memory } => (4, false, memory.offset),
+        Operator::I64Load32S { memory } => (4, true, memory.offset),
+        Operator::I64Load { memory } => (8, false, memory.offset),
+        Operator::I32Load8U { memory } | Operator::I64Load8U { memory } => {
+            (1, false, memory.offset)
+        }
+        Operator::I32Load8S { memory } | Operator::I64Load8S { memory } => (1, true, memory.offset),
+        Operator::I32Load16U { memory } | Operator::I64Load16U { memory } => {
+            (2, false, memory.offset)
+        }
+        Operator::I32Load16S { memory } | Operator::I64Load16S { memory } => {
+            (2, true, memory.offset)
+        }
+        _ => return None,
+    })
+}
+
+/// Where `value` comes from, given the load of the opcode.
+fn classify(body: &FunctionBody, l: &DispatchLoop, opcode: Value, value: Value) -> Option<Param> {
+    let value = body.resolve_alias(value);
+    match &body.values[value] {
+        &ValueDef::BlockParam(block, idx, _) if block == l.header => Some(Param::State(idx)),
+        &ValueDef::BlockParam(block, idx, _) if block == body.entry => Some(Param::Arg(idx)),
+        ValueDef::Operator(op, args, _) => {
+            let (size, signed, offset) = load_layout(op)?;
+            let ValueDef::Operator(opcode_op, opcode_args, _) = &body.values[opcode] else {
+                return None;
+            };
+            let (_, _, opcode_offset) = load_layout(opcode_op)?;
+            dispatch::is_same_expr(
+                body,
+                body.arg_pool[*args][0],
+                body.arg_pool[*opcode_args][0],
+                dispatch::MAX_ADDRESS_DEPTH,
+            )
+            .then(|| Param::Immediate {
+                offset: offset.wrapping_sub(opcode_offset) as i32,
+                size,
+                signed,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Add a stub for each opcode handler in the dispatch loop of `func`
+/// keyed on its parameter number `buffer_param`, and export them.
+pub(crate) fn add(
+    module: &mut Module,
+    func: Func,
+    buffer_param: usize,
+) -> anyhow::Result<Vec<Stub>> {
+    let name = module.funcs[func].name().to_owned();
+    let mut body = module.clone_and_expand_body(func)?;
+    body.recompute_edges();
+    let cfg = CFGInfo::new(&body);
+    let l = dispatch::find_loops(&body, &cfg, buffer_param)
+        .into_iter()
+        .find(|l| l.handler_table.is_none())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "--opcode-stubs: no dispatch loop with a br_table keyed on parameter {} found in `{}`",
+                buffer_param,
+                name
+            )
+        })?;
+    let buffer = body.blocks[body.entry].params[buffer_param].1;
+    let Terminator::Select { value, targets, .. } = body.blocks[l.dispatch].terminator.clone()
+    else {
+        unreachable!("a br_table dispatch loop ends in a select");
+    };
+    let opcode = body.resolve_alias(value);
+
+    // Describe the handlers from the body as it is before outlining.
+    let generic = body.clone();
+    let outlined = crate::outline::outline_loop(module, &mut body, &l, buffer, |entry| {
+        let opcode = targets.iter().position(|target| target.block == entry);
+        format!("{}.stub{}", name, opcode.unwrap_or(targets.len()))
+    });
+    let mut stubs = vec![];
+    for (n, target) in targets.iter().enumerate() {
+        let Some(handler) = outlined.iter().find(|o| o.entry == target.block) else {
+            tracing::debug!("--opcode-stubs: no stub for opcode {}", n);
+            continue;
+        };
+        // The entry block's parameters come from the dispatch.
+        let params = handler
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| {
+                let value = target.args.get(i).copied().unwrap_or(value);
+                classify(&generic, &l, opcode, value)
+            })
+            .collect::<Option<Vec<_>>>();
+        let Some(params) = params else {
+            tracing::debug!(
+                "--opcode-stubs: no stub for opcode {}: its handler reads other state",
+                n
+            );
+            continue;
+        };
+        module.exports.push(Export {
+            name: format!("{}{}", EXPORT_PREFIX, n),
+            kind: ExportKind::Func(handler.func),
+        });
+        stubs.push(Stub {
+            opcode: n as u32,
+            params,
+            exits: handler
+                .exits
+                .iter()
+                .map(|to| !to.is_some_and(|to| l.body.contains(&to)))
+                .collect(),
+        });
+    }
+    tracing::debug!(
+        "--opcode-stubs: {} stubs for {} opcodes in `{}`",
+        stubs.len(),
+        targets.len(),
+        name
+    );
+    Ok(stubs)
+}
+
+/// Append a `weval.opcode-stubs` section describing `stubs` to
+/// `module`.
+pub(crate) fn append_section(module: &mut Vec<u8>, stubs: &[Stub]) {
+    let mut data = vec![];
+    (stubs.len() as u32).encode(&mut data);
+    for stub in stubs {
+        stub.opcode.encode(&mut data);
+        (stub.params.len() as u32).encode(&mut data);
+        for param in &stub.params {
+            match *param {
+                Param::State(idx) => {
+                    data.push(0);
+                    idx.encode(&mut data);
+                }
+                Param::Arg(idx) => {
+                    data.push(1);
+                    idx.encode(&mut data);
+                }
+                Param::Immediate {
+                    offset,
+                    size,
+                    signed,
+                } => {
+                    data.push(2);
+                    offset.encode(&mut data);
+                    size.encode(&mut data);
+                    data.push(signed as u8);
+                }
+            }
+        }
+        (stub.exits.len() as u32).encode(&mut data);
+        data.extend(stub.exits.iter().map(|&leaves| leaves as u8));
+    }
+    wasm_encoder::CustomSection {
+        name: SECTION_NAME.into(),
+        data: data.into(),
+    }
+    .append_to(module);
+}
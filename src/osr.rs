@@ -0,0 +1,229 @@
+//! Entry points into specialized dispatch loops mid-function.
+//!
+//! A specialized interpreter function only helps calls that start
+//! after it exists; a bytecode loop already running stays generic
+//! until it returns. With `--osr-pc PC`, weval also specializes an
+//! entry into the `--auto-dispatch` loop of the `--specialize-func`
+//! function at PC, so that an engine can transfer execution that
+//! reaches PC in the generic loop into specialized code (on-stack
+//! replacement).
+//!
+//! Entries are specialized from one generic function, `FUNC.osr`,
+//! that branches straight to the loop's header. It takes FUNC's
+//! parameters, then the PC (as the context intrinsics see it: the
+//! offset into the bytecode buffer for a PC that points into it, and
+//! the PC otherwise), then the loop's state: the header's other block
+//! parameters, in order, and then any values computed before the loop
+//! that the loop uses, except those recomputable from the parameters
+//! by pure operators.
+
+use crate::dispatch;
+use fxhash::FxHashMap;
+use waffle::cfg::CFGInfo;
+use waffle::{
+    Block, BlockTarget, Func, FuncDecl, FunctionBody, Module, Operator, SignatureData, Terminator,
+    Type, Value, ValueDef,
+};
+
+/// How deep to look into a value from before the loop to recompute.
+const MAX_RECOMPUTE_DEPTH: usize = 8;
+
+/// Add `FUNC.osr`, entering the dispatch loop of `func` keyed on its
+/// parameter number `buffer_param`, to `module`. Returns the new
+/// function and the number of loop-state parameters after the PC.
+pub(crate) fn add_entry(
+    module: &mut Module,
+    func: Func,
+    buffer_param: usize,
+) -> anyhow::Result<(Func, usize)> {
+    let name = module.funcs[func].name().to_owned();
+    let mut body = module.clone_and_expand_body(func)?;
+    body.recompute_edges();
+    let cfg = CFGInfo::new(&body);
+    let loops = dispatch::find_loops(&body, &cfg, buffer_param);
+    let l = loops.first().ok_or_else(|| {
+        anyhow::anyhow!(
+            "--osr-pc: no dispatch loop keyed on parameter {} found in `{}`",
+            buffer_param,
+            name
+        )
+    })?;
+    let header_params = body.blocks[l.header].params.clone();
+    anyhow::ensure!(
+        header_params[l.pc].0 == Type::I32,
+        "--osr-pc: the PC of the dispatch loop in `{}` is not an i32",
+        name
+    );
+
+    // The new entry takes the function's parameters and the PC, and
+    // jumps into the loop with the rest of its state.
+    let old_entry = body.entry;
+    let func_params = body.blocks[old_entry].params.len();
+    let entry = body.add_block();
+    body.blocks[entry].desc = format!("OSR entry into {}", l.header);
+    let mut recomputed = FxHashMap::default();
+    for (ty, param) in body.blocks[old_entry].params.clone() {
+        let new = body.add_blockparam(entry, ty);
+        recomputed.insert(param, new);
+    }
+    let pc_key = body.add_blockparam(entry, Type::I32);
+    let mut args = vec![];
+    for (i, &(ty, _)) in header_params.iter().enumerate() {
+        let arg = if i != l.pc {
+            body.add_blockparam(entry, ty)
+        } else if l.pc_in_buffer {
+            let buffer = recomputed[&body.blocks[old_entry].params[buffer_param].1];
+            body.add_op(entry, Operator::I32Add, &[buffer, pc_key], &[Type::I32])
+        } else {
+            pc_key
+        };
+        args.push(arg);
+    }
+    body.set_terminator(
+        entry,
+        Terminator::Br {
+            target: BlockTarget {
+                block: l.header,
+                args,
+            },
+        },
+    );
+    body.entry = entry;
+    body.recompute_edges();
+
+    // Values defined in blocks no longer reachable are recomputed in
+    // the entry, or passed in.
+    let cfg = CFGInfo::new(&body);
+    let mut def_block = FxHashMap::default();
+    for (block, def) in body.blocks.entries() {
+        for &(_, param) in &def.params {
+            def_block.insert(param, block);
+        }
+        for &inst in &def.insts {
+            def_block.insert(inst, block);
+        }
+    }
+    let mut live_ins = vec![];
+    for &block in cfg.rpo.values() {
+        visit_block_uses(&body, block, |value| {
+            let value = body.resolve_alias(value);
+            if def_block
+                .get(&value)
+                .is_some_and(|&def| cfg.rpo_pos[def].is_none())
+                && !live_ins.contains(&value)
+            {
+                live_ins.push(value);
+            }
+        });
+    }
+    for value in live_ins {
+        let new = recompute(
+            &mut body,
+            entry,
+            value,
+            MAX_RECOMPUTE_DEPTH,
+            &mut recomputed,
+        )
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "--osr-pc: the dispatch loop in `{}` uses {}, of unknown type",
+                name,
+                value
+            )
+        })?;
+        if new != value {
+            body.set_alias(value, new);
+        }
+    }
+
+    // Empty the blocks before the loop, which nothing reaches now.
+    for block in body.blocks.iter().collect::<Vec<Block>>() {
+        if cfg.rpo_pos[block].is_none() {
+            let def = &mut body.blocks[block];
+            def.insts.clear();
+            def.params.clear();
+            def.terminator = Terminator::Unreachable;
+        }
+    }
+    body.recompute_edges();
+    body.validate().map_err(|e| {
+        anyhow::anyhow!(
+            "--osr-pc: cannot enter the dispatch loop of `{}` directly: {}",
+            name,
+            e
+        )
+    })?;
+
+    let state = body.blocks[entry].params.len() - func_params - 1;
+    let params = body.blocks[entry]
+        .params
+        .iter()
+        .map(|&(ty, _)| ty)
+        .collect::<Vec<_>>();
+    let locals = body.locals.values().skip(body.n_params).cloned();
+    body.locals = params
+        .iter()
+        .cloned()
+        .chain(locals)
+        .collect::<Vec<_>>()
+        .into();
+    body.n_params = params.len();
+    let returns = module.signatures[module.funcs[func].sig()].returns.clone();
+    let sig = module.signatures.push(SignatureData { params, returns });
+    let osr = module
+        .funcs
+        .push(FuncDecl::Body(sig, format!("{}.osr", name), body));
+    tracing::info!(
+        "{}: OSR entry {} takes {} loop-state parameters",
+        func,
+        osr,
+        state
+    );
+    Ok((osr, state))
+}
+
+/// Recompute `value`, from before the loop, in `entry`: a parameter
+/// maps to the entry's, a pure operator is cloned, and anything else
+/// becomes a new parameter of the entry. `None` if its type is not
+/// known.
+fn recompute(
+    body: &mut FunctionBody,
+    entry: Block,
+    value: Value,
+    depth: usize,
+    recomputed: &mut FxHashMap<Value, Value>,
+) -> Option<Value> {
+    let value = body.resolve_alias(value);
+    if let Some(&new) = recomputed.get(&value) {
+        return Some(new);
+    }
+    let new = match body.values[value] {
+        ValueDef::Operator(op, args, tys)
+            if op.is_pure() && (depth > 0 || body.arg_pool[args].is_empty()) =>
+        {
+            let tys = body.type_pool[tys].to_vec();
+            let mut args = body.arg_pool[args].to_vec();
+            for arg in &mut args {
+                *arg = recompute(body, entry, *arg, depth - 1, recomputed)?;
+            }
+            body.add_op(entry, op, &args[..], &tys[..])
+        }
+        ref def => {
+            let ty = def.ty(&body.type_pool)?;
+            body.add_blockparam(entry, ty)
+        }
+    };
+    recomputed.insert(value, new);
+    Some(new)
+}
+
+fn visit_block_uses(body: &FunctionBody, block: Block, mut f: impl FnMut(Value)) {
+    for &inst in &body.blocks[block].insts {
+        match &body.values[inst] {
+            ValueDef::Operator(_, args, _) => body.arg_pool[*args].iter().for_each(|&arg| f(arg)),
+            ValueDef::PickOutput(value, ..) => f(*value),
+            _ => {}
+        }
+    }
+    body.blocks[block].terminator.visit_uses(f);
+}
@@ -0,0 +1,672 @@
+//! Dispatch-only specialization: outlining of opcode handlers.
+//!
+//! Specializing a dispatch loop per PC copies the handler of each PC's
+//! opcode into the specialized function, with its operands folded in.
+//! With `--dispatch-only`, the handlers in the loops that
+//! `--auto-dispatch` finds are first outlined into functions of their
+//! own, which all specializations share: only the dispatch is still
+//! specialized per PC, each PC becoming a call to its opcode's handler
+//! followed by a direct branch to the next PC. Handlers then read their
+//! operands at runtime, so less folds, but code grows far less.
+//!
+//! A handler is the part of the loop that a target of the dispatching
+//! `br_table` dominates, up to the blocks where next PCs merge. It is
+//! outlined only if it calls no weval intrinsics, and if the next PC
+//! on each of its exits can be recomputed after the call from values
+//! available before it (by pure operators and loads from the bytecode
+//! buffer), so that the PC stays constant when specializing. Other
+//! handlers stay inline and are specialized as usual. Loops that call
+//! their handlers through a `call_indirect` have none to outline.
+
+use crate::dispatch::{self, DispatchLoop};
+use fxhash::{FxHashMap, FxHashSet};
+use waffle::cfg::CFGInfo;
+use waffle::{
+    Block, BlockTarget, Func, FuncDecl, FunctionBody, ImportKind, Module, Operator, SignatureData,
+    Terminator, Type, Value, ValueDef,
+};
+
+/// How deep to look into a next-PC expression to recompute.
+const MAX_RECOMPUTE_DEPTH: usize = 8;
+
+/// Outline the handlers in the dispatch loops of `body`, the generic
+/// body of `func`, keyed on its parameter number `buffer_param`. The
+/// handlers are added to `module`. Returns the number outlined.
+pub(crate) fn outline_handlers(
+    module: &mut Module,
+    func: Func,
+    body: &mut FunctionBody,
+    buffer_param: usize,
+) -> usize {
+    body.recompute_edges();
+    let cfg = CFGInfo::new(body);
+    let loops = dispatch::find_loops(body, &cfg, buffer_param);
+    let Some(&(_, buffer)) = body.blocks[body.entry].params.get(buffer_param) else {
+        return 0;
+    };
+    let handlers = find_handlers(module, body, &cfg, &loops, buffer);
+
+    let name = module.funcs[func].name().to_owned();
+    for (i, handler) in handlers.iter().enumerate() {
+        handler.outline(module, body, format!("{}.handler{}", name, i));
+    }
+    body.recompute_edges();
+    handlers.len()
+}
+
+/// A handler outlined by `outline_loop`.
+pub(crate) struct Outlined {
+    /// The handler's entry block in the generic body.
+    pub(crate) entry: Block,
+    pub(crate) func: Func,
+    /// The values of the generic body passed to the function: the
+    /// entry block's parameters, then values defined before the
+    /// handler.
+    pub(crate) params: Vec<Value>,
+    /// Where each exit of the handler goes, by the index the function
+    /// returns: a block, or `None` for a return.
+    pub(crate) exits: Vec<Option<Block>>,
+}
+
+/// Outline the handlers of the dispatch loop `l` in `body`, as for
+/// `outline_handlers`, naming each function with `name` from its entry
+/// block.
+pub(crate) fn outline_loop(
+    module: &mut Module,
+    body: &mut FunctionBody,
+    l: &DispatchLoop,
+    buffer: Value,
+    name: impl Fn(Block) -> String,
+) -> Vec<Outlined> {
+    let cfg = CFGInfo::new(body);
+    let handlers = find_handlers(module, body, &cfg, std::slice::from_ref(l), buffer);
+    let mut outlined = vec![];
+    for handler in &handlers {
+        let entry = handler.blocks[0];
+        let params = body.blocks[entry]
+            .params
+            .iter()
+            .map(|&(_, value)| value)
+            .chain(handler.live_ins.iter().map(|&(value, _)| value))
+            .collect();
+        let func = handler.outline(module, body, name(entry));
+        outlined.push(Outlined {
+            entry,
+            func,
+            params,
+            exits: handler.exits.iter().map(|exit| exit.to).collect(),
+        });
+    }
+    body.recompute_edges();
+    outlined
+}
+
+/// The handlers in `loops` that can be outlined.
+fn find_handlers(
+    module: &Module,
+    body: &FunctionBody,
+    cfg: &CFGInfo,
+    loops: &[DispatchLoop],
+    buffer: Value,
+) -> Vec<Handler> {
+    let intrinsics: FxHashSet<Func> = module
+        .imports
+        .iter()
+        .filter(|import| import.module == "weval")
+        .filter_map(|import| match import.kind {
+            ImportKind::Func(f) => Some(f),
+            _ => None,
+        })
+        .collect();
+
+    // Find every handler before rewriting any.
+    let mut regions = vec![];
+    for l in loops.iter().filter(|l| l.handler_table.is_none()) {
+        let mut entries = vec![];
+        body.blocks[l.dispatch].terminator.visit_targets(|target| {
+            if !entries.contains(&target.block) {
+                entries.push(target.block);
+            }
+        });
+        for entry in entries {
+            if entry != l.header
+                && entry != l.dispatch
+                && l.body.contains(&entry)
+                && !l.pc_merges.iter().any(|&(block, _)| block == entry)
+            {
+                regions.push((l, region(body, cfg, l, entry)));
+            }
+        }
+    }
+
+    // A handler's values may only be used outside it through the
+    // arguments of its exits.
+    let mut owner = FxHashMap::default();
+    for (i, (_, blocks)) in regions.iter().enumerate() {
+        for value in defined(body, blocks) {
+            owner.insert(value, i);
+        }
+    }
+    let mut escaping = FxHashSet::default();
+    for &block in cfg.rpo.values() {
+        let user = regions
+            .iter()
+            .position(|(_, blocks)| blocks.contains(&block));
+        visit_block_uses(body, block, |value| {
+            if let Some(&i) = owner.get(&body.resolve_alias(value)) {
+                if user != Some(i) {
+                    escaping.insert(i);
+                }
+            }
+        });
+    }
+
+    let mut handlers = vec![];
+    for (i, (l, blocks)) in regions.into_iter().enumerate() {
+        let entry = blocks[0];
+        if escaping.contains(&i) {
+            tracing::debug!("not outlining handler at {}: its values escape", entry);
+            continue;
+        }
+        match Handler::new(body, l, buffer, blocks, &intrinsics) {
+            Ok(handler) => handlers.push(handler),
+            Err(reason) => tracing::debug!("not outlining handler at {}: {}", entry, reason),
+        }
+    }
+    handlers
+}
+
+/// The blocks of the handler starting at `entry`, in RPO.
+fn region(body: &FunctionBody, cfg: &CFGInfo, l: &DispatchLoop, entry: Block) -> Vec<Block> {
+    let mut blocks = FxHashSet::default();
+    let mut stack = vec![entry];
+    while let Some(block) = stack.pop() {
+        if block == l.header
+            || !cfg.dominates(entry, block)
+            || l.pc_merges.iter().any(|&(merge, _)| merge == block)
+            || !blocks.insert(block)
+        {
+            continue;
+        }
+        stack.extend(body.blocks[block].succs.iter().copied());
+    }
+    let mut blocks = blocks.into_iter().collect::<Vec<_>>();
+    blocks.sort_by_key(|&block| cfg.rpo_pos[block]);
+    blocks
+}
+
+/// The values defined in `blocks`. The parameters of the first block
+/// count only if it can be re-entered from the others; otherwise they
+/// keep their values after the handler.
+fn defined(body: &FunctionBody, blocks: &[Block]) -> Vec<Value> {
+    let entry = blocks[0];
+    let reentered = body.blocks[entry]
+        .preds
+        .iter()
+        .any(|pred| blocks.contains(pred));
+    let mut values = vec![];
+    for &block in blocks {
+        if block != entry || reentered {
+            values.extend(body.blocks[block].params.iter().map(|&(_, value)| value));
+        }
+        values.extend(body.blocks[block].insts.iter().copied());
+    }
+    values
+}
+
+fn visit_block_uses(body: &FunctionBody, block: Block, mut f: impl FnMut(Value)) {
+    for &inst in &body.blocks[block].insts {
+        match &body.values[inst] {
+            ValueDef::Operator(_, args, _) => body.arg_pool[*args].iter().for_each(|&arg| f(arg)),
+            ValueDef::PickOutput(value, ..) => f(*value),
+            _ => {}
+        }
+    }
+    body.blocks[block].terminator.visit_uses(f);
+}
+
+/// An argument of a handler's exit edge.
+enum ExitArg {
+    /// Computed after the call, from values available before it.
+    Recompute(Value),
+    /// Returned by the handler in the given slot.
+    Slot(usize, Value),
+}
+
+struct Exit {
+    /// The block the edge leaves, and its index among the block's
+    /// targets.
+    from: Block,
+    succ: usize,
+    /// Where the edge goes, or `None` for a return.
+    to: Option<Block>,
+    args: Vec<ExitArg>,
+}
+
+struct Handler {
+    /// The handler's blocks, in RPO; the first is its entry.
+    blocks: Vec<Block>,
+    /// Values defined in the handler.
+    defined: FxHashSet<Value>,
+    /// Values defined before the handler and used in it, passed to the
+    /// outlined function after the entry's parameters.
+    live_ins: Vec<(Value, Type)>,
+    exits: Vec<Exit>,
+    /// Types of the values returned for the exits, after the exit
+    /// index if there is more than one exit.
+    slots: Vec<Type>,
+}
+
+impl Handler {
+    fn new(
+        body: &FunctionBody,
+        l: &DispatchLoop,
+        buffer: Value,
+        blocks: Vec<Block>,
+        intrinsics: &FxHashSet<Func>,
+    ) -> Result<Handler, String> {
+        let entry = blocks[0];
+        let defined: FxHashSet<Value> = self::defined(body, &blocks).into_iter().collect();
+        let entry_params: Vec<Value> = body.blocks[entry]
+            .params
+            .iter()
+            .map(|&(_, value)| value)
+            .collect();
+
+        // Live-ins, from everything but the exits' arguments, which
+        // are recomputed or returned.
+        let mut used = vec![];
+        for &block in &blocks {
+            for &inst in &body.blocks[block].insts {
+                match &body.values[inst] {
+                    ValueDef::Operator(Operator::Call { function_index }, ..)
+                        if intrinsics.contains(function_index) =>
+                    {
+                        return Err("calls a weval intrinsic".to_owned());
+                    }
+                    ValueDef::Operator(_, args, _) => used.extend_from_slice(&body.arg_pool[*args]),
+                    ValueDef::PickOutput(value, ..) => used.push(*value),
+                    _ => {}
+                }
+            }
+            match &body.blocks[block].terminator {
+                Terminator::CondBr { cond: value, .. } | Terminator::Select { value, .. } => {
+                    used.push(*value)
+                }
+                _ => {}
+            }
+            body.blocks[block].terminator.visit_targets(|target| {
+                if blocks.contains(&target.block) {
+                    used.extend_from_slice(&target.args);
+                }
+            });
+        }
+        let mut live_ins: Vec<(Value, Type)> = vec![];
+        for value in used {
+            let value = body.resolve_alias(value);
+            if defined.contains(&value)
+                || entry_params.contains(&value)
+                || live_ins.iter().any(|&(v, _)| v == value)
+            {
+                continue;
+            }
+            let ty = body.values[value]
+                .ty(&body.type_pool)
+                .ok_or_else(|| format!("live-in {} has no type", value))?;
+            live_ins.push((value, ty));
+        }
+
+        let mut handler = Handler {
+            blocks,
+            defined,
+            live_ins,
+            exits: vec![],
+            slots: vec![],
+        };
+        let is_pc = |to: Block, idx: usize| {
+            (to == l.header && idx == l.pc) || l.pc_merges.contains(&(to, idx))
+        };
+        for &block in &handler.blocks {
+            let mut edges = vec![];
+            match &body.blocks[block].terminator {
+                Terminator::Return { values } => edges.push((0, None, values.clone())),
+                terminator => {
+                    let mut succ = 0;
+                    terminator.visit_targets(|target| {
+                        if !handler.blocks.contains(&target.block) {
+                            edges.push((succ, Some(target.block), target.args.clone()));
+                        }
+                        succ += 1;
+                    });
+                }
+            }
+            for (succ, to, values) in edges {
+                let mut used = FxHashMap::default();
+                let mut args = vec![];
+                for (idx, value) in values.into_iter().enumerate() {
+                    // Only next PCs are recomputed; other values the
+                    // handler computes, it returns.
+                    let is_pc = to.is_some_and(|to| is_pc(to, idx));
+                    let depth = if is_pc { MAX_RECOMPUTE_DEPTH } else { 0 };
+                    if handler.recomputable(body, l, buffer, value, depth) {
+                        args.push(ExitArg::Recompute(value));
+                        continue;
+                    }
+                    if is_pc {
+                        return Err(format!("next PC {} is computed in the handler", value));
+                    }
+                    let ty = body.values[body.resolve_alias(value)]
+                        .ty(&body.type_pool)
+                        .filter(|ty| matches!(ty, Type::I32 | Type::I64 | Type::F32 | Type::F64))
+                        .ok_or_else(|| format!("cannot return {}", value))?;
+                    // The n-th value of a type goes in the n-th slot of
+                    // that type.
+                    let n = used.entry(ty).or_insert(0);
+                    let slot = match handler
+                        .slots
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, &slot_ty)| slot_ty == ty)
+                        .nth(*n)
+                    {
+                        Some((slot, _)) => slot,
+                        None => {
+                            handler.slots.push(ty);
+                            handler.slots.len() - 1
+                        }
+                    };
+                    *n += 1;
+                    args.push(ExitArg::Slot(slot, value));
+                }
+                handler.exits.push(Exit {
+                    from: block,
+                    succ,
+                    to,
+                    args,
+                });
+            }
+        }
+        Ok(handler)
+    }
+
+    /// Whether `value` can be computed after the handler, from at most
+    /// `depth` levels of operators.
+    fn recomputable(
+        &self,
+        body: &FunctionBody,
+        l: &DispatchLoop,
+        buffer: Value,
+        value: Value,
+        depth: usize,
+    ) -> bool {
+        let value = body.resolve_alias(value);
+        if !self.defined.contains(&value) {
+            return true;
+        }
+        let ValueDef::Operator(op, args, tys) = &body.values[value] else {
+            return false;
+        };
+        let bytecode_load = || {
+            let mut leaves = vec![];
+            dispatch::address_leaves(
+                body,
+                body.arg_pool[*args][0],
+                dispatch::MAX_ADDRESS_DEPTH,
+                &mut leaves,
+            );
+            let pc = body.blocks[l.header].params[l.pc].1;
+            leaves.contains(&buffer) || (l.pc_in_buffer && leaves.contains(&pc))
+        };
+        depth > 0
+            && tys.len() == 1
+            && (op.is_pure() || (dispatch::is_load(op) && bytecode_load()))
+            && body.arg_pool[*args]
+                .iter()
+                .all(|&arg| self.recomputable(body, l, buffer, arg, depth - 1))
+    }
+
+    fn returns(&self) -> Vec<Type> {
+        let index = (self.exits.len() > 1).then_some(Type::I32);
+        index
+            .into_iter()
+            .chain(self.slots.iter().copied())
+            .collect()
+    }
+
+    /// Move the handler into a new function, and call it from its
+    /// entry block instead. Returns the new function.
+    fn outline(&self, module: &mut Module, body: &mut FunctionBody, name: String) -> Func {
+        let entry = self.blocks[0];
+        let entry_params = body.blocks[entry].params.clone();
+        let returns = self.returns();
+        let sig = module.signatures.push(SignatureData {
+            params: entry_params
+                .iter()
+                .map(|&(ty, _)| ty)
+                .chain(self.live_ins.iter().map(|&(_, ty)| ty))
+                .collect(),
+            returns: returns.clone(),
+        });
+
+        let mut f = FunctionBody::new(module, sig);
+        let mut values = FxHashMap::default();
+        for (i, &(value, _)) in self.live_ins.iter().enumerate() {
+            values.insert(value, f.blocks[f.entry].params[entry_params.len() + i].1);
+        }
+        let mut blocks = FxHashMap::default();
+        for &block in &self.blocks {
+            let new_block = f.add_block();
+            blocks.insert(block, new_block);
+            for &(ty, param) in &body.blocks[block].params {
+                let new_param = f.add_blockparam(new_block, ty);
+                values.insert(param, new_param);
+            }
+        }
+        let args = f.blocks[f.entry].params[..entry_params.len()]
+            .iter()
+            .map(|&(_, value)| value)
+            .collect();
+        f.set_terminator(
+            f.entry,
+            Terminator::Br {
+                target: BlockTarget {
+                    block: blocks[&entry],
+                    args,
+                },
+            },
+        );
+
+        // Dominating blocks come first in RPO, so values are copied
+        // before their uses, except for branch arguments.
+        for &block in &self.blocks {
+            for &inst in &body.blocks[block].insts {
+                let new_value = match &body.values[inst] {
+                    ValueDef::Operator(op, args, tys) => {
+                        let args = body.arg_pool[*args]
+                            .iter()
+                            .map(|&arg| values[&body.resolve_alias(arg)])
+                            .collect::<Vec<_>>();
+                        let tys = body.type_pool[*tys].to_vec();
+                        f.add_op(blocks[&block], *op, &args[..], &tys[..])
+                    }
+                    ValueDef::PickOutput(value, idx, ty) => {
+                        let value = values[&body.resolve_alias(*value)];
+                        let pick = f.add_value(ValueDef::PickOutput(value, *idx, *ty));
+                        f.append_to_block(blocks[&block], pick);
+                        pick
+                    }
+                    _ => continue,
+                };
+                values.insert(inst, new_value);
+            }
+        }
+        let map = |value: Value| values[&body.resolve_alias(value)];
+
+        // Each exit returns its index and its values in their slots.
+        let mut exit_blocks = FxHashMap::default();
+        for (k, exit) in self.exits.iter().enumerate() {
+            let exit_block = f.add_block();
+            let mut rets = vec![];
+            if returns.len() > self.slots.len() {
+                rets.push(f.add_op(
+                    exit_block,
+                    Operator::I32Const { value: k as u32 },
+                    &[],
+                    &[Type::I32],
+                ));
+            }
+            for (slot, &ty) in self.slots.iter().enumerate() {
+                let value = exit.args.iter().find_map(|arg| match arg {
+                    ExitArg::Slot(s, value) if *s == slot => Some(map(*value)),
+                    _ => None,
+                });
+                rets.push(value.unwrap_or_else(|| {
+                    let op = match ty {
+                        Type::I32 => Operator::I32Const { value: 0 },
+                        Type::I64 => Operator::I64Const { value: 0 },
+                        Type::F32 => Operator::F32Const { value: 0 },
+                        _ => Operator::F64Const { value: 0 },
+                    };
+                    f.add_op(exit_block, op, &[], &[ty])
+                }));
+            }
+            f.set_terminator(exit_block, Terminator::Return { values: rets });
+            exit_blocks.insert((exit.from, exit.succ), exit_block);
+        }
+        let exit_target = |block: Block| BlockTarget {
+            block,
+            args: vec![],
+        };
+        for &block in &self.blocks {
+            let terminator = match &body.blocks[block].terminator {
+                Terminator::Return { .. } => Terminator::Br {
+                    target: exit_target(exit_blocks[&(block, 0)]),
+                },
+                terminator => {
+                    let mut terminator = terminator.clone();
+                    let mut succ = 0;
+                    terminator.update_targets(|target| {
+                        *target = match exit_blocks.get(&(block, succ)) {
+                            Some(&exit_block) => exit_target(exit_block),
+                            None => BlockTarget {
+                                block: blocks[&target.block],
+                                args: target.args.iter().map(|&arg| map(arg)).collect(),
+                            },
+                        };
+                        succ += 1;
+                    });
+                    if let Terminator::CondBr { cond: value, .. }
+                    | Terminator::Select { value, .. } = &mut terminator
+                    {
+                        *value = map(*value);
+                    }
+                    terminator
+                }
+            };
+            f.set_terminator(blocks[&block], terminator);
+        }
+
+        let handler = module.funcs.push(FuncDecl::Body(sig, name, f));
+
+        // Call it from the entry block.
+        for &block in &self.blocks[1..] {
+            body.blocks[block].insts.clear();
+            body.blocks[block].terminator = Terminator::Unreachable;
+        }
+        body.blocks[entry].insts.clear();
+        let args = entry_params
+            .iter()
+            .map(|&(_, value)| value)
+            .chain(self.live_ins.iter().map(|&(value, _)| value))
+            .collect::<Vec<_>>();
+        let call = body.add_op(
+            entry,
+            Operator::Call {
+                function_index: handler,
+            },
+            &args[..],
+            &returns[..],
+        );
+        let results = match returns.len() {
+            1 => vec![call],
+            _ => returns
+                .iter()
+                .enumerate()
+                .map(|(i, &ty)| {
+                    let pick = body.add_value(ValueDef::PickOutput(call, i as u32, ty));
+                    body.append_to_block(entry, pick);
+                    pick
+                })
+                .collect(),
+        };
+        let slots = &results[returns.len() - self.slots.len()..];
+        let mut exit_blocks = vec![];
+        for exit in &self.exits {
+            let exit_block = body.add_block();
+            let mut recomputed = FxHashMap::default();
+            let args = exit
+                .args
+                .iter()
+                .map(|arg| match arg {
+                    ExitArg::Recompute(value) => {
+                        self.recompute(body, exit_block, *value, &mut recomputed)
+                    }
+                    ExitArg::Slot(slot, _) => slots[*slot],
+                })
+                .collect();
+            body.set_terminator(
+                exit_block,
+                match exit.to {
+                    Some(block) => Terminator::Br {
+                        target: BlockTarget { block, args },
+                    },
+                    None => Terminator::Return { values: args },
+                },
+            );
+            exit_blocks.push(BlockTarget {
+                block: exit_block,
+                args: vec![],
+            });
+        }
+        body.blocks[entry].terminator = match exit_blocks.len() {
+            0 => Terminator::Unreachable,
+            1 => Terminator::Br {
+                target: exit_blocks.pop().unwrap(),
+            },
+            _ => Terminator::Select {
+                value: results[0],
+                default: exit_blocks.pop().unwrap(),
+                targets: exit_blocks,
+            },
+        };
+        handler
+    }
+
+    /// Copy the computation of `value` into `block`.
+    fn recompute(
+        &self,
+        body: &mut FunctionBody,
+        block: Block,
+        value: Value,
+        recomputed: &mut FxHashMap<Value, Value>,
+    ) -> Value {
+        let value = body.resolve_alias(value);
+        if !self.defined.contains(&value) {
+            return value;
+        }
+        if let Some(&copy) = recomputed.get(&value) {
+            return copy;
+        }
+        let ValueDef::Operator(op, args, tys) = body.values[value].clone() else {
+            unreachable!("only operators are recomputed");
+        };
+        let mut args = body.arg_pool[args].to_vec();
+        for arg in &mut args {
+            *arg = self.recompute(body, block, *arg, recomputed);
+        }
+        let tys = body.type_pool[tys].to_vec();
+        let copy = body.add_op(block, op, &args[..], &tys[..]);
+        recomputed.insert(value, copy);
+        copy
+    }
+}
@@ -0,0 +1,82 @@
+//! Execution-count profiles of interpreter PCs.
+//!
+//! Specializing a dispatch loop replicates its body for every PC the
+//! bytecode can reach, including PCs that never run in practice. Given
+//! a profile (`--pc-profile`), only hot PCs get their own contexts:
+//! entering the loop at, or moving to, a cold PC continues in one
+//! shared context per loop where the PC is a runtime value, i.e. in
+//! generic dispatch, until the loop is left.
+//!
+//! A profile is a text file with one `KEY COUNT` pair per line (blank
+//! lines and lines starting with `#` are ignored). Keys are PCs as the
+//! context intrinsics see them: offsets into the bytecode buffer for
+//! PCs that point into a directive's buffer, and addresses otherwise.
+//! With `--pc-profile-by-opcode`, keys are opcodes instead: the byte
+//! at each PC. Keys and counts may be decimal or `0x` hex. PCs whose
+//! count reaches `--hot-pc-min-count` are hot; all others are cold.
+
+use fxhash::FxHashSet;
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+pub(crate) struct HotPcs {
+    /// Hot PCs, or opcodes if `by_opcode`.
+    hot: FxHashSet<u32>,
+    by_opcode: bool,
+}
+
+impl HotPcs {
+    pub(crate) fn load(path: &Path, min_count: u64, by_opcode: bool) -> anyhow::Result<HotPcs> {
+        let context = || format!("reading PC profile {}", path.display());
+        let text =
+            std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", context(), e))?;
+        let mut hot = FxHashSet::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = line
+                .split_once(char::is_whitespace)
+                .and_then(|(key, count)| Some((parse_u64(key)?, parse_u64(count.trim())?)));
+            let (key, count) = entry.ok_or_else(|| {
+                anyhow::anyhow!("{}: line {}: expected `KEY COUNT`", context(), i + 1)
+            })?;
+            let key = u32::try_from(key)
+                .map_err(|_| anyhow::anyhow!("{}: line {}: key out of range", context(), i + 1))?;
+            if count >= min_count {
+                hot.insert(key);
+            }
+        }
+        tracing::info!("{} hot PCs (or opcodes) in {}", hot.len(), path.display());
+        Ok(HotPcs { hot, by_opcode })
+    }
+
+    /// Whether `pc` is hot; `opcode` reads the opcode at `pc`. A PC
+    /// whose opcode cannot be read counts as hot.
+    pub(crate) fn is_hot(&self, pc: u32, opcode: impl FnOnce() -> Option<u8>) -> bool {
+        if self.by_opcode {
+            opcode().is_none_or(|op| self.hot.contains(&u32::from(op)))
+        } else {
+            self.hot.contains(&pc)
+        }
+    }
+
+    /// Bytes identifying the hot set, for cache keys.
+    pub(crate) fn fingerprint(&self) -> Vec<u8> {
+        let mut hot = self.hot.iter().copied().collect::<Vec<_>>();
+        hot.sort_unstable();
+        let mut bytes = vec![u8::from(self.by_opcode)];
+        for key in hot {
+            bytes.extend_from_slice(&key.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+fn parse_u64(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
@@ -0,0 +1,147 @@
+//! Exit-status policy: whether a run that produced output, but with
+//! warnings or with directives left generic, fails.
+//!
+//! By default it doesn't, so that local runs degrade gracefully; CI
+//! can opt into failing with `--fail-on-warning` and
+//! `--fail-on-fallback`. Policy is checked only once the output is
+//! written, so that it can still be inspected.
+//!
+//! Warnings are recorded here, whether printed with `warn` or logged
+//! with `tracing::warn!` (seen by `WarningLayer`).
+
+use crate::error::WevalError;
+use crate::stats::{DirectiveOutcome, DirectiveResult};
+use std::sync::Mutex;
+use structopt::StructOpt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use waffle::Module;
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct ExitPolicy {
+    /// Fail, after writing the output, if there were any warnings.
+    #[structopt(long = "fail-on-warning")]
+    fail_on_warning: bool,
+
+    /// Fail, after writing the output, if any directive fell back to
+    /// the generic function (failed or was abandoned).
+    #[structopt(long = "fail-on-fallback")]
+    fail_on_fallback: bool,
+}
+
+/// How many warnings or fallbacks a policy error lists.
+const MAX_LISTED: usize = 10;
+
+/// The first line of each warning since the last `take_warnings`.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+fn record(message: &str) {
+    let first = message.lines().next().unwrap_or("");
+    WARNINGS.lock().unwrap().push(first.to_owned());
+}
+
+/// Print a warning, or show it in the TUI, and record it.
+pub(crate) fn warn(message: String) {
+    record(&message);
+    if !crate::progress::show_warning(&message) {
+        eprintln!("warning: {}", message);
+    }
+}
+
+/// The warnings recorded so far, clearing them for the next run.
+pub(crate) fn take_warnings() -> Vec<String> {
+    std::mem::take(&mut *WARNINGS.lock().unwrap())
+}
+
+/// The directives that fell back to their generic functions, as
+/// lines for a policy error.
+pub(crate) fn fallbacks(module: &Module, outcomes: &[DirectiveOutcome]) -> Vec<String> {
+    outcomes
+        .iter()
+        .filter_map(|outcome| {
+            let how = match &outcome.result {
+                DirectiveResult::Specialized | DirectiveResult::Cached => return None,
+                DirectiveResult::Abandoned => "abandoned".to_owned(),
+                DirectiveResult::Failed { code, .. } => format!("failed ({})", code),
+            };
+            Some(format!(
+                "directive with user ID {} on `{}`: {}",
+                outcome.user_id,
+                module.funcs[outcome.func].name(),
+                how
+            ))
+        })
+        .collect()
+}
+
+impl ExitPolicy {
+    /// The flags, by long name.
+    pub(crate) fn flags(&mut self) -> [(&'static str, &mut bool); 2] {
+        [
+            ("fail-on-warning", &mut self.fail_on_warning),
+            ("fail-on-fallback", &mut self.fail_on_fallback),
+        ]
+    }
+
+    /// Fail if the run's warnings or fallbacks (see `fallbacks`) break
+    /// the policy.
+    pub(crate) fn check(&self, warnings: &[String], fallbacks: Vec<String>) -> anyhow::Result<()> {
+        if self.fail_on_warning && !warnings.is_empty() {
+            return Err(violation(
+                "--fail-on-warning",
+                format!("{} warnings", warnings.len()),
+                warnings.iter().cloned(),
+            ));
+        }
+        if self.fail_on_fallback && !fallbacks.is_empty() {
+            return Err(violation(
+                "--fail-on-fallback",
+                format!("{} directives fell back to generic code", fallbacks.len()),
+                fallbacks.into_iter(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn violation(
+    flag: &str,
+    summary: String,
+    items: impl ExactSizeIterator<Item = String>,
+) -> anyhow::Error {
+    let more = items.len().saturating_sub(MAX_LISTED);
+    let mut message = format!("{}: {}", flag, summary);
+    for item in items.take(MAX_LISTED) {
+        message += &format!("\n  {}", item);
+    }
+    if more > 0 {
+        message += &format!("\n  ... and {} more", more);
+    }
+    WevalError::PolicyViolation(message).into()
+}
+
+/// A tracing layer that records warnings, and shows them in the TUI.
+pub(crate) struct WarningLayer;
+
+struct MessageRecorder(String);
+
+impl Visit for MessageRecorder {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for WarningLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() > Level::WARN {
+            return;
+        }
+        let mut message = MessageRecorder(String::new());
+        event.record(&mut message);
+        record(&message.0);
+        crate::progress::show_warning(&message.0);
+    }
+}
@@ -0,0 +1,80 @@
+//! Ahead-of-time compilation of the output with Wasmtime.
+//!
+//! The `.cwasm` written here is Wasmtime's own precompiled format:
+//! it loads only in the same Wasmtime version as weval links (18.x),
+//! with a compatible configuration, via `Module::deserialize`.
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Options for precompiling the output module.
+#[derive(Clone, Debug, StructOpt)]
+pub struct PrecompileOptions {
+    /// Also compile the final module ahead of time with Wasmtime 18
+    /// and write the result (a `.cwasm`) to this file.
+    #[structopt(long = "precompile", value_name = "CWASM")]
+    pub output: Option<PathBuf>,
+
+    /// Target triple to precompile for (default: the host).
+    #[structopt(
+        long = "precompile-target",
+        value_name = "TRIPLE",
+        env = "WEVAL_PRECOMPILE_TARGET"
+    )]
+    target: Option<String>,
+
+    /// Cranelift optimization level for precompilation: `none`,
+    /// `speed` or `speed_and_size`.
+    #[structopt(
+        long = "precompile-opt-level",
+        value_name = "LEVEL",
+        default_value = "speed",
+        env = "WEVAL_PRECOMPILE_OPT_LEVEL",
+        parse(try_from_str = parse_opt_level)
+    )]
+    opt_level: String,
+
+    /// Cranelift settings for precompilation, as `NAME=VALUE` (e.g.
+    /// `has_avx2=true` when cross-compiling).
+    #[structopt(long = "precompile-flag", value_name = "NAME=VALUE")]
+    flags: Vec<String>,
+}
+
+fn parse_opt_level(s: &str) -> anyhow::Result<String> {
+    match s {
+        "none" | "speed" | "speed_and_size" => Ok(s.to_owned()),
+        _ => anyhow::bail!("must be `none`, `speed` or `speed_and_size`"),
+    }
+}
+
+/// Compile `bytes` to a Wasmtime `.cwasm` image.
+#[cfg(feature = "precompile")]
+pub(crate) fn precompile(bytes: &[u8], opts: &PrecompileOptions) -> anyhow::Result<Vec<u8>> {
+    let mut config = wasmtime::Config::new();
+    if let Some(target) = &opts.target {
+        config.target(target)?;
+    }
+    config.cranelift_opt_level(match opts.opt_level.as_str() {
+        "none" => wasmtime::OptLevel::None,
+        "speed_and_size" => wasmtime::OptLevel::SpeedAndSize,
+        _ => wasmtime::OptLevel::Speed,
+    });
+    for flag in &opts.flags {
+        let (name, value) = flag.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("invalid precompile flag `{}`: expected NAME=VALUE", flag)
+        })?;
+        // SAFETY: Cranelift settings can produce code that does not
+        // run on the host (that is the point when cross-compiling);
+        // the user asked for them.
+        unsafe {
+            config.cranelift_flag_set(name, value);
+        }
+    }
+    let engine = wasmtime::Engine::new(&config)?;
+    engine.precompile_module(bytes)
+}
+
+#[cfg(not(feature = "precompile"))]
+pub(crate) fn precompile(_bytes: &[u8], _opts: &PrecompileOptions) -> anyhow::Result<Vec<u8>> {
+    anyhow::bail!("weval was built without precompilation support (the `precompile` feature)")
+}
@@ -0,0 +1,77 @@
+//! Named bundles of option defaults (`--preset`) for known kinds of
+//! interpreter.
+//!
+//! Each preset is tuned against the heuristics it configures; when one
+//! of those changes (e.g. what `dedup` considers worth outlining),
+//! revisit the presets here too. Options given explicitly on the
+//! command line take precedence over the preset's.
+
+/// Defaults for a kind of interpreter.
+#[derive(Debug, PartialEq)]
+pub struct Preset {
+    pub name: &'static str,
+    /// One line on what the preset is for.
+    pub about: &'static str,
+    /// The `--max-memory-gb` budget.
+    pub max_memory_gb: Option<f64>,
+    /// Whether to `--outline-common`, and with what
+    /// `--outline-min-insts` and `--outline-min-count`.
+    pub outline_common: bool,
+    pub outline_min_insts: usize,
+    pub outline_min_count: usize,
+    /// Whether to `--link-results` and `--devirtualize-results`.
+    pub link_results: bool,
+    pub devirtualize_results: bool,
+}
+
+/// Outlining thresholds without a preset.
+pub(crate) const DEFAULT_OUTLINE_MIN_INSTS: usize = 8;
+pub(crate) const DEFAULT_OUTLINE_MIN_COUNT: usize = 4;
+
+/// `--opt-size` defaults: outline nearly any repeated block, and keep
+/// specializations within a small multiple of the generic function.
+pub(crate) const OPT_SIZE_OUTLINE_MIN_INSTS: usize = 4;
+pub(crate) const OPT_SIZE_OUTLINE_MIN_COUNT: usize = 2;
+pub(crate) const OPT_SIZE_MAX_GROWTH: f64 = 2.0;
+
+pub(crate) static PRESETS: &[Preset] = &[
+    // Thousands of directives (one per function's bytecode, plus IC
+    // stubs), whose specializations share long stretches of opcode
+    // handlers and call each other through the function table.
+    Preset {
+        name: "spidermonkey",
+        about: "SpiderMonkey's portable baseline interpreter: many directives, large handlers",
+        max_memory_gb: Some(16.0),
+        outline_common: true,
+        outline_min_insts: 12,
+        outline_min_count: 8,
+        link_results: true,
+        devirtualize_results: true,
+    },
+    // A few directives over one switch-dispatch loop, whose handlers are
+    // small enough that outlining only pays for the common ones.
+    Preset {
+        name: "quickjs-style",
+        about: "a single switch-dispatch interpreter loop with small handlers",
+        max_memory_gb: Some(4.0),
+        outline_common: true,
+        outline_min_insts: DEFAULT_OUTLINE_MIN_INSTS,
+        outline_min_count: DEFAULT_OUTLINE_MIN_COUNT,
+        link_results: false,
+        devirtualize_results: false,
+    },
+];
+
+/// Look up a preset by name, for `--preset`.
+pub(crate) fn find(name: &str) -> anyhow::Result<&'static Preset> {
+    PRESETS
+        .iter()
+        .find(|preset| preset.name == name)
+        .ok_or_else(|| {
+            let known = PRESETS
+                .iter()
+                .map(|preset| format!("\n  {}: {}", preset.name, preset.about))
+                .collect::<String>();
+            anyhow::anyhow!("unknown preset `{}`; the presets are:{}", name, known)
+        })
+}
@@ -0,0 +1,179 @@
+//! Profiling of weval's own phases.
+//!
+//! weval's phases (Wizening, each directive, each pass over a
+//! specialized body, ...) are tracing spans. With `--self-profile`, a
+//! tracing layer records when each span is entered and exited, on
+//! which thread, and writes the result in the Chrome trace-event
+//! format, which chrome://tracing, Perfetto and speedscope can load.
+
+use serde_json::{json, Value as Json};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::span;
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// One completed span.
+struct ProfileEvent {
+    name: String,
+    target: &'static str,
+    thread: u64,
+    start: Duration,
+    duration: Duration,
+    args: Vec<(&'static str, String)>,
+}
+
+/// Events recorded so far in this run.
+pub(crate) struct Profile {
+    start: Instant,
+    events: Mutex<Vec<ProfileEvent>>,
+    threads: Mutex<Vec<(u64, String)>>,
+}
+
+impl Profile {
+    pub(crate) fn new() -> Arc<Profile> {
+        Arc::new(Profile {
+            start: Instant::now(),
+            events: Mutex::new(vec![]),
+            threads: Mutex::new(vec![]),
+        })
+    }
+
+    /// A layer that records spans into this profile.
+    pub(crate) fn layer(self: &Arc<Profile>) -> ProfileLayer {
+        ProfileLayer(self.clone())
+    }
+
+    /// Write the profile as a Chrome trace-event JSON file.
+    pub(crate) fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let micros = |d: Duration| d.as_nanos() as f64 / 1000.0;
+        let mut trace_events: Vec<Json> = vec![];
+        for (tid, name) in self.threads.lock().unwrap().iter() {
+            trace_events.push(json!({
+                "ph": "M",
+                "name": "thread_name",
+                "pid": 1,
+                "tid": tid,
+                "args": { "name": name },
+            }));
+        }
+        for event in self.events.lock().unwrap().iter() {
+            let args: serde_json::Map<String, Json> = event
+                .args
+                .iter()
+                .map(|(k, v)| (k.to_string(), Json::String(v.clone())))
+                .collect();
+            trace_events.push(json!({
+                "ph": "X",
+                "name": event.name,
+                "cat": event.target,
+                "pid": 1,
+                "tid": event.thread,
+                "ts": micros(event.start),
+                "dur": micros(event.duration),
+                "args": args,
+            }));
+        }
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer(
+            std::io::BufWriter::new(file),
+            &json!({ "traceEvents": trace_events, "displayTimeUnit": "ms" }),
+        )?;
+        Ok(())
+    }
+
+    /// A small stable ID for the current thread, naming the thread
+    /// the first time it is seen.
+    fn thread_id(&self) -> u64 {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        thread_local! {
+            static ID: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+        }
+        ID.with(|id| {
+            if let Some(id) = id.get() {
+                return id;
+            }
+            let new = NEXT.fetch_add(1, Ordering::Relaxed);
+            let name = match std::thread::current().name() {
+                Some(name) => name.to_owned(),
+                None => format!("worker {}", new),
+            };
+            self.threads.lock().unwrap().push((new, name));
+            id.set(Some(new));
+            new
+        })
+    }
+}
+
+/// Per-span data kept in the registry.
+struct SpanTiming {
+    args: Vec<(&'static str, String)>,
+    entered: Option<Instant>,
+}
+
+struct FieldRecorder<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl<'a> Visit for FieldRecorder<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+pub(crate) struct ProfileLayer(Arc<Profile>);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for ProfileLayer {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut args = vec![];
+        attrs.record(&mut FieldRecorder(&mut args));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanTiming {
+                args,
+                entered: None,
+            });
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+                timing.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        let now = Instant::now();
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(timing) = extensions.get_mut::<SpanTiming>() else {
+            return;
+        };
+        let Some(entered) = timing.entered.take() else {
+            return;
+        };
+        // Passes are all named "pass"; show which one.
+        let name = match timing.args.iter().find(|(k, _)| *k == "name") {
+            Some((_, name)) => format!("{} {}", span.name(), name),
+            None => span.name().to_owned(),
+        };
+        let event = ProfileEvent {
+            name,
+            target: span.metadata().target(),
+            thread: self.0.thread_id(),
+            start: entered.saturating_duration_since(self.0.start),
+            duration: now - entered,
+            args: timing.args.clone(),
+        };
+        self.0.events.lock().unwrap().push(event);
+    }
+}
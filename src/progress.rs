@@ -0,0 +1,303 @@
+//! Progress reporting while specializing.
+//!
+//! A progress bar redraws in place, which a terminal shows well but
+//! a CI log records as a mess of control sequences; in plain mode,
+//! progress is instead an ordinary line every so often. The TUI adds
+//! a line per directive being specialized, with its size so far, the
+//! estimated memory in use and the most recent warnings logged.
+
+use fxhash::FxHashMap;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::VecDeque;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How to show progress (`--progress`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// A bar if stderr is a terminal, otherwise plain lines; only with
+    /// `--verbose`.
+    Auto,
+    /// A bar that redraws in place.
+    Fancy,
+    /// A line with the directive count and elapsed time, every
+    /// `PLAIN_INTERVAL`.
+    Plain,
+    /// A bar, plus a line per directive in progress, memory use and
+    /// recent warnings.
+    Tui,
+    /// Nothing.
+    None,
+}
+
+impl std::str::FromStr for ProgressMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<ProgressMode> {
+        Ok(match s {
+            "auto" => ProgressMode::Auto,
+            "fancy" => ProgressMode::Fancy,
+            "plain" => ProgressMode::Plain,
+            "tui" => ProgressMode::Tui,
+            "none" => ProgressMode::None,
+            _ => anyhow::bail!("must be one of auto, fancy, plain, tui or none"),
+        })
+    }
+}
+
+/// How often plain mode reports.
+const PLAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many warnings the TUI shows.
+const RECENT_WARNINGS: usize = 5;
+
+/// How much of each warning's first line the TUI shows.
+const WARNING_WIDTH: usize = 120;
+
+pub(crate) enum Progress {
+    Fancy(ProgressBar),
+    Plain {
+        start: Instant,
+        done: AtomicU64,
+        total: AtomicU64,
+        last: Mutex<Instant>,
+    },
+    Tui(Arc<Tui>),
+}
+
+impl Progress {
+    /// The progress display for `mode`, if any.
+    pub(crate) fn new(mode: ProgressMode, verbose: bool) -> Option<Progress> {
+        let mode = match mode {
+            ProgressMode::Auto if !verbose => return None,
+            ProgressMode::Auto if std::io::stderr().is_terminal() => ProgressMode::Fancy,
+            ProgressMode::Auto => ProgressMode::Plain,
+            mode => mode,
+        };
+        match mode {
+            ProgressMode::Fancy => Some(Progress::Fancy(ProgressBar::new(0))),
+            ProgressMode::Plain => {
+                let now = Instant::now();
+                Some(Progress::Plain {
+                    start: now,
+                    done: AtomicU64::new(0),
+                    total: AtomicU64::new(0),
+                    last: Mutex::new(now),
+                })
+            }
+            ProgressMode::Tui => Some(Progress::Tui(Tui::new())),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set_length(&self, len: u64) {
+        match self {
+            Progress::Fancy(bar) => bar.set_length(len),
+            Progress::Tui(tui) => tui.overall.set_length(len),
+            Progress::Plain { total, .. } => total.store(len, Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn inc(&self, delta: u64) {
+        match self {
+            Progress::Fancy(bar) => bar.inc(delta),
+            Progress::Tui(tui) => tui.overall.inc(delta),
+            Progress::Plain { done, last, .. } => {
+                done.fetch_add(delta, Ordering::Relaxed);
+                let mut last = last.lock().unwrap();
+                if last.elapsed() >= PLAIN_INTERVAL {
+                    *last = Instant::now();
+                    self.print_plain();
+                }
+            }
+        }
+    }
+
+    pub(crate) fn tick(&self) {
+        match self {
+            Progress::Fancy(bar) => bar.tick(),
+            Progress::Tui(tui) => tui.overall.tick(),
+            Progress::Plain { .. } => {}
+        }
+    }
+
+    /// A line to show `label`'s progress on, in the TUI.
+    pub(crate) fn start_directive(&self, label: String) -> Option<DirectiveLine> {
+        match self {
+            Progress::Tui(tui) => Some(tui.start_directive(label)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        match self {
+            Progress::Fancy(bar) => bar.finish_and_clear(),
+            Progress::Plain { .. } => self.print_plain(),
+            Progress::Tui(tui) => tui.finish(),
+        }
+    }
+
+    fn print_plain(&self) {
+        if let Progress::Plain {
+            start, done, total, ..
+        } = self
+        {
+            eprintln!(
+                "specialized {}/{} directives ({:.0?} elapsed)",
+                done.load(Ordering::Relaxed),
+                total.load(Ordering::Relaxed),
+                start.elapsed()
+            );
+        }
+    }
+}
+
+/// The TUI's state, shared with the lines of directives in progress
+/// and with `show_warning`.
+pub(crate) struct Tui {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    status: ProgressBar,
+    next_id: AtomicU64,
+    /// The estimated memory of each directive in progress.
+    memory: Mutex<FxHashMap<u64, usize>>,
+    warnings: Mutex<VecDeque<String>>,
+}
+
+/// The TUI in use, if any, for `show_warning`.
+static ACTIVE_TUI: Mutex<Option<Arc<Tui>>> = Mutex::new(None);
+
+impl Tui {
+    fn new() -> Arc<Tui> {
+        let multi = MultiProgress::new();
+        let overall = multi.add(ProgressBar::new(0));
+        overall.set_style(
+            ProgressStyle::with_template(
+                "{elapsed_precise} [{wide_bar}] {pos}/{len} directives (ETA {eta})",
+            )
+            .unwrap(),
+        );
+        overall.enable_steady_tick(Duration::from_secs(1));
+        let status = multi.add(ProgressBar::new_spinner());
+        status.set_style(ProgressStyle::with_template("{msg}").unwrap());
+        let tui = Arc::new(Tui {
+            multi,
+            overall,
+            status,
+            next_id: AtomicU64::new(0),
+            memory: Mutex::new(FxHashMap::default()),
+            warnings: Mutex::new(VecDeque::new()),
+        });
+        tui.redraw_status();
+        *ACTIVE_TUI.lock().unwrap() = Some(tui.clone());
+        tui
+    }
+
+    fn start_directive(self: &Arc<Tui>, label: String) -> DirectiveLine {
+        let bar = self
+            .multi
+            .insert_before(&self.status, ProgressBar::new_spinner());
+        bar.set_style(ProgressStyle::with_template("  {spinner} {prefix}: {msg}").unwrap());
+        bar.set_prefix(label);
+        bar.set_message("starting");
+        DirectiveLine {
+            tui: self.clone(),
+            bar,
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn warn(&self, message: &str) {
+        // Warnings can carry whole function bodies; keep the display
+        // to a line each.
+        let first = message.lines().next().unwrap_or("");
+        let mut message = first.chars().take(WARNING_WIDTH).collect::<String>();
+        if message.len() < first.len() {
+            message += "...";
+        }
+        let mut warnings = self.warnings.lock().unwrap();
+        if warnings.len() == RECENT_WARNINGS {
+            warnings.pop_front();
+        }
+        warnings.push_back(message);
+        drop(warnings);
+        self.redraw_status();
+    }
+
+    fn redraw_status(&self) {
+        let memory = self.memory.lock().unwrap();
+        let mut message = format!(
+            "memory: ~{} MiB estimated for {} directives in progress",
+            memory.values().sum::<usize>() >> 20,
+            memory.len()
+        );
+        drop(memory);
+        if let Some(rss) = resident_memory() {
+            message += &format!("; {} MiB resident", rss >> 20);
+        }
+        for warning in self.warnings.lock().unwrap().iter() {
+            message += &format!("\nwarning: {}", warning);
+        }
+        self.status.set_message(message);
+    }
+
+    fn finish(&self) {
+        *ACTIVE_TUI.lock().unwrap() = None;
+        self.overall.finish_and_clear();
+        self.status.finish_and_clear();
+        // Warnings would otherwise vanish with the display.
+        for warning in self.warnings.lock().unwrap().iter() {
+            eprintln!("warning: {}", warning);
+        }
+    }
+}
+
+/// The TUI line of one directive being specialized, removed when
+/// dropped.
+pub(crate) struct DirectiveLine {
+    tui: Arc<Tui>,
+    bar: ProgressBar,
+    id: u64,
+}
+
+impl DirectiveLine {
+    /// Show the specialized function's size so far and the estimated
+    /// memory its specialization holds.
+    pub(crate) fn update(&self, blocks: usize, values: usize, memory: usize) {
+        self.bar.set_message(format!(
+            "{} blocks, {} values, ~{} MiB",
+            blocks,
+            values,
+            memory >> 20
+        ));
+        self.tui.memory.lock().unwrap().insert(self.id, memory);
+        self.tui.redraw_status();
+    }
+}
+
+impl Drop for DirectiveLine {
+    fn drop(&mut self) {
+        self.tui.memory.lock().unwrap().remove(&self.id);
+        self.bar.finish_and_clear();
+        self.tui.multi.remove(&self.bar);
+        self.tui.redraw_status();
+    }
+}
+
+/// The process's resident memory, where `/proc` says.
+fn resident_memory() -> Option<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+/// Show a warning in the TUI, if one is in use; returns whether it
+/// was.
+pub(crate) fn show_warning(message: &str) -> bool {
+    let Some(tui) = ACTIVE_TUI.lock().unwrap().clone() else {
+        return false;
+    };
+    tui.warn(message);
+    true
+}
@@ -0,0 +1,80 @@
+//! Host imports declared pure.
+//!
+//! weval cannot see into an imported function, so a call to one is a
+//! barrier to the passes over specialized code: it may read or write
+//! any memory, global or table, so stores before it stay, pointers
+//! passed to it escape, and the call itself is never removed or moved
+//! out of a loop. Builtins such as math shims are pure, though, and an
+//! interpreter calling them in every opcode handler loses much of its
+//! specialization at each call.
+//!
+//! An import can be declared pure with `--pure-import MODULE:NAME`, or
+//! by listing it in a `weval.pure-imports` custom section in the input,
+//! one `MODULE:NAME` per line (so that a toolchain can declare its own
+//! shims). A pure import accesses no memory, globals or tables, has no
+//! other side effects, does not trap, and returns the same results for
+//! the same arguments; weval does not check this. Calls to it are
+//! removed if unused and hoisted out of loops like any pure operator.
+//!
+//! Section entries that name no imported function are ignored, as the
+//! import may have been removed as unused; command-line ones are an
+//! error.
+
+use fxhash::FxHashSet;
+use waffle::{Func, ImportKind, Module};
+
+pub(crate) const SECTION_NAME: &str = "weval.pure-imports";
+
+/// Parse a `--pure-import` argument, `MODULE:NAME`.
+pub(crate) fn parse_spec(s: &str) -> anyhow::Result<(String, String)> {
+    match s.split_once(':') {
+        Some((module, name)) if !module.is_empty() && !name.is_empty() => {
+            Ok((module.to_owned(), name.to_owned()))
+        }
+        _ => anyhow::bail!("must be of the form MODULE:NAME"),
+    }
+}
+
+/// The imported functions declared pure, on the command line (`specs`)
+/// or in the module's `weval.pure-imports` section.
+pub(crate) fn resolve(
+    module: &Module,
+    specs: &[(String, String)],
+) -> anyhow::Result<FxHashSet<Func>> {
+    let find = |import_module: &str, name: &str| {
+        module.imports.iter().find_map(|import| match import.kind {
+            ImportKind::Func(func) if import.module == import_module && import.name == name => {
+                Some(func)
+            }
+            _ => None,
+        })
+    };
+
+    let mut funcs = FxHashSet::default();
+    for (import_module, name) in specs {
+        let func = find(import_module, name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "--pure-import: no imported function `{}:{}`",
+                import_module,
+                name
+            )
+        })?;
+        funcs.insert(func);
+    }
+    if let Some(data) = module.custom_sections.get(SECTION_NAME) {
+        let data = std::str::from_utf8(data)
+            .map_err(|e| anyhow::anyhow!("{} section is not UTF-8: {}", SECTION_NAME, e))?;
+        for line in data.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (import_module, name) = parse_spec(line)
+                .map_err(|e| anyhow::anyhow!("{} section: `{}`: {}", SECTION_NAME, line, e))?;
+            match find(&import_module, &name) {
+                Some(func) => {
+                    funcs.insert(func);
+                }
+                None => tracing::debug!("{}: no imported function `{}`", SECTION_NAME, line),
+            }
+        }
+    }
+    tracing::trace!("pure imports: {:?}", funcs);
+    Ok(funcs)
+}
@@ -0,0 +1,170 @@
+//! `weval query`: look at the memory image weval would specialize
+//! against.
+//!
+//! The image is built the same way `weval weval` builds it: from the
+//! module's data segments, after Wizening with `-w`. Queries read the
+//! main heap (the first memory).
+
+use crate::image::{self, Image};
+use crate::value::WasmVal;
+use structopt::StructOpt;
+use waffle::{ExportKind, Global, Module};
+
+#[derive(Clone, Debug, StructOpt)]
+pub enum Query {
+    /// Read bytes at an address, also shown as a little-endian integer
+    /// when there are 1, 2, 4 or 8 of them.
+    Read {
+        /// The address (decimal, or hexadecimal with `0x`).
+        #[structopt(parse(try_from_str = parse_u32))]
+        addr: u32,
+        /// How many bytes to read.
+        #[structopt(default_value = "4", parse(try_from_str = parse_u32))]
+        len: u32,
+    },
+    /// Hexdump a range of memory.
+    Hexdump {
+        /// The start address (decimal, or hexadecimal with `0x`).
+        #[structopt(parse(try_from_str = parse_u32))]
+        addr: u32,
+        /// How many bytes to dump.
+        #[structopt(default_value = "256", parse(try_from_str = parse_u32))]
+        len: u32,
+    },
+    /// Show a global's value, by export name or index (e.g. `3` or
+    /// `global3`).
+    Global {
+        /// The global's export name or index.
+        global: String,
+    },
+    /// Find the data segment that initializes an address.
+    Segment {
+        /// The address (decimal, or hexadecimal with `0x`).
+        #[structopt(parse(try_from_str = parse_u32))]
+        addr: u32,
+    },
+}
+
+/// Bytes per hexdump line.
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Answer `query` about `module`, returning the lines to print.
+pub(crate) fn run(module: &Module, query: &Query) -> anyhow::Result<Vec<String>> {
+    let im = image::build_image(module, None)?;
+    Ok(match query {
+        &Query::Read { addr, len } => {
+            let bytes = read(&im, addr, len)?;
+            let hex = bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut line = format!("{:#x}: {}", addr, hex);
+            if matches!(len, 1 | 2 | 4 | 8) {
+                let mut le = [0; 8];
+                le[..bytes.len()].copy_from_slice(bytes);
+                let value = u64::from_le_bytes(le);
+                line += &format!(" = {} ({:#x})", value, value);
+            }
+            vec![line]
+        }
+        &Query::Hexdump { addr, len } => read(&im, addr, len)?
+            .chunks(HEXDUMP_WIDTH)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let hex = chunk
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let ascii = chunk
+                    .iter()
+                    .map(|&b| match b {
+                        0x20..=0x7e => b as char,
+                        _ => '.',
+                    })
+                    .collect::<String>();
+                format!(
+                    "{:08x}  {:<width$}  |{}|",
+                    addr as usize + i * HEXDUMP_WIDTH,
+                    hex,
+                    ascii,
+                    width = HEXDUMP_WIDTH * 3 - 1
+                )
+            })
+            .collect(),
+        Query::Global { global } => {
+            let id = find_global(module, global)?;
+            let value = match im.globals.get(&id) {
+                Some(WasmVal::I32(v)) => format!("i32 {} ({:#x})", *v as i32, v),
+                Some(WasmVal::I64(v)) => format!("i64 {} ({:#x})", *v as i64, v),
+                Some(WasmVal::F32(bits)) => format!("f32 {} ({:#x})", f32::from_bits(*bits), bits),
+                Some(WasmVal::F64(bits)) => format!("f64 {} ({:#x})", f64::from_bits(*bits), bits),
+                Some(WasmVal::V128(bits)) => format!("v128 {:#034x}", bits),
+                None => format!("{} with a non-constant initializer", module.globals[id].ty),
+            };
+            let mutability = if module.globals[id].mutable {
+                "mutable"
+            } else {
+                "immutable"
+            };
+            vec![format!("{} ({}): {}", id, mutability, value)]
+        }
+        &Query::Segment { addr } => {
+            let heap = im.main_heap()?;
+            let found = module.memories[heap]
+                .segments
+                .iter()
+                .enumerate()
+                .find(|(_, segment)| {
+                    (segment.offset..segment.offset + segment.data.len()).contains(&(addr as usize))
+                });
+            match found {
+                Some((i, segment)) => vec![format!(
+                    "{:#x} is at offset {:#x} in data segment {} ({:#x}..{:#x}, {} bytes)",
+                    addr,
+                    addr as usize - segment.offset,
+                    i,
+                    segment.offset,
+                    segment.offset + segment.data.len(),
+                    segment.data.len()
+                )],
+                None => vec![format!(
+                    "{:#x} is not in any data segment (zero-initialized)",
+                    addr
+                )],
+            }
+        }
+    })
+}
+
+fn read(im: &Image, addr: u32, len: u32) -> anyhow::Result<&[u8]> {
+    im.read_slice(im.main_heap()?, addr, len)
+}
+
+fn find_global(module: &Module, name: &str) -> anyhow::Result<Global> {
+    let exported = module.exports.iter().find_map(|export| match export.kind {
+        ExportKind::Global(global) if export.name == name => Some(global),
+        _ => None,
+    });
+    if let Some(global) = exported {
+        return Ok(global);
+    }
+    let index = name
+        .strip_prefix("global")
+        .unwrap_or(name)
+        .parse::<usize>()
+        .map_err(|_| anyhow::anyhow!("no global exported as `{}`", name))?;
+    module
+        .globals
+        .iter()
+        .nth(index)
+        .ok_or_else(|| anyhow::anyhow!("no global {}", index))
+}
+
+fn parse_u32(s: &str) -> anyhow::Result<u32> {
+    Ok(match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => s.parse()?,
+    })
+}
@@ -0,0 +1,32 @@
+//! Detecting irreducible control flow in specialized function bodies.
+//!
+//! Specialization can make a reducible loop in the generic function
+//! irreducible: when the guest jumps into the middle of a guest loop,
+//! its PC copies are entered at more than one block. Wasm can only
+//! express reducible control flow, so waffle's backend restores
+//! reducibility when it emits the function, by duplicating the blocks
+//! between the entries (node splitting). That always yields valid
+//! output, but the duplication can grow the function considerably, so
+//! we count the edges that need it and warn about them.
+
+use waffle::cfg::CFGInfo;
+use waffle::FunctionBody;
+
+/// The number of irreducible edges in `func`: edges to a block no
+/// later in RPO that does not dominate the edge's source, so that
+/// the loop they close has more than one entry. Zero if and only if
+/// the reachable CFG is reducible.
+pub fn irreducible_edges(func: &FunctionBody, cfg: &CFGInfo) -> usize {
+    let mut edges = 0;
+    for &block in cfg.rpo.values() {
+        let pos = cfg.rpo_pos[block].unwrap();
+        for &succ in &func.blocks[block].succs {
+            let retreating = cfg.rpo_pos[succ].is_some_and(|succ_pos| succ_pos <= pos);
+            if retreating && !cfg.dominates(succ, block) {
+                tracing::trace!("reducibility: irreducible edge {} -> {}", block, succ);
+                edges += 1;
+            }
+        }
+    }
+    edges
+}
@@ -0,0 +1,407 @@
+//! Self-contained HTML report of a weval run.
+//!
+//! The report collects the same information as `--show-stats` and
+//! `--show-largest`, plus the outcome of every directive, into one
+//! HTML file with no external resources, so that it can be archived
+//! per release and read in any browser. The same information is also
+//! available as JSON, for tools that drive weval.
+
+use crate::cache::ModuleHash;
+use crate::eval::PartialEvalResult;
+use crate::stats::DirectiveResult;
+use fxhash::FxHashMap;
+use serde_json::{json, Value as Json};
+use std::fmt::Write;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: right; }
+th { background: #eee; }
+td.name { text-align: left; }
+.bar { height: 0.8em; background: #4a7ab5; }
+.bar.generic { background: #bbb; }
+.ok { color: #282; }
+.warn { color: #b60; }
+.err { color: #b22; }
+pre { white-space: pre-wrap; font-size: 0.85em; }
+";
+
+/// Render the report for the result of a run over `input` as JSON.
+pub(crate) fn json(input: &str, result: &PartialEvalResult) -> serde_json::Value {
+    let module = &result.module;
+    let index = |func: waffle::Func| waffle::entity::EntityRef::index(func);
+    let count = |f: fn(&DirectiveResult) -> bool| {
+        result
+            .outcomes
+            .iter()
+            .filter(|outcome| f(&outcome.result))
+            .count()
+    };
+    let functions: Vec<_> = result
+        .stats
+        .iter()
+        .map(|stats| {
+            json!({
+                "func": index(stats.generic),
+                "name": module.funcs[stats.generic].name(),
+                "generic_blocks": stats.generic_blocks,
+                "generic_insts": stats.generic_insts,
+                "specializations": stats.specializations,
+                "specialized_blocks": stats.specialized_blocks,
+                "specialized_insts": stats.specialized_insts,
+                "virtstack_reads": stats.virtstack_reads,
+                "virtstack_reads_mem": stats.virtstack_reads_mem,
+                "virtstack_writes": stats.virtstack_writes,
+                "virtstack_writes_mem": stats.virtstack_writes_mem,
+                "local_reads": stats.local_reads,
+                "local_reads_mem": stats.local_reads_mem,
+                "local_writes": stats.local_writes,
+                "local_writes_mem": stats.local_writes_mem,
+                "flush_stores_elided": stats.flush_stores_elided,
+                "switch_chains": stats.switch_chains,
+                "licm_hoisted": stats.licm_hoisted,
+                "irreducible_edges": stats.irreducible_edges,
+                "live_value_at_block_start": stats.live_value_at_block_start,
+                "module_consts": stats.module_consts,
+                "directive_consts": stats.directive_consts,
+                "nondeterministic_folds": stats.nondeterministic_folds,
+                "inferred_buckets": stats
+                    .inferred_buckets
+                    .iter()
+                    .map(|(user_id, buckets)| {
+                        json!({
+                            "user_id": user_id,
+                            "buckets": buckets
+                                .iter()
+                                .map(|(bucket, pcs)| (bucket.to_string(), json!(pcs)))
+                                .collect::<serde_json::Map<_, _>>(),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    let directives: Vec<_> = result
+        .outcomes
+        .iter()
+        .map(|outcome| {
+            let (status, code, error) = match &outcome.result {
+                DirectiveResult::Specialized => ("specialized", None, None),
+                DirectiveResult::Cached => ("cached", None, None),
+                DirectiveResult::Abandoned => ("abandoned", None, None),
+                DirectiveResult::Failed { code, message } => ("failed", Some(code), Some(message)),
+            };
+            json!({
+                "user_id": outcome.user_id,
+                "func": index(outcome.func),
+                "func_index_out_addr": outcome.func_index_out_addr,
+                "result": status,
+                "error": error,
+                "error_code": code,
+            })
+        })
+        .collect();
+    let sizes: Vec<_> = result
+        .sizes
+        .iter()
+        .map(|size| {
+            json!({
+                "generic": index(size.generic),
+                "specialized": index(size.specialized),
+                "user_id": size.user_id,
+                "func_index_out_addr": size.func_index_out_addr,
+                "generic_bytes": size.generic_bytes,
+                "specialized_bytes": size.specialized_bytes,
+                "cache_hit": size.cache_hit,
+            })
+        })
+        .collect();
+    json!({
+        "input": input,
+        "summary": {
+            "directives": result.outcomes.len(),
+            "specialized": count(|r| matches!(r, DirectiveResult::Specialized)),
+            "cached": count(|r| matches!(r, DirectiveResult::Cached)),
+            "abandoned": count(|r| matches!(r, DirectiveResult::Abandoned)),
+            "failed": count(|r| matches!(r, DirectiveResult::Failed { .. })),
+            "specialized_bytes": result
+                .sizes
+                .iter()
+                .map(|size| size.specialized_bytes)
+                .sum::<usize>(),
+        },
+        "functions": functions,
+        "directives": directives,
+        "sizes": sizes,
+    })
+}
+
+/// Lowercase hex of a hash.
+pub(crate) fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The build-artifact manifest of a run over `input`: one entry per
+/// directive with the function it produced, if any. The caller adds
+/// the output module once it is written.
+pub(crate) fn manifest(input: &str, input_hash: &ModuleHash, result: &PartialEvalResult) -> Json {
+    let module = &result.module;
+    let index = |func: waffle::Func| waffle::entity::EntityRef::index(func);
+    let directives: Vec<_> = result
+        .outcomes
+        .iter()
+        .map(|outcome| {
+            let size = result.sizes.iter().find(|size| {
+                size.func_index_out_addr == outcome.func_index_out_addr
+                    && size.generic == outcome.func
+                    && size.user_id == outcome.user_id
+            });
+            let (status, code, error) = match &outcome.result {
+                DirectiveResult::Specialized => ("specialized", None, None),
+                DirectiveResult::Cached => ("cached", None, None),
+                DirectiveResult::Abandoned => ("abandoned", None, None),
+                DirectiveResult::Failed { code, message } => ("failed", Some(code), Some(message)),
+            };
+            json!({
+                "user_id": outcome.user_id,
+                "func_index_out_addr": outcome.func_index_out_addr,
+                "generic": {
+                    "index": index(outcome.func),
+                    "name": module.funcs[outcome.func].name(),
+                    "bytes": size.map(|size| size.generic_bytes),
+                },
+                "result": status,
+                "error": error,
+                "error_code": code,
+                "cache_hit": size.map(|size| size.cache_hit),
+                "specialized": size.map(|size| json!({
+                    "index": index(size.specialized),
+                    "name": module.funcs[size.specialized].name(),
+                    "bytes": size.specialized_bytes,
+                })),
+            })
+        })
+        .collect();
+    json!({
+        "input": { "path": input, "sha256": hex(input_hash) },
+        "directives": directives,
+    })
+}
+
+/// Rewrite the function indices in a manifest from the module before
+/// filtering to the output module.
+pub(crate) fn remap_manifest_indices(manifest: &mut Json, func_indices: &FxHashMap<u32, u32>) {
+    let Some(directives) = manifest["directives"].as_array_mut() else {
+        return;
+    };
+    for directive in directives {
+        for key in ["generic", "specialized"] {
+            let index = &mut directive[key]["index"];
+            if let Some(new) = index
+                .as_u64()
+                .and_then(|old| func_indices.get(&(old as u32)))
+            {
+                *index = json!(new);
+            }
+        }
+    }
+}
+
+/// Render the report for the result of a run over `input`.
+pub(crate) fn html(input: &str, result: &PartialEvalResult) -> String {
+    let module = &result.module;
+    let mut s = String::new();
+    let input = escape(input);
+    writeln!(&mut s, "<!DOCTYPE html>").unwrap();
+    writeln!(&mut s, "<html><head><meta charset=\"utf-8\">").unwrap();
+    writeln!(&mut s, "<title>weval report: {}</title>", input).unwrap();
+    writeln!(&mut s, "<style>{}</style></head><body>", STYLE).unwrap();
+    writeln!(&mut s, "<h1>weval report: {}</h1>", input).unwrap();
+
+    // Summary.
+    let count = |f: fn(&DirectiveResult) -> bool| {
+        result
+            .outcomes
+            .iter()
+            .filter(|outcome| f(&outcome.result))
+            .count()
+    };
+    let specialized = count(|r| matches!(r, DirectiveResult::Specialized));
+    let cached = count(|r| matches!(r, DirectiveResult::Cached));
+    let abandoned = count(|r| matches!(r, DirectiveResult::Abandoned));
+    let failed = count(|r| matches!(r, DirectiveResult::Failed { .. }));
+    let total_bytes: usize = result.sizes.iter().map(|size| size.specialized_bytes).sum();
+    writeln!(&mut s, "<h2>Summary</h2><table>").unwrap();
+    for (label, value) in [
+        ("Directives", result.outcomes.len()),
+        ("Specialized", specialized),
+        ("From cache", cached),
+        ("Abandoned", abandoned),
+        ("Failed", failed),
+        ("Specialized code bytes", total_bytes),
+    ] {
+        writeln!(&mut s, "<tr><th>{}</th><td>{}</td></tr>", label, value).unwrap();
+    }
+    writeln!(&mut s, "</table>").unwrap();
+
+    // Warnings.
+    let nondeterministic = result
+        .stats
+        .iter()
+        .filter(|stats| stats.nondeterministic_folds > 0)
+        .collect::<Vec<_>>();
+    if abandoned + failed > 0 || !nondeterministic.is_empty() {
+        writeln!(&mut s, "<h2>Warnings</h2><ul>").unwrap();
+        for outcome in &result.outcomes {
+            let func = module.funcs[outcome.func].name();
+            match &outcome.result {
+                DirectiveResult::Abandoned => writeln!(
+                    &mut s,
+                    "<li class=\"warn\">Directive {} ({}, output at {:#x}): abandoned, too many blocks or values</li>",
+                    outcome.user_id,
+                    escape(func),
+                    outcome.func_index_out_addr
+                )
+                .unwrap(),
+                DirectiveResult::Failed { message: e, .. } => writeln!(
+                    &mut s,
+                    "<li class=\"err\">Directive {} ({}, output at {:#x}): failed<pre>{}</pre></li>",
+                    outcome.user_id,
+                    escape(func),
+                    outcome.func_index_out_addr,
+                    escape(e)
+                )
+                .unwrap(),
+                _ => {}
+            }
+        }
+        for stats in &nondeterministic {
+            writeln!(
+                &mut s,
+                "<li class=\"warn\">Function {} ({}): {} instructions fold to NaN, whose bits the engine may choose differently</li>",
+                stats.generic,
+                escape(module.funcs[stats.generic].name()),
+                stats.nondeterministic_folds
+            )
+            .unwrap();
+        }
+        writeln!(&mut s, "</ul>").unwrap();
+    }
+
+    // Per-generic-function stats.
+    writeln!(&mut s, "<h2>Functions</h2><table>").unwrap();
+    writeln!(
+        &mut s,
+        "<tr><th>Function</th><th>Blocks</th><th>Insts</th><th>Specializations</th>\
+         <th>Specialized blocks</th><th>Specialized insts</th>\
+         <th>Virtstack reads (mem)</th><th>Virtstack writes (mem)</th>\
+         <th>Local reads (mem)</th><th>Local writes (mem)</th>\
+         <th>Live values per block</th>\
+         <th>Constants (module-wide / per-directive)</th></tr>"
+    )
+    .unwrap();
+    for stats in &result.stats {
+        writeln!(
+            &mut s,
+            "<tr><td class=\"name\">{} ({})</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{} ({})</td><td>{} ({})</td><td>{} ({})</td><td>{} ({})</td><td>{:.1}</td>\
+             <td>{} / {}</td></tr>",
+            stats.generic,
+            escape(module.funcs[stats.generic].name()),
+            stats.generic_blocks,
+            stats.generic_insts,
+            stats.specializations,
+            stats.specialized_blocks,
+            stats.specialized_insts,
+            stats.virtstack_reads,
+            stats.virtstack_reads_mem,
+            stats.virtstack_writes,
+            stats.virtstack_writes_mem,
+            stats.local_reads,
+            stats.local_reads_mem,
+            stats.local_writes,
+            stats.local_writes_mem,
+            (stats.live_value_at_block_start as f64) / (stats.specialized_blocks as f64),
+            stats.module_consts,
+            stats.directive_consts,
+        )
+        .unwrap();
+    }
+    writeln!(&mut s, "</table>").unwrap();
+
+    // Size chart of specialized functions, largest first, with the
+    // generic function's size for comparison.
+    let mut sizes = result.sizes.clone();
+    sizes.sort_by_key(|size| std::cmp::Reverse(size.specialized_bytes));
+    let max = sizes
+        .iter()
+        .map(|size| std::cmp::max(size.specialized_bytes, size.generic_bytes))
+        .max()
+        .unwrap_or(0);
+    let width = |bytes: usize| 100.0 * (bytes as f64) / (max as f64);
+    writeln!(&mut s, "<h2>Specialized function sizes</h2><table>").unwrap();
+    writeln!(
+        &mut s,
+        "<tr><th>Function</th><th>Directive</th><th>Bytes</th><th>Growth</th>\
+         <th style=\"width: 30em\">Size (generic in grey)</th></tr>"
+    )
+    .unwrap();
+    for size in &sizes {
+        writeln!(
+            &mut s,
+            "<tr><td class=\"name\">{} ({})</td><td>{} @ {:#x}{}</td><td>{}</td><td>{:.2}x</td>\
+             <td><div class=\"bar\" style=\"width: {:.1}%\"></div>\
+             <div class=\"bar generic\" style=\"width: {:.1}%\"></div></td></tr>",
+            size.specialized,
+            escape(module.funcs[size.specialized].name()),
+            size.user_id,
+            size.func_index_out_addr,
+            if size.cache_hit { " (cached)" } else { "" },
+            size.specialized_bytes,
+            size.growth(),
+            width(size.specialized_bytes),
+            width(size.generic_bytes),
+        )
+        .unwrap();
+    }
+    writeln!(&mut s, "</table>").unwrap();
+
+    // Per-directive outcomes.
+    writeln!(&mut s, "<h2>Directives</h2><table>").unwrap();
+    writeln!(
+        &mut s,
+        "<tr><th>User ID</th><th>Function</th><th>Output address</th><th>Outcome</th></tr>"
+    )
+    .unwrap();
+    for outcome in &result.outcomes {
+        let (class, desc) = match &outcome.result {
+            DirectiveResult::Specialized => ("ok", "specialized"),
+            DirectiveResult::Cached => ("ok", "cached"),
+            DirectiveResult::Abandoned => ("warn", "abandoned"),
+            DirectiveResult::Failed { .. } => ("err", "failed"),
+        };
+        writeln!(
+            &mut s,
+            "<tr><td>{}</td><td class=\"name\">{} ({})</td><td>{:#x}</td><td class=\"{}\">{}</td></tr>",
+            outcome.user_id,
+            outcome.func,
+            escape(module.funcs[outcome.func].name()),
+            outcome.func_index_out_addr,
+            class,
+            desc
+        )
+        .unwrap();
+    }
+    writeln!(&mut s, "</table>").unwrap();
+
+    writeln!(&mut s, "</body></html>").unwrap();
+    s
+}
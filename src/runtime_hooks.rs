@@ -0,0 +1,379 @@
+//! Hooks for specializing at runtime.
+//!
+//! weval fulfills the requests a module made before it was wevaled;
+//! requests the module makes once deployed wait on the pending list
+//! forever. With `--runtime-hooks`, the module gets what a host needs
+//! to fulfill them itself, e.g. by running weval on the side, so that
+//! weval can serve as an online tier:
+//!
+//! - an import, `weval-runtime.request: (i32) -> i32`, called with the
+//!   address of each pending request (a `weval_req_t`, as laid out in
+//!   `weval.h`). The host specializes the request's function, appends
+//!   the result to the function table, and returns its table index,
+//!   or 0 to leave the request pending;
+//! - an export, `weval-runtime.service: () -> i32`, that calls the
+//!   import for every pending request, writes each table index
+//!   returned to its request's output address, unlinks those
+//!   requests, and returns how many it fulfilled;
+//! - exports of the function table and memory, if the module does not
+//!   export them already, as `weval-runtime.table` and
+//!   `weval-runtime.memory`. The table has no maximum size. Specialized
+//!   code assumes that the functions already in the table stay put:
+//!   the host may only append to it.
+//!
+//! `weval_request()` in `weval.h` drops requests made after wevaling
+//! unless weval sets the module's `weval_runtime_hooks` flag, which it
+//! does here.
+
+use crate::image::Image;
+use crate::intrinsics::find_global_data_by_exported_func;
+use waffle::entity::EntityRef;
+use waffle::{
+    BlockTarget, Export, ExportKind, Func, FuncDecl, FunctionBody, Import, ImportKind, Memory,
+    MemoryArg, Module, Operator, SignatureData, Table, Terminator, Type,
+};
+
+/// Add the `weval-runtime.request` import. Function imports precede
+/// all other functions, so every function the module defines moves up
+/// one index; all bodies are expanded into IR to renumber their
+/// calls. This must happen before anything refers to functions by
+/// index.
+pub(crate) fn add_request_import(module: &mut Module) -> anyhow::Result<Func> {
+    module.expand_all_funcs()?;
+    let at = module
+        .funcs
+        .values()
+        .take_while(|decl| matches!(decl, FuncDecl::Import(..)))
+        .count();
+    let renumber = |func: Func| {
+        if func.index() >= at {
+            Func::new(func.index() + 1)
+        } else {
+            func
+        }
+    };
+
+    let sig = module.signatures.push(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![Type::I32],
+    });
+    let mut funcs = std::mem::take(&mut module.funcs).into_vec();
+    funcs.insert(
+        at,
+        FuncDecl::Import(sig, "weval-runtime.request".to_owned()),
+    );
+    for decl in &mut funcs {
+        let FuncDecl::Body(_, _, body) = decl else {
+            continue;
+        };
+        for def in body.values.values_mut() {
+            if let waffle::ValueDef::Operator(op, ..) = def {
+                match op {
+                    Operator::Call { function_index } => {
+                        *function_index = renumber(*function_index)
+                    }
+                    Operator::RefFunc { func_index } => *func_index = renumber(*func_index),
+                    _ => {}
+                }
+            }
+        }
+    }
+    module.funcs = funcs.into();
+
+    for table in module.tables.values_mut() {
+        for func in table.func_elements.iter_mut().flatten() {
+            if func.is_valid() {
+                *func = renumber(*func);
+            }
+        }
+    }
+    for import in &mut module.imports {
+        if let ImportKind::Func(func) = &mut import.kind {
+            *func = renumber(*func);
+        }
+    }
+    for export in &mut module.exports {
+        if let ExportKind::Func(func) = &mut export.kind {
+            *func = renumber(*func);
+        }
+    }
+    module.start_func = module.start_func.map(renumber);
+
+    let request = Func::new(at);
+    module.imports.push(Import {
+        module: "weval-runtime".to_owned(),
+        name: "request".to_owned(),
+        kind: ImportKind::Func(request),
+    });
+    Ok(request)
+}
+
+/// Add the `weval-runtime.service` export calling `request`, export
+/// the table and the main heap, and set the `weval_runtime_hooks`
+/// flag in `im`.
+pub(crate) fn add_service(
+    module: &mut Module,
+    request: Func,
+    im: &mut Image,
+) -> anyhow::Result<()> {
+    let heap = im.main_heap()?;
+    let head_addr = find_global_data_by_exported_func(module, "weval.pending.head")
+        .ok_or_else(|| anyhow::anyhow!("--runtime-hooks: the module has no weval request list"))?;
+    let table = Table::from(0);
+    anyhow::ensure!(
+        module
+            .tables
+            .get(table)
+            .is_some_and(|table| table.func_elements.is_some()),
+        "--runtime-hooks: the module has no function table"
+    );
+    module.tables[table].max = None;
+    match find_global_data_by_exported_func(module, "weval.runtime.hooks") {
+        Some(flag) => {
+            tracing::info!("setting `weval_runtime_hooks` flag at {:#x}", flag);
+            im.write_u8(heap, flag, 1)?;
+        }
+        None => crate::policy::warn(
+            "--runtime-hooks: the module does not export `weval.runtime.hooks` \
+             (built with an older weval.h?), so it may drop requests made after wevaling"
+                .to_owned(),
+        ),
+    }
+
+    let sig = module.signatures.push(SignatureData {
+        params: vec![],
+        returns: vec![Type::I32],
+    });
+    let body = service_body(module, sig, request, heap, head_addr);
+    let service = module.funcs.push(FuncDecl::Body(
+        sig,
+        "weval-runtime.service".to_owned(),
+        body,
+    ));
+    module.exports.push(Export {
+        name: "weval-runtime.service".to_owned(),
+        kind: ExportKind::Func(service),
+    });
+
+    if !module
+        .exports
+        .iter()
+        .any(|export| matches!(export.kind, ExportKind::Table(t) if t == table))
+    {
+        module.exports.push(Export {
+            name: "weval-runtime.table".to_owned(),
+            kind: ExportKind::Table(table),
+        });
+    }
+    if !module
+        .exports
+        .iter()
+        .any(|export| matches!(export.kind, ExportKind::Memory(m) if m == heap))
+    {
+        module.exports.push(Export {
+            name: "weval-runtime.memory".to_owned(),
+            kind: ExportKind::Memory(heap),
+        });
+    }
+    Ok(())
+}
+
+/// The body of `weval-runtime.service`: walk the pending list,
+/// unlinking requests as `directive::collect` does.
+fn service_body(
+    module: &Module,
+    sig: waffle::Signature,
+    request: Func,
+    heap: Memory,
+    head_addr: u32,
+) -> FunctionBody {
+    // Keep these offsets in sync with the struct definition in
+    // `include/weval.h`.
+    const NEXT: u32 = 0;
+    const PREV: u32 = 4;
+    const OUT_ADDR: u32 = 28;
+    let mem = |offset| MemoryArg {
+        align: 2,
+        offset,
+        memory: heap,
+    };
+    let br = |block, args| Terminator::Br {
+        target: BlockTarget { block, args },
+    };
+
+    let mut body = FunctionBody::new(module, sig);
+    let entry = body.entry;
+    let header = body.add_block();
+    let node = body.add_blockparam(header, Type::I32);
+    let count = body.add_blockparam(header, Type::I32);
+    let visit = body.add_block();
+    let install = body.add_block();
+    let link_next = body.add_block();
+    let after_next = body.add_block();
+    let link_prev = body.add_block();
+    let link_head = body.add_block();
+    let unlinked = body.add_block();
+    let exit = body.add_block();
+
+    let head = body.add_op(
+        entry,
+        Operator::I32Const { value: head_addr },
+        &[],
+        &[Type::I32],
+    );
+    let first = body.add_op(
+        entry,
+        Operator::I32Load { memory: mem(0) },
+        &[head],
+        &[Type::I32],
+    );
+    let zero = body.add_op(entry, Operator::I32Const { value: 0 }, &[], &[Type::I32]);
+    body.set_terminator(entry, br(header, vec![first, zero]));
+
+    body.set_terminator(
+        header,
+        Terminator::CondBr {
+            cond: node,
+            if_true: BlockTarget {
+                block: visit,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: exit,
+                args: vec![],
+            },
+        },
+    );
+
+    // Ask the host, leaving the request pending if it declines.
+    let next = body.add_op(
+        visit,
+        Operator::I32Load { memory: mem(NEXT) },
+        &[node],
+        &[Type::I32],
+    );
+    let index = body.add_op(
+        visit,
+        Operator::Call {
+            function_index: request,
+        },
+        &[node],
+        &[Type::I32],
+    );
+    body.set_terminator(
+        visit,
+        Terminator::CondBr {
+            cond: index,
+            if_true: BlockTarget {
+                block: install,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: header,
+                args: vec![next, count],
+            },
+        },
+    );
+
+    let out = body.add_op(
+        install,
+        Operator::I32Load {
+            memory: mem(OUT_ADDR),
+        },
+        &[node],
+        &[Type::I32],
+    );
+    body.add_op(
+        install,
+        Operator::I32Store { memory: mem(0) },
+        &[out, index],
+        &[],
+    );
+    let prev = body.add_op(
+        install,
+        Operator::I32Load { memory: mem(PREV) },
+        &[node],
+        &[Type::I32],
+    );
+    body.set_terminator(
+        install,
+        Terminator::CondBr {
+            cond: next,
+            if_true: BlockTarget {
+                block: link_next,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: after_next,
+                args: vec![],
+            },
+        },
+    );
+    body.add_op(
+        link_next,
+        Operator::I32Store { memory: mem(PREV) },
+        &[next, prev],
+        &[],
+    );
+    body.set_terminator(link_next, br(after_next, vec![]));
+    body.set_terminator(
+        after_next,
+        Terminator::CondBr {
+            cond: prev,
+            if_true: BlockTarget {
+                block: link_prev,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: link_head,
+                args: vec![],
+            },
+        },
+    );
+    body.add_op(
+        link_prev,
+        Operator::I32Store { memory: mem(NEXT) },
+        &[prev, next],
+        &[],
+    );
+    body.set_terminator(link_prev, br(unlinked, vec![]));
+    let head = body.add_op(
+        link_head,
+        Operator::I32Const { value: head_addr },
+        &[],
+        &[Type::I32],
+    );
+    body.add_op(
+        link_head,
+        Operator::I32Store { memory: mem(0) },
+        &[head, next],
+        &[],
+    );
+    body.set_terminator(link_head, br(unlinked, vec![]));
+
+    let zero = body.add_op(unlinked, Operator::I32Const { value: 0 }, &[], &[Type::I32]);
+    body.add_op(
+        unlinked,
+        Operator::I32Store { memory: mem(NEXT) },
+        &[node, zero],
+        &[],
+    );
+    body.add_op(
+        unlinked,
+        Operator::I32Store { memory: mem(PREV) },
+        &[node, zero],
+        &[],
+    );
+    let one = body.add_op(unlinked, Operator::I32Const { value: 1 }, &[], &[Type::I32]);
+    let count_next = body.add_op(unlinked, Operator::I32Add, &[count, one], &[Type::I32]);
+    body.set_terminator(unlinked, br(header, vec![next, count_next]));
+
+    body.set_terminator(
+        exit,
+        Terminator::Return {
+            values: vec![count],
+        },
+    );
+    body.recompute_edges();
+    body
+}
@@ -0,0 +1,308 @@
+//! `weval serve`: a long-running weval for build daemons.
+//!
+//! Requests are JSON-RPC 2.0 messages, one per line on stdin, with
+//! one response per line on stdout. Two methods are supported:
+//!
+//! - `weval`, with params `{"args": [...]}`: the arguments of a
+//!   `weval weval` command line (e.g. `["-w", "-i", "in.wasm", "-o",
+//!   "out.wasm"]`). The result is the run's stats, in the same form as
+//!   `--stats-json`. A failed run's error response has the error in
+//!   the form `--error-json` writes (see `error::to_json`) as its
+//!   `data`.
+//! - `shutdown`: reply, then exit.
+//!
+//! Requests that name no `--cache` or `--cache-ro` share an in-memory
+//! results cache per input module, so that re-running weval on the
+//! same module with a few changed directives only specializes those.
+//! Parsed modules are kept too, by hash of the bytes weval specializes
+//! (after Wizening, with `-w`), so a module is parsed once per server.
+//! Both are kept for the `--max-warm-modules` most recently used
+//! modules only.
+//!
+//! The guest sees an empty stdin while Wizening: the server's stdin
+//! carries the requests.
+
+use crate::cache::{Cache, ModuleHash};
+use serde_json::{json, Value as Json};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+/// How many input modules' results caches, and how many parsed
+/// modules, to keep by default (see `--max-warm-modules`).
+pub(crate) const DEFAULT_MAX_WARM_MODULES: usize = 16;
+
+/// Results caches and parsed modules kept for the lifetime of the
+/// server (or of a `weval batch`), per input module. Each map keeps
+/// at most `capacity` entries, evicting the least recently used.
+pub(crate) struct WarmCaches {
+    caches: Mutex<Lru<Arc<Cache>>>,
+    modules: Mutex<Lru<Arc<WarmModule>>>,
+}
+
+impl Default for WarmCaches {
+    fn default() -> WarmCaches {
+        WarmCaches::new(DEFAULT_MAX_WARM_MODULES)
+    }
+}
+
+impl WarmCaches {
+    pub(crate) fn new(capacity: usize) -> WarmCaches {
+        WarmCaches {
+            caches: Mutex::new(Lru::new(capacity)),
+            modules: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    pub(crate) fn get(&self, module_hash: ModuleHash) -> anyhow::Result<Arc<Cache>> {
+        let mut caches = self.caches.lock().unwrap();
+        if let Some(cache) = caches.get(&module_hash) {
+            return Ok(cache.clone());
+        }
+        let path = cfg!(feature = "cache").then(|| std::path::Path::new(":memory:"));
+        let cache = Arc::new(Cache::open(path, None, module_hash)?);
+        caches.insert(module_hash, cache.clone());
+        Ok(cache)
+    }
+
+    /// The module parsed from `bytes`, whose hash is `bytes_hash`,
+    /// parsing it only the first time.
+    pub(crate) fn module(
+        &self,
+        bytes_hash: ModuleHash,
+        bytes: &[u8],
+        opts: &waffle::FrontendOptions,
+    ) -> anyhow::Result<Arc<WarmModule>> {
+        let mut modules = self.modules.lock().unwrap();
+        if let Some(module) = modules.get(&bytes_hash) {
+            return Ok(module.clone());
+        }
+        let module = Arc::new(WarmModule::parse(bytes.into(), opts)?);
+        modules.insert(bytes_hash, module.clone());
+        Ok(module)
+    }
+}
+
+/// A parsed module with the bytes its lazily-parsed function bodies
+/// borrow.
+pub(crate) struct WarmModule {
+    // Declared first so that it is dropped before `bytes`.
+    module: waffle::Module<'static>,
+    bytes: Arc<[u8]>,
+}
+
+impl WarmModule {
+    fn parse(bytes: Arc<[u8]>, opts: &waffle::FrontendOptions) -> anyhow::Result<WarmModule> {
+        // SAFETY: the slice is on the heap, so it stays put when the
+        // `Arc` moves, and `self.bytes` keeps it alive for as long as
+        // `self.module`, which is only handed out borrowing `self`.
+        let slice: &'static [u8] = unsafe { &*(&*bytes as *const [u8]) };
+        let module = waffle::Module::from_wasm_bytes(slice, opts)?;
+        Ok(WarmModule { module, bytes })
+    }
+
+    /// A copy of the module, to specialize.
+    pub(crate) fn module(&self) -> waffle::Module<'_> {
+        self.module.clone()
+    }
+}
+
+/// A map keeping at most `capacity` entries, evicting the least
+/// recently used. Linear scans are fine at the sizes used here.
+struct Lru<V> {
+    capacity: usize,
+    clock: u64,
+    entries: HashMap<ModuleHash, (u64, V)>,
+}
+
+impl<V> Lru<V> {
+    fn new(capacity: usize) -> Lru<V> {
+        Lru {
+            capacity: capacity.max(1),
+            clock: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &ModuleHash) -> Option<&V> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(key).map(|(used, value)| {
+            *used = clock;
+            &*value
+        })
+    }
+
+    fn insert(&mut self, key: ModuleHash, value: V) {
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                tracing::debug!("evicting the least recently used warm module");
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(key, (self.clock, value));
+    }
+}
+
+// Standard JSON-RPC error codes, and ours for a failed run.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const WEVAL_FAILED: i64 = -32000;
+
+pub(crate) fn serve(max_warm_modules: usize) -> anyhow::Result<()> {
+    let mut out = protocol_output()?;
+    let input = protocol_input()?;
+    let warm = WarmCaches::new(max_warm_modules);
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, response, shutdown) = match serde_json::from_str::<Json>(&line) {
+            Ok(request) => {
+                let id = request.get("id").cloned();
+                let shutdown = request.get("method") == Some(&json!("shutdown"));
+                (id, handle(&request, &warm), shutdown)
+            }
+            Err(e) => (
+                Some(Json::Null),
+                Err(RpcError::new(PARSE_ERROR, format!("parse error: {}", e))),
+                false,
+            ),
+        };
+        // Notifications (requests without an ID) get no response.
+        if let Some(id) = id {
+            let response = match response {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err(RpcError {
+                    code,
+                    message,
+                    data,
+                }) => {
+                    let mut error = json!({ "code": code, "message": message });
+                    if let Some(data) = data {
+                        error["data"] = data;
+                    }
+                    json!({ "jsonrpc": "2.0", "id": id, "error": error })
+                }
+            };
+            writeln!(out, "{}", response)?;
+            out.flush()?;
+        }
+        if shutdown {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A JSON-RPC error response.
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Json>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: String) -> RpcError {
+        RpcError {
+            code,
+            message,
+            data: None,
+        }
+    }
+}
+
+fn handle(request: &Json, warm: &WarmCaches) -> Result<Json, RpcError> {
+    let method = request
+        .get("method")
+        .and_then(|m| m.as_str())
+        .ok_or_else(|| RpcError::new(INVALID_REQUEST, "missing method".to_owned()))?;
+    match method {
+        "weval" => {
+            let args = request
+                .pointer("/params/args")
+                .and_then(|args| args.as_array())
+                .and_then(|args| {
+                    args.iter()
+                        .map(|arg| arg.as_str())
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| {
+                    RpcError::new(
+                        INVALID_PARAMS,
+                        "params must be {\"args\": [string, ...]}".to_owned(),
+                    )
+                })?;
+            let cmd = crate::cli::weval_command(args)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+            crate::cli::run(cmd, Some(warm)).map_err(|e| RpcError {
+                code: WEVAL_FAILED,
+                message: format!("{:#}", e),
+                data: Some(crate::error::to_json(&e)),
+            })
+        }
+        "shutdown" => Ok(Json::Null),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method `{}`", method),
+        )),
+    }
+}
+
+/// Where to write responses. The guest's stdout while Wizening is
+/// replayed to our stdout, which would corrupt the stream, so on
+/// unix, responses go to a copy of the original stdout and fd 1
+/// becomes stderr.
+#[cfg(all(unix, feature = "wizer"))]
+fn protocol_output() -> std::io::Result<Box<dyn Write>> {
+    use std::os::unix::io::FromRawFd;
+    std::io::stdout().flush()?;
+    // SAFETY: `dup` returns a fresh descriptor that the `File` takes
+    // ownership of, and `dup2` only replaces fd 1.
+    unsafe {
+        let fd = libc::dup(1);
+        if fd < 0 || libc::dup2(2, 1) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Box::new(std::fs::File::from_raw_fd(fd)))
+    }
+}
+
+#[cfg(not(all(unix, feature = "wizer")))]
+fn protocol_output() -> std::io::Result<Box<dyn Write>> {
+    Ok(Box::new(std::io::stdout()))
+}
+
+/// Where to read requests from. The guest may read stdin while
+/// Wizening, which would consume requests, so on unix, requests come
+/// from a copy of the original stdin and fd 0 becomes `/dev/null`.
+#[cfg(all(unix, feature = "wizer"))]
+fn protocol_input() -> std::io::Result<Box<dyn BufRead>> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    let null = std::fs::File::open("/dev/null")?;
+    // SAFETY: as in `protocol_output`, the `File` owns the fresh
+    // descriptor from `dup`, and `dup2` only replaces fd 0.
+    unsafe {
+        let fd = libc::dup(0);
+        if fd < 0 || libc::dup2(null.as_raw_fd(), 0) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Box::new(std::io::BufReader::new(
+            std::fs::File::from_raw_fd(fd),
+        )))
+    }
+}
+
+#[cfg(not(all(unix, feature = "wizer")))]
+fn protocol_input() -> std::io::Result<Box<dyn BufRead>> {
+    Ok(Box::new(std::io::stdin().lock()))
+}
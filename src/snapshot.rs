@@ -0,0 +1,119 @@
+//! Verification of modules Wizened outside of weval.
+//!
+//! When the input is a snapshot taken by another Wizer invocation
+//! (rather than with `-w`), `--verify-snapshot` checks that it was
+//! built and snapshotted the way weval expects before specializing
+//! it: every import from the `weval` module is one the stubs (built
+//! in, or given with `--stubs`) provide,
+//! with the same signature; the request-list and `is_wevaled`
+//! accessors are exported; the module has not already been
+//! processed; and the pending request list is a well-formed
+//! doubly-linked list of decodable requests. Without this, such
+//! problems show up as silently missing specializations.
+
+use crate::error::WevalError;
+use crate::image::Image;
+use crate::intrinsics::{find_exported_func, find_global_data_by_exported_func};
+use fxhash::FxHashSet;
+use waffle::{ExportKind, ImportKind, Module, Type};
+
+/// Check that `module`, with memory image `im`, is a coherent weval
+/// snapshot, given the stubs module (binary or text format).
+pub(crate) fn verify(module: &Module, im: &Image, stubs: &[u8]) -> anyhow::Result<()> {
+    verify_imports(module, stubs)?;
+
+    let Some(pending_head_addr) = find_global_data_by_exported_func(module, "weval.pending.head")
+    else {
+        anyhow::bail!(
+            "snapshot does not export `weval.pending.head` returning a constant address; \
+             was it built with `WEVAL_DEFINE_GLOBALS()` from weval.h?"
+        );
+    };
+    let Some(is_wevaled_addr) = find_global_data_by_exported_func(module, "weval.is.wevaled")
+    else {
+        anyhow::bail!(
+            "snapshot does not export `weval.is.wevaled` returning a constant address; \
+             was it built with `WEVAL_DEFINE_GLOBALS()` from weval.h?"
+        );
+    };
+    if find_exported_func(module, "wizer.initialize", &[], &[]).is_some() {
+        tracing::warn!(
+            "snapshot still exports `wizer.initialize`; was it Wizened (with --keep-init-func)?"
+        );
+    }
+
+    let heap = im.main_heap()?;
+    if im.read_u8(heap, is_wevaled_addr)? != 0 {
+        anyhow::bail!("snapshot has `weval_is_wevaled` set: it was already processed by weval");
+    }
+
+    let mut seen = FxHashSet::default();
+    let mut prev = 0;
+    let mut head = im.read_u32(heap, pending_head_addr)?;
+    while head != 0 {
+        if !seen.insert(head) {
+            anyhow::bail!("pending request list has a cycle at request {:#x}", head);
+        }
+        let req_prev = im
+            .read_u32(heap, head + 4)
+            .map_err(|_| anyhow::anyhow!("pending request at {:#x} is outside of memory", head))?;
+        if req_prev != prev {
+            anyhow::bail!(
+                "pending request at {:#x} has `prev` {:#x}, expected {:#x}",
+                head,
+                req_prev,
+                prev
+            );
+        }
+        crate::directive::decode_weval_req(im, heap, head)
+            .map_err(|e| anyhow::anyhow!("pending request at {:#x} is invalid: {}", head, e))?;
+        prev = head;
+        head = im.read_u32(heap, head)?;
+    }
+    tracing::info!("snapshot verified: {} pending requests", seen.len());
+    Ok(())
+}
+
+/// Check every import from the `weval` module against the exports
+/// of the stubs.
+fn verify_imports(module: &Module, stubs: &[u8]) -> anyhow::Result<()> {
+    let stubs_bytes = wat::parse_bytes(stubs)?;
+    let stubs = Module::from_wasm_bytes(&stubs_bytes[..], &Default::default())?;
+    let signature = |module: &Module, f| -> (Vec<Type>, Vec<Type>) {
+        let sig = &module.signatures[module.funcs[f].sig()];
+        (sig.params.clone(), sig.returns.clone())
+    };
+
+    for import in module
+        .imports
+        .iter()
+        .filter(|import| import.module == "weval")
+    {
+        let ImportKind::Func(f) = import.kind else {
+            anyhow::bail!(WevalError::IntrinsicMismatch(format!(
+                "import `weval.{}` is not a function",
+                import.name
+            )));
+        };
+        let stub = stubs.exports.iter().find_map(|export| match export.kind {
+            ExportKind::Func(stub) if export.name == import.name => Some(stub),
+            _ => None,
+        });
+        let Some(stub) = stub else {
+            anyhow::bail!(WevalError::IntrinsicMismatch(format!(
+                "import `weval.{}` is not provided by the weval stubs; \
+                 was the snapshot built against a different weval.h?",
+                import.name
+            )));
+        };
+        if signature(module, f) != signature(&stubs, stub) {
+            anyhow::bail!(WevalError::IntrinsicMismatch(format!(
+                "import `weval.{}` has signature {:?}, but the weval stubs have {:?}",
+                import.name,
+                signature(module, f),
+                signature(&stubs, stub)
+            )));
+        }
+    }
+    Ok(())
+}
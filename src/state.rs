@@ -35,8 +35,9 @@
 //! context implies leaving the current loop.
 
 use crate::image::Image;
-use crate::value::{AbstractValue, WasmVal};
+use crate::value::{AbstractValue, ConstOrigin, WasmVal};
 use fxhash::FxHashMap as HashMap;
+use fxhash::FxHashSet as HashSet;
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
 use waffle::entity::{EntityRef, EntityVec, PerEntity};
@@ -51,6 +52,9 @@ pub(crate) type PC = u32;
 pub(crate) enum ContextElem {
     Root,
     Loop(PC),
+    /// A loop at any cold PC (see `crate::pc_profile`), with the PC a
+    /// runtime value.
+    Cold,
     Specialized(Value, u32),
 }
 
@@ -69,12 +73,25 @@ impl Contexts {
             Entry::Occupied(o) => *o.get(),
             Entry::Vacant(v) => {
                 let id = self.contexts.push((parent, elem.clone()));
-                log::trace!("create context: {}: parent {} leaf {:?}", id, parent, elem);
+                tracing::trace!("create context: {}: parent {} leaf {:?}", id, parent, elem);
                 *v.insert(id)
             }
         }
     }
 
+    /// Whether `create` would return an existing context.
+    pub(crate) fn exists(&self, parent: Context, elem: &ContextElem) -> bool {
+        self.dedup.contains_key(&(parent, elem.clone()))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Context> {
+        self.contexts.iter()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
     pub(crate) fn parent(&self, context: Context) -> Context {
         self.contexts[context].0
     }
@@ -86,7 +103,7 @@ impl Contexts {
     pub(crate) fn pop_one_loop(&self, mut context: Context) -> Context {
         loop {
             match &self.contexts[context] {
-                (parent, ContextElem::Loop(_)) => return *parent,
+                (parent, ContextElem::Loop(_) | ContextElem::Cold) => return *parent,
                 (_, ContextElem::Root) => return context,
                 (parent, _) => {
                     context = *parent;
@@ -94,6 +111,19 @@ impl Contexts {
             }
         }
     }
+
+    /// Whether the innermost loop of `context` is at a cold PC.
+    pub(crate) fn in_cold_loop(&self, mut context: Context) -> bool {
+        loop {
+            match &self.contexts[context] {
+                (_, ContextElem::Cold) => return true,
+                (_, ContextElem::Loop(_) | ContextElem::Root) => return false,
+                (parent, _) => {
+                    context = *parent;
+                }
+            }
+        }
+    }
 }
 
 /// The flow-sensitive part of the state.
@@ -180,6 +210,19 @@ impl RegValue {
         }
     }
 
+    /// Whether `self` is at or below `other` in the lattice. The two
+    /// come from different evaluations of a block, which define fresh
+    /// values, so only types and abstract values are compared.
+    fn is_below(&self, other: &RegValue) -> bool {
+        self.ty() == other.ty() && self.abs().is_below(other.abs())
+    }
+
+    pub(crate) fn abs(&self) -> &AbstractValue {
+        match self {
+            RegValue::Value { abs, .. } | RegValue::Merge { abs, .. } => abs,
+        }
+    }
+
     pub(crate) fn value(&self) -> Option<Value> {
         match self {
             RegValue::Value { data, .. } => Some(*data),
@@ -193,6 +236,13 @@ impl RegValue {
             RegValue::Merge { ty, .. } => *ty,
         }
     }
+
+    fn summary(&self) -> String {
+        match self {
+            RegValue::Value { data, abs, .. } => format!("{} = {:?}", data, abs),
+            RegValue::Merge { abs, .. } => format!("merge = {:?}", abs),
+        }
+    }
 }
 
 /// The state for a function body during analysis.
@@ -202,6 +252,9 @@ pub(crate) struct FunctionState {
     /// AbstractValues in specialized function, indexed by specialized
     /// Value.
     pub values: PerEntity<Value, AbstractValue>,
+    /// Whether each value's abstract value holds module-wide or only
+    /// under this directive, indexed by specialized Value.
+    pub origins: PerEntity<Value, ConstOrigin>,
     /// Block-entry abstract values, indexed by specialized Block.
     pub block_entry: PerEntity<Block, ProgPointState>,
     /// Block-exit abstract values, indexed by specialized Block.
@@ -263,6 +316,30 @@ fn map_meet_with<
     changed
 }
 
+/// Meet `other` into `this`, visiting only the entries where `other`
+/// differs from `prev`, an earlier input that `this` was already met
+/// with. `other` and `prev` must have the same keys.
+fn map_meet_changed<K: Ord, V: PartialEq, Meet: Fn(&V, &V) -> V>(
+    this: &mut BTreeMap<K, V>,
+    other: &BTreeMap<K, V>,
+    prev: &BTreeMap<K, V>,
+    meet: Meet,
+) -> bool {
+    let mut changed = false;
+    for ((k, val), (_, prev_val)) in other.iter().zip(prev.iter()) {
+        if val == prev_val {
+            continue;
+        }
+        // A key missing here was already dropped by an earlier meet.
+        if let Some(this_val) = this.get_mut(k) {
+            let met = meet(this_val, val);
+            changed |= met != *this_val;
+            *this_val = met;
+        }
+    }
+    changed
+}
+
 fn set_union<K: PartialEq + Eq + PartialOrd + Ord + Copy>(
     this: &mut BTreeSet<K>,
     other: &BTreeSet<K>,
@@ -275,7 +352,11 @@ fn set_union<K: PartialEq + Eq + PartialOrd + Ord + Copy>(
 }
 
 impl ProgPointState {
-    pub(crate) fn entry(im: &Image) -> ProgPointState {
+    /// The state at function entry. Besides the stack pointer, `i32`
+    /// globals are taken to be pointers into static memory; other
+    /// globals have their image value if in `const_globals`, and are
+    /// unknown otherwise.
+    pub(crate) fn entry(im: &Image, const_globals: &HashSet<Global>) -> ProgPointState {
         let globals: BTreeMap<Global, AbstractValue> = im
             .globals
             .iter()
@@ -286,6 +367,8 @@ impl ProgPointState {
                 } else if let &WasmVal::I32(addr) = init_val {
                     // GOT base global.
                     (*global, AbstractValue::StaticMemory(addr))
+                } else if const_globals.contains(global) {
+                    (*global, AbstractValue::Concrete(*init_val))
                 } else {
                     (*global, AbstractValue::Runtime(None))
                 }
@@ -300,6 +383,126 @@ impl ProgPointState {
         }
     }
 
+    /// A one-line summary of this state for debugging output. Globals
+    /// are only listed if we know something more than "runtime value"
+    /// about them.
+    pub(crate) fn summary(&self) -> String {
+        let mut parts = vec![];
+        if !self.regs.is_empty() {
+            let regs = self
+                .regs
+                .iter()
+                .map(|(slot, value)| format!("{:?}: {}", slot, value.summary()))
+                .collect::<Vec<_>>();
+            parts.push(format!("regs {{{}}}", regs.join(", ")));
+        }
+        if !self.stack.is_empty() {
+            let stack = self
+                .stack
+                .iter()
+                .map(|(addr, data)| format!("[{}] <- {}", addr.summary(), data.summary()))
+                .collect::<Vec<_>>();
+            parts.push(format!("stack [{}]", stack.join(", ")));
+        }
+        if !self.locals.is_empty() {
+            let locals = self
+                .locals
+                .iter()
+                .map(|(idx, (addr, data))| {
+                    format!("{}: [{}] <- {}", idx, addr.summary(), data.summary())
+                })
+                .collect::<Vec<_>>();
+            parts.push(format!("locals {{{}}}", locals.join(", ")));
+        }
+        let globals = self
+            .globals
+            .iter()
+            .filter(|(_, abs)| !matches!(abs, AbstractValue::Runtime(_)))
+            .map(|(global, abs)| format!("{}: {:?}", global, abs))
+            .collect::<Vec<_>>();
+        if !globals.is_empty() {
+            parts.push(format!("globals {{{}}}", globals.join(", ")));
+        }
+        if parts.is_empty() {
+            "(empty)".to_owned()
+        } else {
+            parts.join("; ")
+        }
+    }
+
+    /// Check that `self`, computed by a later evaluation of a block
+    /// than `old`, is at or below it in the lattice. On failure,
+    /// describes the first slot that moved up.
+    pub(crate) fn check_below(&self, old: &ProgPointState) -> Result<(), String> {
+        // A slot absent in `old` is bottom (lives in memory).
+        let reg_below =
+            |new: &RegValue, old: Option<&RegValue>| old.is_some_and(|old| new.is_below(old));
+        for (slot, value) in &self.regs {
+            if !reg_below(value, old.regs.get(slot)) {
+                return Err(format!(
+                    "reg {:?}: {} -> {}",
+                    slot,
+                    old.regs.get(slot).map(|v| v.summary()).unwrap_or_default(),
+                    value.summary()
+                ));
+            }
+        }
+        for (global, abs) in &self.globals {
+            if let Some(old_abs) = old.globals.get(global) {
+                if !abs.is_below(old_abs) {
+                    return Err(format!("global {}: {:?} -> {:?}", global, old_abs, abs));
+                }
+            }
+        }
+        if self.stack.len() > old.stack.len() {
+            return Err(format!(
+                "stack depth: {} -> {}",
+                old.stack.len(),
+                self.stack.len()
+            ));
+        }
+        for (i, ((addr, data), (old_addr, old_data))) in
+            self.stack.iter().zip(old.stack.iter()).enumerate()
+        {
+            if !addr.is_below(old_addr) || !data.is_below(old_data) {
+                return Err(format!(
+                    "stack slot {}: [{}] <- {} -> [{}] <- {}",
+                    i,
+                    old_addr.summary(),
+                    old_data.summary(),
+                    addr.summary(),
+                    data.summary()
+                ));
+            }
+        }
+        for (idx, (addr, data)) in &self.locals {
+            let old_local = old.locals.get(idx);
+            if !reg_below(addr, old_local.map(|(addr, _)| addr))
+                || !reg_below(data, old_local.map(|(_, data)| data))
+            {
+                return Err(format!(
+                    "local {}: {} -> [{}] <- {}",
+                    idx,
+                    old_local
+                        .map(|(addr, data)| format!("[{}] <- {}", addr.summary(), data.summary()))
+                        .unwrap_or_default(),
+                    addr.summary(),
+                    data.summary()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rough size of the maps and stack, not counting `self`.
+    pub(crate) fn approx_bytes(&self) -> usize {
+        use std::mem::size_of;
+        self.regs.len() * size_of::<(RegSlot, RegValue)>()
+            + self.globals.len() * size_of::<(Global, AbstractValue)>()
+            + self.stack.len() * size_of::<(RegValue, RegValue)>()
+            + self.locals.len() * size_of::<(u32, (RegValue, RegValue))>()
+    }
+
     pub(crate) fn meet_with(&mut self, other: &ProgPointState) -> bool {
         let mut changed = false;
         changed |= map_meet_with(&mut self.regs, &other.regs, RegValue::meet, None);
@@ -334,15 +537,66 @@ impl ProgPointState {
         changed
     }
 
+    /// Meet in `other`, the new input along an edge whose previous
+    /// input was `prev`. Because this state is already below `prev`,
+    /// only the entries that changed since then can lower it; when
+    /// the two inputs have different shapes, fall back to a full
+    /// meet.
+    pub(crate) fn meet_with_changes(
+        &mut self,
+        other: &ProgPointState,
+        prev: &ProgPointState,
+    ) -> bool {
+        fn same_keys<K: Ord, V>(a: &BTreeMap<K, V>, b: &BTreeMap<K, V>) -> bool {
+            a.len() == b.len() && a.keys().eq(b.keys())
+        }
+        if other.stack.len() != prev.stack.len()
+            || !same_keys(&other.regs, &prev.regs)
+            || !same_keys(&other.globals, &prev.globals)
+            || !same_keys(&other.locals, &prev.locals)
+        {
+            return self.meet_with(other);
+        }
+
+        let mut changed = false;
+        changed |= map_meet_changed(&mut self.regs, &other.regs, &prev.regs, RegValue::meet);
+        changed |= map_meet_changed(
+            &mut self.globals,
+            &other.globals,
+            &prev.globals,
+            AbstractValue::meet,
+        );
+        for (this, (other, prev)) in self
+            .stack
+            .iter_mut()
+            .zip(other.stack.iter().zip(prev.stack.iter()))
+        {
+            if other == prev {
+                continue;
+            }
+            let new_addr = RegValue::meet(&this.0, &other.0);
+            changed |= new_addr != this.0;
+            this.0 = new_addr;
+            let new_data = RegValue::meet(&this.1, &other.1);
+            changed |= new_data != this.1;
+            this.1 = new_data;
+        }
+        changed |= map_meet_changed(
+            &mut self.locals,
+            &other.locals,
+            &prev.locals,
+            |(a0, a1), (b0, b1)| (RegValue::meet(a0, b0), RegValue::meet(a1, b1)),
+        );
+
+        changed
+    }
+
     pub(crate) fn update_across_edge(&mut self) {
         let create_merge = |value: &mut RegValue| {
             if let RegValue::Value { ty, abs, .. } = value {
                 // Ensure all specialization-register values become
                 // blockparams, even if only one pred.
-                *value = RegValue::Merge {
-                    ty: *ty,
-                    abs: abs.clone(),
-                };
+                *value = RegValue::Merge { ty: *ty, abs: *abs };
             }
         };
 
@@ -371,7 +625,7 @@ impl ProgPointState {
                 *value = RegValue::Value {
                     data: param,
                     ty: *ty,
-                    abs: abs.clone(),
+                    abs: *abs,
                 };
             }
         };
@@ -396,9 +650,13 @@ impl FunctionState {
         FunctionState::default()
     }
 
-    pub(crate) fn init(&mut self, im: &Image) -> (Context, ProgPointState) {
+    pub(crate) fn init(
+        &mut self,
+        im: &Image,
+        const_globals: &HashSet<Global>,
+    ) -> (Context, ProgPointState) {
         let ctx = self.contexts.create(None, ContextElem::Root);
-        (ctx, ProgPointState::entry(im))
+        (ctx, ProgPointState::entry(im, const_globals))
     }
 
     pub(crate) fn set_args(
@@ -417,12 +675,16 @@ impl FunctionState {
             .zip(args.iter().skip(num_globals))
         {
             let spec_value = *value_map.get(&(ctx, *orig_value)).unwrap();
-            self.values[spec_value] = abs.clone();
+            self.values[spec_value] = *abs;
+            self.origins[spec_value] = match abs {
+                AbstractValue::Runtime(_) => ConstOrigin::Module,
+                _ => ConstOrigin::Directive,
+            };
         }
 
         // Set specialization globals, if any.
         for i in 0..num_globals {
-            self.specialization_globals.push(args[i].clone());
+            self.specialization_globals.push(args[i]);
         }
     }
 }
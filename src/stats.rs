@@ -1,6 +1,8 @@
 //! Post-specialization stats.
 
+use crate::liveness::PressureHint;
 use fxhash::FxHashSet;
+use std::collections::{BTreeMap, BTreeSet};
 use waffle::{Block, Func, FunctionBody};
 
 /// Stats per original/generic function.
@@ -23,7 +25,30 @@ pub(crate) struct SpecializationStats {
     pub local_writes: usize,
     pub local_reads_mem: usize,
     pub local_writes_mem: usize,
+    /// Stores syncing the virtual stack and locals to memory that
+    /// were removed because the slot is overwritten before any read.
+    pub flush_stores_elided: usize,
+    /// Chains of compares of one value against constants replaced
+    /// with a `br_table`.
+    pub switch_chains: usize,
+    /// Loop-invariant instructions hoisted out of loops.
+    pub licm_hoisted: usize,
+    /// Edges into a loop at other than its header, which emission
+    /// removes by duplicating blocks.
+    pub irreducible_edges: usize,
     pub live_value_at_block_start: usize,
+    /// Constants in specialized code that are the same in every
+    /// specialization (from code and the memory image).
+    pub module_consts: usize,
+    /// Constants in specialized code that depend on the directive.
+    pub directive_consts: usize,
+    /// Instructions folded (or, with `--deterministic-folds`, not
+    /// folded) to a NaN whose bits the engine may choose differently.
+    pub nondeterministic_folds: usize,
+    /// Per directive (by user ID), the PCs in each context bucket
+    /// inferred from dispatch, for modules that do not use
+    /// `context.bucket`.
+    pub inferred_buckets: Vec<(u32, BTreeMap<u32, BTreeSet<u32>>)>,
 }
 
 impl SpecializationStats {
@@ -48,10 +73,66 @@ impl SpecializationStats {
         self.local_reads_mem += stats.local_reads_mem;
         self.local_writes += stats.local_writes;
         self.local_writes_mem += stats.local_writes_mem;
+        self.flush_stores_elided += stats.flush_stores_elided;
+        self.switch_chains += stats.switch_chains;
+        self.licm_hoisted += stats.licm_hoisted;
+        self.irreducible_edges += stats.irreducible_edges;
         self.live_value_at_block_start += stats.live_value_at_block_start;
+        self.module_consts += stats.module_consts;
+        self.directive_consts += stats.directive_consts;
+        self.nondeterministic_folds += stats.nondeterministic_folds;
+        self.inferred_buckets
+            .extend(stats.inferred_buckets.iter().cloned());
     }
 }
 
+/// Size of one emitted specialized function, relative to its generic
+/// function.
+#[derive(Clone, Debug)]
+pub(crate) struct SpecializedFuncSize {
+    pub generic: Func,
+    pub specialized: Func,
+    /// The user-given ID and output address of the directive that
+    /// produced this function.
+    pub user_id: u32,
+    pub func_index_out_addr: u32,
+    /// Bytecode sizes, including locals declarations.
+    pub generic_bytes: usize,
+    pub specialized_bytes: usize,
+    pub cache_hit: bool,
+    /// Register-pressure estimate, if specialized in this run (not
+    /// taken from the cache).
+    pub pressure: Option<PressureHint>,
+}
+
+impl SpecializedFuncSize {
+    pub fn growth(&self) -> f64 {
+        (self.specialized_bytes as f64) / (self.generic_bytes as f64)
+    }
+}
+
+/// What became of one directive.
+#[derive(Clone, Debug)]
+pub(crate) struct DirectiveOutcome {
+    pub user_id: u32,
+    pub func: Func,
+    pub func_index_out_addr: u32,
+    pub result: DirectiveResult,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum DirectiveResult {
+    /// A new specialized function was emitted.
+    Specialized,
+    /// The specialized function was taken from the cache.
+    Cached,
+    /// Specialization exceeded size limits and was abandoned.
+    Abandoned,
+    /// Specialization failed with an error: its code (see
+    /// `error::code`) and message.
+    Failed { code: &'static str, message: String },
+}
+
 pub(crate) fn count_reachable_blocks_and_insts(
     body: &FunctionBody,
 ) -> (usize, usize, FxHashSet<Block>) {
@@ -0,0 +1,200 @@
+//! Collapsing compare-and-branch chains into `br_table`s.
+//!
+//! Specialization can leave a chain of blocks that each compare the
+//! same value against a constant and branch on the result, falling
+//! through to the next compare: for example, a dispatch on an opcode
+//! that was only partly folded, or a `switch` that the guest's
+//! compiler lowered to a chain of ifs. Baseline compilers of the
+//! output handle a long chain like this much worse than a single
+//! `br_table`, so when the constants are dense enough we replace the
+//! chain with one.
+//!
+//! A block in the chain after the first is skipped by the `br_table`,
+//! so it must do nothing but compute its compare: it takes no
+//! blockparams, has only pure instructions, and defines no value used
+//! outside it (including in its own branch arguments).
+
+use fxhash::{FxHashMap, FxHashSet};
+use waffle::cfg::CFGInfo;
+use waffle::{Block, BlockTarget, FunctionBody, Operator, Terminator, Type, Value, ValueDef};
+
+/// Fewest distinct constants a chain must compare against.
+const MIN_CASES: usize = 4;
+/// Most `br_table` entries per distinct constant, so that a sparse
+/// chain does not become a huge table of defaults.
+const MAX_ENTRIES_PER_CASE: u64 = 2;
+
+/// Collapse dense compare-and-branch chains into `br_table`s.
+/// Returns the number of chains collapsed.
+pub(crate) fn run(func: &mut FunctionBody, cfg: &CFGInfo) -> usize {
+    let mut def_block = FxHashMap::default();
+    for (block, def) in func.blocks.entries() {
+        for &(_, param) in &def.params {
+            def_block.insert(param, block);
+        }
+        for &inst in &def.insts {
+            def_block.insert(inst, block);
+        }
+    }
+    let mut used_outside = FxHashSet::default();
+    for (block, def) in func.blocks.entries() {
+        let mut visit = |value: Value| {
+            if def_block.get(&value) != Some(&block) {
+                used_outside.insert(value);
+            }
+        };
+        for &inst in &def.insts {
+            func.values[inst].visit_uses(&func.arg_pool, &mut visit);
+        }
+        def.terminator.visit_uses(&mut visit);
+    }
+
+    let mut collapsed = 0;
+    // Blocks skipped by a `br_table`, usually now unreachable, which
+    // we leave alone.
+    let mut skipped = FxHashSet::default();
+    for &block in cfg.rpo.values() {
+        if skipped.contains(&block) {
+            continue;
+        }
+        let Some(chain) = find_chain(func, block, &def_block, &used_outside) else {
+            continue;
+        };
+        tracing::trace!(
+            "switch: collapsing chain of {} compares at {}",
+            chain.cases.len(),
+            block
+        );
+        let min = chain.cases.keys().copied().min().unwrap();
+        let max = chain.cases.keys().copied().max().unwrap();
+        let index = if min == 0 {
+            chain.value
+        } else {
+            let min = func.add_op(block, Operator::I32Const { value: min }, &[], &[Type::I32]);
+            func.add_op(block, Operator::I32Sub, &[chain.value, min], &[Type::I32])
+        };
+        let targets = (min..=max)
+            .map(|k| chain.cases.get(&k).unwrap_or(&chain.default).clone())
+            .collect();
+        func.blocks[block].terminator = Terminator::Select {
+            value: index,
+            targets,
+            default: chain.default,
+        };
+        skipped.extend(chain.skipped);
+        collapsed += 1;
+    }
+    if collapsed > 0 {
+        func.recompute_edges();
+    }
+    collapsed
+}
+
+/// A chain of compares of `value` against constants, starting at one
+/// block.
+struct Chain {
+    value: Value,
+    /// Where each constant branches to; the first compare against a
+    /// constant wins.
+    cases: FxHashMap<u32, BlockTarget>,
+    /// Where the chain goes if no compare matches.
+    default: BlockTarget,
+    /// The blocks after the first.
+    skipped: Vec<Block>,
+}
+
+fn find_chain(
+    func: &FunctionBody,
+    start: Block,
+    def_block: &FxHashMap<Value, Block>,
+    used_outside: &FxHashSet<Value>,
+) -> Option<Chain> {
+    let (value, k, hit, mut miss) = compare_branch(func, start)?;
+    let mut cases = FxHashMap::default();
+    cases.insert(k, hit);
+    let mut skipped = vec![];
+    loop {
+        let next = miss.block;
+        if !miss.args.is_empty()
+            || !func.blocks[next].params.is_empty()
+            || next == start
+            || skipped.contains(&next)
+        {
+            break;
+        }
+        let Some((next_value, k, hit, next_miss)) = compare_branch(func, next) else {
+            break;
+        };
+        let skippable = next_value == value
+            && func.blocks[next].insts.iter().all(|&inst| {
+                !used_outside.contains(&inst)
+                    && matches!(&func.values[inst], ValueDef::Operator(op, ..) if op.is_pure())
+            })
+            && hit
+                .args
+                .iter()
+                .chain(next_miss.args.iter())
+                .all(|arg| def_block.get(arg) != Some(&next));
+        if !skippable {
+            break;
+        }
+        cases.entry(k).or_insert(hit);
+        skipped.push(next);
+        miss = next_miss;
+    }
+
+    let min = u64::from(*cases.keys().min()?);
+    let max = u64::from(*cases.keys().max()?);
+    (cases.len() >= MIN_CASES && max - min < cases.len() as u64 * MAX_ENTRIES_PER_CASE).then_some(
+        Chain {
+            value,
+            cases,
+            default: miss,
+            skipped,
+        },
+    )
+}
+
+/// If `block` ends in a branch on whether an `i32` value equals a
+/// constant, the value, the constant, and the targets if equal and
+/// if not.
+fn compare_branch(
+    func: &FunctionBody,
+    block: Block,
+) -> Option<(Value, u32, BlockTarget, BlockTarget)> {
+    let Terminator::CondBr {
+        cond,
+        if_true,
+        if_false,
+    } = &func.blocks[block].terminator
+    else {
+        return None;
+    };
+    let ValueDef::Operator(op, args, _) = &func.values[*cond] else {
+        return None;
+    };
+    let args = &func.arg_pool[*args];
+    let (value, k) = match op {
+        Operator::I32Eqz => (args[0], 0),
+        Operator::I32Eq | Operator::I32Ne => {
+            match (const_u32(func, args[0]), const_u32(func, args[1])) {
+                (_, Some(k)) => (args[0], k),
+                (Some(k), None) => (args[1], k),
+                (None, None) => return None,
+            }
+        }
+        _ => return None,
+    };
+    if matches!(op, Operator::I32Ne) {
+        Some((value, k, if_false.clone(), if_true.clone()))
+    } else {
+        Some((value, k, if_true.clone(), if_false.clone()))
+    }
+}
+
+fn const_u32(func: &FunctionBody, value: Value) -> Option<u32> {
+    match &func.values[value] {
+        &ValueDef::Operator(Operator::I32Const { value }, ..) => Some(value),
+        _ => None,
+    }
+}
@@ -52,7 +52,10 @@ impl std::convert::TryFrom<waffle::Operator> for WasmVal {
     }
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// An abstract value. This is `Copy` (it owns no heap data), since
+/// values are copied in and out of program-point states constantly
+/// during the fixpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub(crate) enum AbstractValue {
     /// "top" default value; undefined.
     #[default]
@@ -60,30 +63,206 @@ pub(crate) enum AbstractValue {
     /// A value known at specialization time.
     Concrete(WasmVal),
     /// A value that points to memory known at specialization time,
-    /// with the given offset.
+    /// with the given offset. Such a pointer is never null. Pointers
+    /// into different buffers may still be equal: a directive may
+    /// pass the same memory twice.
     ConcreteMemory(MemoryBufferIndex, u32),
     /// Static memory pointer.
     StaticMemory(u32),
+    /// One of a small set of values known at specialization time,
+    /// e.g. at a merge of two paths that each carry a constant. The
+    /// set has at least two elements.
+    ConstSet(ConstSet),
+    /// A reference to a known function, from `ref.func` or loaded
+    /// from a table whose contents are fixed.
+    FuncRef(waffle::Func),
+    /// The table index of the function that the directive with the
+    /// given output address produces. This is known only once all
+    /// specialized functions are emitted, so it is resolved then.
+    SpecializedFuncIndex(u32),
     /// A value only computed at runtime. The instruction that
     /// computed it is specified, if known.
     Runtime(Option<waffle::Value>),
 }
 
+// Abstract values are copied in and out of program-point states
+// constantly, so keep them as small as a `Concrete` needs.
+const _: () = assert!(std::mem::size_of::<AbstractValue>() <= 32);
+
+/// Maximum number of constants tracked in a `ConstSet` before it
+/// widens to `Runtime`. This bounds how many times a value can change
+/// during fixpoint iteration, and the fan-out when evaluating an
+/// operator over every combination of constant inputs. It is also
+/// as many as fit in an `AbstractValue` no larger than a `Concrete`.
+pub(crate) const MAX_CONST_SET: usize = 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum ScalarType {
+    #[default]
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+/// A sorted, deduplicated set of at most `MAX_CONST_SET` scalar
+/// constants of one type, stored inline as bits.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct ConstSet {
+    ty: ScalarType,
+    len: u8,
+    /// Sorted; elements past `len` are zero. Stored as big-endian
+    /// bytes, which sort as the numbers do, so that the set needs no
+    /// alignment and fits beside the tag of a `WasmVal`.
+    bits: [[u8; 8]; MAX_CONST_SET],
+}
+
+impl ConstSet {
+    pub(crate) fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn get(&self, i: usize) -> WasmVal {
+        assert!(i < self.len());
+        let bits = u64::from_be_bytes(self.bits[i]);
+        match self.ty {
+            ScalarType::I32 => WasmVal::I32(bits as u32),
+            ScalarType::I64 => WasmVal::I64(bits),
+            ScalarType::F32 => WasmVal::F32(bits as u32),
+            ScalarType::F64 => WasmVal::F64(bits),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = WasmVal> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+
+    /// Add a value to the set. Returns `false` if the set would
+    /// exceed `MAX_CONST_SET` elements or mix types, or if the value
+    /// is a vector, none of which a set can represent.
+    pub(crate) fn insert(&mut self, val: WasmVal) -> bool {
+        let (ty, bits) = match val {
+            WasmVal::I32(k) => (ScalarType::I32, u64::from(k)),
+            WasmVal::I64(k) => (ScalarType::I64, k),
+            WasmVal::F32(k) => (ScalarType::F32, u64::from(k)),
+            WasmVal::F64(k) => (ScalarType::F64, k),
+            WasmVal::V128(_) => return false,
+        };
+        if self.is_empty() {
+            self.ty = ty;
+        } else if self.ty != ty {
+            return false;
+        }
+        let bits = bits.to_be_bytes();
+        let len = self.len();
+        match self.bits[..len].binary_search(&bits) {
+            Ok(_) => true,
+            Err(_) if len == MAX_CONST_SET => false,
+            Err(pos) => {
+                self.bits.copy_within(pos..len, pos + 1);
+                self.bits[pos] = bits;
+                self.len += 1;
+                true
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for ConstSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Whether a value's abstract knowledge holds for the whole module,
+/// or only under the current directive's arguments.
+///
+/// A constant computed only from constants in the code and from the
+/// memory image is the same in every specialization of a function; a
+/// constant that depends on a directive argument, a specialization
+/// global, or a context specialized on a runtime value is not. Passes
+/// that share or deduplicate code across specializations need to tell
+/// the two apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ConstOrigin {
+    /// "top" default value; not yet defined.
+    #[default]
+    Unknown,
+    /// Holds for every specialization. A `Runtime` value carries no
+    /// knowledge, so it trivially has this origin.
+    Module,
+    /// Holds only under the current directive.
+    Directive,
+}
+
+impl ConstOrigin {
+    pub(crate) fn meet(self, other: ConstOrigin) -> ConstOrigin {
+        match (self, other) {
+            (ConstOrigin::Unknown, x) | (x, ConstOrigin::Unknown) => x,
+            (ConstOrigin::Module, ConstOrigin::Module) => ConstOrigin::Module,
+            _ => ConstOrigin::Directive,
+        }
+    }
+
+    /// Whether `self` is at or below `other`: `Directive` is the
+    /// bottom and `Unknown` the top.
+    pub(crate) fn is_below(self, other: ConstOrigin) -> bool {
+        matches!(
+            (self, other),
+            (_, ConstOrigin::Unknown)
+                | (ConstOrigin::Directive, _)
+                | (ConstOrigin::Module, ConstOrigin::Module)
+        )
+    }
+
+    /// The origin to assume for a use of a value with this origin:
+    /// a value not yet defined (e.g. a placeholder for a merge) may
+    /// turn out to depend on the directive.
+    pub(crate) fn for_use(self) -> ConstOrigin {
+        match self {
+            ConstOrigin::Unknown => ConstOrigin::Directive,
+            x => x,
+        }
+    }
+}
+
 /// Memory pointed to by one of the incoming arguments to a
 /// specialized function.
-#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub(crate) struct MemoryBufferIndex(pub u32);
 
 impl AbstractValue {
     pub(crate) fn meet(a: &AbstractValue, b: &AbstractValue) -> AbstractValue {
         match (a, b) {
-            (AbstractValue::Top, x) | (x, AbstractValue::Top) => x.clone(),
-            (x, y) if x == y => x.clone(),
+            (AbstractValue::Top, x) | (x, AbstractValue::Top) => *x,
+            (x, y) if x == y => *x,
             (AbstractValue::Concrete(a), AbstractValue::Concrete(b)) if a == b => {
                 AbstractValue::Concrete(*a)
             }
+            (
+                AbstractValue::Concrete(_) | AbstractValue::ConstSet(_),
+                AbstractValue::Concrete(_) | AbstractValue::ConstSet(_),
+            ) => {
+                // A vector constant has no set representation: two
+                // different ones meet to `Runtime`, not to the empty set.
+                let mut set = a.const_set_elems();
+                let other = b.const_set_elems();
+                if set.is_empty() || other.is_empty() {
+                    return AbstractValue::Runtime(None);
+                }
+                for val in other.iter() {
+                    if !set.insert(val) {
+                        return AbstractValue::Runtime(None);
+                    }
+                }
+                AbstractValue::const_set(set)
+            }
             (AbstractValue::Runtime(cause1), AbstractValue::Runtime(cause2)) => {
-                log::debug!(
+                tracing::debug!(
                     "runtime({:?} meet runtime({:?}) -> runtime({:?})",
                     cause1,
                     cause2,
@@ -98,6 +277,53 @@ impl AbstractValue {
         }
     }
 
+    /// Whether `self` is at or below `other` in the lattice, i.e.,
+    /// claims no more than `other` does. Meeting into a value must
+    /// only ever move it down; debug builds check this.
+    pub(crate) fn is_below(&self, other: &AbstractValue) -> bool {
+        match (self, other) {
+            (x, y) if x == y => true,
+            (_, AbstractValue::Top) => true,
+            (AbstractValue::Top, _) => false,
+            (AbstractValue::Runtime(_), _) => true,
+            (_, AbstractValue::Runtime(_)) => false,
+            (
+                AbstractValue::Concrete(_) | AbstractValue::ConstSet(_),
+                AbstractValue::Concrete(_) | AbstractValue::ConstSet(_),
+            ) => {
+                let elems = self.const_set_elems();
+                let other = other.const_set_elems();
+                !elems.is_empty()
+                    && !other.is_empty()
+                    && other.iter().all(|val| elems.iter().any(|elem| elem == val))
+            }
+            _ => false,
+        }
+    }
+
+    /// Build a value from a set of possible constants.
+    pub(crate) fn const_set(set: ConstSet) -> AbstractValue {
+        match set.len() {
+            0 => AbstractValue::Top,
+            1 => AbstractValue::Concrete(set.get(0)),
+            _ => AbstractValue::ConstSet(set),
+        }
+    }
+
+    /// The possible constant values of a `Concrete` or `ConstSet`
+    /// value; empty otherwise (including for a `Concrete` vector).
+    pub(crate) fn const_set_elems(&self) -> ConstSet {
+        let mut set = ConstSet::default();
+        match self {
+            AbstractValue::Concrete(val) => {
+                set.insert(*val);
+            }
+            AbstractValue::ConstSet(vals) => set = *vals,
+            _ => {}
+        }
+        set
+    }
+
     pub(crate) fn as_const_u32(&self) -> Option<u32> {
         match self {
             &AbstractValue::Concrete(WasmVal::I32(k)) => Some(k),
@@ -109,6 +335,7 @@ impl AbstractValue {
     pub(crate) fn as_const_u32_or_mem_offset(&self) -> Option<u32> {
         match self {
             &AbstractValue::Concrete(WasmVal::I32(k)) => Some(k),
+            &AbstractValue::StaticMemory(addr) => Some(addr),
             &AbstractValue::ConcreteMemory(_, off) => Some(off),
             _ => None,
         }
@@ -123,6 +350,15 @@ impl AbstractValue {
     }
 
     pub(crate) fn as_const_truthy(&self) -> Option<bool> {
-        self.as_const_u32().map(|k| k != 0)
+        match self {
+            AbstractValue::ConstSet(vals) => {
+                let truthy = vals.get(0).is_truthy();
+                vals.iter()
+                    .all(|val| val.is_truthy() == truthy)
+                    .then_some(truthy)
+            }
+            AbstractValue::ConcreteMemory(..) => Some(true),
+            _ => self.as_const_u32().map(|k| k != 0),
+        }
     }
 }
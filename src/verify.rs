@@ -0,0 +1,309 @@
+//! An IR verifier, run between passes with `--verify`.
+//!
+//! A pass that breaks an invariant of the IR usually goes unnoticed
+//! until a later pass trips over the result, or until the function is
+//! compiled, far from the cause. With `--verify`, specialized bodies
+//! are checked after every pass, and the first pass that leaves a body
+//! broken is reported with the function and what is wrong:
+//!
+//! - every reachable block has a terminator, and its successor list
+//!   matches the terminator;
+//! - every value used is defined, by a block parameter or an
+//!   instruction placed in a block, and its definition dominates the
+//!   use;
+//! - operators' arguments each have a single type; calls, selects,
+//!   memory accesses and constants take and produce the types their
+//!   definitions say; and `PickOutput`s pick an output that exists, of
+//!   the right type;
+//! - branches pass as many arguments as their targets take, of the
+//!   parameters' types, branch conditions are `i32`s, and returns
+//!   return the function's result types.
+
+use fxhash::FxHashMap;
+use waffle::cfg::CFGInfo;
+use waffle::{
+    Block, BlockTarget, FunctionBody, Module, Operator, Terminator, Type, Value, ValueDef,
+};
+
+/// How many violations to report per function.
+const MAX_VIOLATIONS: usize = 10;
+
+/// An IR invariant broken by a pass.
+#[derive(Debug)]
+pub(crate) struct VerifyError {
+    pub pass: &'static str,
+    pub func: String,
+    pub violations: Vec<String>,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "IR verification failed after `{}` in `{}`:",
+            self.pass, self.func
+        )?;
+        for violation in self.violations.iter().take(MAX_VIOLATIONS) {
+            write!(f, "\n  {}", violation)?;
+        }
+        if self.violations.len() > MAX_VIOLATIONS {
+            write!(
+                f,
+                "\n  ... and {} more",
+                self.violations.len() - MAX_VIOLATIONS
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Check `body`, the function `func`, as left by `pass`.
+pub(crate) fn check(
+    module: &Module,
+    body: &FunctionBody,
+    pass: &'static str,
+    func: &str,
+) -> Result<(), VerifyError> {
+    let violations = violations(module, body);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(VerifyError {
+            pass,
+            func: func.to_owned(),
+            violations,
+        })
+    }
+}
+
+fn violations(module: &Module, body: &FunctionBody) -> Vec<String> {
+    let mut bad = vec![];
+
+    // Check successor lists first: the dominance checks below rely on
+    // them.
+    for (block, def) in body.blocks.entries() {
+        let mut succs = vec![];
+        def.terminator.visit_successors(|succ| succs.push(succ));
+        if succs != def.succs {
+            bad.push(format!(
+                "{}: successors {:?}, but its terminator branches to {:?}",
+                block, def.succs, succs
+            ));
+        }
+    }
+    if !bad.is_empty() {
+        return bad;
+    }
+
+    let params = body.locals.values().take(body.n_params).cloned();
+    if !body.blocks[body.entry]
+        .params
+        .iter()
+        .map(|&(ty, _)| ty)
+        .eq(params)
+    {
+        bad.push(format!(
+            "entry {}: parameters do not match the function's",
+            body.entry
+        ));
+    }
+
+    let cfg = CFGInfo::new(body);
+    // Where each value is defined: its block, and its position there
+    // (`None` for block parameters).
+    let mut defs: FxHashMap<Value, (Block, Option<usize>)> = FxHashMap::default();
+    for &block in cfg.rpo.values() {
+        let def = &body.blocks[block];
+        for &(ty, param) in &def.params {
+            if defs.insert(param, (block, None)).is_some() {
+                bad.push(format!("{}: {} is defined twice", block, param));
+            }
+            match body.values[param] {
+                ValueDef::BlockParam(b, _, t) if b == block && t == ty => {}
+                ref other => bad.push(format!(
+                    "{}: parameter {} of type {} is defined as {:?}",
+                    block, param, ty, other
+                )),
+            }
+        }
+        for (i, &inst) in def.insts.iter().enumerate() {
+            if defs.insert(inst, (block, Some(i))).is_some() {
+                bad.push(format!("{}: {} is defined twice", block, inst));
+            }
+        }
+    }
+
+    let ty = |value: Value| body.values[body.resolve_alias(value)].ty(&body.type_pool);
+    for &block in cfg.rpo.values() {
+        let def = &body.blocks[block];
+        let use_error = |value: Value, at: usize, user: &dyn std::fmt::Display| {
+            let value = body.resolve_alias(value);
+            let Some(&(def_block, def_at)) = defs.get(&value) else {
+                return Some(format!(
+                    "{}: {} uses {}, which is not defined in any reachable block",
+                    block, user, value
+                ));
+            };
+            let dominates = if def_block == block {
+                def_at.is_none_or(|def_at| def_at < at)
+            } else {
+                cfg.dominates(def_block, block)
+            };
+            (!dominates).then(|| {
+                format!(
+                    "{}: {} uses {}, whose definition in {} does not dominate it",
+                    block, user, value, def_block
+                )
+            })
+        };
+
+        for (i, &inst) in def.insts.iter().enumerate() {
+            match &body.values[inst] {
+                ValueDef::Operator(op, args, tys) => {
+                    let args = &body.arg_pool[*args];
+                    let tys = &body.type_pool[*tys];
+                    for &arg in args {
+                        bad.extend(use_error(arg, i, &inst));
+                    }
+                    let arg_tys = args.iter().map(|&arg| ty(arg)).collect::<Vec<_>>();
+                    if let Some(n) = arg_tys.iter().position(|ty| ty.is_none()) {
+                        bad.push(format!(
+                            "{}: {} ({}) uses {}, which has no single type",
+                            block, inst, op, args[n]
+                        ));
+                    } else if let Some((inputs, outputs)) = signature(module, op, &arg_tys) {
+                        if !inputs.iter().map(|&t| Some(t)).eq(arg_tys.iter().cloned()) {
+                            bad.push(format!(
+                                "{}: {} ({}) takes {:?}, but is given {:?}",
+                                block, inst, op, inputs, arg_tys
+                            ));
+                        }
+                        if outputs.as_ref().is_some_and(|outputs| &outputs[..] != tys) {
+                            bad.push(format!(
+                                "{}: {} ({}) produces {:?}, but is declared to produce {:?}",
+                                block, inst, op, outputs, tys
+                            ));
+                        }
+                    }
+                }
+                &ValueDef::PickOutput(from, index, pick_ty) => {
+                    bad.extend(use_error(from, i, &inst));
+                    let from = body.resolve_alias(from);
+                    let output = match &body.values[from] {
+                        ValueDef::Operator(_, _, tys) => {
+                            body.type_pool[*tys].get(index as usize).copied()
+                        }
+                        _ => None,
+                    };
+                    if output != Some(pick_ty) {
+                        bad.push(format!(
+                            "{}: {} picks output {} of {}, of type {:?}, as {}",
+                            block, inst, index, from, output, pick_ty
+                        ));
+                    }
+                }
+                // Aliases may stay in place until `resolve_aliases`.
+                &ValueDef::Alias(to) => bad.extend(use_error(to, i, &inst)),
+                other => bad.push(format!(
+                    "{}: instruction {} is defined as {:?}",
+                    block, inst, other
+                )),
+            }
+        }
+
+        let at = def.insts.len();
+        def.terminator
+            .visit_uses(|value| bad.extend(use_error(value, at, &"the terminator")));
+        match &def.terminator {
+            Terminator::None => bad.push(format!("{}: no terminator", block)),
+            Terminator::CondBr { cond: value, .. } | Terminator::Select { value, .. }
+                if ty(*value) != Some(Type::I32) =>
+            {
+                bad.push(format!(
+                    "{}: branches on {} of type {:?}, not i32",
+                    block,
+                    value,
+                    ty(*value)
+                ))
+            }
+            Terminator::Return { values } => {
+                let tys = values.iter().map(|&v| ty(v)).collect::<Vec<_>>();
+                if !body.rets.iter().map(|&t| Some(t)).eq(tys.iter().cloned()) {
+                    bad.push(format!(
+                        "{}: returns {:?}, but the function returns {:?}",
+                        block, tys, body.rets
+                    ));
+                }
+            }
+            _ => {}
+        }
+        let mut check_target = |target: &BlockTarget| {
+            let params = &body.blocks[target.block].params;
+            if target.args.len() != params.len() {
+                bad.push(format!(
+                    "{}: branches to {} with {} arguments, but it takes {}",
+                    block,
+                    target.block,
+                    target.args.len(),
+                    params.len()
+                ));
+                return;
+            }
+            for (&arg, &(param_ty, param)) in target.args.iter().zip(params) {
+                if ty(arg) != Some(param_ty) {
+                    bad.push(format!(
+                        "{}: passes {} of type {:?} to {} of type {} on {}",
+                        block,
+                        arg,
+                        ty(arg),
+                        param,
+                        param_ty,
+                        target.block
+                    ));
+                }
+            }
+        };
+        def.terminator.visit_targets(|target| check_target(target));
+    }
+    bad
+}
+
+/// The types `op` takes and, if known, produces, given the types of
+/// its arguments; `None` if not checked. (waffle does not export its
+/// operator type tables.)
+fn signature(
+    module: &Module,
+    op: &Operator,
+    args: &[Option<Type>],
+) -> Option<(Vec<Type>, Option<Vec<Type>>)> {
+    let sig = |sig: waffle::Signature, extra: Option<Type>| {
+        let sig = &module.signatures[sig];
+        let mut params = sig.params.clone();
+        params.extend(extra);
+        (params, Some(sig.returns.clone()))
+    };
+    Some(match *op {
+        // A function being patched elsewhere is `FuncDecl::None`.
+        Operator::Call { function_index } => match module.funcs.get(function_index)? {
+            waffle::FuncDecl::None => return None,
+            decl => sig(decl.sig(), None),
+        },
+        Operator::CallIndirect { sig_index, .. } => sig(sig_index, Some(Type::I32)),
+        Operator::Select | Operator::TypedSelect { .. } => {
+            let arm = args.first().copied().flatten()?;
+            (vec![arm, arm, Type::I32], Some(vec![arm]))
+        }
+        Operator::I32Const { .. } => (vec![], Some(vec![Type::I32])),
+        Operator::I64Const { .. } => (vec![], Some(vec![Type::I64])),
+        Operator::F32Const { .. } => (vec![], Some(vec![Type::F32])),
+        Operator::F64Const { .. } => (vec![], Some(vec![Type::F64])),
+        _ if op.is_load() => (vec![Type::I32], None),
+        _ if op.is_store() => {
+            let value = args.get(1).copied().flatten()?;
+            (vec![Type::I32, value], Some(vec![]))
+        }
+        _ => return None,
+    })
+}
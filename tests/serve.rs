@@ -0,0 +1,92 @@
+//! `weval serve` end to end, as a build daemon drives it.
+
+#![cfg(all(unix, feature = "wizer"))]
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// A guest that reads stdin while Wizening.
+const READS_STDIN: &str = r#"
+    (module
+      (import "wasi_snapshot_preview1" "fd_read"
+        (func $fd_read (param i32 i32 i32 i32) (result i32)))
+      (memory (export "memory") 1)
+      ;; One iovec: 256 bytes at 16. The count read goes at 8.
+      (data (i32.const 0) "\10\00\00\00\00\01\00\00")
+      (func (export "wizer.initialize")
+        (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8))))
+      (func (export "_start")))
+"#;
+
+/// A server process, killed if the test fails.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn guest_stdin_is_not_the_request_stream() {
+    let dir = std::env::temp_dir().join(format!("weval-serve-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("guest.wasm");
+    let output = dir.join("guest.wevaled.wasm");
+    std::fs::write(&input, wat::parse_str(READS_STDIN).unwrap()).unwrap();
+
+    let mut server = Server(
+        Command::new(env!("CARGO_BIN_EXE_weval"))
+            .arg("serve")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+    let mut requests = server.0.stdin.take().unwrap();
+    let responses = BufReader::new(server.0.stdout.take().unwrap());
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in responses.lines() {
+            let _ = tx.send(line.unwrap());
+        }
+    });
+    let next_response = || {
+        let response = rx.recv_timeout(Duration::from_secs(60));
+        serde_json::from_str::<serde_json::Value>(&response.expect("no response")).unwrap()
+    };
+
+    // Had the guest inherited stdin, it would wait for the next
+    // request and consume it.
+    let args = serde_json::json!([
+        "-w",
+        "-i",
+        input.to_str().unwrap(),
+        "-o",
+        output.to_str().unwrap()
+    ]);
+    let request = serde_json::json!({
+        "jsonrpc": "2.0", "id": 1, "method": "weval", "params": { "args": args }
+    });
+    writeln!(requests, "{}", request).unwrap();
+    let response = next_response();
+    assert!(
+        response["id"] == 1 && response.get("error").is_none(),
+        "unexpected response: {}",
+        response
+    );
+    assert!(output.exists());
+
+    writeln!(
+        requests,
+        r#"{{"jsonrpc": "2.0", "id": 2, "method": "shutdown"}}"#
+    )
+    .unwrap();
+    assert_eq!(next_response()["id"], 2);
+    assert!(server.0.wait().unwrap().success());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}